@@ -0,0 +1,203 @@
+//! # Data Capabilities
+//!
+//! After the handshake both nodes advertise which data types they can produce
+//! and consume. [PeerCapabilities] holds what the two lists have in common, so
+//! an application only ever sends a peer types it can decode.
+
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::DataCodec;
+
+/// Whether a node sends a data type, receives it, or both.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DataUsage {
+    pub produce: bool,
+    pub consume: bool,
+}
+
+impl DataUsage {
+    pub const PRODUCE: DataUsage = DataUsage { produce: true, consume: false };
+    pub const CONSUME: DataUsage = DataUsage { produce: false, consume: true };
+    pub const BOTH: DataUsage = DataUsage { produce: true, consume: true };
+}
+
+/// One data type a node advertises, with the range of versions and the
+/// codecs it handles.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DataCapability {
+    pub data_type: Uuid,
+    pub min_version: u32,
+    pub max_version: u32,
+    pub usage: DataUsage,
+    /// In the node's order of preference
+    pub codecs: Vec<DataCodec>,
+}
+
+impl DataCapability {
+    /// The highest version both capabilities cover, if any.
+    fn common_version(&self, other: &DataCapability) -> Option<u32> {
+        let max = self.max_version.min(other.max_version);
+        (max >= self.min_version.max(other.min_version)).then_some(max)
+    }
+}
+
+/// The version and codec picked for one data type in one direction.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Negotiated {
+    version: u32,
+    codec: DataCodec,
+}
+
+/// The data types that can flow in each direction between us and one peer,
+/// and the version and codec to use for each.
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct PeerCapabilities {
+    send: HashMap<Uuid, Negotiated>,
+    receive: HashMap<Uuid, Negotiated>,
+}
+
+impl PeerCapabilities {
+    /// Intersect our capabilities with the peer's. A type can be sent if we
+    /// produce it and the peer consumes it, and received the other way round,
+    /// as long as the version ranges overlap and there is a codec in common.
+    /// The sender's preferred codec is used in each direction.
+    pub fn negotiate(ours: &[DataCapability], theirs: &[DataCapability]) -> Self {
+        let mut capabilities = PeerCapabilities::default();
+        for our in ours {
+            for their in theirs.iter().filter(|their| their.data_type == our.data_type) {
+                let Some(version) = our.common_version(their) else {
+                    continue;
+                };
+                if our.usage.produce && their.usage.consume {
+                    if let Some(codec) = preferred_codec(&our.codecs, &their.codecs) {
+                        capabilities.send.insert(our.data_type, Negotiated { version, codec });
+                    }
+                }
+                if our.usage.consume && their.usage.produce {
+                    if let Some(codec) = preferred_codec(&their.codecs, &our.codecs) {
+                        capabilities.receive.insert(our.data_type, Negotiated { version, codec });
+                    }
+                }
+            }
+        }
+        capabilities
+    }
+
+    pub fn can_send(&self, data_type: &Uuid) -> bool {
+        self.send.contains_key(data_type)
+    }
+
+    pub fn can_receive(&self, data_type: &Uuid) -> bool {
+        self.receive.contains_key(data_type)
+    }
+
+    /// The version to encode `data_type` with when sending it to the peer
+    pub fn send_version(&self, data_type: &Uuid) -> Option<u32> {
+        self.send.get(data_type).map(|negotiated| negotiated.version)
+    }
+
+    /// The version the peer encodes `data_type` with when sending it to us
+    pub fn receive_version(&self, data_type: &Uuid) -> Option<u32> {
+        self.receive.get(data_type).map(|negotiated| negotiated.version)
+    }
+
+    /// The codec to encode `data_type` with when sending it to the peer
+    pub fn send_codec(&self, data_type: &Uuid) -> Option<DataCodec> {
+        self.send.get(data_type).map(|negotiated| negotiated.codec)
+    }
+
+    /// The codec the peer encodes `data_type` with when sending it to us
+    pub fn receive_codec(&self, data_type: &Uuid) -> Option<DataCodec> {
+        self.receive.get(data_type).map(|negotiated| negotiated.codec)
+    }
+
+    /// Every data type that can be sent to the peer
+    pub fn sendable(&self) -> impl Iterator<Item = &Uuid> {
+        self.send.keys()
+    }
+
+    /// Every data type the peer can send to us
+    pub fn receivable(&self) -> impl Iterator<Item = &Uuid> {
+        self.receive.keys()
+    }
+}
+
+/// The first of the sender's codecs the receiver also supports.
+fn preferred_codec(sender: &[DataCodec], receiver: &[DataCodec]) -> Option<DataCodec> {
+    sender.iter().find(|codec| receiver.contains(codec)).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::DataCodec;
+    use crate::capability::{DataCapability, DataUsage, PeerCapabilities};
+
+    fn capability(data_type: Uuid, versions: (u32, u32), usage: DataUsage) -> DataCapability {
+        DataCapability { data_type, min_version: versions.0, max_version: versions.1, usage, codecs: vec![DataCodec::Bincode] }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let (post, like, poll, only_ours) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let ours = [
+            capability(post, (1, 3), DataUsage::BOTH),
+            capability(like, (1, 1), DataUsage::PRODUCE),
+            capability(poll, (1, 2), DataUsage::BOTH),
+            capability(only_ours, (1, 1), DataUsage::BOTH),
+        ];
+        let theirs = [
+            capability(post, (2, 5), DataUsage::BOTH),
+            capability(like, (1, 1), DataUsage::PRODUCE),
+            capability(poll, (3, 4), DataUsage::BOTH),
+        ];
+
+        let capabilities = PeerCapabilities::negotiate(&ours, &theirs);
+        assert_eq!(capabilities.send_version(&post), Some(3));
+        assert_eq!(capabilities.receive_version(&post), Some(3));
+        // Neither side consumes likes
+        assert!(!capabilities.can_send(&like) && !capabilities.can_receive(&like));
+        // No version in common
+        assert!(!capabilities.can_send(&poll) && !capabilities.can_receive(&poll));
+        assert!(!capabilities.can_send(&only_ours));
+        assert_eq!(capabilities.sendable().collect::<Vec<_>>(), vec![&post]);
+    }
+
+    #[test]
+    fn test_negotiate_one_way() {
+        let post = Uuid::new_v4();
+        let capabilities = PeerCapabilities::negotiate(
+            &[capability(post, (1, 1), DataUsage::PRODUCE)],
+            &[capability(post, (1, 1), DataUsage::CONSUME)],
+        );
+        assert!(capabilities.can_send(&post));
+        assert!(!capabilities.can_receive(&post));
+    }
+
+    /// Each direction uses the sender's most preferred codec that the receiver
+    /// also knows.
+    #[test]
+    fn test_negotiate_codecs() {
+        let (post, like) = (Uuid::new_v4(), Uuid::new_v4());
+        let with_codecs = |data_type, codecs: &[DataCodec]| DataCapability {
+            codecs: codecs.to_vec(),
+            ..capability(data_type, (1, 1), DataUsage::BOTH)
+        };
+        let capabilities = PeerCapabilities::negotiate(
+            &[
+                with_codecs(post, &[DataCodec::Json, DataCodec::Cbor, DataCodec::Bincode]),
+                with_codecs(like, &[DataCodec::Json]),
+            ],
+            &[
+                with_codecs(post, &[DataCodec::Bincode, DataCodec::Cbor]),
+                with_codecs(like, &[DataCodec::MessagePack]),
+            ],
+        );
+        assert_eq!(capabilities.send_codec(&post), Some(DataCodec::Cbor));
+        assert_eq!(capabilities.receive_codec(&post), Some(DataCodec::Bincode));
+        assert!(!capabilities.can_send(&like) && !capabilities.can_receive(&like));
+    }
+}
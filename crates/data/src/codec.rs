@@ -0,0 +1,133 @@
+//! # Data Codecs
+//!
+//! The wire formats a [Data] payload can be encoded in. Bincode is compact and
+//! always available. CBOR, MessagePack and JSON are self-describing, for
+//! debugging and for implementations that aren't written in Rust, and are
+//! available for types that implement serde's traits and list them in
+//! [Data::codecs]. Which one is used for a type is negotiated per peer along
+//! with the rest of the capabilities.
+//!
+//! [Data]: crate::Data
+//! [Data::codecs]: crate::Data::codecs
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use bincode::{Decode, Encode};
+use bincode::error::{DecodeError, EncodeError};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// A wire format for [Data](crate::Data) payloads.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DataCodec {
+    /// bincode with its standard config
+    Bincode,
+    Cbor,
+    MessagePack,
+    Json,
+}
+
+impl DataCodec {
+    /// Every codec, in the order a node prefers them by default
+    pub const ALL: [DataCodec; 4] = [DataCodec::Bincode, DataCodec::Cbor, DataCodec::MessagePack, DataCodec::Json];
+
+    /// Encode `value` with bincode.
+    pub fn encode_bincode<T: Encode>(value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(bincode::encode_to_vec(value, bincode::config::standard())?)
+    }
+
+    /// Decode a whole bincode payload.
+    pub fn decode_bincode<T: Decode>(buf: &[u8]) -> Result<T, CodecError> {
+        let (value, len) = bincode::decode_from_slice(buf, bincode::config::standard())?;
+        if len != buf.len() {
+            return Err(CodecError::Decode(format!("{} trailing bytes after bincode payload", buf.len() - len).into()));
+        }
+        Ok(value)
+    }
+
+    /// Encode `value` with one of the serde based codecs.
+    pub fn encode_serde<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            DataCodec::Bincode => Err(CodecError::Unsupported(self)),
+            DataCodec::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::into_writer(value, &mut buf).map_err(|e| CodecError::Encode(e.into()))?;
+                Ok(buf)
+            }
+            DataCodec::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| CodecError::Encode(e.into())),
+            DataCodec::Json => serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.into())),
+        }
+    }
+
+    /// Decode a payload encoded with one of the serde based codecs.
+    pub fn decode_serde<T: DeserializeOwned>(self, buf: &[u8]) -> Result<T, CodecError> {
+        match self {
+            DataCodec::Bincode => Err(CodecError::Unsupported(self)),
+            DataCodec::Cbor => ciborium::from_reader(buf).map_err(|e| CodecError::Decode(e.into())),
+            DataCodec::MessagePack => rmp_serde::from_slice(buf).map_err(|e| CodecError::Decode(e.into())),
+            DataCodec::Json => serde_json::from_slice(buf).map_err(|e| CodecError::Decode(e.into())),
+        }
+    }
+}
+
+impl From<DataCodec> for u8 {
+    fn from(codec: DataCodec) -> Self {
+        match codec {
+            DataCodec::Bincode => 1,
+            DataCodec::Cbor => 2,
+            DataCodec::MessagePack => 3,
+            DataCodec::Json => 4,
+        }
+    }
+}
+
+impl TryFrom<u8> for DataCodec {
+    type Error = CodecError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(DataCodec::Bincode),
+            2 => Ok(DataCodec::Cbor),
+            3 => Ok(DataCodec::MessagePack),
+            4 => Ok(DataCodec::Json),
+            _ => Err(CodecError::Unknown(value)),
+        }
+    }
+}
+
+/// Why a payload couldn't be encoded or decoded.
+#[derive(Debug)]
+pub enum CodecError {
+    /// The type can't be encoded with this codec
+    Unsupported(DataCodec),
+    /// A codec id this node doesn't know
+    Unknown(u8),
+    Encode(Box<dyn Error + Send + Sync>),
+    Decode(Box<dyn Error + Send + Sync>),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Unsupported(codec) => write!(f, "Type can't be encoded with {codec:?}"),
+            CodecError::Unknown(id) => write!(f, "Unknown codec id {id}"),
+            CodecError::Encode(e) => write!(f, "Failed to encode data: {e}"),
+            CodecError::Decode(e) => write!(f, "Failed to decode data: {e}"),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+impl From<EncodeError> for CodecError {
+    fn from(value: EncodeError) -> Self {
+        CodecError::Encode(value.into())
+    }
+}
+
+impl From<DecodeError> for CodecError {
+    fn from(value: DecodeError) -> Self {
+        CodecError::Decode(value.into())
+    }
+}
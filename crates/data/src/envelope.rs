@@ -0,0 +1,148 @@
+//! # Signed Envelopes
+//!
+//! A [SignedData] wraps a [RawData] payload with where it came from: the origin
+//! node's hostname, the object's id and when it was created. The origin signs
+//! all of it with the key it publishes at `_osp.<hostname>`, so a receiver can
+//! check the object against the origin's key no matter how many relays it
+//! passed through. Signing and verifying need the node's keys and DNS, and are
+//! done by the server SDK.
+//!
+//! The signature covers the encoded bytes, so relays have to forward the
+//! payload as is. Re-encoding it with another codec drops the signature.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bincode::{BorrowDecode, Decode, Encode};
+use bincode::de::{BorrowDecoder, Decoder};
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use uuid::Uuid;
+
+use crate::RawData;
+
+const TRANSCRIPT_LABEL: &[u8] = b"osp data envelope v1";
+
+/// A payload signed by the node it originated on.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SignedData {
+    /// Hostname of the node that created the object
+    pub origin: String,
+    pub object_id: String,
+    /// Milliseconds since the Unix epoch
+    pub created_at: u64,
+    pub data: RawData,
+    /// The origin's signature over [SignedData::transcript]
+    pub signature: Vec<u8>,
+}
+
+impl SignedData {
+    /// An envelope created now, to be signed by setting
+    /// [SignedData::signature].
+    pub fn unsigned(origin: String, object_id: String, data: RawData) -> Self {
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        SignedData { origin, object_id, created_at, data, signature: Vec::new() }
+    }
+
+    pub fn type_id(&self) -> Uuid {
+        self.data.type_id
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.created_at)
+    }
+
+    /// What the origin signs. Every field is length prefixed so no two
+    /// envelopes share a transcript.
+    pub fn transcript(&self) -> Vec<u8> {
+        let mut transcript = TRANSCRIPT_LABEL.to_vec();
+        for field in [self.origin.as_bytes(), self.object_id.as_bytes()] {
+            transcript.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            transcript.extend_from_slice(field);
+        }
+        transcript.extend_from_slice(&self.created_at.to_be_bytes());
+        transcript.extend_from_slice(self.data.type_id.as_bytes());
+        transcript.extend_from_slice(&self.data.version.to_be_bytes());
+        transcript.push(self.data.codec.into());
+        transcript.extend_from_slice(&(self.data.bytes.len() as u32).to_be_bytes());
+        transcript.extend_from_slice(&self.data.bytes);
+        transcript
+    }
+}
+
+impl Encode for SignedData {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.origin.encode(encoder)?;
+        self.object_id.encode(encoder)?;
+        self.created_at.encode(encoder)?;
+        self.data.encode(encoder)?;
+        self.signature.encode(encoder)
+    }
+}
+
+impl Decode for SignedData {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(SignedData {
+            origin: String::decode(decoder)?,
+            object_id: String::decode(decoder)?,
+            created_at: u64::decode(decoder)?,
+            data: <RawData as Decode>::decode(decoder)?,
+            signature: Vec::decode(decoder)?,
+        })
+    }
+}
+
+impl<'de> BorrowDecode<'de> for SignedData {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::{DataCodec, RawData, SignedData};
+
+    fn envelope() -> SignedData {
+        SignedData {
+            origin: "a.example".to_string(),
+            object_id: "post/1".to_string(),
+            created_at: 1_700_000_000_000,
+            data: RawData::new(Uuid::new_v4(), 1, DataCodec::Bincode, vec![1, 2, 3]),
+            signature: vec![9; 4],
+        }
+    }
+
+    /// Moving bytes between the origin and the object id changes the
+    /// transcript, as does any other field.
+    #[test]
+    fn test_transcript_covers_every_field() {
+        let original = envelope();
+        let mut shifted = envelope();
+        shifted.data = original.data.clone();
+        shifted.origin = "a.exampl".to_string();
+        shifted.object_id = "epost/1".to_string();
+        assert_ne!(original.transcript(), shifted.transcript());
+
+        let mut changed = original.clone();
+        changed.data.codec = DataCodec::Json;
+        assert_ne!(original.transcript(), changed.transcript());
+        changed = original.clone();
+        changed.created_at += 1;
+        assert_ne!(original.transcript(), changed.transcript());
+        changed = original.clone();
+        changed.signature.clear();
+        assert_eq!(original.transcript(), changed.transcript());
+    }
+
+    #[test]
+    fn test_envelope_round_trip() {
+        let envelope = envelope();
+        let config = bincode::config::standard();
+        let encoded = bincode::encode_to_vec(&envelope, config).unwrap();
+        let (decoded, _): (SignedData, _) = bincode::decode_from_slice(&encoded, config).unwrap();
+        assert_eq!(decoded, envelope);
+    }
+}
@@ -1,12 +1,47 @@
 use bincode::{Decode, Encode};
 use bincode::error::{DecodeError, EncodeError};
-use bytes::{Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use uuid::Uuid;
 
+// Lets the derive macro's `::osp_data` paths resolve inside this crate too
+extern crate self as osp_data;
+
+mod capability;
+mod codec;
+mod envelope;
+mod raw;
+mod registry;
+mod schema;
+mod value;
+
+pub use bincode;
+pub use uuid;
+
+pub use capability::{DataCapability, DataUsage, PeerCapabilities};
+pub use codec::{CodecError, DataCodec};
+pub use envelope::SignedData;
+pub use osp_data_derive::{Data, Describe};
+pub use raw::RawData;
+pub use registry::{assert_unique_ids, AnyData, DataRegistry, RegistryError};
+pub use schema::{DataSchema, Describe, SchemaField, SchemaType, SchemaVariant, MAX_SCHEMA_DEPTH};
+pub use value::{DataValue, MAX_EMPTY_ITEMS};
+
 
 pub trait Data {
     fn get_id() -> Uuid where Self : Sized;
 
+    /// The version of the type's encoding, advertised to peers along with its
+    /// id. Defaults to `1`.
+    fn get_version() -> u32 where Self : Sized {
+        1
+    }
+
+    /// A description of the type's fields, if it has one. `#[derive(Data)]`
+    /// provides it.
+    fn schema() -> Option<DataSchema> where Self : Sized {
+        None
+    }
+
     fn decode_from_bytes(buf: &Bytes) -> Result<(Self, usize), DecodeError>
     where
         Self : Decode + Sized
@@ -16,12 +51,253 @@ pub trait Data {
         Ok(res)
     }
 
+    /// Append `obj` to `buf` with bincode, growing it as needed, and return
+    /// how many bytes were written.
     fn encode_to_bytes(buf: &mut BytesMut, obj: Self) -> Result<usize, EncodeError>
     where
         Self : Encode + Sized
     {
         let config = bincode::config::standard();
-        let len = bincode::encode_into_slice(obj, buf, config)?;
+        let len = bincode::encode_into_std_write(obj, &mut buf.writer(), config)?;
         Ok(len)
     }
+
+    /// The codecs this type can be encoded with, in order of preference.
+    /// Defaults to bincode only.
+    fn codecs() -> &'static [DataCodec] where Self : Sized {
+        &[DataCodec::Bincode]
+    }
+
+    /// Encode `self` with `codec`, which must be one of [Data::codecs].
+    fn encode_with(&self, codec: DataCodec) -> Result<Vec<u8>, CodecError>
+    where
+        Self : Encode + Sized
+    {
+        match codec {
+            DataCodec::Bincode => DataCodec::encode_bincode(self),
+            codec => Err(CodecError::Unsupported(codec)),
+        }
+    }
+
+    /// Decode a whole payload encoded with `codec`, which must be one of
+    /// [Data::codecs].
+    fn decode_with(codec: DataCodec, buf: &[u8]) -> Result<Self, CodecError>
+    where
+        Self : Decode + Sized
+    {
+        match codec {
+            DataCodec::Bincode => DataCodec::decode_bincode(buf),
+            codec => Err(CodecError::Unsupported(codec)),
+        }
+    }
+}
+
+/// The id and version of a [Data] type as constants, so they can be checked at
+/// compile time. Implemented by `#[derive(Data)]`.
+pub trait DataType: Data {
+    const ID: Uuid;
+    const VERSION: u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::{Decode, Encode};
+    use bytes::{Bytes, BytesMut};
+    use serde::{Deserialize, Serialize};
+    use uuid::{uuid, Uuid};
+
+    use crate::{assert_unique_ids, data_registry, CodecError, Data, DataCodec, DataRegistry, DataType, Describe, SchemaType};
+
+    #[derive(Data, PartialEq, Debug)]
+    #[osp(id = "1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c01", version = 3)]
+    struct Post {
+        title: String,
+        tags: Vec<String>,
+        reply_to: Option<u64>,
+    }
+
+    #[derive(Data, PartialEq, Debug)]
+    #[osp(id = "1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c02")]
+    struct Like(u64, bool);
+
+    #[derive(Data, PartialEq, Debug)]
+    #[osp(id = "1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c03")]
+    enum Reaction {
+        Cleared,
+        Emoji(String),
+        Custom { name: String, url: String },
+    }
+
+    #[derive(Data, PartialEq, Debug)]
+    #[osp(id = "1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c06", version = 2)]
+    struct Thread {
+        root: Post,
+        replies: Vec<Reaction>,
+        pinned: Option<Pin>,
+    }
+
+    #[derive(Describe, Encode, Decode, PartialEq, Debug)]
+    struct Pin {
+        until: u64,
+    }
+
+    #[derive(Data, PartialEq, Debug)]
+    #[osp(id = "1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c04")]
+    struct Wrapper<T> {
+        inner: T,
+    }
+
+    #[derive(Data, Serialize, Deserialize, PartialEq, Debug)]
+    #[osp(id = "1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c05", codecs(json, cbor, msgpack))]
+    struct Profile {
+        name: String,
+        followers: u64,
+        links: Vec<String>,
+    }
+
+    fn profile() -> Profile {
+        Profile { name: "alice".to_string(), followers: 12, links: vec!["https://example.com".to_string()] }
+    }
+
+    fn round_trip<T: Data + Encode + Decode + PartialEq + std::fmt::Debug>(value: T) {
+        let encoded = bincode::encode_to_vec(&value, bincode::config::standard()).unwrap();
+        let (decoded, len) = T::decode_from_bytes(&Bytes::from(encoded.clone())).unwrap();
+        assert_eq!(len, encoded.len());
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_derive_ids() {
+        assert_eq!(Post::get_id(), uuid!("1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c01"));
+        assert_eq!(<Post as DataType>::ID, Post::get_id());
+        assert_eq!(Post::get_version(), 3);
+        assert_eq!(Like::get_version(), 1);
+        assert_eq!(Wrapper::<u8>::get_id(), uuid!("1d6e4a0c-8f0e-4b7a-9c55-2e1f0a3b4c04"));
+    }
+
+    #[test]
+    fn test_derive_round_trip() {
+        round_trip(Post { title: "Hello".to_string(), tags: vec!["a".to_string()], reply_to: Some(4) });
+        round_trip(Like(7, true));
+        round_trip(Reaction::Cleared);
+        round_trip(Reaction::Emoji("+1".to_string()));
+        round_trip(Reaction::Custom { name: "party".to_string(), url: "https://example.com".to_string() });
+        round_trip(Wrapper { inner: Like(1, false) });
+    }
+
+    /// Matches what bincode's own derive produces, so the two can be mixed.
+    #[test]
+    fn test_derive_matches_bincode() {
+        #[derive(Encode)]
+        struct Plain {
+            title: String,
+            tags: Vec<String>,
+            reply_to: Option<u64>,
+        }
+
+        let config = bincode::config::standard();
+        let derived = Post { title: "Hi".to_string(), tags: vec![], reply_to: None };
+        let plain = Plain { title: "Hi".to_string(), tags: vec![], reply_to: None };
+        assert_eq!(bincode::encode_to_vec(derived, config).unwrap(), bincode::encode_to_vec(plain, config).unwrap());
+    }
+
+    #[test]
+    fn test_unknown_variant_rejected() {
+        let encoded = bincode::encode_to_vec(9u32, bincode::config::standard()).unwrap();
+        assert!(Reaction::decode_from_bytes(&Bytes::from(encoded)).is_err());
+    }
+
+    #[test]
+    fn test_data_registry_macro() {
+        let registry = data_registry![Post, Like, Reaction];
+        for id in [Post::get_id(), Like::get_id(), Reaction::get_id()] {
+            assert!(registry.contains(&id));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Two data types share the same id")]
+    fn test_duplicate_ids_caught() {
+        let id = Uuid::new_v4();
+        assert_unique_ids(&[Post::get_id(), id, Like::get_id(), id]);
+    }
+
+    /// Used to panic once the payload outgrew the buffer's capacity.
+    #[test]
+    fn test_encode_to_bytes_grows() {
+        let mut buf = BytesMut::new();
+        let post = Post { title: "x".repeat(1024), tags: vec![], reply_to: None };
+        let len = Post::encode_to_bytes(&mut buf, post).unwrap();
+        let len = len + Like::encode_to_bytes(&mut buf, Like(1, true)).unwrap();
+        assert_eq!(len, buf.len());
+
+        let (decoded, read) = Post::decode_from_bytes(&buf.freeze()).unwrap();
+        assert_eq!(decoded.title.len(), 1024);
+        assert!(read < len);
+    }
+
+    #[test]
+    fn test_codecs_round_trip() {
+        assert_eq!(Profile::codecs(), &[DataCodec::Json, DataCodec::Cbor, DataCodec::MessagePack, DataCodec::Bincode]);
+        for codec in DataCodec::ALL {
+            let encoded = profile().encode_with(codec).unwrap();
+            assert_eq!(Profile::decode_with(codec, &encoded).unwrap(), profile(), "{codec:?}");
+        }
+
+        let json: serde_json::Value = serde_json::from_slice(&profile().encode_with(DataCodec::Json).unwrap()).unwrap();
+        assert_eq!(json["followers"], 12);
+    }
+
+    #[test]
+    fn test_unsupported_codec() {
+        assert_eq!(Like::codecs(), &[DataCodec::Bincode]);
+        assert!(matches!(Like(1, true).encode_with(DataCodec::Json), Err(CodecError::Unsupported(DataCodec::Json))));
+        assert!(matches!(Like::decode_with(DataCodec::Cbor, &[]), Err(CodecError::Unsupported(DataCodec::Cbor))));
+        assert!(matches!(DataCodec::try_from(0), Err(CodecError::Unknown(0))));
+    }
+
+    #[test]
+    fn test_trailing_bytes_rejected() {
+        let mut encoded = Like(1, true).encode_with(DataCodec::Bincode).unwrap();
+        encoded.push(0);
+        assert!(matches!(Like::decode_with(DataCodec::Bincode, &encoded), Err(CodecError::Decode(_))));
+    }
+
+    #[test]
+    fn test_registry_decodes_with_codec() {
+        let registry = data_registry![Profile, Like];
+        let encoded = profile().encode_with(DataCodec::MessagePack).unwrap();
+        let value = registry.decode_with(Profile::get_id(), DataCodec::MessagePack, &encoded).unwrap();
+        assert_eq!(value.downcast_ref::<Profile>(), Some(&profile()));
+
+        let capability = registry.capabilities().into_iter().find(|c| c.data_type == Profile::get_id()).unwrap();
+        assert_eq!(capability.codecs, Profile::codecs());
+        assert!(DataRegistry::new().decode_with(Like::get_id(), DataCodec::Json, &[]).is_err());
+    }
+
+    #[test]
+    fn test_derived_schema() {
+        let schema = Thread::schema().unwrap();
+        assert_eq!((schema.id, schema.version, schema.name()), (Thread::get_id(), 2, Some("Thread")));
+        let SchemaType::Struct { fields, .. } = &schema.shape else {
+            panic!("Expected a struct schema");
+        };
+        assert_eq!(fields.iter().map(|field| field.name.as_str()).collect::<Vec<_>>(), ["root", "replies", "pinned"]);
+        assert!(fields[2].is_optional());
+        assert_eq!(fields[0].shape, Post::describe());
+
+        assert_eq!(Like::describe(), SchemaType::TupleStruct { name: "Like".to_string(), fields: vec![SchemaType::U64, SchemaType::Bool] });
+        let SchemaType::Enum { variants, .. } = Reaction::describe() else {
+            panic!("Expected an enum schema");
+        };
+        assert_eq!(variants[0].shape, SchemaType::Unit);
+        assert_eq!(variants[1].shape, SchemaType::String);
+        assert!(matches!(&variants[2].shape, SchemaType::Struct { name, .. } if name == "Custom"));
+
+        let registry = data_registry![Thread, Like];
+        assert_eq!(registry.schema(&Thread::get_id()), Some(&schema));
+        assert_eq!(registry.schema_version(&Thread::get_id(), 1), None);
+        let json = registry.schema(&Like::get_id()).unwrap().to_json_schema();
+        assert_eq!(json["prefixItems"][1]["type"], "boolean");
+    }
 }
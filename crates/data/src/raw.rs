@@ -0,0 +1,246 @@
+//! # Raw Data
+//!
+//! [RawData] carries an encoded payload along with everything needed to make
+//! sense of it later: the type's id and version and the codec it was encoded
+//! with. Relays store and forward it without registering the type, and decode
+//! it into a [DataValue] when a schema for the type is available.
+
+use bincode::{BorrowDecode, Decode, Encode};
+use bincode::de::{BorrowDecoder, Decoder};
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use bytes::Bytes;
+use uuid::Uuid;
+
+use crate::{AnyData, CodecError, Data, DataCodec, DataRegistry, DataSchema, DataValue, RegistryError};
+
+/// An encoded payload of any data type.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RawData {
+    pub type_id: Uuid,
+    pub version: u32,
+    pub codec: DataCodec,
+    pub bytes: Bytes,
+}
+
+impl RawData {
+    pub fn new(type_id: Uuid, version: u32, codec: DataCodec, bytes: impl Into<Bytes>) -> Self {
+        RawData { type_id, version, codec, bytes: bytes.into() }
+    }
+
+    /// Encode `value` with `codec`, which must be one of [Data::codecs].
+    pub fn encode<T: Data + Encode>(value: &T, codec: DataCodec) -> Result<Self, CodecError> {
+        Ok(RawData::new(T::get_id(), T::get_version(), codec, value.encode_with(codec)?))
+    }
+
+    /// Decode the payload as `T`, which has to be the payload's type and
+    /// version. Use [RawData::decode_any] to upgrade older versions.
+    pub fn decode<T: Data + Decode>(&self) -> Result<T, CodecError> {
+        if self.type_id != T::get_id() || self.version != T::get_version() {
+            return Err(CodecError::Decode(format!(
+                "Payload is version {} of {}, not version {} of {}",
+                self.version, self.type_id, T::get_version(), T::get_id()
+            ).into()));
+        }
+        T::decode_with(self.codec, &self.bytes)
+    }
+
+    /// Decode the payload with the type registered for it, upgrading it to
+    /// the newest version.
+    pub fn decode_any(&self, registry: &DataRegistry) -> Result<AnyData, RegistryError> {
+        registry.decode_version(self.type_id, self.version, self.codec, &self.bytes)
+    }
+
+    /// Decode the payload without its Rust type by following `schema`, which
+    /// has to describe the payload's type and version.
+    pub fn to_value(&self, schema: &DataSchema) -> Result<DataValue, CodecError> {
+        self.check_schema(schema)?;
+        DataValue::decode(&schema.shape, self.codec, &self.bytes)
+    }
+
+    /// Encode `value`, laid out as `schema` describes, with `codec`.
+    pub fn from_value(value: &DataValue, schema: &DataSchema, codec: DataCodec) -> Result<Self, CodecError> {
+        Ok(RawData::new(schema.id, schema.version, codec, value.encode(&schema.shape, codec)?))
+    }
+
+    /// The same payload encoded with `codec` instead, going through a
+    /// [DataValue] described by `schema`.
+    pub fn reencode(&self, codec: DataCodec, schema: &DataSchema) -> Result<Self, CodecError> {
+        if codec == self.codec {
+            return Ok(self.clone());
+        }
+        RawData::from_value(&self.to_value(schema)?, schema, codec)
+    }
+
+    fn check_schema(&self, schema: &DataSchema) -> Result<(), CodecError> {
+        if schema.id != self.type_id || schema.version != self.version {
+            return Err(CodecError::Decode(format!(
+                "Schema describes version {} of {}, but the payload is version {} of {}",
+                schema.version, schema.id, self.version, self.type_id
+            ).into()));
+        }
+        Ok(())
+    }
+}
+
+/// Stores the envelope with its payload, so it can be kept and read back
+/// without knowing the type.
+impl Encode for RawData {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.type_id.as_u128().encode(encoder)?;
+        self.version.encode(encoder)?;
+        u8::from(self.codec).encode(encoder)?;
+        self.bytes.as_ref().encode(encoder)
+    }
+}
+
+impl Decode for RawData {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let type_id = Uuid::from_u128(u128::decode(decoder)?);
+        let version = u32::decode(decoder)?;
+        let codec = DataCodec::try_from(u8::decode(decoder)?).map_err(|e| DecodeError::OtherString(e.to_string()))?;
+        let bytes = Vec::<u8>::decode(decoder)?;
+        Ok(RawData::new(type_id, version, codec, bytes))
+    }
+}
+
+impl<'de> BorrowDecode<'de> for RawData {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        <Self as Decode>::decode(decoder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::{data_registry, Data, DataCodec, DataValue, Describe, RawData, SchemaType};
+
+    #[derive(Data, Serialize, Deserialize, PartialEq, Debug)]
+    #[osp(id = "5a1b8f64-0f1c-4f4a-8a1e-3c0d9b7e2a11", version = 2, codecs(json, cbor, msgpack))]
+    struct Event {
+        title: String,
+        attendees: Vec<u32>,
+        starts: i64,
+        location: Option<Location>,
+        status: Status,
+        tags: BTreeMap<u16, String>,
+        score: f64,
+        grid: [u8; 2],
+        id: Id,
+    }
+
+    #[derive(Describe, bincode::Encode, bincode::Decode, Serialize, Deserialize, PartialEq, Debug)]
+    struct Location {
+        lat: f32,
+        name: Option<String>,
+    }
+
+    #[derive(Describe, bincode::Encode, bincode::Decode, Serialize, Deserialize, PartialEq, Debug)]
+    enum Status {
+        Cancelled,
+        Moved(String),
+        Delayed { minutes: u16, reason: char },
+    }
+
+    #[derive(Describe, bincode::Encode, bincode::Decode, Serialize, Deserialize, PartialEq, Debug)]
+    struct Id(u64);
+
+    fn event(status: Status) -> Event {
+        Event {
+            title: "Launch".to_string(),
+            attendees: vec![1, 2, 300],
+            starts: -5,
+            location: Some(Location { lat: 1.5, name: None }),
+            status,
+            tags: BTreeMap::from([(1, "a".to_string()), (7, "b".to_string())]),
+            score: 0.25,
+            grid: [3, 4],
+            id: Id(9),
+        }
+    }
+
+    /// Every codec decodes to the same value, and converting between codecs
+    /// without the Rust type still decodes as the Rust type afterwards.
+    #[test]
+    fn test_value_across_codecs() {
+        let schema = Event::schema().unwrap();
+        for status in [Status::Cancelled, Status::Moved("Hall B".to_string()), Status::Delayed { minutes: 5, reason: '!' }] {
+            let event = event(status);
+            let expected = RawData::encode(&event, DataCodec::Bincode).unwrap().to_value(&schema).unwrap();
+            for codec in DataCodec::ALL {
+                let raw = RawData::encode(&event, codec).unwrap();
+                assert_eq!(raw.to_value(&schema).unwrap(), expected, "{codec:?}");
+                for target in DataCodec::ALL {
+                    let converted = raw.reencode(target, &schema).unwrap();
+                    assert_eq!(converted.codec, target);
+                    assert_eq!(converted.decode::<Event>().unwrap(), event, "{codec:?} -> {target:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_value_contents() {
+        let raw = RawData::encode(&event(Status::Cancelled), DataCodec::Json).unwrap();
+        let DataValue::Struct(fields) = raw.to_value(&Event::schema().unwrap()).unwrap() else {
+            panic!("Expected a struct value");
+        };
+        assert_eq!(fields[0], ("title".to_string(), DataValue::String("Launch".to_string())));
+        assert_eq!(fields[2].1, DataValue::Int(-5));
+        assert_eq!(fields[4].1, DataValue::Variant { name: "Cancelled".to_string(), value: Box::new(DataValue::Unit) });
+        assert_eq!(fields[8].1, DataValue::Tuple(vec![DataValue::UInt(9)]));
+    }
+
+    #[test]
+    fn test_empty_items_bounded() {
+        let units = SchemaType::List(Box::new(SchemaType::Unit));
+        let value = DataValue::decode(&units, DataCodec::Bincode, &[3]).unwrap();
+        assert_eq!(value, DataValue::List(vec![DataValue::Unit; 3]));
+
+        // A billion units in five bytes
+        let mut encoded = vec![252];
+        encoded.extend_from_slice(&1_000_000_000u32.to_le_bytes());
+        assert!(DataValue::decode(&units, DataCodec::Bincode, &encoded).is_err());
+
+        // Spread over many lists, and over arrays the schema asks for
+        let mut encoded = vec![251, 1, 1];
+        encoded.extend([251, 0, 1].repeat(257));
+        assert!(DataValue::decode(&SchemaType::List(Box::new(units)), DataCodec::Bincode, &encoded).is_err());
+        let array = SchemaType::Array { item: Box::new(SchemaType::Tuple(vec![])), len: u32::MAX };
+        assert!(DataValue::decode(&array, DataCodec::Bincode, &[]).is_err());
+    }
+
+    #[test]
+    fn test_mismatches_rejected() {
+        let schema = Event::schema().unwrap();
+        let raw = RawData::encode(&event(Status::Cancelled), DataCodec::Bincode).unwrap();
+        let mut other_version = raw.clone();
+        other_version.version = 1;
+        assert!(other_version.to_value(&schema).is_err());
+        assert!(other_version.decode::<Event>().is_err());
+
+        let value = DataValue::Struct(vec![("title".to_string(), DataValue::Bool(true))]);
+        for codec in DataCodec::ALL {
+            assert!(RawData::from_value(&value, &schema, codec).is_err());
+        }
+
+        let json = RawData::new(raw.type_id, raw.version, DataCodec::Json, &br#"{"title": 4}"#[..]);
+        assert!(json.to_value(&schema).is_err());
+    }
+
+    #[test]
+    fn test_stored_and_decoded_by_registry() {
+        let raw = RawData::encode(&event(Status::Cancelled), DataCodec::MessagePack).unwrap();
+        let config = bincode::config::standard();
+        let stored = bincode::encode_to_vec(&raw, config).unwrap();
+        let (read, _): (RawData, _) = bincode::decode_from_slice(&stored, config).unwrap();
+        assert_eq!(read, raw);
+
+        let registry = data_registry![Event];
+        let decoded = read.decode_any(&registry).unwrap();
+        assert_eq!(decoded.downcast_ref::<Event>(), Some(&event(Status::Cancelled)));
+    }
+}
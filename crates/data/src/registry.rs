@@ -0,0 +1,662 @@
+//! # Data Type Registry
+//!
+//! Maps the [Data::get_id] of every type a node understands back to a decoder,
+//! so incoming bytes tagged only with a type UUID can be turned into a value,
+//! either as an [AnyData] or by dispatching to a handler for the concrete type.
+//!
+//! Older versions of a type are registered as their own structs, each with an
+//! upgrade to the next version. Payloads in an older version are decoded as
+//! that struct and upgraded step by step, so handlers only ever see the newest
+//! version.
+
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+
+use bincode::Decode;
+use uuid::Uuid;
+
+use crate::{CodecError, Data, DataCapability, DataCodec, DataSchema, DataUsage};
+
+type Decoder = Box<dyn Fn(DataCodec, &[u8]) -> Result<Box<dyn Any + Send + Sync>, CodecError> + Send + Sync>;
+type Handler = Box<dyn Fn(Box<dyn Any + Send + Sync>) + Send + Sync>;
+type Upgrade = Box<dyn Fn(Box<dyn Any + Send + Sync>) -> Box<dyn Any + Send + Sync> + Send + Sync>;
+
+/// Build a [DataRegistry] holding each of the given [DataType]s.
+///
+/// ```no_run
+/// # use osp_data::Data;
+/// #[derive(Data)]
+/// #[osp(id = "6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1")]
+/// struct Post {
+///     title: String,
+/// }
+///
+/// #[derive(Data)]
+/// #[osp(id = "0b5f5c1e-5f7e-4d43-8a8a-6a9c1f3e2b10")]
+/// struct Like {
+///     post: String,
+/// }
+///
+/// let registry = osp_data::data_registry![Post, Like];
+/// ```
+///
+/// Two types with the same id fail to compile:
+///
+/// ```compile_fail
+/// # use osp_data::Data;
+/// #[derive(Data)]
+/// #[osp(id = "6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1")]
+/// struct Post {
+///     title: String,
+/// }
+///
+/// #[derive(Data)]
+/// #[osp(id = "6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1")]
+/// struct Like {
+///     post: String,
+/// }
+///
+/// let registry = osp_data::data_registry![Post, Like];
+/// ```
+///
+/// [DataType]: crate::DataType
+#[macro_export]
+macro_rules! data_registry {
+    ($($data:ty),* $(,)?) => {{
+        const _: () = $crate::assert_unique_ids(&[$(<$data as $crate::DataType>::ID),*]);
+        let mut registry = $crate::DataRegistry::new();
+        $(
+            registry.register::<$data>().expect("Data type ids are checked at compile time");
+        )*
+        registry
+    }};
+}
+
+/// Panic if any two of `ids` are the same. Used by [data_registry] to reject
+/// duplicate ids at compile time.
+pub const fn assert_unique_ids(ids: &[Uuid]) {
+    let mut i = 0;
+    while i < ids.len() {
+        let mut j = i + 1;
+        while j < ids.len() {
+            if ids[i].as_u128() == ids[j].as_u128() {
+                panic!("Two data types share the same id");
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Why the [DataRegistry] couldn't register or decode a type.
+#[derive(Debug)]
+pub enum RegistryError {
+    /// Two types were registered with the same [Data::get_id]
+    DuplicateId {
+        id: Uuid,
+        registered: &'static str,
+        duplicate: &'static str,
+    },
+    /// No type is registered with this id
+    UnknownType(Uuid),
+    /// The type is registered, but not this version of it
+    UnknownVersion {
+        id: Uuid,
+        version: u32,
+    },
+    /// An upgrade has to go from a registered version to the one right before
+    /// it, under the same id
+    InvalidUpgrade {
+        from: &'static str,
+        to: &'static str,
+    },
+    /// The bytes couldn't be decoded as the registered type
+    Decode(CodecError),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegistryError::DuplicateId { id, registered, duplicate } =>
+                write!(f, "Data type id {id} of {duplicate} is already used by {registered}"),
+            RegistryError::UnknownType(id) => write!(f, "No data type registered with id {id}"),
+            RegistryError::UnknownVersion { id, version } =>
+                write!(f, "Version {version} of data type {id} isn't registered"),
+            RegistryError::InvalidUpgrade { from, to } =>
+                write!(f, "Can't upgrade {from} to {to}, it must be the previous version of a registered type"),
+            RegistryError::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<CodecError> for RegistryError {
+    fn from(value: CodecError) -> Self {
+        RegistryError::Decode(value)
+    }
+}
+
+/// A decoded value of any registered type, tagged with its type's id.
+pub struct AnyData {
+    data_type: Uuid,
+    value: Box<dyn Any + Send + Sync>,
+}
+
+impl AnyData {
+    /// The [Data::get_id] of the value's type
+    pub fn data_type(&self) -> Uuid {
+        self.data_type
+    }
+
+    pub fn is<T: Data + 'static>(&self) -> bool {
+        self.value.is::<T>()
+    }
+
+    pub fn downcast_ref<T: Data + 'static>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+
+    /// Take the value out as `T`, or get `self` back if it is another type.
+    pub fn downcast<T: Data + 'static>(self) -> Result<T, Self> {
+        match self.value.downcast() {
+            Ok(value) => Ok(*value),
+            Err(value) => Err(AnyData { data_type: self.data_type, value }),
+        }
+    }
+}
+
+impl Debug for AnyData {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnyData").field("data_type", &self.data_type).finish_non_exhaustive()
+    }
+}
+
+struct RegisteredType {
+    type_id: TypeId,
+    name: &'static str,
+    version: u32,
+    usage: DataUsage,
+    codecs: &'static [DataCodec],
+    schema: Option<DataSchema>,
+    decode: Decoder,
+    handler: Option<Handler>,
+    /// Keyed by version, each upgrading to the version after it
+    older: HashMap<u32, OlderVersion>,
+}
+
+impl RegisteredType {
+    /// The oldest version that can still be decoded
+    fn min_version(&self) -> u32 {
+        self.older.keys().copied().min().unwrap_or(self.version)
+    }
+
+    /// The Rust type decoded for `version`
+    fn type_of(&self, version: u32) -> Option<TypeId> {
+        match self.older.get(&version) {
+            Some(older) => Some(older.type_id),
+            None => (version == self.version).then_some(self.type_id),
+        }
+    }
+
+    /// Decode a payload in `version` of type `id` and upgrade it to the
+    /// newest version.
+    fn decode(&self, id: Uuid, version: u32, codec: DataCodec, buf: &[u8]) -> Result<Box<dyn Any + Send + Sync>, RegistryError> {
+        if version == self.version {
+            return Ok((self.decode)(codec, buf)?);
+        }
+        let older = self.older.get(&version).ok_or(RegistryError::UnknownVersion { id, version })?;
+        let mut value = (older.decode)(codec, buf)?;
+        for version in version..self.version {
+            // Versions are only registered in an unbroken chain up to the newest
+            value = (self.older[&version].upgrade)(value);
+        }
+        Ok(value)
+    }
+}
+
+struct OlderVersion {
+    type_id: TypeId,
+    schema: Option<DataSchema>,
+    decode: Decoder,
+    upgrade: Upgrade,
+}
+
+/// The [Data] types a node understands, keyed by [Data::get_id].
+#[derive(Default)]
+pub struct DataRegistry {
+    types: HashMap<Uuid, RegisteredType>,
+}
+
+impl DataRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `T` as a type this node both produces and consumes, failing
+    /// if another type already uses its id.
+    pub fn register<T>(&mut self) -> Result<(), RegistryError>
+    where
+        T: Data + Decode + Send + Sync + 'static,
+    {
+        self.register_with_usage::<T>(DataUsage::BOTH)
+    }
+
+    /// Register `T`, advertising to peers that this node only produces or only
+    /// consumes it as given by `usage`.
+    pub fn register_with_usage<T>(&mut self, usage: DataUsage) -> Result<(), RegistryError>
+    where
+        T: Data + Decode + Send + Sync + 'static,
+    {
+        let id = T::get_id();
+        if let Some(registered) = self.types.get(&id) {
+            return Err(RegistryError::DuplicateId {
+                id,
+                registered: registered.name,
+                duplicate: type_name::<T>(),
+            });
+        }
+
+        self.types.insert(id, RegisteredType {
+            type_id: TypeId::of::<T>(),
+            name: type_name::<T>(),
+            version: T::get_version(),
+            usage,
+            codecs: T::codecs(),
+            schema: T::schema(),
+            decode: Box::new(|codec, buf| Ok(Box::new(T::decode_with(codec, buf)?))),
+            handler: None,
+            older: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Register `Old`, the version of a type right before `New`, so payloads
+    /// in `Old`'s version are decoded and passed through `upgrade`. `New` must
+    /// be registered already, either as the newest version or with its own
+    /// upgrade, so the oldest versions are registered last.
+    ///
+    /// ```no_run
+    /// # use osp_data::{Data, DataRegistry};
+    /// #[derive(Data)]
+    /// #[osp(id = "6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1", version = 1)]
+    /// struct PostV1 {
+    ///     title: String,
+    /// }
+    ///
+    /// #[derive(Data)]
+    /// #[osp(id = "6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1", version = 2)]
+    /// struct Post {
+    ///     title: String,
+    ///     tags: Vec<String>,
+    /// }
+    ///
+    /// # fn main() -> Result<(), osp_data::RegistryError> {
+    /// let mut registry = DataRegistry::new();
+    /// registry.register::<Post>()?;
+    /// registry.register_upgrade(|post: PostV1| Post { title: post.title, tags: Vec::new() })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn register_upgrade<Old, New, F>(&mut self, upgrade: F) -> Result<(), RegistryError>
+    where
+        Old: Data + Decode + Send + Sync + 'static,
+        New: Data + Send + Sync + 'static,
+        F: Fn(Old) -> New + Send + Sync + 'static,
+    {
+        let invalid = RegistryError::InvalidUpgrade { from: type_name::<Old>(), to: type_name::<New>() };
+        let id = New::get_id();
+        let Some(registered) = self.types.get_mut(&id) else {
+            return Err(RegistryError::UnknownType(id));
+        };
+        let version = Old::get_version();
+        let valid = Old::get_id() == id
+            && version.checked_add(1) == Some(New::get_version())
+            && registered.type_of(New::get_version()) == Some(TypeId::of::<New>())
+            && !registered.older.contains_key(&version);
+        if !valid {
+            return Err(invalid);
+        }
+
+        registered.older.insert(version, OlderVersion {
+            type_id: TypeId::of::<Old>(),
+            schema: Old::schema(),
+            decode: Box::new(|codec, buf| Ok(Box::new(Old::decode_with(codec, buf)?))),
+            upgrade: Box::new(move |value| {
+                // Only values decoded as this version reach its upgrade
+                Box::new(upgrade(*value.downcast::<Old>().expect("Older decoder returned another type")))
+            }),
+        });
+        Ok(())
+    }
+
+    /// Call `handler` with every value of `T` passed to
+    /// [DataRegistry::dispatch]. `T` must already be registered. Replaces any
+    /// handler set before.
+    pub fn set_handler<T, F>(&mut self, handler: F) -> Result<(), RegistryError>
+    where
+        T: Data + 'static,
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let id = T::get_id();
+        let registered = self.types.get_mut(&id).ok_or(RegistryError::UnknownType(id))?;
+        if registered.type_id != TypeId::of::<T>() {
+            return Err(RegistryError::DuplicateId {
+                id,
+                registered: registered.name,
+                duplicate: type_name::<T>(),
+            });
+        }
+        registered.handler = Some(Box::new(move |value| {
+            // Only values decoded for this id reach the handler
+            handler(*value.downcast::<T>().expect("Registered decoder returned another type"))
+        }));
+        Ok(())
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.types.contains_key(id)
+    }
+
+    /// The ids of every registered type
+    pub fn ids(&self) -> impl Iterator<Item = &Uuid> {
+        self.types.keys()
+    }
+
+    /// What this node advertises to peers for every registered type. Older
+    /// versions can be consumed but only the newest is produced, so a type
+    /// with upgrades is advertised once for each.
+    pub fn capabilities(&self) -> Vec<DataCapability> {
+        let mut capabilities = Vec::with_capacity(self.types.len());
+        for (id, registered) in &self.types {
+            let capability = |min_version, usage| DataCapability {
+                data_type: *id,
+                min_version,
+                max_version: registered.version,
+                usage,
+                codecs: registered.codecs.to_vec(),
+            };
+            let min_version = registered.min_version();
+            if min_version == registered.version || !registered.usage.consume {
+                capabilities.push(capability(registered.version, registered.usage));
+                continue;
+            }
+            capabilities.push(capability(min_version, DataUsage::CONSUME));
+            if registered.usage.produce {
+                capabilities.push(capability(registered.version, DataUsage::PRODUCE));
+            }
+        }
+        capabilities
+    }
+
+    /// The schema of the newest version of the type registered with `id`, if
+    /// the type describes itself
+    pub fn schema(&self, id: &Uuid) -> Option<&DataSchema> {
+        self.types.get(id)?.schema.as_ref()
+    }
+
+    /// The schema of `version` of the type registered with `id`
+    pub fn schema_version(&self, id: &Uuid, version: u32) -> Option<&DataSchema> {
+        let registered = self.types.get(id)?;
+        match registered.older.get(&version) {
+            Some(older) => older.schema.as_ref(),
+            None if version == registered.version => registered.schema.as_ref(),
+            None => None,
+        }
+    }
+
+    /// The newest version of the type registered with `id`
+    pub fn version(&self, id: &Uuid) -> Option<u32> {
+        self.types.get(id).map(|registered| registered.version)
+    }
+
+    /// Decode a bincode payload as the type registered with `id`.
+    pub fn decode(&self, id: Uuid, buf: &[u8]) -> Result<AnyData, RegistryError> {
+        self.decode_with(id, DataCodec::Bincode, buf)
+    }
+
+    /// Decode a payload encoded with `codec` as the type registered with `id`.
+    pub fn decode_with(&self, id: Uuid, codec: DataCodec, buf: &[u8]) -> Result<AnyData, RegistryError> {
+        let registered = self.types.get(&id).ok_or(RegistryError::UnknownType(id))?;
+        self.decode_version(id, registered.version, codec, buf)
+    }
+
+    /// Decode a payload in `version` of the type registered with `id`,
+    /// upgrading it to the newest version.
+    pub fn decode_version(&self, id: Uuid, version: u32, codec: DataCodec, buf: &[u8]) -> Result<AnyData, RegistryError> {
+        let registered = self.types.get(&id).ok_or(RegistryError::UnknownType(id))?;
+        Ok(AnyData {
+            data_type: id,
+            value: registered.decode(id, version, codec, buf)?,
+        })
+    }
+
+    /// Decode a bincode payload as the type registered with `id` and pass it
+    /// to that type's handler. Returns the value instead if the type has no
+    /// handler.
+    pub fn dispatch(&self, id: Uuid, buf: &[u8]) -> Result<Option<AnyData>, RegistryError> {
+        self.dispatch_with(id, DataCodec::Bincode, buf)
+    }
+
+    /// Like [DataRegistry::dispatch], for a payload encoded with `codec`.
+    pub fn dispatch_with(&self, id: Uuid, codec: DataCodec, buf: &[u8]) -> Result<Option<AnyData>, RegistryError> {
+        let registered = self.types.get(&id).ok_or(RegistryError::UnknownType(id))?;
+        self.dispatch_version(id, registered.version, codec, buf)
+    }
+
+    /// Like [DataRegistry::dispatch], for a payload in `version` encoded with
+    /// `codec`. Older versions are upgraded before reaching the handler.
+    pub fn dispatch_version(&self, id: Uuid, version: u32, codec: DataCodec, buf: &[u8]) -> Result<Option<AnyData>, RegistryError> {
+        let registered = self.types.get(&id).ok_or(RegistryError::UnknownType(id))?;
+        let value = registered.decode(id, version, codec, buf)?;
+        match &registered.handler {
+            Some(handler) => {
+                handler(value);
+                Ok(None)
+            }
+            None => Ok(Some(AnyData { data_type: id, value })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use bincode::{Decode, Encode};
+    use bytes::{Bytes, BytesMut};
+    use uuid::{uuid, Uuid};
+
+    use crate::{Data, DataCodec, DataUsage, PeerCapabilities};
+    use crate::registry::{DataRegistry, RegistryError};
+
+    #[derive(Encode, Decode, PartialEq, Debug)]
+    struct Post {
+        title: String,
+    }
+
+    impl Data for Post {
+        fn get_id() -> Uuid {
+            uuid!("6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1")
+        }
+    }
+
+    #[derive(Encode, Decode, PartialEq, Debug)]
+    struct Like {
+        post: u64,
+    }
+
+    impl Data for Like {
+        fn get_id() -> Uuid {
+            uuid!("0b8e6a3a-7d55-4b5e-bb0e-2a3f3a1f7c42")
+        }
+    }
+
+    /// Claims the same id as [Post]
+    #[derive(Encode, Decode)]
+    struct Impostor;
+
+    impl Data for Impostor {
+        fn get_id() -> Uuid {
+            Post::get_id()
+        }
+    }
+
+    /// The first version of [Post], before it had an author
+    #[derive(Encode, Decode)]
+    struct PostV1 {
+        title: String,
+    }
+
+    impl Data for PostV1 {
+        fn get_id() -> Uuid {
+            Post::get_id()
+        }
+    }
+
+    #[derive(Encode, Decode)]
+    struct PostV2 {
+        title: String,
+        author: String,
+    }
+
+    impl Data for PostV2 {
+        fn get_id() -> Uuid {
+            Post::get_id()
+        }
+
+        fn get_version() -> u32 {
+            2
+        }
+    }
+
+    #[derive(Encode, Decode, PartialEq, Debug)]
+    struct PostV3 {
+        title: String,
+        author: String,
+        likes: u64,
+    }
+
+    impl Data for PostV3 {
+        fn get_id() -> Uuid {
+            Post::get_id()
+        }
+
+        fn get_version() -> u32 {
+            3
+        }
+    }
+
+    fn versioned_registry() -> DataRegistry {
+        let mut registry = DataRegistry::new();
+        registry.register::<PostV3>().unwrap();
+        registry.register_upgrade(|post: PostV2| PostV3 { title: post.title, author: post.author, likes: 0 }).unwrap();
+        registry.register_upgrade(|post: PostV1| PostV2 { title: post.title, author: "unknown".to_string() }).unwrap();
+        registry
+    }
+
+    fn encode<T: Data + Encode>(value: T) -> Bytes {
+        let mut buf = BytesMut::new();
+        let len = T::encode_to_bytes(&mut buf, value).unwrap();
+        assert_eq!(len, buf.len());
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_duplicate_id_rejected() {
+        let mut registry = DataRegistry::new();
+        registry.register::<Post>().unwrap();
+        assert!(matches!(
+            registry.register::<Impostor>(),
+            Err(RegistryError::DuplicateId { id, .. }) if id == Post::get_id()
+        ));
+        assert!(registry.register::<Post>().is_err());
+    }
+
+    #[test]
+    fn test_decode_by_id() {
+        let mut registry = DataRegistry::new();
+        registry.register::<Post>().unwrap();
+        registry.register::<Like>().unwrap();
+
+        let value = registry.decode(Post::get_id(), &encode(Post { title: "Hello".to_string() })).unwrap();
+        assert_eq!(value.data_type(), Post::get_id());
+        assert!(!value.is::<Like>());
+        assert_eq!(value.downcast::<Post>().unwrap(), Post { title: "Hello".to_string() });
+
+        let unknown = Uuid::new_v4();
+        assert!(matches!(registry.decode(unknown, &Bytes::new()), Err(RegistryError::UnknownType(id)) if id == unknown));
+    }
+
+    #[test]
+    fn test_dispatch_to_handler() {
+        let mut registry = DataRegistry::new();
+        assert!(matches!(registry.set_handler::<Post, _>(|_| {}), Err(RegistryError::UnknownType(_))));
+        registry.register::<Post>().unwrap();
+        registry.register::<Like>().unwrap();
+        assert!(matches!(registry.set_handler::<Impostor, _>(|_| {}), Err(RegistryError::DuplicateId { .. })));
+
+        let likes = Arc::new(Mutex::new(Vec::new()));
+        let seen = likes.clone();
+        registry.set_handler(move |like: Like| seen.lock().unwrap().push(like.post)).unwrap();
+
+        assert!(registry.dispatch(Like::get_id(), &encode(Like { post: 4 })).unwrap().is_none());
+        assert_eq!(*likes.lock().unwrap(), vec![4]);
+
+        let unhandled = registry.dispatch(Post::get_id(), &encode(Post { title: "Hi".to_string() })).unwrap();
+        assert_eq!(unhandled.unwrap().downcast_ref::<Post>().unwrap().title, "Hi");
+    }
+
+    #[test]
+    fn test_older_versions_upgraded() {
+        let registry = versioned_registry();
+        let post = PostV3 { title: "Hi".to_string(), author: "unknown".to_string(), likes: 0 };
+        let old = registry.decode_version(Post::get_id(), 1, DataCodec::Bincode, &encode(PostV1 { title: "Hi".to_string() })).unwrap();
+        assert_eq!(old.downcast_ref::<PostV3>(), Some(&post));
+
+        let current = registry.decode(Post::get_id(), &encode(PostV3 { likes: 5, ..post })).unwrap();
+        assert_eq!(current.downcast_ref::<PostV3>().unwrap().likes, 5);
+        assert!(matches!(
+            registry.decode_version(Post::get_id(), 4, DataCodec::Bincode, &[]),
+            Err(RegistryError::UnknownVersion { version: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_upgrades_rejected() {
+        let mut registry = DataRegistry::new();
+        assert!(matches!(registry.register_upgrade(|_: PostV1| PostV2 { title: String::new(), author: String::new() }), Err(RegistryError::UnknownType(_))));
+        registry.register::<PostV3>().unwrap();
+        // Skips version 2
+        assert!(matches!(registry.register_upgrade(|_: PostV1| PostV3 { title: String::new(), author: String::new(), likes: 0 }), Err(RegistryError::InvalidUpgrade { .. })));
+        // Version 2 isn't registered yet
+        assert!(matches!(registry.register_upgrade(|_: PostV1| PostV2 { title: String::new(), author: String::new() }), Err(RegistryError::InvalidUpgrade { .. })));
+        // Different id
+        assert!(matches!(registry.register_upgrade(|_: Like| PostV3 { title: String::new(), author: String::new(), likes: 0 }), Err(RegistryError::InvalidUpgrade { .. })));
+    }
+
+    /// A node that can upgrade from version 1 still only produces its newest
+    /// version, and receives the newest version an older peer sends.
+    #[test]
+    fn test_versioned_capabilities() {
+        let new = versioned_registry();
+        let mut old = DataRegistry::new();
+        old.register::<PostV2>().unwrap();
+        old.register_upgrade(|post: PostV1| PostV2 { title: post.title, author: String::new() }).unwrap();
+
+        let consume = new.capabilities().into_iter().find(|c| c.usage == DataUsage::CONSUME).unwrap();
+        assert_eq!((consume.min_version, consume.max_version), (1, 3));
+        assert_eq!(new.version(&Post::get_id()), Some(3));
+
+        let capabilities = PeerCapabilities::negotiate(&new.capabilities(), &old.capabilities());
+        assert!(!capabilities.can_send(&Post::get_id()));
+        assert_eq!(capabilities.receive_version(&Post::get_id()), Some(2));
+
+        let mut upgraded = DataRegistry::new();
+        upgraded.register::<PostV3>().unwrap();
+        let capabilities = PeerCapabilities::negotiate(&new.capabilities(), &upgraded.capabilities());
+        assert_eq!(capabilities.send_version(&Post::get_id()), Some(3));
+        assert_eq!(capabilities.receive_version(&Post::get_id()), Some(3));
+    }
+}
@@ -0,0 +1,545 @@
+//! # Data Schemas
+//!
+//! A machine-readable description of what a [Data] type contains, so peers and
+//! tools can render types they have no Rust definition for. `#[derive(Data)]`
+//! describes the type from its fields, each of which has to implement
+//! [Describe]. Schemas can be exported as JSON Schema, which describes the
+//! payload as the JSON codec encodes it.
+//!
+//! [Data]: crate::Data
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use bincode::{BorrowDecode, Decode, Encode};
+use bincode::de::{BorrowDecoder, Decoder};
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use serde_json::{json, Map, Value};
+use uuid::Uuid;
+
+/// The schema of one version of a data type.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DataSchema {
+    pub id: Uuid,
+    pub version: u32,
+    pub shape: SchemaType,
+}
+
+/// How deeply shapes may nest inside a decoded schema. Decoding recurses
+/// once per level, so a schema from a peer can't be allowed to go on forever.
+pub const MAX_SCHEMA_DEPTH: usize = 64;
+
+/// The shape of a value, as it is laid out by the data codecs.
+#[derive(Encode, Clone, PartialEq, Eq, Debug)]
+pub enum SchemaType {
+    Unit,
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    F32,
+    F64,
+    Char,
+    String,
+    /// A value that may be missing
+    Option(Box<SchemaType>),
+    /// Any number of items
+    List(Box<SchemaType>),
+    /// Exactly `len` items
+    Array {
+        item: Box<SchemaType>,
+        len: u32,
+    },
+    Map {
+        key: Box<SchemaType>,
+        value: Box<SchemaType>,
+    },
+    Tuple(Vec<SchemaType>),
+    /// A struct with named fields
+    Struct {
+        name: String,
+        fields: Vec<SchemaField>,
+    },
+    /// A tuple or unit struct
+    TupleStruct {
+        name: String,
+        fields: Vec<SchemaType>,
+    },
+    Enum {
+        name: String,
+        variants: Vec<SchemaVariant>,
+    },
+}
+
+#[derive(Encode, Clone, PartialEq, Eq, Debug)]
+pub struct SchemaField {
+    pub name: String,
+    pub shape: SchemaType,
+}
+
+impl SchemaField {
+    /// Whether the field may be left out
+    pub fn is_optional(&self) -> bool {
+        matches!(self.shape, SchemaType::Option(_))
+    }
+}
+
+/// One variant of an enum. Unit variants have the [SchemaType::Unit] shape,
+/// variants with one unnamed field the shape of that field, with several
+/// unnamed fields a [SchemaType::Tuple] and with named fields a
+/// [SchemaType::Struct] named after the variant.
+#[derive(Encode, Clone, PartialEq, Eq, Debug)]
+pub struct SchemaVariant {
+    pub name: String,
+    pub shape: SchemaType,
+}
+
+impl DataSchema {
+    /// The name of the described type, if it is a struct or enum
+    pub fn name(&self) -> Option<&str> {
+        match &self.shape {
+            SchemaType::Struct { name, .. }
+            | SchemaType::TupleStruct { name, .. }
+            | SchemaType::Enum { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Export as a JSON Schema (draft 2020-12) document. The type's id and
+    /// version are kept in `$id` and `x-osp-version`.
+    pub fn to_json_schema(&self) -> Value {
+        let mut schema = Map::new();
+        schema.insert("$schema".to_string(), json!("https://json-schema.org/draft/2020-12/schema"));
+        schema.insert("$id".to_string(), json!(self.id.urn().to_string()));
+        schema.insert("x-osp-version".to_string(), json!(self.version));
+        match self.shape.to_json_schema() {
+            Value::Object(shape) => schema.extend(shape),
+            shape => {
+                schema.insert("allOf".to_string(), json!([shape]));
+            }
+        }
+        Value::Object(schema)
+    }
+}
+
+impl SchemaType {
+    /// The JSON Schema for values of this shape, without the document level
+    /// keywords [DataSchema::to_json_schema] adds.
+    pub fn to_json_schema(&self) -> Value {
+        match self {
+            SchemaType::Unit => json!({ "type": "null" }),
+            SchemaType::Bool => json!({ "type": "boolean" }),
+            SchemaType::U8 => integer(u8::MIN, u8::MAX),
+            SchemaType::U16 => integer(u16::MIN, u16::MAX),
+            SchemaType::U32 => integer(u32::MIN, u32::MAX),
+            SchemaType::U64 => integer(u64::MIN, u64::MAX),
+            // Beyond what JSON numbers are guaranteed to hold
+            SchemaType::U128 => json!({ "type": "integer", "minimum": 0 }),
+            SchemaType::I8 => integer(i8::MIN, i8::MAX),
+            SchemaType::I16 => integer(i16::MIN, i16::MAX),
+            SchemaType::I32 => integer(i32::MIN, i32::MAX),
+            SchemaType::I64 => integer(i64::MIN, i64::MAX),
+            SchemaType::I128 => json!({ "type": "integer" }),
+            SchemaType::F32 | SchemaType::F64 => json!({ "type": "number" }),
+            SchemaType::Char => json!({ "type": "string", "minLength": 1, "maxLength": 1 }),
+            SchemaType::String => json!({ "type": "string" }),
+            SchemaType::Option(inner) => json!({ "anyOf": [inner.to_json_schema(), { "type": "null" }] }),
+            SchemaType::List(item) => json!({ "type": "array", "items": item.to_json_schema() }),
+            SchemaType::Array { item, len } => json!({
+                "type": "array",
+                "items": item.to_json_schema(),
+                "minItems": len,
+                "maxItems": len,
+            }),
+            // JSON object keys are always strings, so only the values are described
+            SchemaType::Map { value, .. } => json!({ "type": "object", "additionalProperties": value.to_json_schema() }),
+            SchemaType::Tuple(items) => tuple(items),
+            SchemaType::Struct { name, fields } => {
+                let properties: Map<String, Value> = fields.iter()
+                    .map(|field| (field.name.clone(), field.shape.to_json_schema()))
+                    .collect();
+                let required: Vec<&str> = fields.iter()
+                    .filter(|field| !field.is_optional())
+                    .map(|field| field.name.as_str())
+                    .collect();
+                json!({
+                    "title": name,
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                    "additionalProperties": false,
+                })
+            }
+            SchemaType::TupleStruct { name, fields } => {
+                let mut schema = match fields.as_slice() {
+                    [] => SchemaType::Unit.to_json_schema(),
+                    // Newtypes are encoded as their one field
+                    [field] => json!({ "allOf": [field.to_json_schema()] }),
+                    fields => tuple(fields),
+                };
+                schema["title"] = json!(name);
+                schema
+            }
+            SchemaType::Enum { name, variants } => {
+                let variants: Vec<Value> = variants.iter()
+                    .map(|variant| match variant.shape {
+                        SchemaType::Unit => json!({ "const": variant.name }),
+                        ref shape => json!({
+                            "type": "object",
+                            "properties": { variant.name.as_str(): shape.to_json_schema() },
+                            "required": [variant.name],
+                            "additionalProperties": false,
+                        }),
+                    })
+                    .collect();
+                json!({ "title": name, "oneOf": variants })
+            }
+        }
+    }
+}
+
+fn integer(min: impl Into<Value>, max: impl Into<Value>) -> Value {
+    json!({ "type": "integer", "minimum": min.into(), "maximum": max.into() })
+}
+
+fn tuple(items: &[SchemaType]) -> Value {
+    let items: Vec<Value> = items.iter().map(SchemaType::to_json_schema).collect();
+    json!({
+        "type": "array",
+        "minItems": items.len(),
+        "maxItems": items.len(),
+        "prefixItems": items,
+        "items": false,
+    })
+}
+
+impl Encode for DataSchema {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.id.as_u128().encode(encoder)?;
+        self.version.encode(encoder)?;
+        self.shape.encode(encoder)
+    }
+}
+
+impl Decode for DataSchema {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(DataSchema {
+            id: Uuid::from_u128(u128::decode(decoder)?),
+            version: u32::decode(decoder)?,
+            shape: SchemaType::decode(decoder)?,
+        })
+    }
+}
+
+impl<'de> BorrowDecode<'de> for DataSchema {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+// Decoded by hand, in the layout the derived `Encode` writes, to count how
+// deeply shapes nest. The lists are claimed from the decoder's limit like
+// bincode's own containers are.
+
+impl Decode for SchemaType {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        decode_shape(decoder, 0)
+    }
+}
+
+impl<'de> BorrowDecode<'de> for SchemaType {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+impl Decode for SchemaField {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(SchemaField { name: String::decode(decoder)?, shape: decode_shape(decoder, 0)? })
+    }
+}
+
+impl<'de> BorrowDecode<'de> for SchemaField {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+impl Decode for SchemaVariant {
+    fn decode<D: Decoder>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(SchemaVariant { name: String::decode(decoder)?, shape: decode_shape(decoder, 0)? })
+    }
+}
+
+impl<'de> BorrowDecode<'de> for SchemaVariant {
+    fn borrow_decode<D: BorrowDecoder<'de>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Self::decode(decoder)
+    }
+}
+
+fn decode_shape<D: Decoder>(decoder: &mut D, depth: usize) -> Result<SchemaType, DecodeError> {
+    if depth >= MAX_SCHEMA_DEPTH {
+        return Err(DecodeError::OtherString(format!("Schema nests deeper than {MAX_SCHEMA_DEPTH} levels")));
+    }
+    let inner = |decoder: &mut D| decode_shape(decoder, depth + 1).map(Box::new);
+    Ok(match u32::decode(decoder)? {
+        0 => SchemaType::Unit,
+        1 => SchemaType::Bool,
+        2 => SchemaType::U8,
+        3 => SchemaType::U16,
+        4 => SchemaType::U32,
+        5 => SchemaType::U64,
+        6 => SchemaType::U128,
+        7 => SchemaType::I8,
+        8 => SchemaType::I16,
+        9 => SchemaType::I32,
+        10 => SchemaType::I64,
+        11 => SchemaType::I128,
+        12 => SchemaType::F32,
+        13 => SchemaType::F64,
+        14 => SchemaType::Char,
+        15 => SchemaType::String,
+        16 => SchemaType::Option(inner(decoder)?),
+        17 => SchemaType::List(inner(decoder)?),
+        18 => SchemaType::Array { item: inner(decoder)?, len: u32::decode(decoder)? },
+        19 => SchemaType::Map { key: inner(decoder)?, value: inner(decoder)? },
+        20 => SchemaType::Tuple(decode_list(decoder, |decoder| decode_shape(decoder, depth + 1))?),
+        21 => SchemaType::Struct {
+            name: String::decode(decoder)?,
+            fields: decode_list(decoder, |decoder| Ok(SchemaField {
+                name: String::decode(decoder)?,
+                shape: decode_shape(decoder, depth + 1)?,
+            }))?,
+        },
+        22 => SchemaType::TupleStruct {
+            name: String::decode(decoder)?,
+            fields: decode_list(decoder, |decoder| decode_shape(decoder, depth + 1))?,
+        },
+        23 => SchemaType::Enum {
+            name: String::decode(decoder)?,
+            variants: decode_list(decoder, |decoder| Ok(SchemaVariant {
+                name: String::decode(decoder)?,
+                shape: decode_shape(decoder, depth + 1)?,
+            }))?,
+        },
+        found => return Err(DecodeError::UnexpectedVariant {
+            type_name: "SchemaType",
+            allowed: &bincode::error::AllowedEnumVariants::Range { min: 0, max: 23 },
+            found,
+        }),
+    })
+}
+
+fn decode_list<D: Decoder, T>(decoder: &mut D, mut item: impl FnMut(&mut D) -> Result<T, DecodeError>) -> Result<Vec<T>, DecodeError> {
+    let len = u64::decode(decoder)?;
+    let len = usize::try_from(len).map_err(|_| DecodeError::OutsideUsizeRange(len))?;
+    decoder.claim_container_read::<T>(len)?;
+    // The length is untrusted, so only the items actually read are allocated
+    let mut items = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        decoder.unclaim_bytes_read(std::mem::size_of::<T>());
+        items.push(item(decoder)?);
+    }
+    Ok(items)
+}
+
+/// A type that can describe its own [SchemaType]. Implemented for the standard
+/// types bincode encodes, and by `#[derive(Data)]` and `#[derive(Describe)]`.
+pub trait Describe {
+    fn describe() -> SchemaType;
+}
+
+macro_rules! describe_as {
+    ($($ty:ty => $shape:ident),* $(,)?) => {
+        $(
+            impl Describe for $ty {
+                fn describe() -> SchemaType {
+                    SchemaType::$shape
+                }
+            }
+        )*
+    };
+}
+
+describe_as! {
+    () => Unit,
+    bool => Bool,
+    u8 => U8,
+    u16 => U16,
+    u32 => U32,
+    u64 => U64,
+    u128 => U128,
+    i8 => I8,
+    i16 => I16,
+    i32 => I32,
+    i64 => I64,
+    i128 => I128,
+    f32 => F32,
+    f64 => F64,
+    char => Char,
+    String => String,
+}
+
+impl<T: Describe> Describe for Option<T> {
+    fn describe() -> SchemaType {
+        SchemaType::Option(Box::new(T::describe()))
+    }
+}
+
+impl<T: Describe> Describe for Box<T> {
+    fn describe() -> SchemaType {
+        T::describe()
+    }
+}
+
+impl<T: Describe> Describe for Vec<T> {
+    fn describe() -> SchemaType {
+        SchemaType::List(Box::new(T::describe()))
+    }
+}
+
+impl<T: Describe> Describe for HashSet<T> {
+    fn describe() -> SchemaType {
+        SchemaType::List(Box::new(T::describe()))
+    }
+}
+
+impl<T: Describe> Describe for BTreeSet<T> {
+    fn describe() -> SchemaType {
+        SchemaType::List(Box::new(T::describe()))
+    }
+}
+
+impl<T: Describe, const N: usize> Describe for [T; N] {
+    fn describe() -> SchemaType {
+        SchemaType::Array { item: Box::new(T::describe()), len: N as u32 }
+    }
+}
+
+impl<K: Describe, V: Describe> Describe for HashMap<K, V> {
+    fn describe() -> SchemaType {
+        SchemaType::Map { key: Box::new(K::describe()), value: Box::new(V::describe()) }
+    }
+}
+
+impl<K: Describe, V: Describe> Describe for BTreeMap<K, V> {
+    fn describe() -> SchemaType {
+        SchemaType::Map { key: Box::new(K::describe()), value: Box::new(V::describe()) }
+    }
+}
+
+macro_rules! describe_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Describe),+> Describe for ($($name,)+) {
+            fn describe() -> SchemaType {
+                SchemaType::Tuple(vec![$($name::describe()),+])
+            }
+        }
+    };
+}
+
+describe_tuple!(A);
+describe_tuple!(A, B);
+describe_tuple!(A, B, C);
+describe_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use crate::schema::{DataSchema, Describe, SchemaField, SchemaType, SchemaVariant, MAX_SCHEMA_DEPTH};
+
+    #[test]
+    fn test_describe_std_types() {
+        assert_eq!(Option::<Vec<u8>>::describe(), SchemaType::Option(Box::new(SchemaType::List(Box::new(SchemaType::U8)))));
+        assert_eq!(<[u16; 4]>::describe(), SchemaType::Array { item: Box::new(SchemaType::U16), len: 4 });
+        assert_eq!(<(bool, String)>::describe(), SchemaType::Tuple(vec![SchemaType::Bool, SchemaType::String]));
+    }
+
+    #[test]
+    fn test_json_schema() {
+        let schema = DataSchema {
+            id: Uuid::nil(),
+            version: 2,
+            shape: SchemaType::Struct {
+                name: "Post".to_string(),
+                fields: vec![
+                    SchemaField { name: "title".to_string(), shape: SchemaType::String },
+                    SchemaField { name: "reply_to".to_string(), shape: Option::<u64>::describe() },
+                    SchemaField {
+                        name: "state".to_string(),
+                        shape: SchemaType::Enum {
+                            name: "State".to_string(),
+                            variants: vec![
+                                SchemaVariant { name: "Draft".to_string(), shape: SchemaType::Unit },
+                                SchemaVariant { name: "Edited".to_string(), shape: SchemaType::U32 },
+                            ],
+                        },
+                    },
+                ],
+            },
+        };
+
+        let json = schema.to_json_schema();
+        assert_eq!(json["$id"], "urn:uuid:00000000-0000-0000-0000-000000000000");
+        assert_eq!(json["x-osp-version"], 2);
+        assert_eq!(json["title"], "Post");
+        assert_eq!(json["required"], json!(["title", "state"]));
+        assert_eq!(json["properties"]["reply_to"]["anyOf"][1], json!({ "type": "null" }));
+        assert_eq!(json["properties"]["state"]["oneOf"][0], json!({ "const": "Draft" }));
+        assert_eq!(json["properties"]["state"]["oneOf"][1]["required"], json!(["Edited"]));
+    }
+
+    #[test]
+    fn test_schema_round_trip() {
+        let shapes = [
+            SchemaType::TupleStruct { name: "Like".to_string(), fields: vec![SchemaType::U64, SchemaType::Bool] },
+            SchemaType::Struct {
+                name: "Post".to_string(),
+                fields: vec![
+                    SchemaField { name: "tags".to_string(), shape: <[String; 2]>::describe() },
+                    SchemaField { name: "counts".to_string(), shape: std::collections::BTreeMap::<char, i128>::describe() },
+                ],
+            },
+            SchemaType::Enum {
+                name: "State".to_string(),
+                variants: vec![SchemaVariant { name: "Edited".to_string(), shape: <(f32, Option<u16>)>::describe() }],
+            },
+        ];
+        let config = bincode::config::standard();
+        for shape in shapes {
+            let schema = DataSchema { id: Uuid::new_v4(), version: 1, shape };
+            let encoded = bincode::encode_to_vec(&schema, config).unwrap();
+            let (decoded, _): (DataSchema, _) = bincode::decode_from_slice(&encoded, config).unwrap();
+            assert_eq!(decoded, schema);
+        }
+    }
+
+    #[test]
+    fn test_deep_nesting_refused() {
+        let config = bincode::config::standard();
+        let mut shape = SchemaType::U8;
+        for _ in 1..MAX_SCHEMA_DEPTH {
+            shape = SchemaType::List(Box::new(shape));
+        }
+        let encoded = bincode::encode_to_vec(&shape, config).unwrap();
+        let (decoded, _): (SchemaType, _) = bincode::decode_from_slice(&encoded, config).unwrap();
+        assert_eq!(decoded, shape);
+
+        let nested = SchemaType::Tuple(vec![SchemaType::Option(Box::new(shape))]);
+        let encoded = bincode::encode_to_vec(&nested, config).unwrap();
+        assert!(bincode::decode_from_slice::<SchemaType, _>(&encoded, config).is_err());
+
+        // Far deeper than the stack could take
+        let encoded = [17u8; 1_000_000];
+        assert!(bincode::decode_from_slice::<SchemaType, _>(&encoded, config).is_err());
+    }
+}
@@ -0,0 +1,672 @@
+//! # Data Values
+//!
+//! A [DataValue] is a payload decoded without its Rust type, by following the
+//! type's [SchemaType] instead. Relays and debugging tools use it to look
+//! inside types they never registered, and to re-encode them with another
+//! codec.
+
+use std::fmt::Formatter;
+
+use bincode::{Decode, Encode};
+use bincode::de::{Decoder, DecoderImpl};
+use bincode::de::read::{Reader, SliceReader};
+use bincode::enc::Encoder;
+use bincode::error::{DecodeError, EncodeError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::ser::{SerializeMap, SerializeSeq, SerializeTuple};
+
+use crate::{CodecError, DataCodec, SchemaField, SchemaType, SchemaVariant};
+
+/// How many items that take up no bytes, like `()`, a bincode payload may
+/// hold. Their count can't be bounded by the payload's length the way other
+/// items are.
+pub const MAX_EMPTY_ITEMS: usize = 64 * 1024;
+
+/// A value of any [SchemaType]. Integers keep their sign but not their width,
+/// which comes from the schema again when the value is encoded.
+#[derive(Clone, PartialEq, Debug)]
+pub enum DataValue {
+    Unit,
+    Bool(bool),
+    UInt(u128),
+    Int(i128),
+    Float(f64),
+    Char(char),
+    String(String),
+    Option(Option<Box<DataValue>>),
+    /// A list or fixed size array
+    List(Vec<DataValue>),
+    Map(Vec<(DataValue, DataValue)>),
+    /// A tuple or tuple struct
+    Tuple(Vec<DataValue>),
+    /// A struct's fields in declaration order
+    Struct(Vec<(String, DataValue)>),
+    /// An enum variant, with [DataValue::Unit] for unit variants
+    Variant {
+        name: String,
+        value: Box<DataValue>,
+    },
+}
+
+impl DataValue {
+    /// Decode a whole payload of `shape` encoded with `codec`.
+    pub fn decode(shape: &SchemaType, codec: DataCodec, buf: &[u8]) -> Result<DataValue, CodecError> {
+        match codec {
+            DataCodec::Bincode => {
+                let mut decoder = DecoderImpl::new(SliceReader::new(buf), bincode::config::standard());
+                let mut empty_items = MAX_EMPTY_ITEMS;
+                let value = decode_bincode(shape, &mut decoder, &mut empty_items)?;
+                if decoder.reader().peek_read(1).is_some() {
+                    return Err(CodecError::Decode("Trailing bytes after bincode payload".into()));
+                }
+                Ok(value)
+            }
+            codec => {
+                let untyped: Untyped = codec.decode_serde(buf)?;
+                from_untyped(shape, untyped).map_err(|e| CodecError::Decode(e.into()))
+            }
+        }
+    }
+
+    /// Encode `self` as `shape` with `codec`. Fails if the value doesn't have
+    /// that shape.
+    pub fn encode(&self, shape: &SchemaType, codec: DataCodec) -> Result<Vec<u8>, CodecError> {
+        let shaped = Shaped { shape, value: self };
+        match codec {
+            DataCodec::Bincode => DataCodec::encode_bincode(&shaped),
+            codec => codec.encode_serde(&shaped),
+        }
+    }
+
+    fn as_u128(&self) -> Option<u128> {
+        match *self {
+            DataValue::UInt(value) => Some(value),
+            DataValue::Int(value) => value.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    fn as_i128(&self) -> Option<i128> {
+        match *self {
+            DataValue::UInt(value) => value.try_into().ok(),
+            DataValue::Int(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match *self {
+            DataValue::Float(value) => Some(value),
+            DataValue::UInt(value) => Some(value as f64),
+            DataValue::Int(value) => Some(value as f64),
+            _ => None,
+        }
+    }
+}
+
+fn decode_len<D: Decoder>(decoder: &mut D) -> Result<usize, DecodeError> {
+    let len = u64::decode(decoder)?;
+    usize::try_from(len).map_err(|_| DecodeError::OutsideUsizeRange(len))
+}
+
+/// Whether values of `shape` take up no bytes at all in bincode
+fn is_empty(shape: &SchemaType) -> bool {
+    match shape {
+        SchemaType::Unit => true,
+        SchemaType::Tuple(items) | SchemaType::TupleStruct { fields: items, .. } => items.iter().all(is_empty),
+        SchemaType::Struct { fields, .. } => fields.iter().all(|field| is_empty(&field.shape)),
+        SchemaType::Array { item, len } => *len == 0 || is_empty(item),
+        _ => false,
+    }
+}
+
+/// Take `len` items of `shape` out of the `empty_items` a payload may hold,
+/// if they take up no bytes.
+fn claim_empty_items(shape: &SchemaType, len: usize, empty_items: &mut usize) -> Result<(), DecodeError> {
+    if is_empty(shape) {
+        *empty_items = empty_items.checked_sub(len).ok_or(DecodeError::Other("Too many empty items in payload"))?;
+    }
+    Ok(())
+}
+
+fn decode_items<D: Decoder>(shape: &SchemaType, len: usize, decoder: &mut D, empty_items: &mut usize) -> Result<Vec<DataValue>, DecodeError> {
+    claim_empty_items(shape, len, empty_items)?;
+    // The length is untrusted, so only the items actually read are allocated
+    let mut items = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        items.push(decode_bincode(shape, decoder, empty_items)?);
+    }
+    Ok(items)
+}
+
+/// Decode a value of `shape` laid out the way bincode's standard config and
+/// `#[derive(Data)]` lay it out. Every other item runs out of input before it
+/// runs out of memory, but items that take up no bytes are taken out of
+/// `empty_items` instead.
+fn decode_bincode<D: Decoder>(shape: &SchemaType, decoder: &mut D, empty_items: &mut usize) -> Result<DataValue, DecodeError> {
+    Ok(match shape {
+        SchemaType::Unit => DataValue::Unit,
+        SchemaType::Bool => DataValue::Bool(bool::decode(decoder)?),
+        SchemaType::U8 => DataValue::UInt(u8::decode(decoder)?.into()),
+        SchemaType::U16 => DataValue::UInt(u16::decode(decoder)?.into()),
+        SchemaType::U32 => DataValue::UInt(u32::decode(decoder)?.into()),
+        SchemaType::U64 => DataValue::UInt(u64::decode(decoder)?.into()),
+        SchemaType::U128 => DataValue::UInt(u128::decode(decoder)?),
+        SchemaType::I8 => DataValue::Int(i8::decode(decoder)?.into()),
+        SchemaType::I16 => DataValue::Int(i16::decode(decoder)?.into()),
+        SchemaType::I32 => DataValue::Int(i32::decode(decoder)?.into()),
+        SchemaType::I64 => DataValue::Int(i64::decode(decoder)?.into()),
+        SchemaType::I128 => DataValue::Int(i128::decode(decoder)?),
+        SchemaType::F32 => DataValue::Float(f32::decode(decoder)?.into()),
+        SchemaType::F64 => DataValue::Float(f64::decode(decoder)?),
+        SchemaType::Char => DataValue::Char(char::decode(decoder)?),
+        SchemaType::String => DataValue::String(String::decode(decoder)?),
+        SchemaType::Option(inner) => match u8::decode(decoder)? {
+            0 => DataValue::Option(None),
+            1 => DataValue::Option(Some(Box::new(decode_bincode(inner, decoder, empty_items)?))),
+            found => return Err(DecodeError::UnexpectedVariant {
+                type_name: "Option",
+                allowed: &bincode::error::AllowedEnumVariants::Range { min: 0, max: 1 },
+                found: found.into(),
+            }),
+        },
+        SchemaType::List(item) => {
+            let len = decode_len(decoder)?;
+            DataValue::List(decode_items(item, len, decoder, empty_items)?)
+        }
+        SchemaType::Array { item, len } => DataValue::List(decode_items(item, *len as usize, decoder, empty_items)?),
+        SchemaType::Map { key, value } => {
+            let len = decode_len(decoder)?;
+            if is_empty(key) {
+                claim_empty_items(value, len, empty_items)?;
+            }
+            let mut entries = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                entries.push((decode_bincode(key, decoder, empty_items)?, decode_bincode(value, decoder, empty_items)?));
+            }
+            DataValue::Map(entries)
+        }
+        SchemaType::Tuple(items) | SchemaType::TupleStruct { fields: items, .. } => DataValue::Tuple(
+            items.iter().map(|item| decode_bincode(item, decoder, empty_items)).collect::<Result<_, _>>()?
+        ),
+        SchemaType::Struct { fields, .. } => DataValue::Struct(
+            fields.iter()
+                .map(|field| Ok((field.name.clone(), decode_bincode(&field.shape, decoder, empty_items)?)))
+                .collect::<Result<_, DecodeError>>()?
+        ),
+        SchemaType::Enum { name, variants } => {
+            let index = u32::decode(decoder)?;
+            let variant = variants.get(index as usize).ok_or_else(|| DecodeError::OtherString(
+                format!("Variant {index} of {name} isn't in the schema")
+            ))?;
+            DataValue::Variant {
+                name: variant.name.clone(),
+                value: Box::new(decode_bincode(&variant.shape, decoder, empty_items)?),
+            }
+        }
+    })
+}
+
+/// A [DataValue] together with the shape to encode it as.
+struct Shaped<'a> {
+    shape: &'a SchemaType,
+    value: &'a DataValue,
+}
+
+impl<'a> Shaped<'a> {
+    fn of(shape: &'a SchemaType, value: &'a DataValue) -> Self {
+        Shaped { shape, value }
+    }
+
+    fn mismatch(&self) -> String {
+        format!("Expected a value of shape {:?}, found {:?}", self.shape, self.value)
+    }
+
+    /// Check a struct value against its fields, in the schema's order
+    fn struct_fields(&self, fields: &'a [SchemaField], values: &'a [(String, DataValue)]) -> Result<Vec<Shaped<'a>>, String> {
+        if fields.len() != values.len() || fields.iter().zip(values).any(|(field, (name, _))| field.name != *name) {
+            return Err(self.mismatch());
+        }
+        Ok(fields.iter().zip(values).map(|(field, (_, value))| Shaped::of(&field.shape, value)).collect())
+    }
+
+    fn variant(&self, variants: &'a [SchemaVariant], name: &str, value: &'a DataValue) -> Result<(u32, Shaped<'a>), String> {
+        let index = variants.iter().position(|variant| variant.name == name).ok_or_else(|| self.mismatch())?;
+        Ok((index as u32, Shaped::of(&variants[index].shape, value)))
+    }
+}
+
+macro_rules! encode_as {
+    ($encoder:expr, $value:expr, $shaped:expr, $ty:ty, $as:ident) => {{
+        let value: $ty = $value.$as().and_then(|value| value.try_into().ok())
+            .ok_or_else(|| EncodeError::OtherString($shaped.mismatch()))?;
+        value.encode($encoder)
+    }};
+}
+
+impl Encode for Shaped<'_> {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let mismatch = || EncodeError::OtherString(self.mismatch());
+        match (self.shape, self.value) {
+            (SchemaType::Unit, DataValue::Unit) => Ok(()),
+            (SchemaType::Bool, DataValue::Bool(value)) => value.encode(encoder),
+            (SchemaType::U8, value) => encode_as!(encoder, value, self, u8, as_u128),
+            (SchemaType::U16, value) => encode_as!(encoder, value, self, u16, as_u128),
+            (SchemaType::U32, value) => encode_as!(encoder, value, self, u32, as_u128),
+            (SchemaType::U64, value) => encode_as!(encoder, value, self, u64, as_u128),
+            (SchemaType::U128, value) => encode_as!(encoder, value, self, u128, as_u128),
+            (SchemaType::I8, value) => encode_as!(encoder, value, self, i8, as_i128),
+            (SchemaType::I16, value) => encode_as!(encoder, value, self, i16, as_i128),
+            (SchemaType::I32, value) => encode_as!(encoder, value, self, i32, as_i128),
+            (SchemaType::I64, value) => encode_as!(encoder, value, self, i64, as_i128),
+            (SchemaType::I128, value) => encode_as!(encoder, value, self, i128, as_i128),
+            (SchemaType::F32, value) => (value.as_f64().ok_or_else(mismatch)? as f32).encode(encoder),
+            (SchemaType::F64, value) => value.as_f64().ok_or_else(mismatch)?.encode(encoder),
+            (SchemaType::Char, DataValue::Char(value)) => value.encode(encoder),
+            (SchemaType::String, DataValue::String(value)) => value.encode(encoder),
+            (SchemaType::Option(_), DataValue::Option(None)) => 0u8.encode(encoder),
+            (SchemaType::Option(inner), DataValue::Option(Some(value))) => {
+                1u8.encode(encoder)?;
+                Shaped::of(inner, value).encode(encoder)
+            }
+            (SchemaType::List(item), DataValue::List(items)) => {
+                (items.len() as u64).encode(encoder)?;
+                items.iter().try_for_each(|value| Shaped::of(item, value).encode(encoder))
+            }
+            (SchemaType::Array { item, len }, DataValue::List(items)) if items.len() == *len as usize => {
+                items.iter().try_for_each(|value| Shaped::of(item, value).encode(encoder))
+            }
+            (SchemaType::Map { key, value: value_shape }, DataValue::Map(entries)) => {
+                (entries.len() as u64).encode(encoder)?;
+                entries.iter().try_for_each(|(k, v)| {
+                    Shaped::of(key, k).encode(encoder)?;
+                    Shaped::of(value_shape, v).encode(encoder)
+                })
+            }
+            (SchemaType::Tuple(shapes) | SchemaType::TupleStruct { fields: shapes, .. }, DataValue::Tuple(items))
+                if shapes.len() == items.len() =>
+            {
+                shapes.iter().zip(items).try_for_each(|(shape, value)| Shaped::of(shape, value).encode(encoder))
+            }
+            (SchemaType::Struct { fields, .. }, DataValue::Struct(values)) => {
+                self.struct_fields(fields, values).map_err(EncodeError::OtherString)?
+                    .iter()
+                    .try_for_each(|field| field.encode(encoder))
+            }
+            (SchemaType::Enum { variants, .. }, DataValue::Variant { name, value }) => {
+                let (index, value) = self.variant(variants, name, value).map_err(EncodeError::OtherString)?;
+                index.encode(encoder)?;
+                value.encode(encoder)
+            }
+            _ => Err(mismatch()),
+        }
+    }
+}
+
+/// Lays values out the way serde's derives would for the Rust type the schema
+/// was made from: structs as maps, newtypes as their field, and enums
+/// externally tagged.
+impl Serialize for Shaped<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        let mismatch = || S::Error::custom(self.mismatch());
+        match (self.shape, self.value) {
+            (SchemaType::Unit, DataValue::Unit) => serializer.serialize_unit(),
+            (SchemaType::Bool, DataValue::Bool(value)) => serializer.serialize_bool(*value),
+            (SchemaType::U8 | SchemaType::U16 | SchemaType::U32 | SchemaType::U64, value) => {
+                let value = value.as_u128().and_then(|value| u64::try_from(value).ok()).ok_or_else(mismatch)?;
+                serializer.serialize_u64(value)
+            }
+            (SchemaType::U128, value) => serializer.serialize_u128(value.as_u128().ok_or_else(mismatch)?),
+            (SchemaType::I8 | SchemaType::I16 | SchemaType::I32 | SchemaType::I64, value) => {
+                let value = value.as_i128().and_then(|value| i64::try_from(value).ok()).ok_or_else(mismatch)?;
+                serializer.serialize_i64(value)
+            }
+            (SchemaType::I128, value) => serializer.serialize_i128(value.as_i128().ok_or_else(mismatch)?),
+            (SchemaType::F32 | SchemaType::F64, value) => serializer.serialize_f64(value.as_f64().ok_or_else(mismatch)?),
+            (SchemaType::Char, DataValue::Char(value)) => serializer.serialize_char(*value),
+            (SchemaType::String, DataValue::String(value)) => serializer.serialize_str(value),
+            (SchemaType::Option(_), DataValue::Option(None)) => serializer.serialize_none(),
+            (SchemaType::Option(inner), DataValue::Option(Some(value))) => serializer.serialize_some(&Shaped::of(inner, value)),
+            (SchemaType::List(item), DataValue::List(items)) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for value in items {
+                    seq.serialize_element(&Shaped::of(item, value))?;
+                }
+                seq.end()
+            }
+            (SchemaType::Array { item, len }, DataValue::List(items)) if items.len() == *len as usize => {
+                let mut tuple = serializer.serialize_tuple(items.len())?;
+                for value in items {
+                    tuple.serialize_element(&Shaped::of(item, value))?;
+                }
+                tuple.end()
+            }
+            (SchemaType::Map { key, value: value_shape }, DataValue::Map(entries)) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(&Shaped::of(key, k), &Shaped::of(value_shape, v))?;
+                }
+                map.end()
+            }
+            (SchemaType::TupleStruct { fields, .. }, DataValue::Tuple(items)) if fields.len() == items.len() => {
+                match (fields.as_slice(), items.as_slice()) {
+                    ([], []) => serializer.serialize_unit_struct(""),
+                    ([field], [value]) => Shaped::of(field, value).serialize(serializer),
+                    _ => serialize_tuple(serializer, fields, items),
+                }
+            }
+            (SchemaType::Tuple(shapes), DataValue::Tuple(items)) if shapes.len() == items.len() => {
+                serialize_tuple(serializer, shapes, items)
+            }
+            (SchemaType::Struct { fields, .. }, DataValue::Struct(values)) => {
+                let fields = self.struct_fields(fields, values).map_err(S::Error::custom)?;
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for ((name, _), field) in values.iter().zip(&fields) {
+                    map.serialize_entry(name, field)?;
+                }
+                map.end()
+            }
+            (SchemaType::Enum { variants, .. }, DataValue::Variant { name, value }) => {
+                let (_, value) = self.variant(variants, name, value).map_err(S::Error::custom)?;
+                if let SchemaType::Unit = value.shape {
+                    return serializer.serialize_str(name);
+                }
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(name, &value)?;
+                map.end()
+            }
+            _ => Err(mismatch()),
+        }
+    }
+}
+
+fn serialize_tuple<S: Serializer>(serializer: S, shapes: &[SchemaType], items: &[DataValue]) -> Result<S::Ok, S::Error> {
+    let mut tuple = serializer.serialize_tuple(items.len())?;
+    for (shape, value) in shapes.iter().zip(items) {
+        tuple.serialize_element(&Shaped::of(shape, value))?;
+    }
+    tuple.end()
+}
+
+/// What a self-describing codec holds before the schema gives it meaning.
+enum Untyped {
+    Null,
+    Bool(bool),
+    UInt(u128),
+    Int(i128),
+    Float(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Seq(Vec<Untyped>),
+    Map(Vec<(Untyped, Untyped)>),
+}
+
+impl<'de> Deserialize<'de> for Untyped {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UntypedVisitor)
+    }
+}
+
+struct UntypedVisitor;
+
+impl<'de> Visitor<'de> for UntypedVisitor {
+    type Value = Untyped;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Untyped, E> {
+        Ok(Untyped::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Untyped, E> {
+        Ok(Untyped::Int(v.into()))
+    }
+
+    fn visit_i128<E>(self, v: i128) -> Result<Untyped, E> {
+        Ok(Untyped::Int(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Untyped, E> {
+        Ok(Untyped::UInt(v.into()))
+    }
+
+    fn visit_u128<E>(self, v: u128) -> Result<Untyped, E> {
+        Ok(Untyped::UInt(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Untyped, E> {
+        Ok(Untyped::Float(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Untyped, E> {
+        Ok(Untyped::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Untyped, E> {
+        Ok(Untyped::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Untyped, E> {
+        Ok(Untyped::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Untyped, E> {
+        Ok(Untyped::Bytes(v))
+    }
+
+    fn visit_none<E>(self) -> Result<Untyped, E> {
+        Ok(Untyped::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Untyped, D::Error> {
+        Untyped::deserialize(deserializer)
+    }
+
+    fn visit_unit<E>(self) -> Result<Untyped, E> {
+        Ok(Untyped::Null)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Untyped, A::Error> {
+        let mut items = Vec::new();
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Untyped::Seq(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Untyped, A::Error> {
+        let mut entries = Vec::new();
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Untyped::Map(entries))
+    }
+}
+
+/// Interpret a value from a self-describing codec as `shape`.
+fn from_untyped(shape: &SchemaType, value: Untyped) -> Result<DataValue, String> {
+    let mismatch = |value: &Untyped| format!("Expected a value of shape {shape:?}, found {}", value.kind());
+    Ok(match (shape, value) {
+        (SchemaType::Unit, Untyped::Null) => DataValue::Unit,
+        (SchemaType::Bool, Untyped::Bool(value)) => DataValue::Bool(value),
+        (SchemaType::U8 | SchemaType::U16 | SchemaType::U32 | SchemaType::U64 | SchemaType::U128, value) => {
+            let uint = match value {
+                Untyped::UInt(v) => Some(v),
+                Untyped::Int(v) => v.try_into().ok(),
+                _ => None,
+            };
+            let uint = uint.filter(|v| *v <= unsigned_max(shape)).ok_or_else(|| mismatch(&value))?;
+            DataValue::UInt(uint)
+        }
+        (SchemaType::I8 | SchemaType::I16 | SchemaType::I32 | SchemaType::I64 | SchemaType::I128, value) => {
+            let int = match value {
+                Untyped::UInt(v) => v.try_into().ok(),
+                Untyped::Int(v) => Some(v),
+                _ => None,
+            };
+            let (min, max) = signed_range(shape);
+            let int = int.filter(|v| (min..=max).contains(v)).ok_or_else(|| mismatch(&value))?;
+            DataValue::Int(int)
+        }
+        (SchemaType::F32 | SchemaType::F64, Untyped::Float(v)) => DataValue::Float(v),
+        (SchemaType::F32 | SchemaType::F64, Untyped::UInt(v)) => DataValue::Float(v as f64),
+        (SchemaType::F32 | SchemaType::F64, Untyped::Int(v)) => DataValue::Float(v as f64),
+        (SchemaType::Char, Untyped::String(value)) => {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => DataValue::Char(c),
+                _ => return Err(format!("Expected a single character, found {value:?}")),
+            }
+        }
+        (SchemaType::String, Untyped::String(value)) => DataValue::String(value),
+        (SchemaType::Option(_), Untyped::Null) => DataValue::Option(None),
+        (SchemaType::Option(inner), value) => DataValue::Option(Some(Box::new(from_untyped(inner, value)?))),
+        (SchemaType::List(item), Untyped::Seq(items)) => DataValue::List(from_items(item, items)?),
+        (SchemaType::List(item), Untyped::Bytes(bytes)) | (SchemaType::Array { item, .. }, Untyped::Bytes(bytes)) => {
+            let items = bytes.into_iter().map(|byte| Untyped::UInt(byte.into())).collect();
+            return from_untyped(&SchemaType::List(item.clone()), Untyped::Seq(items))
+                .and_then(|list| check_array(shape, list));
+        }
+        (SchemaType::Array { item, .. }, Untyped::Seq(items)) => check_array(shape, DataValue::List(from_items(item, items)?))?,
+        (SchemaType::Map { key, value: value_shape }, Untyped::Map(entries)) => DataValue::Map(
+            entries.into_iter()
+                .map(|(k, v)| Ok((from_untyped(key, map_key(key, k))?, from_untyped(value_shape, v)?)))
+                .collect::<Result<_, String>>()?
+        ),
+        (SchemaType::TupleStruct { fields, .. }, value) if fields.is_empty() => match value {
+            // MessagePack writes unit structs as an empty array
+            Untyped::Null => DataValue::Tuple(Vec::new()),
+            Untyped::Seq(items) if items.is_empty() => DataValue::Tuple(Vec::new()),
+            value => return Err(mismatch(&value)),
+        },
+        (SchemaType::TupleStruct { fields, .. }, value) if fields.len() == 1 => {
+            DataValue::Tuple(vec![from_untyped(&fields[0], value)?])
+        }
+        (SchemaType::Tuple(shapes) | SchemaType::TupleStruct { fields: shapes, .. }, Untyped::Seq(items))
+            if shapes.len() == items.len() =>
+        {
+            DataValue::Tuple(
+                shapes.iter().zip(items).map(|(shape, item)| from_untyped(shape, item)).collect::<Result<_, _>>()?
+            )
+        }
+        (SchemaType::Struct { fields, .. }, Untyped::Map(entries)) => {
+            let mut entries: Vec<(String, Untyped)> = entries.into_iter()
+                .map(|(k, v)| match k {
+                    Untyped::String(name) => Ok((name, v)),
+                    k => Err(format!("Expected a field name, found {}", k.kind())),
+                })
+                .collect::<Result<_, _>>()?;
+            let mut values = Vec::with_capacity(fields.len());
+            for field in fields {
+                let value = match entries.iter().position(|(name, _)| *name == field.name) {
+                    Some(i) => from_untyped(&field.shape, entries.swap_remove(i).1)?,
+                    None if field.is_optional() => DataValue::Option(None),
+                    None => return Err(format!("Missing field {}", field.name)),
+                };
+                values.push((field.name.clone(), value));
+            }
+            if let Some((name, _)) = entries.first() {
+                return Err(format!("Unknown field {name}"));
+            }
+            DataValue::Struct(values)
+        }
+        (SchemaType::Struct { fields, .. }, Untyped::Seq(items)) if fields.len() == items.len() => DataValue::Struct(
+            // Structs written as arrays, like MessagePack's compact form
+            fields.iter()
+                .zip(items)
+                .map(|(field, item)| Ok((field.name.clone(), from_untyped(&field.shape, item)?)))
+                .collect::<Result<_, String>>()?
+        ),
+        (SchemaType::Enum { name: enum_name, variants }, value) => {
+            let (name, value) = match value {
+                Untyped::String(name) => (name, None),
+                Untyped::Map(entries) if entries.len() == 1 => match entries.into_iter().next() {
+                    Some((Untyped::String(name), value)) => (name, Some(value)),
+                    _ => return Err(format!("Expected a variant of {enum_name}")),
+                },
+                value => return Err(mismatch(&value)),
+            };
+            let variant = variants.iter()
+                .find(|variant| variant.name == name)
+                .ok_or_else(|| format!("{name} isn't a variant of {enum_name}"))?;
+            let value = match (value, &variant.shape) {
+                (None, SchemaType::Unit) => DataValue::Unit,
+                (None, _) => return Err(format!("Variant {name} of {enum_name} needs a value")),
+                (Some(value), shape) => from_untyped(shape, value)?,
+            };
+            DataValue::Variant { name, value: Box::new(value) }
+        }
+        (_, value) => return Err(mismatch(&value)),
+    })
+}
+
+fn from_items(item: &SchemaType, items: Vec<Untyped>) -> Result<Vec<DataValue>, String> {
+    items.into_iter().map(|value| from_untyped(item, value)).collect()
+}
+
+fn check_array(shape: &SchemaType, list: DataValue) -> Result<DataValue, String> {
+    match (shape, &list) {
+        (SchemaType::Array { len, .. }, DataValue::List(items)) if items.len() != *len as usize => {
+            Err(format!("Expected {len} items, found {}", items.len()))
+        }
+        _ => Ok(list),
+    }
+}
+
+/// JSON only has string keys, so numeric and boolean keys come back as strings
+fn map_key(shape: &SchemaType, key: Untyped) -> Untyped {
+    let Untyped::String(text) = &key else {
+        return key;
+    };
+    let parsed = match shape {
+        SchemaType::U8 | SchemaType::U16 | SchemaType::U32 | SchemaType::U64 | SchemaType::U128 => {
+            text.parse().ok().map(Untyped::UInt)
+        }
+        SchemaType::I8 | SchemaType::I16 | SchemaType::I32 | SchemaType::I64 | SchemaType::I128 => {
+            text.parse().ok().map(Untyped::Int)
+        }
+        SchemaType::Bool => text.parse().ok().map(Untyped::Bool),
+        _ => None,
+    };
+    parsed.unwrap_or(key)
+}
+
+fn unsigned_max(shape: &SchemaType) -> u128 {
+    match shape {
+        SchemaType::U8 => u8::MAX.into(),
+        SchemaType::U16 => u16::MAX.into(),
+        SchemaType::U32 => u32::MAX.into(),
+        SchemaType::U64 => u64::MAX.into(),
+        _ => u128::MAX,
+    }
+}
+
+fn signed_range(shape: &SchemaType) -> (i128, i128) {
+    match shape {
+        SchemaType::I8 => (i8::MIN.into(), i8::MAX.into()),
+        SchemaType::I16 => (i16::MIN.into(), i16::MAX.into()),
+        SchemaType::I32 => (i32::MIN.into(), i32::MAX.into()),
+        SchemaType::I64 => (i64::MIN.into(), i64::MAX.into()),
+        _ => (i128::MIN, i128::MAX),
+    }
+}
+
+impl Untyped {
+    fn kind(&self) -> &'static str {
+        match self {
+            Untyped::Null => "null",
+            Untyped::Bool(_) => "a boolean",
+            Untyped::UInt(_) | Untyped::Int(_) => "an integer",
+            Untyped::Float(_) => "a float",
+            Untyped::String(_) => "a string",
+            Untyped::Bytes(_) => "bytes",
+            Untyped::Seq(_) => "a sequence",
+            Untyped::Map(_) => "a map",
+        }
+    }
+}
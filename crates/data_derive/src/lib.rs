@@ -0,0 +1,364 @@
+//! `#[derive(Data)]` for `osp_data`. Use it through the re-export,
+//! `osp_data::Data`.
+
+use proc_macro::TokenStream;
+
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+
+use quote::{format_ident, quote};
+
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, Generics, Ident, LitInt, LitStr, Type};
+use syn::spanned::Spanned;
+
+use uuid::Uuid;
+
+/// Implement `osp_data::Data`, `osp_data::DataType`, `osp_data::Describe` and
+/// bincode's `Encode` and `Decode` for a struct or enum.
+///
+/// ```no_run
+/// # use osp_data::Data;
+/// #[derive(Data)]
+/// #[osp(id = "6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1", version = 1)]
+/// struct Post {
+///     title: String,
+/// }
+/// ```
+///
+/// The id is checked when the macro runs, and `version` defaults to `1`.
+///
+/// `codecs(cbor, msgpack, json)` adds self-describing codecs in order of
+/// preference. These need serde's `Serialize` and `Deserialize` as well.
+/// Bincode is always available, after the listed codecs unless listed itself.
+///
+/// Every field has to implement `osp_data::Describe` so the type's schema can
+/// be built.
+#[proc_macro_derive(Data, attributes(osp))]
+pub fn derive_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Implement `osp_data::Describe` for a struct or enum used inside a data
+/// type, describing it from its fields.
+#[proc_macro_derive(Describe)]
+pub fn derive_describe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_describe(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+struct DataAttributes {
+    id: Uuid,
+    version: u32,
+    /// Variant names of `osp_data::DataCodec`
+    codecs: Vec<Ident>,
+}
+
+fn parse_attributes(input: &DeriveInput) -> syn::Result<DataAttributes> {
+    let mut id = None;
+    let mut version = 1;
+    let mut codecs = Vec::new();
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("osp")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let value: LitStr = meta.value()?.parse()?;
+                let parsed = Uuid::parse_str(&value.value())
+                    .map_err(|e| Error::new(value.span(), format!("Invalid data type id: {e}")))?;
+                id = Some(parsed);
+                Ok(())
+            } else if meta.path.is_ident("version") {
+                let value: LitInt = meta.value()?.parse()?;
+                version = value.base10_parse()?;
+                Ok(())
+            } else if meta.path.is_ident("codecs") {
+                meta.parse_nested_meta(|codec| {
+                    let variant = match codec.path.get_ident().map(Ident::to_string).as_deref() {
+                        Some("bincode") => "Bincode",
+                        Some("cbor") => "Cbor",
+                        Some("msgpack") => "MessagePack",
+                        Some("json") => "Json",
+                        _ => return Err(codec.error("Expected one of `bincode`, `cbor`, `msgpack` or `json`")),
+                    };
+                    let variant = Ident::new(variant, codec.path.span());
+                    if !codecs.contains(&variant) {
+                        codecs.push(variant);
+                    }
+                    Ok(())
+                })
+            } else {
+                Err(meta.error("Expected `id`, `version` or `codecs`"))
+            }
+        })?;
+    }
+
+    let id = id.ok_or_else(|| Error::new(
+        Span::call_site(),
+        "Missing data type id, add #[osp(id = \"<uuid>\")]"
+    ))?;
+    let bincode = Ident::new("Bincode", Span::call_site());
+    if !codecs.contains(&bincode) {
+        codecs.push(bincode);
+    }
+    Ok(DataAttributes { id, version, codecs })
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let DataAttributes { id, version, codecs } = parse_attributes(&input)?;
+    let serde_codecs: Vec<_> = codecs.iter().filter(|codec| *codec != "Bincode").collect();
+    let name = &input.ident;
+    let id = Literal::u128_unsuffixed(id.as_u128());
+
+    let describe = expand_describe(&input)?;
+    let data_generics = with_bound(&input.generics, quote!(::osp_data::Describe));
+    let encode_generics = with_bound(&input.generics, quote!(::osp_data::bincode::Encode));
+    let decode_generics = with_bound(&input.generics, quote!(::osp_data::bincode::Decode));
+    let (impl_generics, ty_generics, where_clause) = data_generics.split_for_impl();
+    let (encode_impl_generics, _, encode_where_clause) = encode_generics.split_for_impl();
+    let (decode_impl_generics, _, decode_where_clause) = decode_generics.split_for_impl();
+
+    let (encode_body, decode_body) = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, encode) = destructure(&data.fields);
+            let construct = construct(quote!(Self), &data.fields);
+            (
+                quote! {
+                    let Self #pattern = self;
+                    #encode
+                },
+                quote!(::core::result::Result::Ok(#construct)),
+            )
+        }
+        Data::Enum(data) => {
+            let mut encode_arms = Vec::new();
+            let mut decode_arms = Vec::new();
+            for (index, variant) in data.variants.iter().enumerate() {
+                let index = index as u32;
+                let variant_name = &variant.ident;
+                let (pattern, encode) = destructure(&variant.fields);
+                let construct = construct(quote!(Self::#variant_name), &variant.fields);
+                encode_arms.push(quote! {
+                    Self::#variant_name #pattern => {
+                        ::osp_data::bincode::Encode::encode(&#index, encoder)?;
+                        #encode
+                    }
+                });
+                decode_arms.push(quote!(#index => ::core::result::Result::Ok(#construct),));
+            }
+            let max = data.variants.len().saturating_sub(1) as u32;
+            (
+                quote! {
+                    match self {
+                        #(#encode_arms)*
+                    }
+                },
+                quote! {
+                    let variant: u32 = ::osp_data::bincode::Decode::decode(decoder)?;
+                    match variant {
+                        #(#decode_arms)*
+                        found => ::core::result::Result::Err(::osp_data::bincode::error::DecodeError::UnexpectedVariant {
+                            type_name: ::core::stringify!(#name),
+                            allowed: &::osp_data::bincode::error::AllowedEnumVariants::Range { min: 0, max: #max },
+                            found,
+                        }),
+                    }
+                },
+            )
+        }
+        Data::Union(_) => return Err(Error::new(Span::call_site(), "Data can't be derived for unions")),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::osp_data::DataType for #name #ty_generics #where_clause {
+            const ID: ::osp_data::uuid::Uuid = ::osp_data::uuid::Uuid::from_u128(#id);
+            const VERSION: u32 = #version;
+        }
+
+        impl #impl_generics ::osp_data::Data for #name #ty_generics #where_clause {
+            fn get_id() -> ::osp_data::uuid::Uuid {
+                <Self as ::osp_data::DataType>::ID
+            }
+
+            fn get_version() -> u32 {
+                <Self as ::osp_data::DataType>::VERSION
+            }
+
+            fn schema() -> ::core::option::Option<::osp_data::DataSchema> {
+                ::core::option::Option::Some(::osp_data::DataSchema {
+                    id: <Self as ::osp_data::DataType>::ID,
+                    version: <Self as ::osp_data::DataType>::VERSION,
+                    shape: <Self as ::osp_data::Describe>::describe(),
+                })
+            }
+
+            fn codecs() -> &'static [::osp_data::DataCodec] {
+                &[#(::osp_data::DataCodec::#codecs),*]
+            }
+
+            fn encode_with(&self, codec: ::osp_data::DataCodec) -> ::core::result::Result<::std::vec::Vec<u8>, ::osp_data::CodecError>
+            where
+                Self: ::osp_data::bincode::Encode,
+            {
+                match codec {
+                    ::osp_data::DataCodec::Bincode => ::osp_data::DataCodec::encode_bincode(self),
+                    #(::osp_data::DataCodec::#serde_codecs => codec.encode_serde(self),)*
+                    #[allow(unreachable_patterns)]
+                    codec => ::core::result::Result::Err(::osp_data::CodecError::Unsupported(codec)),
+                }
+            }
+
+            fn decode_with(codec: ::osp_data::DataCodec, buf: &[u8]) -> ::core::result::Result<Self, ::osp_data::CodecError>
+            where
+                Self: ::osp_data::bincode::Decode,
+            {
+                match codec {
+                    ::osp_data::DataCodec::Bincode => ::osp_data::DataCodec::decode_bincode(buf),
+                    #(::osp_data::DataCodec::#serde_codecs => codec.decode_serde(buf),)*
+                    #[allow(unreachable_patterns)]
+                    codec => ::core::result::Result::Err(::osp_data::CodecError::Unsupported(codec)),
+                }
+            }
+        }
+
+        #describe
+
+        impl #encode_impl_generics ::osp_data::bincode::Encode for #name #ty_generics #encode_where_clause {
+            fn encode<E: ::osp_data::bincode::enc::Encoder>(
+                &self,
+                encoder: &mut E,
+            ) -> ::core::result::Result<(), ::osp_data::bincode::error::EncodeError> {
+                #encode_body
+                ::core::result::Result::Ok(())
+            }
+        }
+
+        impl #decode_impl_generics ::osp_data::bincode::Decode for #name #ty_generics #decode_where_clause {
+            fn decode<D: ::osp_data::bincode::de::Decoder>(
+                decoder: &mut D,
+            ) -> ::core::result::Result<Self, ::osp_data::bincode::error::DecodeError> {
+                #decode_body
+            }
+        }
+    })
+}
+
+fn expand_describe(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let type_name = name.to_string();
+    let shape = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(_) => {
+                let fields = describe_fields(&data.fields);
+                quote!(::osp_data::SchemaType::Struct {
+                    name: ::std::string::String::from(#type_name),
+                    fields: ::std::vec![#(#fields),*],
+                })
+            }
+            fields => {
+                let fields = fields.iter().map(|field| describe_type(&field.ty));
+                quote!(::osp_data::SchemaType::TupleStruct {
+                    name: ::std::string::String::from(#type_name),
+                    fields: ::std::vec![#(#fields),*],
+                })
+            }
+        },
+        Data::Enum(data) => {
+            let variants = data.variants.iter().map(|variant| {
+                let variant_name = variant.ident.to_string();
+                let shape = match &variant.fields {
+                    Fields::Named(_) => {
+                        let fields = describe_fields(&variant.fields);
+                        quote!(::osp_data::SchemaType::Struct {
+                            name: ::std::string::String::from(#variant_name),
+                            fields: ::std::vec![#(#fields),*],
+                        })
+                    }
+                    Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => describe_type(&unnamed.unnamed[0].ty),
+                    Fields::Unnamed(unnamed) => {
+                        let fields = unnamed.unnamed.iter().map(|field| describe_type(&field.ty));
+                        quote!(::osp_data::SchemaType::Tuple(::std::vec![#(#fields),*]))
+                    }
+                    Fields::Unit => quote!(::osp_data::SchemaType::Unit),
+                };
+                quote!(::osp_data::SchemaVariant {
+                    name: ::std::string::String::from(#variant_name),
+                    shape: #shape,
+                })
+            });
+            quote!(::osp_data::SchemaType::Enum {
+                name: ::std::string::String::from(#type_name),
+                variants: ::std::vec![#(#variants),*],
+            })
+        }
+        Data::Union(_) => return Err(Error::new(Span::call_site(), "Describe can't be derived for unions")),
+    };
+
+    let generics = with_bound(&input.generics, quote!(::osp_data::Describe));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::osp_data::Describe for #name #ty_generics #where_clause {
+            fn describe() -> ::osp_data::SchemaType {
+                #shape
+            }
+        }
+    })
+}
+
+/// A `SchemaField` for each named field.
+fn describe_fields(fields: &Fields) -> Vec<TokenStream2> {
+    fields.iter()
+        .map(|field| {
+            let name = field.ident.as_ref().map(Ident::to_string);
+            let shape = describe_type(&field.ty);
+            quote!(::osp_data::SchemaField {
+                name: ::std::string::String::from(#name),
+                shape: #shape,
+            })
+        })
+        .collect()
+}
+
+fn describe_type(ty: &Type) -> TokenStream2 {
+    quote!(<#ty as ::osp_data::Describe>::describe())
+}
+
+/// Add `bound` to every type parameter.
+fn with_bound(generics: &Generics, bound: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+/// A pattern binding every field by reference, and the code encoding them in
+/// order.
+fn destructure(fields: &Fields) -> (TokenStream2, TokenStream2) {
+    let bindings: Vec<_> = (0..fields.len()).map(|i| format_ident!("field_{i}")).collect();
+    let pattern = match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!({ #(#names: #bindings),* })
+        }
+        Fields::Unnamed(_) => quote!(( #(#bindings),* )),
+        Fields::Unit => quote!(),
+    };
+    let encode = quote! {
+        #(::osp_data::bincode::Encode::encode(#bindings, encoder)?;)*
+    };
+    (pattern, encode)
+}
+
+/// An expression building `path` from fields decoded in order.
+fn construct(path: TokenStream2, fields: &Fields) -> TokenStream2 {
+    let decode = quote!(::osp_data::bincode::Decode::decode(decoder)?);
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|field| &field.ident);
+            quote!(#path { #(#names: #decode),* })
+        }
+        Fields::Unnamed(unnamed) => {
+            let decodes = unnamed.unnamed.iter().map(|_| &decode);
+            quote!(#path( #(#decodes),* ))
+        }
+        Fields::Unit => path,
+    }
+}
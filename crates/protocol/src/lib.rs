@@ -13,8 +13,12 @@
 // }
 
 mod protocol;
+mod split;
 mod utils;
 mod url;
 pub mod packet;
+pub mod quic;
+pub mod tls;
+pub mod websocket;
 
-pub use {protocol::*, url::OSPUrl, utils::ConnectionType};
\ No newline at end of file
+pub use {protocol::*, split::{ProtocolReceiver, ProtocolSender, CONTROL_QUEUE_CAPACITY, OUTBOUND_QUEUE_CAPACITY}, url::{OSPUrl, ResourcePath, DEFAULT_PORT}, utils::{ConnectionType, Transport}};
\ No newline at end of file
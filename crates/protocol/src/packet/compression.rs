@@ -0,0 +1,205 @@
+//! # Frame Compression
+//!
+//! The algorithm is negotiated during the handshake and applied to each
+//! transfer-phase frame by [PacketEncoder] and [PacketDecoder].
+//!
+//! [PacketEncoder]: crate::packet::PacketEncoder
+//! [PacketDecoder]: crate::packet::PacketDecoder
+
+use std::io::Read;
+
+use tokio::io;
+
+/// Frames whose serialized length is below this are never compressed, as the
+/// savings would not be worth the work.
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Compression {
+    /// Every algorithm this implementation can speak, in order of preference.
+    pub const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+    pub(crate) fn from_u8(t: u8) -> Option<Compression> {
+        match t {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Zstd),
+            2 => Some(Compression::Lz4),
+            _ => None
+        }
+    }
+
+    /// Pick the first algorithm in `preferred` that also appears in `offered`,
+    /// or [Compression::None] if there is no overlap.
+    pub fn negotiate(preferred: &[Compression], offered: &[Compression]) -> Compression {
+        preferred.iter()
+            .find(|algorithm| **algorithm != Compression::None && offered.contains(algorithm))
+            .copied()
+            .unwrap_or(Compression::None)
+    }
+
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Compression::Lz4 => {
+                // lz4 blocks don't record their decompressed size, so prefix
+                // it ourselves to be checked before decompressing
+                let mut compressed = (data.len() as u32).to_be_bytes().to_vec();
+                compressed.extend_from_slice(&lz4_flex::block::compress(data));
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// Decompress `data`, failing instead of producing more than `max_length`
+    /// bytes so a small frame can't expand into an unbounded allocation.
+    pub(crate) fn decompress(&self, data: &[u8], max_length: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => decompress_zstd(data, max_length),
+            Compression::Lz4 => {
+                if data.len() < 4 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated lz4 frame"));
+                }
+                let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                if length > max_length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Decompressed frame of length {} is too large.", length)
+                    ));
+                }
+                lz4_flex::block::decompress(&data[4..], length)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+/// Decompress a zstd frame into a buffer of the length its header declares,
+/// rather than one of `max_length` bytes for every frame. Frames that don't
+/// declare their length are decompressed as a stream that is cut off past
+/// `max_length`.
+fn decompress_zstd(data: &[u8], max_length: usize) -> io::Result<Vec<u8>> {
+    let too_large = |length: u64| io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Decompressed frame of length {} is too large.", length)
+    );
+    match zstd::zstd_safe::get_frame_content_size(data) {
+        Ok(Some(length)) if length > max_length as u64 => Err(too_large(length)),
+        Ok(Some(length)) => zstd::bulk::decompress(data, length as usize),
+        Ok(None) => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::new(data)?
+                .take(max_length as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+            match decompressed.len() > max_length {
+                true => Err(too_large(decompressed.len() as u64)),
+                false => Ok(decompressed),
+            }
+        }
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+    }
+}
+
+impl From<&Compression> for u8 {
+    fn from(c: &Compression) -> Self {
+        *c as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio::io;
+    use tokio_util::codec::{Decoder, Encoder};
+    use uuid::Uuid;
+
+    use crate::packet::{PacketDecoder, PacketEncoder, PACKET_MAX_LENGTH};
+    use crate::packet::compression::{Compression, COMPRESSION_THRESHOLD};
+    use crate::packet::transfer::TransferPacket;
+
+    fn chunk(data: Vec<u8>) -> TransferPacket {
+        TransferPacket::Chunk { transfer_id: Uuid::new_v4(), last: true, data }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Compression::negotiate(&Compression::SUPPORTED, &[Compression::Lz4]), Compression::Lz4);
+        assert_eq!(Compression::negotiate(&[Compression::Lz4, Compression::Zstd], &Compression::SUPPORTED), Compression::Lz4);
+        assert_eq!(Compression::negotiate(&Compression::SUPPORTED, &[]), Compression::None);
+    }
+
+    #[test]
+    fn test_compressed_frame_round_trip() -> io::Result<()> {
+        let text = "syndicated data is mostly text and compresses well. ".repeat(1000).into_bytes();
+        for compression in Compression::SUPPORTED {
+            let mut encoder = PacketEncoder::<TransferPacket>::new().with_compression(compression);
+            let mut decoder = PacketDecoder::<TransferPacket>::new().with_compression(compression);
+            let buf = &mut BytesMut::new();
+
+            encoder.encode(chunk(text.clone()), buf)?;
+            assert_eq!(buf[4], u8::from(&compression));
+            assert!(buf.len() < text.len() / 4);
+
+            match decoder.decode(buf)? {
+                Some(TransferPacket::Chunk { data, .. }) => assert_eq!(data, text),
+                _ => panic!("Expected chunk packet"),
+            }
+            assert!(buf.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_small_frame_left_uncompressed() -> io::Result<()> {
+        let mut encoder = PacketEncoder::<TransferPacket>::new().with_compression(Compression::Zstd);
+        let mut decoder = PacketDecoder::<TransferPacket>::new().with_compression(Compression::Zstd);
+        let buf = &mut BytesMut::new();
+
+        encoder.encode(chunk(vec![0u8; COMPRESSION_THRESHOLD / 2]), buf)?;
+        assert_eq!(buf[4], u8::from(&Compression::None));
+        assert!(matches!(decoder.decode(buf)?, Some(TransferPacket::Chunk { .. })));
+        Ok(())
+    }
+
+    /// Frames that don't declare their length are still held to the limit.
+    #[test]
+    fn test_zstd_stream_frames() -> io::Result<()> {
+        let text = "syndicated data is mostly text and compresses well. ".repeat(100).into_bytes();
+        let compressed = zstd::stream::encode_all(&text[..], 3)?;
+        assert_eq!(zstd::zstd_safe::get_frame_content_size(&compressed).ok(), Some(None));
+
+        assert_eq!(Compression::Zstd.decompress(&compressed, text.len())?, text);
+        let err = Compression::Zstd.decompress(&compressed, text.len() - 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    /// A tiny frame that would decompress past [PACKET_MAX_LENGTH] must be
+    /// rejected rather than expanded.
+    #[test]
+    fn test_decompression_bomb_rejected() -> io::Result<()> {
+        let bomb = vec![0u8; PACKET_MAX_LENGTH * 2];
+        for compression in Compression::SUPPORTED {
+            let compressed = compression.compress(&bomb)?;
+            assert!(compressed.len() < PACKET_MAX_LENGTH);
+
+            let buf = &mut BytesMut::new();
+            buf.put_u32_le(compressed.len() as u32 + 1);
+            buf.put_u8(u8::from(&compression));
+            buf.put_slice(&compressed);
+
+            let mut decoder = PacketDecoder::<TransferPacket>::new().with_compression(compression);
+            assert!(decoder.decode(buf).is_err());
+        }
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 use crate::packet::{DeserializePacket, SerializePacket};
 
 pub struct DataPacket {
@@ -24,10 +24,9 @@ impl DeserializePacket for DataPacket {
     type Output = Self;
 
     fn deserialize(buf: &mut BytesMut) -> std::io::Result<Self::Output> {
-        let length = buf.get_u64() as usize;
+        let length = Self::read_u64(buf)? as usize;
 
-        let mut data = vec![0u8; length];
-        buf.copy_to_slice(&mut data);
+        let data = Self::read_fixed(buf, length)?;
 
         Ok(DataPacket {
             length,
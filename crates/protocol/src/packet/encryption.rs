@@ -0,0 +1,338 @@
+//! # Session Encryption
+//!
+//! During the handshake each side generates an [EphemeralKey] and signs it with
+//! the RSA key published in its `_osp` DNS record. The X25519 exchange of the
+//! two ephemeral keys yields one [SessionCipher] per direction, which
+//! [PacketEncoder] and [PacketDecoder] use to seal every transfer-phase frame
+//! with ChaCha20-Poly1305.
+//!
+//! Encrypted frames look like `[flags: u8][ciphertext][tag: 16 bytes]`. Nonces
+//! are never sent: both sides count the frames under the current key, so a
+//! dropped, replayed or reordered frame fails authentication. Once a key has
+//! sealed [REKEY_AFTER_BYTES] or is older than [REKEY_AFTER], the sender
+//! ratchets it forward and sets [KEY_UPDATE] on the first frame under the new
+//! key so the receiver can follow.
+//!
+//! [PacketEncoder]: crate::packet::PacketEncoder
+//! [PacketDecoder]: crate::packet::PacketDecoder
+
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+
+use openssl::derive::Deriver;
+use openssl::md::Md;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::pkey_ctx::PkeyCtx;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+/// Length of the symmetric key used in each direction
+pub const SESSION_KEY_LENGTH: usize = 32;
+
+/// Length of the Poly1305 authentication tag at the end of every frame
+pub const TAG_LENGTH: usize = 16;
+
+/// How many bytes encryption adds to every frame
+pub const ENCRYPTION_OVERHEAD: usize = 1 + TAG_LENGTH;
+
+/// Rekey once a key has sealed this many bytes
+pub const REKEY_AFTER_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Rekey once a key has been in use for this long
+pub const REKEY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Frame flag set when the sender ratcheted its key before sealing the frame
+pub const KEY_UPDATE: u8 = 0b0000_0001;
+
+const GUEST_TO_HOST_INFO: &[u8] = b"osp session key guest to host";
+const HOST_TO_GUEST_INFO: &[u8] = b"osp session key host to guest";
+const REKEY_INFO: &[u8] = b"osp session rekey";
+const STREAM_INFO: &[u8] = b"osp session stream ";
+
+/// Which end of the handshake we are, deciding which derived key is used for
+/// sending and which for receiving.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SessionRole {
+    Host,
+    Guest,
+}
+
+/// The pair of keys derived for one connection.
+pub struct SessionKeys {
+    pub send: [u8; SESSION_KEY_LENGTH],
+    pub receive: [u8; SESSION_KEY_LENGTH],
+}
+
+/// An X25519 key pair generated for a single handshake and thrown away after.
+pub struct EphemeralKey {
+    key: PKey<Private>,
+}
+
+impl EphemeralKey {
+    pub fn generate() -> io::Result<Self> {
+        Ok(Self {
+            key: PKey::generate_x25519()?,
+        })
+    }
+
+    /// The raw 32 byte public key to send to the peer
+    pub fn public_key(&self) -> io::Result<Vec<u8>> {
+        Ok(self.key.raw_public_key()?)
+    }
+
+    /// Run the key exchange against the peer's public key and derive the
+    /// session keys for each direction, salted with the handshake nonce.
+    pub fn derive_session_keys(&self, peer_public_key: &[u8], nonce: &Uuid, role: SessionRole) -> io::Result<SessionKeys> {
+        let peer_key = PKey::public_key_from_raw_bytes(peer_public_key, Id::X25519)?;
+        let mut deriver = Deriver::new(&self.key)?;
+        deriver.set_peer(&peer_key)?;
+        let shared_secret = deriver.derive_to_vec()?;
+
+        let salt = nonce.as_bytes();
+        let guest_to_host = hkdf(&shared_secret, salt, GUEST_TO_HOST_INFO)?;
+        let host_to_guest = hkdf(&shared_secret, salt, HOST_TO_GUEST_INFO)?;
+        Ok(match role {
+            SessionRole::Host => SessionKeys { send: host_to_guest, receive: guest_to_host },
+            SessionRole::Guest => SessionKeys { send: guest_to_host, receive: host_to_guest },
+        })
+    }
+}
+
+/// HKDF-SHA256 expanding `key` into a single session key
+fn hkdf(key: &[u8], salt: &[u8], info: &[u8]) -> io::Result<[u8; SESSION_KEY_LENGTH]> {
+    let mut ctx = PkeyCtx::new_id(Id::HKDF)?;
+    ctx.derive_init()?;
+    ctx.set_hkdf_md(Md::sha256())?;
+    ctx.set_hkdf_key(key)?;
+    ctx.set_hkdf_salt(salt)?;
+    ctx.add_hkdf_info(info)?;
+
+    let mut out = [0u8; SESSION_KEY_LENGTH];
+    ctx.derive(Some(&mut out))?;
+    Ok(out)
+}
+
+/// The sealing or opening state for one direction of a connection.
+pub struct SessionCipher {
+    key: [u8; SESSION_KEY_LENGTH],
+    sequence: u64,
+    bytes_sealed: u64,
+    key_created: Instant,
+    rekey_after_bytes: u64,
+    rekey_after: Duration,
+}
+
+impl SessionCipher {
+    pub fn new(key: [u8; SESSION_KEY_LENGTH]) -> Self {
+        Self {
+            key,
+            sequence: 0,
+            bytes_sealed: 0,
+            key_created: Instant::now(),
+            rekey_after_bytes: REKEY_AFTER_BYTES,
+            rekey_after: REKEY_AFTER,
+        }
+    }
+
+    /// Override when the sealing side ratchets its key. Defaults to
+    /// [REKEY_AFTER_BYTES] and [REKEY_AFTER].
+    pub fn with_rekey_limits(mut self, after_bytes: u64, after: Duration) -> Self {
+        self.rekey_after_bytes = after_bytes;
+        self.rekey_after = after;
+        self
+    }
+
+    /// An independent cipher for another stream of the same session, for
+    /// transports that carry several streams at once. Both peers derive the
+    /// same key for the same `stream`, and because each stream gets its own
+    /// key the sequence numbers never collide. Call before the cipher is used.
+    pub fn for_stream(&self, stream: u32) -> io::Result<SessionCipher> {
+        let mut info = STREAM_INFO.to_vec();
+        info.extend_from_slice(&stream.to_be_bytes());
+        Ok(SessionCipher::new(hkdf(&self.key, &[], &info)?)
+            .with_rekey_limits(self.rekey_after_bytes, self.rekey_after))
+    }
+
+    /// Encrypt `plaintext` as the next frame and append it to `dst`.
+    pub fn seal(&mut self, plaintext: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        let mut flags = 0u8;
+        if self.bytes_sealed >= self.rekey_after_bytes || self.key_created.elapsed() >= self.rekey_after {
+            self.rekey()?;
+            flags |= KEY_UPDATE;
+        }
+
+        let nonce = self.next_nonce()?;
+        let mut tag = [0u8; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.key,
+            Some(&nonce),
+            &[flags],
+            plaintext,
+            &mut tag,
+        )?;
+        self.bytes_sealed += plaintext.len() as u64;
+
+        dst.put_u8(flags);
+        dst.put_slice(&ciphertext);
+        dst.put_slice(&tag);
+        Ok(())
+    }
+
+    /// Authenticate and decrypt the next frame.
+    pub fn open(&mut self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        if frame.len() < ENCRYPTION_OVERHEAD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Encrypted frame is truncated"));
+        }
+        let flags = frame[0];
+        if flags & KEY_UPDATE != 0 {
+            self.rekey()?;
+        }
+
+        let nonce = self.next_nonce()?;
+        let (ciphertext, tag) = frame[1..].split_at(frame.len() - ENCRYPTION_OVERHEAD);
+        decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.key,
+            Some(&nonce),
+            &[flags],
+            ciphertext,
+            tag,
+        ).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Frame failed authentication"))
+    }
+
+    /// How many times the current key has been used
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn next_nonce(&mut self) -> io::Result<[u8; 12]> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.sequence.to_be_bytes());
+        self.sequence = self.sequence.checked_add(1)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Session nonce exhausted"))?;
+        Ok(nonce)
+    }
+
+    fn rekey(&mut self) -> io::Result<()> {
+        self.key = hkdf(&self.key, &[], REKEY_INFO)?;
+        self.sequence = 0;
+        self.bytes_sealed = 0;
+        self.key_created = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use tokio::io;
+    use uuid::Uuid;
+
+    use tokio_util::codec::{Decoder, Encoder};
+
+    use crate::packet::{PacketDecoder, PacketEncoder};
+    use crate::packet::compression::Compression;
+    use crate::packet::encryption::{EphemeralKey, SessionCipher, SessionRole, KEY_UPDATE};
+    use crate::packet::transfer::TransferPacket;
+
+    fn session_pair() -> io::Result<(SessionCipher, SessionCipher)> {
+        let nonce = Uuid::new_v4();
+        let host = EphemeralKey::generate()?;
+        let guest = EphemeralKey::generate()?;
+        let host_keys = host.derive_session_keys(&guest.public_key()?, &nonce, SessionRole::Host)?;
+        let guest_keys = guest.derive_session_keys(&host.public_key()?, &nonce, SessionRole::Guest)?;
+        assert_eq!(host_keys.send, guest_keys.receive);
+        assert_eq!(host_keys.receive, guest_keys.send);
+        assert_ne!(host_keys.send, host_keys.receive);
+        Ok((SessionCipher::new(host_keys.send), SessionCipher::new(guest_keys.receive)))
+    }
+
+    #[test]
+    fn test_seal_and_open() -> io::Result<()> {
+        let (mut sealer, mut opener) = session_pair()?;
+        for message in [&b"first"[..], b"second", b""] {
+            let frame = &mut BytesMut::new();
+            sealer.seal(message, frame)?;
+            assert_eq!(opener.open(frame)?, message);
+        }
+        Ok(())
+    }
+
+    /// Tampered, replayed and dropped frames all fail authentication.
+    #[test]
+    fn test_rejects_tampered_and_replayed_frames() -> io::Result<()> {
+        let (mut sealer, mut opener) = session_pair()?;
+        let first = &mut BytesMut::new();
+        sealer.seal(b"hello", first)?;
+
+        let mut tampered = first.clone();
+        tampered[2] ^= 0xff;
+        assert!(opener.open(&tampered).is_err());
+
+        let (mut sealer, mut opener) = session_pair()?;
+        let first = &mut BytesMut::new();
+        sealer.seal(b"hello", first)?;
+        assert!(opener.open(first).is_ok());
+        assert!(opener.open(first).is_err());
+
+        let (mut sealer, mut opener) = session_pair()?;
+        sealer.seal(b"dropped", &mut BytesMut::new())?;
+        let second = &mut BytesMut::new();
+        sealer.seal(b"second", second)?;
+        assert!(opener.open(second).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_after_byte_limit() -> io::Result<()> {
+        let (sealer, mut opener) = session_pair()?;
+        let mut sealer = sealer.with_rekey_limits(16, Duration::from_secs(3600));
+
+        for i in 0..10u8 {
+            let frame = &mut BytesMut::new();
+            sealer.seal(&[i; 10], frame)?;
+            // Every second frame crosses the 16 byte limit
+            assert_eq!(frame[0] & KEY_UPDATE != 0, i > 0 && i % 2 == 0);
+            assert_eq!(opener.open(frame)?, vec![i; 10]);
+        }
+        assert_eq!(sealer.sequence(), opener.sequence());
+        Ok(())
+    }
+
+    /// Frames are compressed, then sealed, and come back out of the decoder
+    /// intact. The plaintext never appears on the wire.
+    #[test]
+    fn test_encrypted_compressed_codec() -> io::Result<()> {
+        let (sealer, opener) = session_pair()?;
+        let mut encoder = PacketEncoder::<TransferPacket>::new()
+            .with_compression(Compression::Zstd)
+            .with_encryption(sealer);
+        let mut decoder = PacketDecoder::<TransferPacket>::new()
+            .with_compression(Compression::Zstd)
+            .with_encryption(opener);
+
+        let text = "private syndicated data ".repeat(100).into_bytes();
+        let buf = &mut BytesMut::new();
+        for _ in 0..3 {
+            encoder.encode(TransferPacket::Chunk { transfer_id: Uuid::new_v4(), last: true, data: text.clone() }, buf)?;
+        }
+        encoder.encode(TransferPacket::Ping { nonce: 7 }, buf)?;
+        assert!(!buf.windows(7).any(|window| window == b"private"));
+
+        for _ in 0..3 {
+            match decoder.decode(buf)? {
+                Some(TransferPacket::Chunk { data, .. }) => assert_eq!(data, text),
+                _ => panic!("Expected chunk packet"),
+            }
+        }
+        assert!(matches!(decoder.decode(buf)?, Some(TransferPacket::Ping { nonce: 7 })));
+        Ok(())
+    }
+}
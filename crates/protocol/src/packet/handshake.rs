@@ -1,7 +1,7 @@
 //! # Handshake Packets
 //!
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 
 use tokio::io;
 
@@ -9,21 +9,31 @@ use uuid::Uuid;
 
 use crate::ConnectionType;
 use crate::packet::{DeserializePacket, SerializePacket};
+use crate::packet::compression::Compression;
 
 
 pub enum HandshakePacketGuestToHost {
     // in
     Hello {
         connection_type: ConnectionType,
+        /// Compression algorithms the guest supports for transfer frames
+        compression: Vec<Compression>,
+        /// The longest transfer frame the guest will accept
+        max_frame_length: u32,
     },
     /// Send my hostname to the other server
     Identify {
         hostname: String,
     },
-    /// Send the client-decrypted challenge bytes back to the server
+    /// Send the client-decrypted challenge bytes back to the server, along
+    /// with the guest's half of the session key exchange
     Verify {
         challenge: Vec<u8>,
         nonce: Uuid,
+        /// The guest's ephemeral X25519 public key
+        ephemeral_key: Vec<u8>,
+        /// The guest's `_osp` RSA key signing the handshake transcript
+        signature: Vec<u8>,
     },
 }
 
@@ -32,12 +42,21 @@ pub enum HandshakePacketHostToGuest {
     Acknowledge {
         ok: bool,
         err: Option<String>,
+        /// The compression algorithm picked for transfer frames
+        compression: Compression,
+        /// The longest transfer frame the host will accept
+        max_frame_length: u32,
     },
 
-    /// Send the challenge bytes to the client to decrypt
+    /// Send the challenge bytes to the client to decrypt, along with the
+    /// host's half of the session key exchange
     Challenge {
         encrypted_challenge: Vec<u8>,
         nonce: Uuid,
+        /// The host's ephemeral X25519 public key
+        ephemeral_key: Vec<u8>,
+        /// The host's `_osp` RSA key signing the handshake transcript
+        signature: Vec<u8>,
     },
     Close {
         can_continue: bool,
@@ -71,19 +90,32 @@ impl SerializePacket for HandshakePacketGuestToHost {
         buf.put_u8(self.into()); // Message Type byte
         let mut bytes_written: usize = 1;
         match self {
-            HandshakePacketGuestToHost::Hello { connection_type } => {
+            HandshakePacketGuestToHost::Hello { connection_type, compression, max_frame_length } => {
                 buf.put_u8(u8::from(connection_type));
-                bytes_written += 1
+                bytes_written += 1;
+
+                buf.put_u8(compression.len() as u8);
+                bytes_written += 1;
+                for algorithm in compression {
+                    buf.put_u8(u8::from(algorithm));
+                    bytes_written += 1;
+                }
+
+                buf.put_u32(*max_frame_length);
+                bytes_written += 4;
             }
             HandshakePacketGuestToHost::Identify { hostname } => {
-                bytes_written += self.write_string(buf, hostname);
+                bytes_written += self.write_string(buf, hostname)?;
             }
-            HandshakePacketGuestToHost::Verify { challenge, nonce } => {
+            HandshakePacketGuestToHost::Verify { challenge, nonce, ephemeral_key, signature } => {
                 bytes_written += self.write_uuid(buf, nonce);
 
                 // since this is always 256 bytes we can leave the len header out
                 buf.put_slice(challenge);
                 bytes_written += 256;
+
+                bytes_written += self.write_bytes(buf, ephemeral_key);
+                bytes_written += self.write_bytes(buf, signature);
             }
         }
         Ok(bytes_written)
@@ -95,25 +127,34 @@ impl SerializePacket for HandshakePacketHostToGuest {
         buf.put_u8(self.into()); // Message Type byte
         let mut bytes_written: usize = 1;
         match self {
-            HandshakePacketHostToGuest::Acknowledge { ok, err } => {
+            HandshakePacketHostToGuest::Acknowledge { ok, err, compression, max_frame_length } => {
                 buf.put_u8(*ok as u8);
                 bytes_written += 1;
 
-                bytes_written += self.write_optional_string(buf, err);
+                bytes_written += self.write_optional_message(buf, err);
+
+                buf.put_u8(u8::from(compression));
+                bytes_written += 1;
+
+                buf.put_u32(*max_frame_length);
+                bytes_written += 4;
             }
-            HandshakePacketHostToGuest::Challenge { encrypted_challenge, nonce } => {
+            HandshakePacketHostToGuest::Challenge { encrypted_challenge, nonce, ephemeral_key, signature } => {
                 buf.put_u16(encrypted_challenge.len() as u16);
                 bytes_written += 2;
                 buf.put_slice(encrypted_challenge);
                 bytes_written += encrypted_challenge.len();
 
                 bytes_written += self.write_uuid(buf, nonce);
+
+                bytes_written += self.write_bytes(buf, ephemeral_key);
+                bytes_written += self.write_bytes(buf, signature);
             }
             HandshakePacketHostToGuest::Close { can_continue: ok, err} => {
                 buf.put_u8(*ok as u8);
                 bytes_written += 1;
 
-                bytes_written += self.write_optional_string(buf, err);
+                bytes_written += self.write_optional_message(buf, err);
             }
         }
 
@@ -126,21 +167,34 @@ impl DeserializePacket for HandshakePacketGuestToHost {
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
         // We'll match the same `u8` that is used to recognize which request type this is
-        match buf.get_u8() {
-            1 => Ok(HandshakePacketGuestToHost::Hello {
-                connection_type: ConnectionType::from_u8(buf.get_u8()),
-            }),
+        match Self::read_u8(buf)? {
+            1 => {
+                let connection_type = ConnectionType::from_u8(Self::read_u8(buf)?);
+                let count = Self::read_u8(buf)?;
+                // Algorithms we don't know about can't be picked, so skip them
+                let mut compression = Vec::new();
+                for _ in 0..count {
+                    compression.extend(Compression::from_u8(Self::read_u8(buf)?));
+                }
+
+                Ok(HandshakePacketGuestToHost::Hello {
+                    connection_type,
+                    compression,
+                    max_frame_length: Self::read_u32(buf)?,
+                })
+            },
             2 => Ok(HandshakePacketGuestToHost::Identify {
                 hostname: Self::read_string(buf)?,
             }),
             3 => {
-                let nonce = Self::read_uuid(buf);
-                let mut challenge_bytes = vec![0u8; 256];
-                buf.copy_to_slice(&mut challenge_bytes);
+                let nonce = Self::read_uuid(buf)?;
+                let challenge_bytes = Self::read_fixed(buf, 256)?;
 
                 Ok(HandshakePacketGuestToHost::Verify {
                     challenge: challenge_bytes,
                     nonce,
+                    ephemeral_key: Self::read_bytes(buf)?,
+                    signature: Self::read_bytes(buf)?,
                 })
             },
             _ => Err(io::Error::new(
@@ -155,23 +209,29 @@ impl DeserializePacket for HandshakePacketHostToGuest {
     type Output = HandshakePacketHostToGuest;
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
-        match buf.get_u8() {
+        match Self::read_u8(buf)? {
             1 => Ok(HandshakePacketHostToGuest::Acknowledge {
-                ok: buf.get_u8() != 0,
+                ok: Self::read_u8(buf)? != 0,
                 err: Self::read_optional_string(buf)?,
+                compression: Compression::from_u8(Self::read_u8(buf)?).ok_or(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unknown compression algorithm",
+                ))?,
+                max_frame_length: Self::read_u32(buf)?,
             }),
             2 => {
-                let challenge_len = buf.get_u16();
-                let mut challenge_encrypted = vec![0u8; challenge_len as usize];
-                buf.copy_to_slice(&mut challenge_encrypted);
+                let challenge_len = Self::read_u16(buf)?;
+                let challenge_encrypted = Self::read_fixed(buf, challenge_len as usize)?;
 
                 Ok(HandshakePacketHostToGuest::Challenge {
                     encrypted_challenge: challenge_encrypted,
-                    nonce: Self::read_uuid(buf),
+                    nonce: Self::read_uuid(buf)?,
+                    ephemeral_key: Self::read_bytes(buf)?,
+                    signature: Self::read_bytes(buf)?,
                 })
             },
             3 => Ok(HandshakePacketHostToGuest::Close {
-                can_continue: buf.get_u8() != 0,
+                can_continue: Self::read_u8(buf)? != 0,
                 err: Self::read_optional_string(buf)?,
             }),
             _ => Err(io::Error::new(
@@ -184,7 +244,62 @@ impl DeserializePacket for HandshakePacketHostToGuest {
 
 #[cfg(test)]
 mod tests {
-    async fn serialize_handshake_packets() {
+    use bytes::BytesMut;
+    use tokio::io;
+    use uuid::Uuid;
+
+    use crate::ConnectionType;
+    use crate::packet::{DeserializePacket, SerializePacket};
+    use crate::packet::compression::Compression;
+    use crate::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+
+    #[test]
+    fn serialize_handshake_packets() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        let nonce = Uuid::new_v4();
+
+        let bytes_written = HandshakePacketGuestToHost::Verify {
+            challenge: vec![7u8; 256],
+            nonce,
+            ephemeral_key: vec![1u8; 32],
+            signature: vec![2u8; 512],
+        }.serialize(buf)?;
+        assert_eq!(bytes_written, buf.len());
+        match HandshakePacketGuestToHost::deserialize(buf)? {
+            HandshakePacketGuestToHost::Verify { challenge, nonce: nonce_de, ephemeral_key, signature } => {
+                assert_eq!(challenge, vec![7u8; 256]);
+                assert_eq!(nonce_de, nonce);
+                assert_eq!(ephemeral_key, vec![1u8; 32]);
+                assert_eq!(signature, vec![2u8; 512]);
+            }
+            _ => panic!("Expected verify packet"),
+        }
 
+        HandshakePacketGuestToHost::Hello {
+            connection_type: ConnectionType::Server,
+            compression: vec![Compression::Lz4, Compression::Zstd],
+            max_frame_length: 64 * 1024,
+        }.serialize(buf)?;
+        match HandshakePacketGuestToHost::deserialize(buf)? {
+            HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server, compression, max_frame_length } => {
+                assert_eq!(compression, vec![Compression::Lz4, Compression::Zstd]);
+                assert_eq!(max_frame_length, 64 * 1024);
+            }
+            _ => panic!("Expected hello packet"),
+        }
+
+        HandshakePacketHostToGuest::Close {
+            can_continue: false,
+            err: Some("Challenge failed".to_string()),
+        }.serialize(buf)?;
+        match HandshakePacketHostToGuest::deserialize(buf)? {
+            HandshakePacketHostToGuest::Close { can_continue, err } => {
+                assert!(!can_continue);
+                assert_eq!(err.as_deref(), Some("Challenge failed"));
+            }
+            _ => panic!("Expected close packet"),
+        }
+        assert!(buf.is_empty());
+        Ok(())
     }
 }
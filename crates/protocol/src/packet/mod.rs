@@ -10,31 +10,112 @@ use uuid::Uuid;
 pub mod handshake;
 pub mod transfer;
 pub mod data;
+pub mod compression;
+pub mod encryption;
 
-/// The maximum length a packet can be. Any data that needs to be sent and is
-/// longer than this maximum should be chunked into multiple packets.
-const PACKET_MAX_LENGTH: usize = 8 * 1024 * 1024;
+use compression::{Compression, COMPRESSION_THRESHOLD};
+use encryption::{SessionCipher, ENCRYPTION_OVERHEAD};
+
+/// The default maximum length a packet can be. Any data that needs to be sent
+/// and is longer than the maximum should be chunked into multiple packets.
+///
+/// Handshake packets always use this limit. For the transfer phase each side
+/// advertises its own maximum during the handshake, see
+/// [PacketDecoder::with_max_length] and [PacketEncoder::with_max_length].
+pub const PACKET_MAX_LENGTH: usize = 8 * 1024 * 1024;
+
+/// The smallest maximum packet length a node may advertise. Anything lower
+/// couldn't reliably carry the protocol's own control packets.
+pub const PACKET_MIN_MAX_LENGTH: usize = 4 * 1024;
+
+/// The longest string, in bytes, that fits in a packet. Strings are written
+/// with a `u16` length header.
+pub const STRING_MAX_LENGTH: usize = u16::MAX as usize;
+
+/// Which outbound lane a packet is queued on once a [Protocol] has been
+/// split. Control packets are always written before any queued bulk packets,
+/// so they only ever wait for the bulk frame currently being written.
+///
+/// [Protocol]: crate::Protocol
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    /// Small, latency sensitive frames such as pings, acks and replies
+    Control,
+    /// Everything else, including chunks of large transfers
+    Bulk,
+}
 
 /// This trait is used to serialize from a packet to a [BytesMut]
 pub trait SerializePacket {
     /// Serialize to a [BytesMut]
     fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize>;
 
+    /// The outbound lane this packet should be queued on, see [Priority].
+    fn priority(&self) -> Priority {
+        Priority::Bulk
+    }
+
+    /// The transfer this packet is part of, if any. On transports with
+    /// several streams every transfer is written to a stream of its own, so
+    /// a stalled transfer doesn't hold up the others.
+    fn transfer_id(&self) -> Option<Uuid> {
+        None
+    }
+
+    /// Whether this is the last packet of its transfer, after which the
+    /// stream of the transfer is closed.
+    fn ends_transfer(&self) -> bool {
+        false
+    }
+
     /// Write a `String` to `buf` and return how many bytes were written.
-    fn write_string(&self, buf: &mut BytesMut, string: &String) -> usize where Self : Sized {
+    /// Fails with [io::ErrorKind::InvalidInput] if the string is longer than
+    /// [STRING_MAX_LENGTH], since its length header couldn't hold it.
+    fn write_string(&self, buf: &mut BytesMut, string: &String) -> io::Result<usize> where Self : Sized {
         let bytes = string.as_bytes();
+        if bytes.len() > STRING_MAX_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("String of {} bytes is longer than {} bytes", bytes.len(), STRING_MAX_LENGTH),
+            ));
+        }
         buf.put_u16(bytes.len() as u16);
-        buf.put_slice(&bytes);
-        2 + bytes.len() // u16 = 2 bytes
+        buf.put_slice(bytes);
+        Ok(2 + bytes.len()) // u16 = 2 bytes
     }
 
     /// Write an `Option<String>` to `buf` and return how many bytes were
-    /// written.
-    fn write_optional_string(&self, buf: &mut BytesMut, string: &Option<String>) -> usize where Self: Sized {
+    /// written. Fails like [SerializePacket::write_string].
+    fn write_optional_string(&self, buf: &mut BytesMut, string: &Option<String>) -> io::Result<usize> where Self: Sized {
         buf.put_u8(string.is_some() as u8);
         let mut bytes_written = 1;
         if let Some(str) = string {
-            bytes_written += self.write_string(buf, str);
+            bytes_written += self.write_string(buf, str)?;
+        }
+        Ok(bytes_written)
+    }
+
+    /// Write a human readable message, such as an error or a rejection
+    /// reason, to `buf` and return how many bytes were written. Unlike
+    /// [SerializePacket::write_string] an over long message is cut at the
+    /// last char boundary within [STRING_MAX_LENGTH] rather than failing.
+    fn write_message(&self, buf: &mut BytesMut, message: &str) -> usize where Self: Sized {
+        let mut length = message.len().min(STRING_MAX_LENGTH);
+        while !message.is_char_boundary(length) {
+            length -= 1;
+        }
+        buf.put_u16(length as u16);
+        buf.put_slice(&message.as_bytes()[..length]);
+        2 + length // u16 = 2 bytes
+    }
+
+    /// Write an optional human readable message to `buf` and return how many
+    /// bytes were written, see [SerializePacket::write_message].
+    fn write_optional_message(&self, buf: &mut BytesMut, message: &Option<String>) -> usize where Self: Sized {
+        buf.put_u8(message.is_some() as u8);
+        let mut bytes_written = 1;
+        if let Some(message) = message {
+            bytes_written += self.write_message(buf, message);
         }
         bytes_written
     }
@@ -47,6 +128,14 @@ pub trait SerializePacket {
         16 // u128 is 16 bytes
     }
 
+    /// Write a byte slice with a `u32` length header to `buf` and return how
+    /// many bytes were written.
+    fn write_bytes(&self, buf: &mut BytesMut, bytes: &[u8]) -> usize where Self: Sized {
+        buf.put_u32(bytes.len() as u32);
+        buf.put_slice(bytes);
+        4 + bytes.len() // u32 = 4 bytes
+    }
+
     /// Write an `Option<Uuid>` to `buf` and return how many bytes were written.
     fn write_optional_uuid(&self, buf: &mut BytesMut, uuid: &Option<Uuid>) -> usize where Self: Sized {
         buf.put_u8(uuid.is_some() as u8);
@@ -56,6 +145,17 @@ pub trait SerializePacket {
         }
         bytes_written
     }
+
+    /// Write an `Option<u64>` to `buf` and return how many bytes were written.
+    fn write_optional_u64(&self, buf: &mut BytesMut, value: &Option<u64>) -> usize where Self: Sized {
+        buf.put_u8(value.is_some() as u8);
+        let mut bytes_written = 1;
+        if let Some(value) = value {
+            buf.put_u64(*value);
+            bytes_written += 8;
+        }
+        bytes_written
+    }
 }
 
 /// Trait for a packet that can be deserialized from a [BytesMut].
@@ -66,14 +166,51 @@ pub trait DeserializePacket {
     /// Deserialize from a [BytesMut]
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output>;
 
+    /// Fail with [io::ErrorKind::UnexpectedEof] unless `buf` holds at least
+    /// `length` more bytes. Every read helper checks this first, so a
+    /// truncated packet is an error rather than a panic.
+    fn need(buf: &BytesMut, length: usize) -> io::Result<()> {
+        if buf.remaining() < length {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Packet ended early"));
+        }
+        Ok(())
+    }
+
+    fn read_u8(buf: &mut BytesMut) -> io::Result<u8> {
+        Self::need(buf, 1)?;
+        Ok(buf.get_u8())
+    }
+
+    fn read_u16(buf: &mut BytesMut) -> io::Result<u16> {
+        Self::need(buf, 2)?;
+        Ok(buf.get_u16())
+    }
+
+    fn read_u32(buf: &mut BytesMut) -> io::Result<u32> {
+        Self::need(buf, 4)?;
+        Ok(buf.get_u32())
+    }
+
+    fn read_u64(buf: &mut BytesMut) -> io::Result<u64> {
+        Self::need(buf, 8)?;
+        Ok(buf.get_u64())
+    }
+
+    /// Read exactly `length` bytes from `buf`
+    fn read_fixed(buf: &mut BytesMut, length: usize) -> io::Result<Vec<u8>> {
+        Self::need(buf, length)?;
+        let mut bytes = vec![0u8; length];
+        buf.copy_to_slice(&mut bytes);
+        Ok(bytes)
+    }
+
     /// From a given [BytesMut], read the next length (u16) and extract the
     /// string bytes, returning a [String].
     fn read_string(buf: &mut BytesMut) -> io::Result<String> {
-        let length = buf.get_u16();
+        let length = Self::read_u16(buf)?;
 
         // Given the length of our string, only read in that quantity of bytes
-        let mut bytes = vec![0u8; length as usize];
-        buf.copy_to_slice(&mut bytes);
+        let bytes = Self::read_fixed(buf, length as usize)?;
 
         // And attempt to decode it as UTF8
         String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid utf8"))
@@ -81,21 +218,35 @@ pub trait DeserializePacket {
 
     /// Read an `Option<String>` from `buf`
     fn read_optional_string(buf: &mut BytesMut) -> io::Result<Option<String>> {
-        Ok(if buf.get_u8() != 0 { // if the boolean is set read the optional value
+        Ok(if Self::read_u8(buf)? != 0 { // if the boolean is set read the optional value
             Some(Self::read_string(buf)?)
         } else { None })
     }
 
+    /// Read a byte vector with a `u32` length header from `buf`
+    fn read_bytes(buf: &mut BytesMut) -> io::Result<Vec<u8>> {
+        let length = Self::read_u32(buf)? as usize;
+        Self::read_fixed(buf, length)
+    }
+
     /// Read a `Uuid` from `buf`
-    fn read_uuid(buf: &mut BytesMut) -> Uuid {
-        Uuid::from_u128(buf.get_u128())
+    fn read_uuid(buf: &mut BytesMut) -> io::Result<Uuid> {
+        Self::need(buf, 16)?;
+        Ok(Uuid::from_u128(buf.get_u128()))
     }
 
     /// Read an `Option<Uuid>` from `buf`
-    fn read_optional_uuid(buf: &mut BytesMut) -> Option<Uuid> {
-        if buf.get_u8() != 0 { // if the boolean is set read the optional value
-            Some(Self::read_uuid(buf))
-        } else { None }
+    fn read_optional_uuid(buf: &mut BytesMut) -> io::Result<Option<Uuid>> {
+        Ok(if Self::read_u8(buf)? != 0 { // if the boolean is set read the optional value
+            Some(Self::read_uuid(buf)?)
+        } else { None })
+    }
+
+    /// Read an `Option<u64>` from `buf`
+    fn read_optional_u64(buf: &mut BytesMut) -> io::Result<Option<u64>> {
+        Ok(if Self::read_u8(buf)? != 0 { // if the boolean is set read the optional value
+            Some(Self::read_u64(buf)?)
+        } else { None })
     }
 }
 
@@ -104,15 +255,64 @@ pub trait DeserializePacket {
 ///
 /// [FramedRead]: tokio_util::codec::FramedRead
 pub struct PacketDecoder<PacketType: DeserializePacket> {
+    max_length: usize,
+    compression: Compression,
+    cipher: Option<SessionCipher>,
     _packet_type: PhantomData<PacketType>
 }
 
 impl<PacketType: DeserializePacket> PacketDecoder<PacketType> {
     pub fn new() -> PacketDecoder<PacketType> {
         PacketDecoder::<PacketType> {
-            _packet_type: PhantomData::default(),
+            max_length: PACKET_MAX_LENGTH,
+            compression: Compression::None,
+            cipher: None,
+            _packet_type: PhantomData,
         }
     }
+
+    /// Reject frames longer than `max_length`, the limit this side
+    /// advertised during the handshake. Defaults to [PACKET_MAX_LENGTH].
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// The longest frame this decoder will accept
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Expect frames to carry a compression header for the negotiated
+    /// `compression` algorithm. See [PacketEncoder::with_compression].
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Authenticate and decrypt every frame with the session `cipher` derived
+    /// during the handshake. See [encryption].
+    pub fn with_encryption(mut self, cipher: SessionCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// A decoder with the same settings for another stream of the same
+    /// connection, see [SessionCipher::for_stream].
+    pub fn for_stream(&self, stream: u32) -> io::Result<Self> {
+        Ok(Self {
+            max_length: self.max_length,
+            compression: self.compression,
+            cipher: self.cipher.as_ref().map(|cipher| cipher.for_stream(stream)).transpose()?,
+            _packet_type: PhantomData,
+        })
+    }
+}
+
+impl<PacketType: DeserializePacket> Default for PacketDecoder<PacketType> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
@@ -131,8 +331,11 @@ impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
         let length = u32::from_le_bytes(length_bytes) as usize;
 
         // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if length > PACKET_MAX_LENGTH {
+        // service attack where the server runs out of memory. Compression and
+        // encryption headers may wrap a maximum length packet.
+        let header_length = (self.compression != Compression::None) as usize
+            + if self.cipher.is_some() { ENCRYPTION_OVERHEAD } else { 0 };
+        if length > self.max_length + header_length {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("Frame of length {} is too large.", length)
@@ -153,9 +356,29 @@ impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
 
         // Use advance to modify src such that it no longer contains
         // this frame.
-        let data = src[4..4 + length].to_vec();
+        let mut data = src[4..4 + length].to_vec();
         src.advance(4 + length);
 
+        if let Some(cipher) = &mut self.cipher {
+            data = cipher.open(&data)?;
+        }
+
+        if self.compression != Compression::None {
+            if data.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Missing compression header"));
+            }
+            match Compression::from_u8(data[0]) {
+                Some(Compression::None) => { data.remove(0); },
+                Some(algorithm) if algorithm == self.compression => {
+                    data = algorithm.decompress(&data[1..], self.max_length)?;
+                }
+                _ => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Frame uses compression {} which was not negotiated", data[0])
+                )),
+            }
+        }
+
         let packet = PacketType::deserialize(&mut BytesMut::from(data.as_slice()))?;
 
         Ok(Some(packet))
@@ -163,41 +386,121 @@ impl<PacketType: DeserializePacket> Decoder for PacketDecoder<PacketType> {
 }
 
 pub struct PacketEncoder<PacketType : SerializePacket> {
+    max_length: usize,
+    compression: Compression,
+    cipher: Option<SessionCipher>,
     _packet_type: PhantomData<PacketType>,
 }
 
 impl<PacketType: SerializePacket> PacketEncoder<PacketType> {
     pub fn new() -> Self {
         PacketEncoder::<PacketType> {
-            _packet_type: PhantomData::default(),
+            max_length: PACKET_MAX_LENGTH,
+            compression: Compression::None,
+            cipher: None,
+            _packet_type: PhantomData,
         }
     }
+
+    /// Refuse to encode frames longer than `max_length`, the limit the peer
+    /// advertised during the handshake. Defaults to [PACKET_MAX_LENGTH].
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = max_length;
+        self
+    }
+
+    /// The longest frame this encoder will produce
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    /// Compress frames of at least [COMPRESSION_THRESHOLD] bytes with the
+    /// negotiated `compression` algorithm. Unless this is
+    /// [Compression::None], every frame starts with a one byte header naming
+    /// the algorithm used, or `0` if that frame was left uncompressed.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Seal every frame with the session `cipher` derived during the
+    /// handshake. Frames are compressed before they are encrypted. See
+    /// [encryption].
+    pub fn with_encryption(mut self, cipher: SessionCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// A encoder with the same settings for another stream of the same
+    /// connection, see [SessionCipher::for_stream].
+    pub fn for_stream(&self, stream: u32) -> io::Result<Self> {
+        Ok(Self {
+            max_length: self.max_length,
+            compression: self.compression,
+            cipher: self.cipher.as_ref().map(|cipher| cipher.for_stream(stream)).transpose()?,
+            _packet_type: PhantomData,
+        })
+    }
+}
+
+impl<PacketType: SerializePacket> Default for PacketEncoder<PacketType> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<PacketType: SerializePacket> Encoder<PacketType> for PacketEncoder<PacketType> {
     type Error = io::Error;
 
     fn encode(&mut self, item: PacketType, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let mut buf = &mut BytesMut::with_capacity(PACKET_MAX_LENGTH);
-        item.serialize(& mut buf)?;
+        // A frame that fails half way is taken back out, as whatever follows
+        // it in `dst` would otherwise be read as part of it
+        let start = dst.len();
+        let result = self.write_frame(item, dst, start);
+        if result.is_err() {
+            dst.truncate(start);
+        }
+        result
+    }
+}
+
+impl<PacketType: SerializePacket> PacketEncoder<PacketType> {
+    fn write_frame(&mut self, item: PacketType, dst: &mut BytesMut, start: usize) -> io::Result<()> {
+        // Serialize straight into `dst` behind a placeholder length marker
+        // which is filled in once the packet length is known.
+        dst.put_u32_le(0);
+        if self.compression != Compression::None {
+            dst.put_u8(u8::from(&Compression::None));
+        }
+        let body_start = dst.len();
+        item.serialize(dst)?;
+        let body_length = dst.len() - body_start;
 
-        if buf.len() > PACKET_MAX_LENGTH {
+        if body_length > self.max_length {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", buf.len())
+                format!("Frame of length {} is too large.", body_length)
             ));
         }
 
-        // Convert the length into a byte array.
-        // The cast to u32 cannot overflow due to the length check above.
-        let len_slice = u32::to_le_bytes(buf.len() as u32);
+        if self.compression != Compression::None && body_length >= COMPRESSION_THRESHOLD {
+            let compressed = self.compression.compress(&dst[body_start..])?;
+            // Incompressible frames are cheaper to send as they are
+            if compressed.len() < body_length {
+                dst.truncate(body_start);
+                dst[body_start - 1] = u8::from(&self.compression);
+                dst.extend_from_slice(&compressed);
+            }
+        }
 
-        // Reserve space in the buffer.
-        dst.reserve(4 + buf.len());
+        if let Some(cipher) = &mut self.cipher {
+            let plaintext = dst.split_off(start + 4);
+            cipher.seal(&plaintext, dst)?;
+        }
+        let length = dst.len() - start - 4;
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.extend_from_slice(buf);
+        // The cast to u32 cannot overflow due to the length check above.
+        dst[start..start + 4].copy_from_slice(&u32::to_le_bytes(length as u32));
         Ok(())
     }
 }
@@ -205,9 +508,11 @@ impl<PacketType: SerializePacket> Encoder<PacketType> for PacketEncoder<PacketTy
 #[cfg(test)]
 mod tests {
     use tokio::io;
+    use tokio_util::codec::{Decoder, Encoder};
     use bytes::{Buf, BufMut, BytesMut};
     use uuid::Uuid;
-    use crate::packet::{DeserializePacket, PACKET_MAX_LENGTH, SerializePacket};
+    use crate::packet::{DeserializePacket, PacketDecoder, PacketEncoder, PACKET_MAX_LENGTH, SerializePacket};
+    use crate::packet::transfer::TransferPacket;
 
     /// A basic test packet for validating basic serialization and
     /// deserialization of values that implement [SerializePacket] and
@@ -226,7 +531,7 @@ mod tests {
             buf.put_u8(self.test_int);
             bytes_written += 1;
 
-            bytes_written += self.write_string(buf, &self.test_string);
+            bytes_written += self.write_string(buf, &self.test_string)?;
             Ok(bytes_written)
         }
     }
@@ -261,7 +566,7 @@ mod tests {
 
         fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
             Ok(TestUuidPacket {
-                test_uuid: Self::read_uuid(buf),
+                test_uuid: Self::read_uuid(buf)?,
             })
         }
     }
@@ -329,4 +634,75 @@ mod tests {
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Frames over the negotiated limit are refused on both ends, while frames
+    /// that fit pass through unchanged.
+    #[test]
+    fn test_negotiated_max_length() -> io::Result<()> {
+        let max_length = 4 * 1024;
+        let mut encoder = PacketEncoder::<TransferPacket>::new().with_max_length(max_length);
+        let mut decoder = PacketDecoder::<TransferPacket>::new().with_max_length(max_length);
+        let buf = &mut BytesMut::new();
+
+        let fits = TransferPacket::chunks(Uuid::new_v4(), &vec![1u8; TransferPacket::chunk_size_for(max_length)]);
+        encoder.encode(fits.into_iter().next().unwrap(), buf)?;
+        assert_eq!(buf.len(), 4 + max_length);
+        assert!(matches!(decoder.decode(buf)?, Some(TransferPacket::Chunk { .. })));
+
+        let too_long = TransferPacket::chunks(Uuid::new_v4(), &vec![1u8; max_length]);
+        assert!(encoder.encode(too_long.into_iter().next().unwrap(), buf).is_err());
+        assert!(buf.is_empty());
+
+        // A peer ignoring our limit is cut off as soon as the length arrives
+        let mut unlimited = PacketEncoder::<TransferPacket>::new();
+        unlimited.encode(TransferPacket::chunks(Uuid::new_v4(), &vec![1u8; max_length]).remove(0), buf)?;
+        assert!(decoder.decode(buf).is_err());
+        Ok(())
+    }
+
+    /// Writes part of itself before failing
+    struct FailingPacket;
+
+    impl SerializePacket for FailingPacket {
+        fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+            buf.put_slice(b"partial");
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Can't be serialized"))
+        }
+    }
+
+    /// A packet that fails to serialize leaves nothing behind, so the frames
+    /// around it still decode.
+    #[test]
+    fn test_failed_encode_leaves_no_trace() -> io::Result<()> {
+        let buf = &mut BytesMut::new();
+        PacketEncoder::<TestPacket>::new().encode(create_test_packet(), buf)?;
+        let length = buf.len();
+        assert!(PacketEncoder::<FailingPacket>::new().encode(FailingPacket, buf).is_err());
+        assert_eq!(buf.len(), length);
+
+        PacketEncoder::<TestPacket>::new().encode(create_test_packet(), buf)?;
+        let mut decoder = PacketDecoder::<TestPacket>::new();
+        assert_eq!(decoder.decode(buf)?, Some(create_test_packet()));
+        assert_eq!(decoder.decode(buf)?, Some(create_test_packet()));
+        Ok(())
+    }
+
+    /// Every truncation of a valid packet is refused instead of panicking.
+    #[test]
+    fn test_truncated_packets() -> io::Result<()> {
+        let packets = [
+            TransferPacket::Fetch { transfer_id: Uuid::new_v4(), data_type: Uuid::new_v4(), object_id: "post/1".to_string(), version: Some(3) },
+            TransferPacket::Follow { request_id: Uuid::new_v4(), data_types: vec![Uuid::new_v4(), Uuid::new_v4()] },
+            TransferPacket::Chunk { transfer_id: Uuid::new_v4(), last: true, data: vec![1, 2, 3] },
+        ];
+        for packet in packets {
+            let full = &mut BytesMut::new();
+            packet.serialize(full)?;
+            for length in 0..full.len() {
+                let err = TransferPacket::deserialize(&mut BytesMut::from(&full[..length])).err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            }
+        }
+        Ok(())
+    }
+}
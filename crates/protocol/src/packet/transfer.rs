@@ -1,39 +1,797 @@
-use bytes::BytesMut;
+//! # Transfer Packets
+//!
+//! Once the handshake is complete both nodes may send any of these packets, so
+//! the same [TransferPacket] type is used in both directions.
+
+use bytes::{BufMut, BytesMut};
+
 use tokio::io;
-use crate::packet::{DeserializePacket, SerializePacket};
 
-pub enum TransferPacketGuestToHost {
+use uuid::Uuid;
+
+use osp_data::{bincode, DataCapability, DataCodec, DataSchema, DataUsage, SignedData};
+use osp_data::bincode::{Decode, Encode};
+use osp_data::bincode::enc::EncoderImpl;
+use osp_data::bincode::enc::write::SizeWriter;
+use osp_data::bincode::error::DecodeError;
+
+use crate::packet::{DeserializePacket, Priority, SerializePacket};
+
+/// The largest payload carried by a single [TransferPacket::Chunk]. Large
+/// transfers are split at this size so control packets can be interleaved
+/// between chunks instead of waiting for the whole transfer.
+pub const CHUNK_SIZE: usize = 64 * 1024;
 
+/// How many bytes a [TransferPacket::Chunk] adds on top of its payload.
+pub const CHUNK_OVERHEAD: usize = 1 + 16 + 1 + 4;
+
+/// How many bytes a [TransferPacket::SyncPage] adds on top of its objects and
+/// cursor, at most.
+pub const SYNC_PAGE_OVERHEAD: usize = 1 + 16 + 4 + 9 + 2 + 1;
+
+/// How many times its encoded length a legitimate bincode value may claim in
+/// memory while it is decoded, counting every container it holds.
+const DECODE_CLAIM_FACTOR: usize = 16;
+
+pub enum TransferPacket {
+    /// Ask the peer to reply with a [TransferPacket::Pong] carrying the same
+    /// nonce
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// One piece of a larger transfer, see [TransferPacket::chunks]
+    Chunk {
+        transfer_id: Uuid,
+        last: bool,
+        data: Vec<u8>,
+    },
+    /// Tell the peer this connection is being closed
+    Close {
+        err: Option<String>,
+    },
+    /// Ask the peer for one of its objects, as named by a [ResourcePath]. The
+    /// peer answers with the encoded object as [TransferPacket::Chunk]s using
+    /// the same `transfer_id`, or with a [TransferPacket::FetchFailed].
+    ///
+    /// [ResourcePath]: crate::ResourcePath
+    Fetch {
+        transfer_id: Uuid,
+        data_type: Uuid,
+        object_id: String,
+        version: Option<u64>,
+    },
+    /// The object asked for by the [TransferPacket::Fetch] with this
+    /// `transfer_id` can't be sent
+    FetchFailed {
+        transfer_id: Uuid,
+        err: String,
+    },
+    /// The data types the sender can produce and consume. Each node sends
+    /// this once, right after the handshake.
+    Capabilities {
+        data_types: Vec<DataCapability>,
+    },
+    /// Ask the peer to describe `data_type`, answered with
+    /// [TransferPacket::Schema]
+    SchemaRequest {
+        data_type: Uuid,
+    },
+    /// The schema of the newest version of `data_type` the sender knows, or
+    /// `None` if it doesn't know the type or the type doesn't describe itself
+    Schema {
+        data_type: Uuid,
+        schema: Option<DataSchema>,
+    },
+    /// Ask the peer to send new objects of `data_type` as
+    /// [TransferPacket::Publish]. Without a topic every new object of the
+    /// type is sent.
+    Subscribe {
+        data_type: Uuid,
+        topic: Option<String>,
+    },
+    /// Undo the [TransferPacket::Subscribe] with the same type and topic
+    Unsubscribe {
+        data_type: Uuid,
+        topic: Option<String>,
+    },
+    /// A new object for one of the receiver's subscriptions, published under
+    /// `topic`
+    Publish {
+        topic: Option<String>,
+        envelope: SignedData,
+    },
+    /// Ask to follow the receiver's `data_types` for good. Once approved the
+    /// receiver pushes new objects of those types to the sender, connecting
+    /// to it if needed. Answered with [TransferPacket::FollowResponse].
+    Follow {
+        request_id: Uuid,
+        data_types: Vec<Uuid>,
+    },
+    /// Whether the [TransferPacket::Follow] with this `request_id` was
+    /// approved, with the reason if it wasn't
+    FollowResponse {
+        request_id: Uuid,
+        err: Option<String>,
+    },
+    /// Stop following the receiver's `data_types`
+    Unfollow {
+        data_types: Vec<Uuid>,
+    },
+    /// The receiver of a [TransferPacket::Publish] has handled the object
+    /// with this id and creation time, so the sender can stop retrying it
+    Ack {
+        object_id: String,
+        created_at: u64,
+    },
+    /// The receiver of a [TransferPacket::Publish] won't accept the object
+    /// with this id and creation time, for instance because it doesn't match
+    /// the type's schema. The sender shouldn't retry it.
+    Reject {
+        object_id: String,
+        created_at: u64,
+        reason: String,
+    },
+    /// Ask the peer for the objects it published after `cursor`, oldest
+    /// first, answered with a [TransferPacket::SyncPage] or a
+    /// [TransferPacket::SyncFailed]. Without a cursor the peer starts at its
+    /// first object. Only objects of `data_types` are sent, or of every type
+    /// if it is empty, and at most `limit` of them per page.
+    SyncRequest {
+        request_id: Uuid,
+        cursor: Option<String>,
+        data_types: Vec<Uuid>,
+        limit: u16,
+    },
+    /// One page of objects for the [TransferPacket::SyncRequest] with this
+    /// `request_id`, each with the topic it was published under. Asking again
+    /// with `cursor` continues after the page, `more` says whether there is
+    /// anything left to ask for right now.
+    SyncPage {
+        request_id: Uuid,
+        objects: Vec<(Option<String>, SignedData)>,
+        cursor: String,
+        more: bool,
+    },
+    /// The [TransferPacket::SyncRequest] with this `request_id` can't be
+    /// answered, for instance because its cursor isn't one the sender issued
+    SyncFailed {
+        request_id: Uuid,
+        err: String,
+    },
 }
 
-pub enum TransferPacketHostToGuest {
+pub type TransferPacketGuestToHost = TransferPacket;
+pub type TransferPacketHostToGuest = TransferPacket;
+
+impl TransferPacket {
+    /// Split `data` into [TransferPacket::Chunk] packets of at most
+    /// [CHUNK_SIZE] bytes, the last of which has `last` set.
+    pub fn chunks(transfer_id: Uuid, data: &[u8]) -> Vec<TransferPacket> {
+        Self::chunks_of(transfer_id, data, CHUNK_SIZE)
+    }
+
+    /// The largest chunk payload that fits in a frame of `max_frame_length`,
+    /// capped at [CHUNK_SIZE].
+    pub fn chunk_size_for(max_frame_length: usize) -> usize {
+        CHUNK_SIZE.min(max_frame_length.saturating_sub(CHUNK_OVERHEAD)).max(1)
+    }
+
+    /// How many bytes an object published under `topic` takes up in a
+    /// [TransferPacket::SyncPage], so pages can be sized to fit in a frame.
+    pub fn sync_object_len(topic: &Option<String>, envelope: &SignedData) -> usize {
+        let mut encoder = EncoderImpl::new(SizeWriter::default(), bincode::config::standard());
+        // Only writing can fail, and counting the bytes can't
+        let _ = (topic, envelope).encode(&mut encoder);
+        encoder.into_writer().bytes_written
+    }
+
+    /// Split `data` into [TransferPacket::Chunk] packets of at most
+    /// `chunk_size` bytes, the last of which has `last` set.
+    pub fn chunks_of(transfer_id: Uuid, data: &[u8], chunk_size: usize) -> Vec<TransferPacket> {
+        if data.is_empty() {
+            return vec![TransferPacket::Chunk { transfer_id, last: true, data: vec![] }];
+        }
+
+        let count = data.len().div_ceil(chunk_size);
+        data.chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| TransferPacket::Chunk {
+                transfer_id,
+                last: i + 1 == count,
+                data: chunk.to_vec(),
+            })
+            .collect()
+    }
+}
 
+impl From<&TransferPacket> for u8 {
+    fn from(pkt: &TransferPacket) -> Self {
+        match pkt {
+            TransferPacket::Ping { .. } => 1,
+            TransferPacket::Pong { .. } => 2,
+            TransferPacket::Chunk { .. } => 3,
+            TransferPacket::Close { .. } => 4,
+            TransferPacket::Fetch { .. } => 5,
+            TransferPacket::FetchFailed { .. } => 6,
+            TransferPacket::Capabilities { .. } => 7,
+            TransferPacket::SchemaRequest { .. } => 8,
+            TransferPacket::Schema { .. } => 9,
+            TransferPacket::Subscribe { .. } => 10,
+            TransferPacket::Unsubscribe { .. } => 11,
+            TransferPacket::Publish { .. } => 12,
+            TransferPacket::Follow { .. } => 13,
+            TransferPacket::FollowResponse { .. } => 14,
+            TransferPacket::Unfollow { .. } => 15,
+            TransferPacket::Ack { .. } => 16,
+            TransferPacket::Reject { .. } => 17,
+            TransferPacket::SyncRequest { .. } => 18,
+            TransferPacket::SyncPage { .. } => 19,
+            TransferPacket::SyncFailed { .. } => 20,
+        }
+    }
 }
 
-impl SerializePacket for TransferPacketGuestToHost {
+impl SerializePacket for TransferPacket {
     fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
-        todo!()
+        buf.put_u8(self.into()); // Message Type byte
+        let mut bytes_written: usize = 1;
+        match self {
+            TransferPacket::Ping { nonce } | TransferPacket::Pong { nonce } => {
+                buf.put_u64(*nonce);
+                bytes_written += 8;
+            }
+            TransferPacket::Chunk { transfer_id, last, data } => {
+                bytes_written += self.write_uuid(buf, transfer_id);
+
+                buf.put_u8(*last as u8);
+                bytes_written += 1;
+
+                bytes_written += self.write_bytes(buf, data);
+            }
+            TransferPacket::Close { err } => {
+                bytes_written += self.write_optional_message(buf, err);
+            }
+            TransferPacket::Fetch { transfer_id, data_type, object_id, version } => {
+                bytes_written += self.write_uuid(buf, transfer_id);
+                bytes_written += self.write_uuid(buf, data_type);
+                bytes_written += self.write_string(buf, object_id)?;
+                bytes_written += self.write_optional_u64(buf, version);
+            }
+            TransferPacket::FetchFailed { transfer_id, err } => {
+                bytes_written += self.write_uuid(buf, transfer_id);
+                bytes_written += self.write_message(buf, err);
+            }
+            TransferPacket::Capabilities { data_types } => {
+                buf.put_u16(data_types.len() as u16);
+                bytes_written += 2;
+                for capability in data_types {
+                    bytes_written += self.write_uuid(buf, &capability.data_type);
+
+                    buf.put_u32(capability.min_version);
+                    buf.put_u32(capability.max_version);
+                    buf.put_u8(capability.usage.produce as u8 | (capability.usage.consume as u8) << 1);
+                    buf.put_u8(capability.codecs.len() as u8);
+                    for codec in &capability.codecs {
+                        buf.put_u8((*codec).into());
+                    }
+                    bytes_written += 10 + capability.codecs.len();
+                }
+            }
+            TransferPacket::SchemaRequest { data_type } => {
+                bytes_written += self.write_uuid(buf, data_type);
+            }
+            TransferPacket::Schema { data_type, schema } => {
+                bytes_written += self.write_uuid(buf, data_type);
+                buf.put_u8(schema.is_some() as u8);
+                bytes_written += 1;
+                if let Some(schema) = schema {
+                    let encoded = bincode::encode_to_vec(schema, bincode::config::standard())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                    bytes_written += self.write_bytes(buf, &encoded);
+                }
+            }
+            TransferPacket::Subscribe { data_type, topic } | TransferPacket::Unsubscribe { data_type, topic } => {
+                bytes_written += self.write_uuid(buf, data_type);
+                bytes_written += self.write_optional_string(buf, topic)?;
+            }
+            TransferPacket::Publish { topic, envelope } => {
+                bytes_written += self.write_optional_string(buf, topic)?;
+                let encoded = bincode::encode_to_vec(envelope, bincode::config::standard())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                bytes_written += self.write_bytes(buf, &encoded);
+            }
+            TransferPacket::Follow { request_id, data_types } => {
+                bytes_written += self.write_uuid(buf, request_id);
+                bytes_written += write_uuids(buf, data_types);
+            }
+            TransferPacket::FollowResponse { request_id, err } => {
+                bytes_written += self.write_uuid(buf, request_id);
+                bytes_written += self.write_optional_message(buf, err);
+            }
+            TransferPacket::Unfollow { data_types } => {
+                bytes_written += write_uuids(buf, data_types);
+            }
+            TransferPacket::Ack { object_id, created_at } => {
+                bytes_written += self.write_string(buf, object_id)?;
+                buf.put_u64(*created_at);
+                bytes_written += 8;
+            }
+            TransferPacket::Reject { object_id, created_at, reason } => {
+                bytes_written += self.write_string(buf, object_id)?;
+                buf.put_u64(*created_at);
+                bytes_written += 8;
+                bytes_written += self.write_message(buf, reason);
+            }
+            TransferPacket::SyncRequest { request_id, cursor, data_types, limit } => {
+                bytes_written += self.write_uuid(buf, request_id);
+                bytes_written += self.write_optional_string(buf, cursor)?;
+                bytes_written += write_uuids(buf, data_types);
+                buf.put_u16(*limit);
+                bytes_written += 2;
+            }
+            TransferPacket::SyncPage { request_id, objects, cursor, more } => {
+                bytes_written += self.write_uuid(buf, request_id);
+                let encoded = bincode::encode_to_vec(objects, bincode::config::standard())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                bytes_written += self.write_bytes(buf, &encoded);
+                bytes_written += self.write_string(buf, cursor)?;
+                buf.put_u8(*more as u8);
+                bytes_written += 1;
+            }
+            TransferPacket::SyncFailed { request_id, err } => {
+                bytes_written += self.write_uuid(buf, request_id);
+                bytes_written += self.write_message(buf, err);
+            }
+        }
+        Ok(bytes_written)
+    }
+
+    fn priority(&self) -> Priority {
+        match self {
+            TransferPacket::Chunk { .. } | TransferPacket::Publish { .. } | TransferPacket::SyncPage { .. } => Priority::Bulk,
+            _ => Priority::Control,
+        }
+    }
+
+    fn transfer_id(&self) -> Option<Uuid> {
+        match self {
+            TransferPacket::Chunk { transfer_id, .. } => Some(*transfer_id),
+            _ => None,
+        }
+    }
+
+    fn ends_transfer(&self) -> bool {
+        matches!(self, TransferPacket::Chunk { last: true, .. })
     }
 }
 
-impl DeserializePacket for TransferPacketGuestToHost {
-    type Output = TransferPacketGuestToHost;
+impl DeserializePacket for TransferPacket {
+    type Output = TransferPacket;
 
     fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
-        todo!()
+        match Self::read_u8(buf)? {
+            1 => Ok(TransferPacket::Ping {
+                nonce: Self::read_u64(buf)?,
+            }),
+            2 => Ok(TransferPacket::Pong {
+                nonce: Self::read_u64(buf)?,
+            }),
+            3 => Ok(TransferPacket::Chunk {
+                transfer_id: Self::read_uuid(buf)?,
+                last: Self::read_u8(buf)? != 0,
+                data: Self::read_bytes(buf)?,
+            }),
+            4 => Ok(TransferPacket::Close {
+                err: Self::read_optional_string(buf)?,
+            }),
+            5 => Ok(TransferPacket::Fetch {
+                transfer_id: Self::read_uuid(buf)?,
+                data_type: Self::read_uuid(buf)?,
+                object_id: Self::read_string(buf)?,
+                version: Self::read_optional_u64(buf)?,
+            }),
+            6 => Ok(TransferPacket::FetchFailed {
+                transfer_id: Self::read_uuid(buf)?,
+                err: Self::read_string(buf)?,
+            }),
+            7 => {
+                let count = Self::read_u16(buf)?;
+                let mut data_types = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    let data_type = Self::read_uuid(buf)?;
+                    let min_version = Self::read_u32(buf)?;
+                    let max_version = Self::read_u32(buf)?;
+                    let usage = Self::read_u8(buf)?;
+                    let codec_count = Self::read_u8(buf)?;
+                    // Codecs added after this node was built are skipped
+                    let mut codecs = Vec::new();
+                    for _ in 0..codec_count {
+                        codecs.extend(DataCodec::try_from(Self::read_u8(buf)?).ok());
+                    }
+                    data_types.push(DataCapability {
+                        data_type,
+                        min_version,
+                        max_version,
+                        usage: DataUsage { produce: usage & 1 != 0, consume: usage & 2 != 0 },
+                        codecs,
+                    });
+                }
+                Ok(TransferPacket::Capabilities { data_types })
+            }
+            8 => Ok(TransferPacket::SchemaRequest {
+                data_type: Self::read_uuid(buf)?,
+            }),
+            9 => {
+                let data_type = Self::read_uuid(buf)?;
+                let schema = if Self::read_u8(buf)? != 0 {
+                    Some(decode_limited(&Self::read_bytes(buf)?)?)
+                } else { None };
+                Ok(TransferPacket::Schema { data_type, schema })
+            }
+            10 => Ok(TransferPacket::Subscribe {
+                data_type: Self::read_uuid(buf)?,
+                topic: Self::read_optional_string(buf)?,
+            }),
+            11 => Ok(TransferPacket::Unsubscribe {
+                data_type: Self::read_uuid(buf)?,
+                topic: Self::read_optional_string(buf)?,
+            }),
+            12 => {
+                let topic = Self::read_optional_string(buf)?;
+                let envelope = decode_limited(&Self::read_bytes(buf)?)?;
+                Ok(TransferPacket::Publish { topic, envelope })
+            }
+            13 => Ok(TransferPacket::Follow {
+                request_id: Self::read_uuid(buf)?,
+                data_types: read_uuids(buf)?,
+            }),
+            14 => Ok(TransferPacket::FollowResponse {
+                request_id: Self::read_uuid(buf)?,
+                err: Self::read_optional_string(buf)?,
+            }),
+            15 => Ok(TransferPacket::Unfollow {
+                data_types: read_uuids(buf)?,
+            }),
+            16 => Ok(TransferPacket::Ack {
+                object_id: Self::read_string(buf)?,
+                created_at: Self::read_u64(buf)?,
+            }),
+            17 => Ok(TransferPacket::Reject {
+                object_id: Self::read_string(buf)?,
+                created_at: Self::read_u64(buf)?,
+                reason: Self::read_string(buf)?,
+            }),
+            18 => Ok(TransferPacket::SyncRequest {
+                request_id: Self::read_uuid(buf)?,
+                cursor: Self::read_optional_string(buf)?,
+                data_types: read_uuids(buf)?,
+                limit: Self::read_u16(buf)?,
+            }),
+            19 => {
+                let request_id = Self::read_uuid(buf)?;
+                let objects = decode_limited(&Self::read_bytes(buf)?)?;
+                Ok(TransferPacket::SyncPage {
+                    request_id,
+                    objects,
+                    cursor: Self::read_string(buf)?,
+                    more: Self::read_u8(buf)? != 0,
+                })
+            }
+            20 => Ok(TransferPacket::SyncFailed {
+                request_id: Self::read_uuid(buf)?,
+                err: Self::read_string(buf)?,
+            }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid Request Type",
+            )),
+        }
     }
 }
 
-impl SerializePacket for TransferPacketHostToGuest {
-    fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
-        todo!()
+/// Write a list of `Uuid`s with a `u16` count header to `buf` and return how
+/// many bytes were written.
+fn write_uuids(buf: &mut BytesMut, uuids: &[Uuid]) -> usize {
+    buf.put_u16(uuids.len() as u16);
+    for uuid in uuids {
+        buf.put_u128(uuid.as_u128());
     }
+    2 + uuids.len() * 16
+}
+
+fn read_uuids(buf: &mut BytesMut) -> io::Result<Vec<Uuid>> {
+    let count = TransferPacket::read_u16(buf)?;
+    (0..count).map(|_| TransferPacket::read_uuid(buf)).collect()
 }
 
-impl DeserializePacket for TransferPacketHostToGuest {
-    type Output = TransferPacketHostToGuest;
+/// Decode a bincode value sent by the peer. The lengths inside `encoded` are
+/// untrusted, so any that would claim far more memory than `encoded` could
+/// hold are refused before anything is allocated.
+fn decode_limited<T: Decode>(encoded: &[u8]) -> io::Result<T> {
+    fn decode<T: Decode, const LIMIT: usize>(encoded: &[u8]) -> Result<(T, usize), DecodeError> {
+        bincode::decode_from_slice(encoded, bincode::config::standard().with_limit::<LIMIT>())
+    }
 
-    fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
-        todo!()
+    // bincode only takes the limit as a constant, so pick the smallest one
+    // covering the encoded length. Nodes that raise their frame length past
+    // the largest accept the memory that comes with it
+    let decoded = match encoded.len() {
+        0..=0xFFFF => decode::<T, { 0x1_0000 * DECODE_CLAIM_FACTOR }>(encoded),
+        0x1_0000..=0xF_FFFF => decode::<T, { 0x10_0000 * DECODE_CLAIM_FACTOR }>(encoded),
+        0x10_0000..=0xFF_FFFF => decode::<T, { 0x100_0000 * DECODE_CLAIM_FACTOR }>(encoded),
+        _ => decode::<T, { usize::MAX }>(encoded),
+    };
+    decoded
+        .map(|(value, _)| value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_data::{DataCapability, DataCodec, DataSchema, DataUsage, RawData, SchemaType, SignedData};
+
+    use crate::packet::{DeserializePacket, SerializePacket, STRING_MAX_LENGTH};
+    use crate::packet::transfer::{CHUNK_SIZE, SYNC_PAGE_OVERHEAD, TransferPacket};
+
+    #[test]
+    fn test_chunks_round_trip() -> io::Result<()> {
+        let transfer_id = Uuid::new_v4();
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let chunks = TransferPacket::chunks(transfer_id, &data);
+        assert_eq!(chunks.len(), 3);
+
+        let buf = &mut BytesMut::new();
+        let mut reassembled = Vec::new();
+        for chunk in chunks {
+            chunk.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::Chunk { transfer_id: id, last, data } => {
+                    assert_eq!(id, transfer_id);
+                    reassembled.extend_from_slice(&data);
+                    assert_eq!(last, reassembled.len() == CHUNK_SIZE * 2 + 10);
+                }
+                _ => panic!("Expected chunk packet"),
+            }
+        }
+        assert_eq!(reassembled, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_round_trip() -> io::Result<()> {
+        let transfer_id = Uuid::new_v4();
+        let data_type = Uuid::new_v4();
+        let buf = &mut BytesMut::new();
+        for version in [None, Some(7)] {
+            TransferPacket::Fetch { transfer_id, data_type, object_id: "post/1".to_string(), version }.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::Fetch { transfer_id: id, data_type: ty, object_id, version: v } => {
+                    assert_eq!((id, ty, object_id.as_str(), v), (transfer_id, data_type, "post/1", version));
+                }
+                _ => panic!("Expected fetch packet"),
+            }
+        }
+
+        TransferPacket::FetchFailed { transfer_id, err: "Not found".to_string() }.serialize(buf)?;
+        assert!(matches!(
+            TransferPacket::deserialize(buf)?,
+            TransferPacket::FetchFailed { transfer_id: id, err } if id == transfer_id && err == "Not found"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities_round_trip() -> io::Result<()> {
+        let data_types = vec![
+            DataCapability {
+                data_type: Uuid::new_v4(),
+                min_version: 1,
+                max_version: 3,
+                usage: DataUsage::BOTH,
+                codecs: vec![DataCodec::Json, DataCodec::Bincode],
+            },
+            DataCapability {
+                data_type: Uuid::new_v4(),
+                min_version: 2,
+                max_version: 2,
+                usage: DataUsage::CONSUME,
+                codecs: vec![DataCodec::Bincode],
+            },
+        ];
+        let buf = &mut BytesMut::new();
+        TransferPacket::Capabilities { data_types: data_types.clone() }.serialize(buf)?;
+        match TransferPacket::deserialize(buf)? {
+            TransferPacket::Capabilities { data_types: read } => assert_eq!(read, data_types),
+            _ => panic!("Expected capabilities packet"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_round_trip() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let schema = DataSchema {
+            id: data_type,
+            version: 2,
+            shape: SchemaType::TupleStruct { name: "Like".to_string(), fields: vec![SchemaType::U64] },
+        };
+        let buf = &mut BytesMut::new();
+        TransferPacket::SchemaRequest { data_type }.serialize(buf)?;
+        assert!(matches!(TransferPacket::deserialize(buf)?, TransferPacket::SchemaRequest { data_type: ty } if ty == data_type));
+
+        for schema in [Some(schema), None] {
+            TransferPacket::Schema { data_type, schema: schema.clone() }.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::Schema { data_type: ty, schema: read } => assert_eq!((ty, read), (data_type, schema)),
+                _ => panic!("Expected schema packet"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_subscription_round_trip() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let buf = &mut BytesMut::new();
+        for topic in [None, Some("rust".to_string())] {
+            TransferPacket::Subscribe { data_type, topic: topic.clone() }.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::Subscribe { data_type: ty, topic: read } => assert_eq!((ty, read), (data_type, topic.clone())),
+                _ => panic!("Expected subscribe packet"),
+            }
+            TransferPacket::Unsubscribe { data_type, topic: topic.clone() }.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::Unsubscribe { data_type: ty, topic: read } => assert_eq!((ty, read), (data_type, topic)),
+                _ => panic!("Expected unsubscribe packet"),
+            }
+        }
+
+        let envelope = SignedData {
+            origin: "a.example".to_string(),
+            object_id: "post/1".to_string(),
+            created_at: 1_700_000_000_000,
+            data: RawData::new(data_type, 1, DataCodec::Json, &b"{}"[..]),
+            signature: vec![7; 16],
+        };
+        TransferPacket::Publish { topic: Some("rust".to_string()), envelope: envelope.clone() }.serialize(buf)?;
+        match TransferPacket::deserialize(buf)? {
+            TransferPacket::Publish { topic, envelope: read } => assert_eq!((topic.as_deref(), read), (Some("rust"), envelope)),
+            _ => panic!("Expected publish packet"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_follow_round_trip() -> io::Result<()> {
+        let request_id = Uuid::new_v4();
+        let data_types = vec![Uuid::new_v4(), Uuid::new_v4()];
+        let buf = &mut BytesMut::new();
+        TransferPacket::Follow { request_id, data_types: data_types.clone() }.serialize(buf)?;
+        match TransferPacket::deserialize(buf)? {
+            TransferPacket::Follow { request_id: id, data_types: read } => assert_eq!((id, read), (request_id, data_types.clone())),
+            _ => panic!("Expected follow packet"),
+        }
+
+        for err in [None, Some("Not accepting followers".to_string())] {
+            TransferPacket::FollowResponse { request_id, err: err.clone() }.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::FollowResponse { request_id: id, err: read } => assert_eq!((id, read), (request_id, err)),
+                _ => panic!("Expected follow response packet"),
+            }
+        }
+
+        TransferPacket::Unfollow { data_types: vec![] }.serialize(buf)?;
+        assert!(matches!(TransferPacket::deserialize(buf)?, TransferPacket::Unfollow { data_types } if data_types.is_empty()));
+
+        TransferPacket::Ack { object_id: "post/1".to_string(), created_at: 1_700_000_000_000 }.serialize(buf)?;
+        assert!(matches!(
+            TransferPacket::deserialize(buf)?,
+            TransferPacket::Ack { object_id, created_at: 1_700_000_000_000 } if object_id == "post/1"
+        ));
+
+        TransferPacket::Reject { object_id: "post/1".to_string(), created_at: 5, reason: "Bad title".to_string() }.serialize(buf)?;
+        assert!(matches!(
+            TransferPacket::deserialize(buf)?,
+            TransferPacket::Reject { object_id, created_at: 5, reason } if object_id == "post/1" && reason == "Bad title"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_round_trip() -> io::Result<()> {
+        let request_id = Uuid::new_v4();
+        let data_types = vec![Uuid::new_v4()];
+        let buf = &mut BytesMut::new();
+        for cursor in [None, Some("000000000000002a".to_string())] {
+            TransferPacket::SyncRequest { request_id, cursor: cursor.clone(), data_types: data_types.clone(), limit: 50 }.serialize(buf)?;
+            match TransferPacket::deserialize(buf)? {
+                TransferPacket::SyncRequest { request_id: id, cursor: read, data_types: types, limit } => {
+                    assert_eq!((id, read, types, limit), (request_id, cursor, data_types.clone(), 50));
+                }
+                _ => panic!("Expected sync request packet"),
+            }
+        }
+
+        let envelope = SignedData {
+            origin: "a.example".to_string(),
+            object_id: "post/1".to_string(),
+            created_at: 1_700_000_000_000,
+            data: RawData::new(data_types[0], 1, DataCodec::Bincode, &b"\x01"[..]),
+            signature: vec![7; 16],
+        };
+        let objects = vec![(Some("rust".to_string()), envelope.clone()), (None, envelope)];
+        let len = TransferPacket::SyncPage { request_id, objects: objects.clone(), cursor: "2b".to_string(), more: true }.serialize(buf)?;
+        let objects_len: usize = objects.iter().map(|(topic, envelope)| TransferPacket::sync_object_len(topic, envelope)).sum();
+        assert!(len <= SYNC_PAGE_OVERHEAD + 2 + objects_len);
+        match TransferPacket::deserialize(buf)? {
+            TransferPacket::SyncPage { request_id: id, objects: read, cursor, more } => {
+                assert_eq!((id, read, cursor.as_str(), more), (request_id, objects, "2b", true));
+            }
+            _ => panic!("Expected sync page packet"),
+        }
+
+        TransferPacket::SyncFailed { request_id, err: "Invalid cursor".to_string() }.serialize(buf)?;
+        assert!(matches!(
+            TransferPacket::deserialize(buf)?,
+            TransferPacket::SyncFailed { request_id: id, err } if id == request_id && err == "Invalid cursor"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_strings() -> io::Result<()> {
+        // A reason longer than a length header can hold is cut at a char
+        // boundary rather than wrapping the header
+        let reason = "é".repeat(35 * 1024);
+        let buf = &mut BytesMut::new();
+        TransferPacket::Reject { object_id: "post/1".to_string(), created_at: 5, reason: reason.clone() }.serialize(buf)?;
+        match TransferPacket::deserialize(buf)? {
+            TransferPacket::Reject { object_id, reason: read, .. } => {
+                assert_eq!(object_id, "post/1");
+                assert_eq!(read.len(), STRING_MAX_LENGTH - 1);
+                assert!(reason.starts_with(&read));
+            }
+            _ => panic!("Expected reject packet"),
+        }
+        assert!(buf.is_empty());
+
+        // Identifiers can't be cut, so they fail to serialize
+        let long = "a".repeat(STRING_MAX_LENGTH + 1);
+        let ack = TransferPacket::Ack { object_id: long.clone(), created_at: 5 }.serialize(buf);
+        assert_eq!(ack.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        let page = TransferPacket::SyncPage { request_id: Uuid::new_v4(), objects: vec![], cursor: long, more: false }.serialize(&mut BytesMut::new());
+        assert_eq!(page.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidInput));
+        Ok(())
+    }
+
+    #[test]
+    fn test_forged_length_refused() {
+        // An envelope whose origin claims to be a terabyte long
+        let mut encoded = vec![253];
+        encoded.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        encoded.extend_from_slice(b"a.example");
+
+        let buf = &mut BytesMut::new();
+        buf.put_u8(12);
+        buf.put_u8(0);
+        buf.put_u32(encoded.len() as u32);
+        buf.put_slice(&encoded);
+        assert_eq!(TransferPacket::deserialize(buf).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        // A page claiming a billion objects
+        let mut encoded = vec![252];
+        encoded.extend_from_slice(&1_000_000_000u32.to_le_bytes());
+        buf.put_u8(19);
+        buf.put_u128(Uuid::new_v4().as_u128());
+        buf.put_u32(encoded.len() as u32);
+        buf.put_slice(&encoded);
+        assert_eq!(TransferPacket::deserialize(buf).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
     }
 }
@@ -1,30 +1,74 @@
 use std::net::{SocketAddr};
+use std::sync::Arc;
 
-use tokio::io::{self};
+use tokio::io::{self, AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
 use futures_util::{SinkExt};
+use futures_util::future::BoxFuture;
+use futures_util::stream::BoxStream;
 
 use crate::packet::{DeserializePacket, PacketDecoder, PacketEncoder, SerializePacket};
+use crate::split::{self, ProtocolReceiver, ProtocolSender};
+
+/// The read half of whatever transport a [Protocol] runs over.
+pub type TransportRead = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The write half of whatever transport a [Protocol] runs over.
+pub type TransportWrite = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The stream id the keys of every stream opened through [BulkStreams] are
+/// derived from.
+pub(crate) const BULK_STREAM: u32 = 1;
+
+/// Opens a new stream to the peer, telling it the id of the stream.
+pub(crate) type OpenStream = Arc<dyn Fn(u32) -> BoxFuture<'static, io::Result<TransportWrite>> + Send + Sync>;
+
+/// How to reach more streams on transports that carry several independent
+/// streams over one connection. The bulk lane gets a stream of its own, and so
+/// does every transfer, so neither control packets nor other transfers are
+/// held up behind a transfer at the transport level.
+pub(crate) struct BulkStreams {
+    /// Opens a stream for our bulk packets
+    pub(crate) open: OpenStream,
+    /// Every stream the peer opens for its bulk packets, with its id
+    pub(crate) accept: BoxStream<'static, io::Result<(u32, TransportRead)>>,
+}
 
 pub struct Protocol<InPacketType: DeserializePacket, OutPacketType : SerializePacket> {
-    pub read: FramedRead<OwnedReadHalf, PacketDecoder<InPacketType>>,
-    pub write: FramedWrite<OwnedWriteHalf, PacketEncoder<OutPacketType>>
+    pub read: FramedRead<TransportRead, PacketDecoder<InPacketType>>,
+    pub write: FramedWrite<TransportWrite, PacketEncoder<OutPacketType>>,
+    pub(crate) bulk: Option<BulkStreams>,
 }
 
 impl<InPacketType: DeserializePacket, OutPacketType : SerializePacket> Protocol<InPacketType, OutPacketType> {
     /// Wrap a TcpStream with Protocol
     pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
+        let (read, write) = stream.into_split();
+        Ok(Self::with_halves(Box::new(read), Box::new(write)))
+    }
+
+    /// Wrap any other bidirectional stream, such as a TLS session, with
+    /// Protocol
+    pub fn with_io<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (read, write) = io::split(stream);
+        Self::with_halves(Box::new(read), Box::new(write))
+    }
+
+    /// Wrap a transport that is already split into its two halves
+    pub fn with_halves(read: TransportRead, write: TransportWrite) -> Self {
         let read_codec: PacketDecoder<InPacketType> = PacketDecoder::new();
         let write_codec: PacketEncoder<OutPacketType> = PacketEncoder::new();
-        let (read, write) = stream.into_split();
-        Ok(Self {
+        Self {
             read: FramedRead::new(read, read_codec),
             write: FramedWrite::new(write, write_codec),
-        })
+            bulk: None,
+        }
     }
 
     /// Establish a connection, and wrap the stream in a new [Protocol].
@@ -47,6 +91,7 @@ impl<InPacketType: DeserializePacket, OutPacketType : SerializePacket> Protocol<
         Protocol::<NewInPacketType, NewOutPacketType> {
             read: self.read.map_decoder(map_in),
             write: self.write.map_encoder(map_out),
+            bulk: self.bulk,
         }
     }
 
@@ -55,11 +100,27 @@ impl<InPacketType: DeserializePacket, OutPacketType : SerializePacket> Protocol<
         self.write.send(message).await
     }
 
+    /// Split into a cloneable [ProtocolSender], backed by a writer task that
+    /// owns the write half, and a [ProtocolReceiver] stream of inbound
+    /// packets. Must be called from within a tokio runtime.
+    ///
+    /// On transports with several streams, such as QUIC, the bulk lane is
+    /// moved to a stream of its own at this point, and every transfer gets
+    /// another one while its chunks are being sent.
+    pub fn split(self) -> (ProtocolSender<OutPacketType>, ProtocolReceiver<InPacketType>)
+    where
+        InPacketType: Send + 'static,
+        InPacketType::Output: Send,
+        OutPacketType: Send + 'static,
+    {
+        split::split(self.read, self.write, self.bulk)
+    }
+
     /// Read a message from the inner [FramedRead]
     pub async fn read_frame(&mut self) -> io::Result<InPacketType::Output> {
         loop {
             if let Some(packet) = self.read.next().await {
-                return Ok(packet?);
+                return packet;
             }
         }
     }
@@ -0,0 +1,361 @@
+//! # QUIC Transport
+//!
+//! An alternative to TCP for links where head-of-line blocking and slow
+//! reconnects hurt. The handshake runs on the first bidirectional stream, which
+//! then carries the control lane for the rest of the connection. Once the
+//! [Protocol] is split, each side opens a unidirectional stream for its bulk
+//! lane and another one for every transfer it sends, so a lost packet in a
+//! large transfer never holds up control packets or the other transfers.
+//!
+//! Like [tls], the [channel binding] exported from the QUIC session is signed as
+//! part of the OSP handshake.
+//!
+//! [tls]: crate::tls
+//! [channel binding]: crate::tls::CHANNEL_BINDING_LABEL
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_util::{FutureExt, StreamExt};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self, BoxStream};
+
+use quinn::{Connection, ConnectionError, Endpoint, Incoming, RecvStream, SendStream};
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+
+use tokio::io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::Protocol;
+use crate::packet::{DeserializePacket, SerializePacket};
+use crate::protocol::{BulkStreams, OpenStream, TransportRead, TransportWrite};
+use crate::tls::{rustls, CHANNEL_BINDING_LABEL, CHANNEL_BINDING_LENGTH};
+
+pub use quinn;
+
+/// The ALPN protocol OSP speaks over QUIC
+pub const ALPN: &[u8] = b"osp";
+
+/// Written first on every bulk stream, followed by the id of the stream, so the
+/// peer can tell it apart from any other stream.
+const BULK_STREAM_TAG: u8 = 1;
+
+/// Build a QUIC client config from a TLS client config.
+pub fn client_config(tls: &rustls::ClientConfig) -> io::Result<quinn::ClientConfig> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(tls)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+/// Build a QUIC server config from a TLS server config.
+pub fn server_config(tls: &rustls::ServerConfig) -> io::Result<quinn::ServerConfig> {
+    let mut tls = tls.clone();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Connect to `addr` through `endpoint`, returning a [Protocol] on the first
+/// bidirectional stream and the channel binding for the session.
+pub async fn connect<InPacketType, OutPacketType>(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    server_name: &str,
+) -> io::Result<(Protocol<InPacketType, OutPacketType>, Vec<u8>)>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+{
+    let connection = endpoint.connect(addr, server_name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .await?;
+    // Nothing reaches the host until the first packet is written, which the
+    // guest always does first
+    let (send, recv) = connection.open_bi().await?;
+    with_connection(connection, send, recv)
+}
+
+/// Accept an incoming QUIC connection, returning a [Protocol] on the first
+/// bidirectional stream and the channel binding for the session.
+pub async fn accept<InPacketType, OutPacketType>(
+    incoming: Incoming,
+) -> io::Result<(Protocol<InPacketType, OutPacketType>, Vec<u8>)>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+{
+    let connection = incoming.await?;
+    let (send, recv) = connection.accept_bi().await?;
+    with_connection(connection, send, recv)
+}
+
+fn with_connection<InPacketType, OutPacketType>(
+    connection: Connection,
+    send: SendStream,
+    recv: RecvStream,
+) -> io::Result<(Protocol<InPacketType, OutPacketType>, Vec<u8>)>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+{
+    let mut binding = vec![0u8; CHANNEL_BINDING_LENGTH];
+    connection.export_keying_material(&mut binding, CHANNEL_BINDING_LABEL, &[])
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Failed to export channel binding"))?;
+
+    let mut protocol = Protocol::with_halves(Box::new(recv), Box::new(QuicWrite::new(send)));
+    protocol.bulk = Some(BulkStreams {
+        open: open_bulk(connection.clone()),
+        accept: accept_bulk(connection),
+    });
+    Ok((protocol, binding))
+}
+
+fn open_bulk(connection: Connection) -> OpenStream {
+    Arc::new(move |stream| {
+        let connection = connection.clone();
+        async move {
+            let mut send = connection.open_uni().await?;
+            send.write_u8(BULK_STREAM_TAG).await?;
+            send.write_u32(stream).await?;
+            Ok(Box::new(QuicWrite::new(send)) as TransportWrite)
+        }.boxed()
+    })
+}
+
+fn accept_bulk(connection: Connection) -> BoxStream<'static, io::Result<(u32, TransportRead)>> {
+    stream::try_unfold(connection, |connection| async move {
+        let mut recv = match connection.accept_uni().await {
+            Ok(recv) => recv,
+            // The peer is gone, so no more streams are coming
+            Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if recv.read_u8().await? != BULK_STREAM_TAG {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Unexpected QUIC stream"));
+        }
+        let stream = recv.read_u32().await?;
+        Ok(Some(((stream, Box::new(recv) as TransportRead), connection)))
+    }).boxed()
+}
+
+/// A [SendStream] that waits for the peer to receive everything before
+/// finishing shutdown. Otherwise the connection could be closed with the end of
+/// the stream still in flight once the last handle to it is dropped.
+struct QuicWrite {
+    send: SendStream,
+    stopped: Option<BoxFuture<'static, ()>>,
+}
+
+impl QuicWrite {
+    fn new(send: SendStream) -> Self {
+        Self { send, stopped: None }
+    }
+}
+
+impl AsyncWrite for QuicWrite {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.send), cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.stopped.is_none() {
+            // Finishing twice is an error, so only the first call finishes
+            self.send.finish().map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+            self.stopped = Some(self.send.stopped().map(|_| ()).boxed());
+        }
+        self.stopped.as_mut().unwrap().poll_unpin(cx).map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rcgen::generate_simple_self_signed;
+
+    use tokio::io;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use quinn::Endpoint;
+
+    use crate::Protocol;
+    use crate::packet::compression::Compression;
+    use crate::packet::encryption::SessionCipher;
+    use crate::packet::transfer::{CHUNK_SIZE, TransferPacket};
+    use crate::{quic, OUTBOUND_QUEUE_CAPACITY};
+    use crate::tls::rustls::{self, pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer}};
+
+    fn self_signed() -> (rustls::ServerConfig, rustls::ClientConfig) {
+        let certified = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+        let server = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (server, client)
+    }
+
+    /// Control packets come through the first stream and chunks through the
+    /// bulk streams, both encrypted, in each direction.
+    #[tokio::test]
+    async fn test_quic_lanes() -> io::Result<()> {
+        let (server_tls, client_tls) = self_signed();
+        let server = Endpoint::server(quic::server_config(&server_tls)?, SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let addr = server.local_addr()?;
+        let (guest_key, host_key) = ([7u8; 32], [8u8; 32]);
+        let transfer_id = Uuid::new_v4();
+        let data = vec![3u8; 200_000];
+
+        let expected = data.clone();
+        let host = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let (protocol, binding): (Protocol<TransferPacket, TransferPacket>, _) = quic::accept(incoming).await?;
+            let protocol = protocol.map_codecs(
+                |decoder| decoder.with_compression(Compression::Zstd).with_encryption(SessionCipher::new(guest_key)),
+                |encoder| encoder.with_compression(Compression::Zstd).with_encryption(SessionCipher::new(host_key)),
+            );
+            let (sender, mut receiver) = protocol.split();
+            let mut received = Vec::new();
+            while let Some(packet) = receiver.next().await {
+                match packet? {
+                    TransferPacket::Ping { nonce } => sender.send(TransferPacket::Pong { nonce }).await?,
+                    TransferPacket::Chunk { data, last, .. } => {
+                        received.extend_from_slice(&data);
+                        if last {
+                            for chunk in TransferPacket::chunks(transfer_id, &received) {
+                                sender.send(chunk).await?;
+                            }
+                        }
+                    }
+                    _ => panic!("Unexpected packet"),
+                }
+            }
+            assert_eq!(received, expected);
+            receiver.shutdown().await?;
+            io::Result::Ok(binding)
+        });
+
+        let mut client = Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        client.set_default_client_config(quic::client_config(&client_tls)?);
+        let (protocol, binding): (Protocol<TransferPacket, TransferPacket>, _) =
+            quic::connect(&client, addr, "localhost").await?;
+        let protocol = protocol.map_codecs(
+            |decoder| decoder.with_compression(Compression::Zstd).with_encryption(SessionCipher::new(host_key)),
+            |encoder| encoder.with_compression(Compression::Zstd).with_encryption(SessionCipher::new(guest_key)),
+        );
+        let (sender, mut receiver) = protocol.split();
+
+        sender.send(TransferPacket::Ping { nonce: 1 }).await?;
+        for chunk in TransferPacket::chunks(transfer_id, &data) {
+            sender.send(chunk).await?;
+        }
+
+        let mut pong = false;
+        let mut echoed = Vec::new();
+        while let Some(packet) = receiver.next().await {
+            match packet? {
+                TransferPacket::Pong { nonce: 1 } => pong = true,
+                TransferPacket::Chunk { data, last, .. } => {
+                    echoed.extend_from_slice(&data);
+                    if last {
+                        break;
+                    }
+                }
+                _ => panic!("Unexpected packet"),
+            }
+        }
+        assert!(pong);
+        assert_eq!(echoed, data);
+
+        receiver.shutdown().await?;
+        assert_eq!(host.await??, binding);
+        Ok(())
+    }
+
+    /// A transfer that is still being written doesn't hold up one that starts
+    /// after it, since each one has a stream of its own.
+    #[tokio::test]
+    async fn test_transfers_dont_block_each_other() -> io::Result<()> {
+        let (server_tls, client_tls) = self_signed();
+        let server = Endpoint::server(quic::server_config(&server_tls)?, SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        let addr = server.local_addr()?;
+        let host = tokio::spawn(async move {
+            let incoming = server.accept().await.unwrap();
+            let (protocol, _): (Protocol<TransferPacket, TransferPacket>, _) = quic::accept(incoming).await?;
+            io::Result::Ok(protocol.split())
+        });
+
+        let mut client = Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+        client.set_default_client_config(quic::client_config(&client_tls)?);
+        let (protocol, _): (Protocol<TransferPacket, TransferPacket>, _) =
+            quic::connect(&client, addr, "localhost").await?;
+        let (sender, _receiver) = protocol.split();
+        // Nothing reaches the host before the guest writes to the first stream
+        sender.send(TransferPacket::Ping { nonce: 1 }).await?;
+        let (_host_sender, mut host_receiver) = host.await??;
+        assert!(matches!(host_receiver.next().await.unwrap()?, TransferPacket::Ping { nonce: 1 }));
+
+        let (large, small) = (Uuid::new_v4(), Uuid::new_v4());
+        let large_chunks = TransferPacket::chunks(large, &vec![1u8; CHUNK_SIZE * OUTBOUND_QUEUE_CAPACITY * 4]);
+        let large_sender = sender.clone();
+        let large_transfer = tokio::spawn(async move {
+            for chunk in large_chunks {
+                large_sender.send(chunk).await?;
+            }
+            io::Result::Ok(())
+        });
+
+        // Wait for the large transfer to get going before starting the other
+        let mut large_received = 0;
+        while large_received == 0 {
+            if let TransferPacket::Chunk { transfer_id, .. } = host_receiver.next().await.unwrap()? {
+                assert_eq!(transfer_id, large);
+                large_received += 1;
+            }
+        }
+        let small_sender = sender.clone();
+        let small_transfer = tokio::spawn(async move {
+            for chunk in TransferPacket::chunks(small, &[2u8; 100]) {
+                small_sender.send(chunk).await?;
+            }
+            io::Result::Ok(())
+        });
+
+        let large_before_small = loop {
+            match host_receiver.next().await.unwrap()? {
+                TransferPacket::Chunk { transfer_id, last, .. } if transfer_id == small => {
+                    assert!(last);
+                    break large_received;
+                }
+                TransferPacket::Chunk { last, .. } => {
+                    assert!(!last, "The large transfer finished first");
+                    large_received += 1;
+                }
+                _ => panic!("Unexpected packet"),
+            }
+        };
+        // On a shared stream the small transfer would wait behind every chunk
+        // already queued for the large one.
+        assert!(large_before_small < OUTBOUND_QUEUE_CAPACITY / 2, "{large_before_small} chunks came first");
+        small_transfer.await??;
+
+        sender.close();
+        large_transfer.abort();
+        Ok(())
+    }
+}
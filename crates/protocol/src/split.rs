@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures_util::{SinkExt, StreamExt};
+use futures_util::future::{self, poll_fn, BoxFuture};
+use futures_util::stream::{self, BoxStream};
+
+use tokio::io;
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+
+use tokio_stream::Stream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use crate::packet::{DeserializePacket, PacketDecoder, PacketEncoder, Priority, SerializePacket};
+use crate::protocol::{BulkStreams, OpenStream, TransportRead, TransportWrite, BULK_STREAM};
+
+use uuid::Uuid;
+
+/// How many outgoing bulk packets can be queued for the writer task before
+/// [ProtocolSender::send] starts waiting for room.
+pub const OUTBOUND_QUEUE_CAPACITY: usize = 128;
+
+/// How many outgoing control packets can be queued for the writer task. These
+/// are written ahead of any bulk packets, so the lane rarely fills up.
+pub const CONTROL_QUEUE_CAPACITY: usize = 32;
+
+/// How many outgoing packets of one transfer can be queued for the writer of
+/// its stream before [ProtocolSender::send] starts waiting for room.
+pub const TRANSFER_QUEUE_CAPACITY: usize = 16;
+
+/// A cloneable handle for sending packets on a split [Protocol].
+///
+/// Every clone feeds the same queues, which are drained by a single writer
+/// task that owns the write half of the connection, so any number of tasks can
+/// send to the peer at once. Packets are queued on the lane picked by
+/// [SerializePacket::priority], and the writer always empties the control lane
+/// before writing the next bulk packet.
+///
+/// On transports with several streams, the bulk packets of each transfer are
+/// queued for a writer of their own instead, see
+/// [SerializePacket::transfer_id].
+///
+/// [Protocol]: crate::Protocol
+pub struct ProtocolSender<OutPacketType: SerializePacket> {
+    control: mpsc::Sender<OutPacketType>,
+    bulk: mpsc::Sender<OutPacketType>,
+    transfers: Option<Arc<Mutex<TransferStreams<OutPacketType>>>>,
+    shutdown: CancellationToken,
+}
+
+impl<OutPacketType: SerializePacket> Clone for ProtocolSender<OutPacketType> {
+    fn clone(&self) -> Self {
+        Self {
+            control: self.control.clone(),
+            bulk: self.bulk.clone(),
+            transfers: self.transfers.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+}
+
+impl<OutPacketType: SerializePacket + Send + 'static> ProtocolSender<OutPacketType> {
+    /// Queue a message for the writer task on the lane matching its
+    /// [Priority], waiting if that lane is full.
+    pub async fn send(&self, message: OutPacketType) -> io::Result<()> {
+        let priority = message.priority();
+        self.send_with_priority(message, priority).await
+    }
+
+    /// Queue a message on an explicit lane, ignoring the packet's own
+    /// [SerializePacket::priority].
+    pub async fn send_with_priority(&self, message: OutPacketType, priority: Priority) -> io::Result<()> {
+        if self.shutdown.is_cancelled() {
+            return Err(closed_error());
+        }
+        let queue = match (priority, message.transfer_id(), &self.transfers) {
+            (Priority::Control, _, _) => self.control.clone(),
+            (Priority::Bulk, Some(transfer_id), Some(transfers)) => {
+                let mut transfers = transfers.lock().unwrap();
+                transfers.lane(transfer_id, message.ends_transfer(), &self.shutdown)?
+            }
+            (Priority::Bulk, _, _) => self.bulk.clone(),
+        };
+        queue.send(message).await.map_err(|_| closed_error())
+    }
+}
+
+impl<OutPacketType: SerializePacket> ProtocolSender<OutPacketType> {
+    /// Shut the connection down. Messages that were already queued are still
+    /// written before the write half is closed, and the paired
+    /// [ProtocolReceiver] stops yielding packets.
+    pub fn close(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Whether the connection has been shut down by either half.
+    pub fn is_closed(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Wait until the connection has been shut down by either half.
+    pub async fn closed(&self) {
+        self.shutdown.cancelled().await
+    }
+}
+
+/// The streams the transfers of a [ProtocolSender] are written to.
+struct TransferStreams<OutPacketType: SerializePacket> {
+    open: OpenStream,
+    /// The keys of each stream are derived from this encoder's
+    encoder: PacketEncoder<OutPacketType>,
+    next_stream: u32,
+    /// The queue of every transfer that hasn't sent its last packet yet
+    lanes: HashMap<Uuid, mpsc::Sender<OutPacketType>>,
+    writers: Arc<Mutex<TransferWriters>>,
+}
+
+impl<OutPacketType: SerializePacket + Send + 'static> TransferStreams<OutPacketType> {
+    /// The queue of a transfer, opening a stream for it if this is its first
+    /// packet. The queue is forgotten once the last packet has been handed
+    /// out, so the stream is closed as soon as that packet is written.
+    fn lane(&mut self, transfer_id: Uuid, last: bool, shutdown: &CancellationToken) -> io::Result<mpsc::Sender<OutPacketType>> {
+        let lane = match self.lanes.get(&transfer_id) {
+            Some(lane) => lane.clone(),
+            None => {
+                let stream = self.next_stream;
+                self.next_stream = stream.checked_add(1)
+                    .ok_or_else(|| io::Error::other("Out of stream ids"))?;
+                let (lane, queue) = mpsc::channel(TRANSFER_QUEUE_CAPACITY);
+                let writer = write_transfer((self.open)(stream), self.encoder.for_stream(stream), queue, shutdown.clone());
+                self.writers.lock().unwrap().spawn(writer);
+                self.lanes.insert(transfer_id, lane.clone());
+                lane
+            }
+        };
+        if last {
+            self.lanes.remove(&transfer_id);
+        }
+        Ok(lane)
+    }
+}
+
+/// The writer tasks of the transfer streams, and the first error any of them
+/// ran into.
+#[derive(Default)]
+struct TransferWriters {
+    tasks: JoinSet<io::Result<()>>,
+    error: Option<io::Error>,
+}
+
+impl TransferWriters {
+    fn spawn(&mut self, writer: impl Future<Output = io::Result<()>> + Send + 'static) {
+        // Collect the writers of finished transfers as we go
+        while let Some(result) = self.tasks.try_join_next() {
+            self.record(result);
+        }
+        self.tasks.spawn(writer);
+    }
+
+    fn record(&mut self, result: Result<io::Result<()>, JoinError>) {
+        if let Err(e) = result.map_err(io::Error::other).and_then(|result| result) {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    /// Wait for every writer to finish, returning the first error.
+    async fn join(writers: &Mutex<Self>) -> io::Result<()> {
+        let mut writers = std::mem::take(&mut *writers.lock().unwrap());
+        while let Some(result) = writers.tasks.join_next().await {
+            writers.record(result);
+        }
+        writers.error.map_or(Ok(()), Err)
+    }
+}
+
+/// The inbound half of a split [Protocol], yielding each packet read from the
+/// peer as a [Stream].
+///
+/// The stream ends once the peer closes the connection, a read fails, or the
+/// connection is shut down from either half. Ending the stream because of the
+/// peer also shuts down the writer task.
+///
+/// [Protocol]: crate::Protocol
+pub struct ProtocolReceiver<InPacketType: DeserializePacket> {
+    read: FramedRead<TransportRead, PacketDecoder<InPacketType>>,
+    /// Packets from the peer's bulk and transfer streams, if they have their own
+    bulk: Option<BoxStream<'static, io::Result<InPacketType::Output>>>,
+    shutdown: CancellationToken,
+    shutdown_signal: Pin<Box<WaitForCancellationFutureOwned>>,
+    writer: JoinHandle<io::Result<()>>,
+    transfer_writers: Arc<Mutex<TransferWriters>>,
+}
+
+impl<InPacketType: DeserializePacket> ProtocolReceiver<InPacketType> {
+    /// Shut the connection down, see [ProtocolSender::close].
+    pub fn close(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Shut the connection down and wait for the writer tasks to flush the
+    /// queued messages and close the write half.
+    pub async fn shutdown(self) -> io::Result<()> {
+        self.shutdown.cancel();
+        let result = self.writer.await.map_err(io::Error::other).and_then(|result| result);
+        result.and(TransferWriters::join(&self.transfer_writers).await)
+    }
+}
+
+impl<InPacketType: DeserializePacket> Stream for ProtocolReceiver<InPacketType>
+where
+    InPacketType: Unpin,
+{
+    type Item = io::Result<InPacketType::Output>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.shutdown_signal.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+
+        let next = Pin::new(&mut self.read).poll_next(cx);
+        match &next {
+            // The peer hung up or the stream is unusable, so there is nothing
+            // left for the writer to do either.
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => self.shutdown.cancel(),
+            Poll::Ready(Some(Ok(_))) => {}
+            Poll::Pending => {
+                if let Some(bulk) = self.bulk.as_mut() {
+                    match bulk.as_mut().poll_next(cx) {
+                        Poll::Ready(Some(Err(e))) => {
+                            self.shutdown.cancel();
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                        Poll::Ready(Some(Ok(packet))) => return Poll::Ready(Some(Ok(packet))),
+                        // The connection lives as long as the first stream
+                        Poll::Ready(None) => self.bulk = None,
+                        Poll::Pending => {}
+                    }
+                }
+            }
+        }
+        next
+    }
+}
+
+/// Split the halves of a [Protocol] into a [ProtocolSender] and a
+/// [ProtocolReceiver], spawning the writer task that owns the write half.
+///
+/// With [BulkStreams] the bulk lane gets its own writer on a stream of its
+/// own, as does every transfer, and every stream the peer opens is read
+/// alongside the first one.
+///
+/// [Protocol]: crate::Protocol
+pub(crate) fn split<InPacketType, OutPacketType>(
+    read: FramedRead<TransportRead, PacketDecoder<InPacketType>>,
+    write: FramedWrite<TransportWrite, PacketEncoder<OutPacketType>>,
+    bulk_streams: Option<BulkStreams>,
+) -> (ProtocolSender<OutPacketType>, ProtocolReceiver<InPacketType>)
+where
+    InPacketType: DeserializePacket + Send + 'static,
+    InPacketType::Output: Send,
+    OutPacketType: SerializePacket + Send + 'static,
+{
+    let (control, control_rx) = mpsc::channel(CONTROL_QUEUE_CAPACITY);
+    let (bulk, bulk_rx) = mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
+    let shutdown = CancellationToken::new();
+    let transfer_writers = Arc::new(Mutex::new(TransferWriters::default()));
+
+    // The keys of every stream are derived from these, rather than from the
+    // first stream's, which are replaced as the first stream is rekeyed.
+    let stream_codecs = bulk_streams.map(|streams| {
+        let codecs = write.encoder().for_stream(BULK_STREAM)
+            .and_then(|encoder| Ok((encoder, read.decoder().for_stream(BULK_STREAM)?)));
+        (streams, codecs)
+    });
+
+    let (writer, bulk_read, transfers) = match stream_codecs {
+        None => (
+            tokio::spawn(write_loop(write, vec![control_rx, bulk_rx], shutdown.clone())),
+            None,
+            None,
+        ),
+        Some((_, Err(e))) => {
+            shutdown.cancel();
+            (tokio::spawn(async move { Err(e) }), None, None)
+        }
+        Some((BulkStreams { open, accept }, Ok((encoder, decoder)))) => {
+            let control_writer = write_loop(write, vec![control_rx], shutdown.clone());
+            let bulk_shutdown = shutdown.clone();
+            let bulk_encoder = encoder.for_stream(0);
+            let bulk_write = open(0);
+            let bulk_writer = async move {
+                let bulk_write = FramedWrite::new(bulk_write.await?, bulk_encoder?);
+                write_loop(bulk_write, vec![bulk_rx], bulk_shutdown).await
+            };
+            let bulk_read = accept
+                .map(move |accepted| match accepted.and_then(|(stream, read)| Ok((decoder.for_stream(stream)?, read))) {
+                    Ok((decoder, read)) => FramedRead::new(read, decoder).boxed(),
+                    Err(e) => stream::once(future::ready(Err(e))).boxed(),
+                })
+                .flatten_unordered(None)
+                .boxed();
+            let writer = tokio::spawn(async move {
+                let (control_result, bulk_result) = tokio::join!(control_writer, bulk_writer);
+                control_result.and(bulk_result)
+            });
+            let transfers = TransferStreams {
+                open,
+                encoder,
+                next_stream: 1,
+                lanes: HashMap::new(),
+                writers: transfer_writers.clone(),
+            };
+            (writer, Some(bulk_read), Some(Arc::new(Mutex::new(transfers))))
+        }
+    };
+
+    (
+        ProtocolSender {
+            control,
+            bulk,
+            transfers,
+            shutdown: shutdown.clone(),
+        },
+        ProtocolReceiver {
+            read,
+            bulk: bulk_read,
+            shutdown_signal: Box::pin(shutdown.clone().cancelled_owned()),
+            shutdown,
+            writer,
+            transfer_writers,
+        },
+    )
+}
+
+/// Write the packets queued on `lanes` until shut down, always taking from
+/// the earliest lane that has a packet waiting.
+async fn write_loop<OutPacketType: SerializePacket>(
+    mut write: FramedWrite<TransportWrite, PacketEncoder<OutPacketType>>,
+    mut lanes: Vec<mpsc::Receiver<OutPacketType>>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let result = write_queued(&mut write, &mut lanes, &shutdown).await;
+    shutdown.cancel();
+    result?;
+    finish(write, lanes).await
+}
+
+/// Write the packets of one transfer to a stream of its own. Unlike
+/// [write_loop], the connection carries on once the transfer is done.
+async fn write_transfer<OutPacketType: SerializePacket>(
+    open: BoxFuture<'static, io::Result<TransportWrite>>,
+    encoder: io::Result<PacketEncoder<OutPacketType>>,
+    queue: mpsc::Receiver<OutPacketType>,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    let mut lanes = vec![queue];
+    let result = async {
+        let mut write = FramedWrite::new(open.await?, encoder?);
+        write_queued(&mut write, &mut lanes, &shutdown).await?;
+        finish(write, lanes).await
+    }.await;
+    if result.is_err() {
+        shutdown.cancel();
+    }
+    result
+}
+
+/// Write the packets queued on `lanes` until shut down or every sender is
+/// gone.
+async fn write_queued<OutPacketType: SerializePacket>(
+    write: &mut FramedWrite<TransportWrite, PacketEncoder<OutPacketType>>,
+    lanes: &mut [mpsc::Receiver<OutPacketType>],
+    shutdown: &CancellationToken,
+) -> io::Result<()> {
+    // Each packet is flushed on its own, so a control packet never waits for
+    // more than the bulk frame that is currently being written.
+    loop {
+        let message = tokio::select! {
+            biased;
+            message = next_message(lanes) => message,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        match message {
+            Some(message) => write.send(message).await?,
+            // Every sender has been dropped
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Write whatever was queued before the shutdown, then close the write half
+/// so the peer sees a clean end of stream.
+async fn finish<OutPacketType: SerializePacket>(
+    mut write: FramedWrite<TransportWrite, PacketEncoder<OutPacketType>>,
+    mut lanes: Vec<mpsc::Receiver<OutPacketType>>,
+) -> io::Result<()> {
+    for queue in lanes.iter_mut() {
+        queue.close();
+        while let Some(message) = queue.recv().await {
+            write.feed(message).await?;
+        }
+    }
+    write.close().await
+}
+
+/// The next packet from the first lane that has one, or [None] once the
+/// senders are gone.
+async fn next_message<OutPacketType>(lanes: &mut [mpsc::Receiver<OutPacketType>]) -> Option<OutPacketType> {
+    poll_fn(|cx| {
+        for lane in lanes.iter_mut() {
+            if let Poll::Ready(message) = lane.poll_recv(cx) {
+                return Poll::Ready(message);
+            }
+        }
+        Poll::Pending
+    }).await
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Connection has been shut down")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, BufMut, BytesMut};
+    use tokio::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::Instant;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use crate::packet::{DeserializePacket, SerializePacket};
+    use crate::packet::transfer::{CHUNK_SIZE, TransferPacket};
+    use crate::{Protocol, OUTBOUND_QUEUE_CAPACITY};
+
+    struct NumberPacket(u32);
+
+    impl SerializePacket for NumberPacket {
+        fn serialize(&self, buf: &mut BytesMut) -> io::Result<usize> {
+            buf.put_u32(self.0);
+            Ok(4)
+        }
+    }
+
+    impl DeserializePacket for NumberPacket {
+        type Output = u32;
+
+        fn deserialize(buf: &mut BytesMut) -> io::Result<Self::Output> {
+            Ok(buf.get_u32())
+        }
+    }
+
+    async fn connected_pair() -> io::Result<(Protocol<NumberPacket, NumberPacket>, Protocol<NumberPacket, NumberPacket>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let client = TcpStream::connect(listener.local_addr()?).await?;
+        let (server, _) = listener.accept().await?;
+        Ok((Protocol::with_stream(client)?, Protocol::with_stream(server)?))
+    }
+
+    /// Messages sent from several tasks through clones of the same sender all
+    /// arrive on the other end.
+    #[tokio::test]
+    async fn test_send_from_many_tasks() -> io::Result<()> {
+        let (client, server) = connected_pair().await?;
+        let (sender, _client_receiver) = client.split();
+        let (_server_sender, mut server_receiver) = server.split();
+
+        let mut tasks = Vec::new();
+        for task in 0..4u32 {
+            let sender = sender.clone();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..25u32 {
+                    sender.send(NumberPacket(task * 100 + i)).await?;
+                }
+                io::Result::Ok(())
+            }));
+        }
+        for task in tasks {
+            task.await??;
+        }
+
+        let mut received = Vec::new();
+        while received.len() < 100 {
+            received.push(server_receiver.next().await.unwrap()?);
+        }
+        received.sort();
+        let expected: Vec<u32> = (0..4u32).flat_map(|task| (0..25u32).map(move |i| task * 100 + i)).collect();
+        assert_eq!(received, expected);
+        Ok(())
+    }
+
+    /// Closing the sender flushes queued messages before the peer sees the
+    /// end of the stream, and the peer's writer shuts down with it.
+    #[tokio::test]
+    async fn test_close_flushes_and_ends_peer_stream() -> io::Result<()> {
+        let (client, server) = connected_pair().await?;
+        let (sender, client_receiver) = client.split();
+        let (server_sender, mut server_receiver) = server.split();
+
+        sender.send(NumberPacket(1)).await?;
+        sender.send(NumberPacket(2)).await?;
+        client_receiver.shutdown().await?;
+        assert!(sender.is_closed());
+        assert!(sender.send(NumberPacket(3)).await.is_err());
+
+        assert_eq!(server_receiver.next().await.unwrap()?, 1);
+        assert_eq!(server_receiver.next().await.unwrap()?, 2);
+        assert!(server_receiver.next().await.is_none());
+        server_sender.closed().await;
+        Ok(())
+    }
+
+    /// While a large transfer keeps the bulk lane full, a ping is written at
+    /// the next chunk boundary instead of behind every queued chunk. The
+    /// clock is paused, so the timings only depend on the slow consumer.
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_latency_during_large_transfer() -> io::Result<()> {
+        // Keep the pipe small so that the queues, not the transport, hold
+        // the backlog of the transfer.
+        let (client, server) = io::duplex(CHUNK_SIZE);
+        let (sender, _client_receiver) = Protocol::<TransferPacket, TransferPacket>::with_io(client).split();
+        let (_server_sender, mut server_receiver) = Protocol::<TransferPacket, TransferPacket>::with_io(server).split();
+
+        let chunks_received = Arc::new(AtomicUsize::new(0));
+        let reader_count = chunks_received.clone();
+        let reader = tokio::spawn(async move {
+            while let Some(packet) = server_receiver.next().await {
+                match packet? {
+                    TransferPacket::Chunk { .. } => {
+                        reader_count.fetch_add(1, Ordering::SeqCst);
+                        // A slow consumer, so the transfer outlasts the test
+                        tokio::time::sleep(Duration::from_millis(2)).await;
+                    }
+                    TransferPacket::Ping { nonce: 42 } => {
+                        return Ok((reader_count.load(Ordering::SeqCst), Instant::now()));
+                    }
+                    _ => {}
+                }
+            }
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Ping never arrived"))
+        });
+
+        let transfer = TransferPacket::chunks(Uuid::new_v4(), &vec![0u8; CHUNK_SIZE * OUTBOUND_QUEUE_CAPACITY * 2]);
+        let bulk_sender = sender.clone();
+        let bulk = tokio::spawn(async move {
+            for chunk in transfer {
+                bulk_sender.send(chunk).await?;
+            }
+            io::Result::Ok(())
+        });
+
+        // Give the transfer time to fill the bulk lane
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let chunks_before_ping = chunks_received.load(Ordering::SeqCst);
+        let sent_at = Instant::now();
+        sender.send(TransferPacket::Ping { nonce: 42 }).await?;
+
+        let (chunks_at_ping, received_at) = reader.await??;
+        // Without a control lane the ping would sit behind a full bulk queue,
+        // i.e. at least OUTBOUND_QUEUE_CAPACITY chunks and ~256ms.
+        assert!(chunks_at_ping - chunks_before_ping < OUTBOUND_QUEUE_CAPACITY / 8);
+        assert!(received_at - sent_at < Duration::from_millis(100));
+
+        sender.close();
+        bulk.abort();
+        Ok(())
+    }
+}
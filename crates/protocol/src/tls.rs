@@ -0,0 +1,173 @@
+//! # TLS Transport
+//!
+//! For peers that can only reach each other through proxies which let TLS
+//! through, the TCP stream can be wrapped in TLS before the OSP handshake
+//! starts. The handshake still authenticates both nodes through their `_osp`
+//! keys, and the [channel binding] exported from the TLS session is signed
+//! along with it, so a proxy that terminates TLS and re-encrypts towards the
+//! other node can't sit in the middle unnoticed.
+//!
+//! [channel binding]: CHANNEL_BINDING_LABEL
+
+use std::sync::Arc;
+
+use tokio::io;
+use tokio::net::TcpStream;
+
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+use tokio_rustls::rustls::{ClientConfig, ConnectionCommon, ServerConfig};
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::Protocol;
+use crate::packet::{DeserializePacket, SerializePacket};
+
+pub use tokio_rustls::rustls;
+
+/// The TLS exporter label (RFC 5705) for the value mixed into the OSP
+/// handshake transcript.
+pub const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-osp-channel-binding";
+
+/// How many bytes are exported from the TLS session for the channel binding.
+pub const CHANNEL_BINDING_LENGTH: usize = 32;
+
+/// Run the client side of a TLS handshake over `stream`, returning the
+/// wrapped [Protocol] and the channel binding for the session.
+pub async fn connect<InPacketType, OutPacketType>(
+    stream: TcpStream,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+) -> io::Result<(Protocol<InPacketType, OutPacketType>, Vec<u8>)>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+{
+    let (stream, binding) = connect_stream(stream, config, server_name).await?;
+    Ok((Protocol::with_io(stream), binding))
+}
+
+/// Like [connect], but returns the TLS stream itself, for layering another
+/// transport such as [websocket](crate::websocket) on top.
+pub async fn connect_stream(
+    stream: TcpStream,
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+) -> io::Result<(client::TlsStream<TcpStream>, Vec<u8>)> {
+    let stream = TlsConnector::from(config).connect(server_name, stream).await?;
+    let binding = channel_binding(stream.get_ref().1)?;
+    Ok((stream, binding))
+}
+
+/// Run the server side of a TLS handshake over `stream`, returning the
+/// wrapped [Protocol] and the channel binding for the session.
+pub async fn accept<InPacketType, OutPacketType>(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+) -> io::Result<(Protocol<InPacketType, OutPacketType>, Vec<u8>)>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+{
+    let (stream, binding) = accept_stream(stream, config).await?;
+    Ok((Protocol::with_io(stream), binding))
+}
+
+/// Like [accept], but returns the TLS stream itself, for layering another
+/// transport such as [websocket](crate::websocket) on top.
+pub async fn accept_stream(
+    stream: TcpStream,
+    config: Arc<ServerConfig>,
+) -> io::Result<(server::TlsStream<TcpStream>, Vec<u8>)> {
+    let stream = TlsAcceptor::from(config).accept(stream).await?;
+    let binding = channel_binding(stream.get_ref().1)?;
+    Ok((stream, binding))
+}
+
+fn channel_binding<Data>(connection: &ConnectionCommon<Data>) -> io::Result<Vec<u8>> {
+    connection.export_keying_material(vec![0u8; CHANNEL_BINDING_LENGTH], CHANNEL_BINDING_LABEL, None)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+    use tokio_rustls::rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+
+    use crate::Protocol;
+    use crate::packet::transfer::TransferPacket;
+    use crate::tls;
+
+    /// A self-signed certificate for `localhost`, with a client that trusts
+    /// only that certificate.
+    fn self_signed() -> (Arc<ServerConfig>, Arc<ClientConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()));
+
+        let server = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        (Arc::new(server), Arc::new(client))
+    }
+
+    #[tokio::test]
+    async fn test_tls_round_trip_and_binding() -> io::Result<()> {
+        let (server_config, client_config) = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let (mut protocol, binding): (Protocol<TransferPacket, TransferPacket>, _) =
+                tls::accept(stream, server_config).await?;
+            match protocol.read_frame().await? {
+                TransferPacket::Ping { nonce } => protocol.send_message(TransferPacket::Pong { nonce }).await?,
+                _ => panic!("Expected ping packet"),
+            }
+            io::Result::Ok(binding)
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let (mut protocol, binding): (Protocol<TransferPacket, TransferPacket>, _) =
+            tls::connect(stream, client_config, server_name).await?;
+        protocol.send_message(TransferPacket::Ping { nonce: 7 }).await?;
+        assert!(matches!(protocol.read_frame().await?, TransferPacket::Pong { nonce: 7 }));
+
+        let server_binding = server.await??;
+        assert_eq!(binding.len(), tls::CHANNEL_BINDING_LENGTH);
+        assert_eq!(binding, server_binding);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_certificate_rejected() -> io::Result<()> {
+        let (server_config, _) = self_signed();
+        let (_, other_client_config) = self_signed();
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            tls::accept::<TransferPacket, TransferPacket>(stream, server_config).await.map(|_| ())
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let result = tls::connect::<TransferPacket, TransferPacket>(stream, other_client_config, server_name).await;
+        assert!(result.is_err());
+        Ok(())
+    }
+}
@@ -1,43 +1,273 @@
 use std::fmt::{Display, Formatter};
-use url::Url;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
 
-#[derive(PartialEq, Debug)]
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+use tokio::io;
+use url::{Host, Url};
+use uuid::Uuid;
+
+/// The port nodes listen on when none is given, and the port used for an
+/// [OSPUrl] without one when the domain has no `_osp._tcp` SRV record.
+pub const DEFAULT_PORT: u16 = 57401;
+
+/// Characters escaped in an object id so it stays a single path segment
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ').add(b'"').add(b'#').add(b'%').add(b'/').add(b'<').add(b'>')
+    .add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// The address of an OSP node, `osp://<domain>[:<port>]`, or of an object
+/// it syndicates, `osp://<domain>[:<port>]/<data-type>/<object-id>[?version=<n>]`.
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct OSPUrl {
+    /// A domain name, or an IP address without brackets
     pub domain: String,
-    pub port: u16,
+    /// [None] when the URL has no port, in which case it is looked up through
+    /// the domain's `_osp._tcp` SRV records, falling back to [DEFAULT_PORT].
+    pub port: Option<u16>,
+    /// The object this URL points at on the node, if any
+    pub resource: Option<ResourcePath>,
 }
 
-impl From<Url> for OSPUrl {
-    fn from(value: Url) -> Self {
-        assert_eq!(value.scheme(), "osp");
+/// Names one object on a node.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ResourcePath {
+    /// The id of the object's [Data] type
+    ///
+    /// [Data]: osp_data::Data
+    pub data_type: Uuid,
+    pub object_id: String,
+    /// A specific version of the object, or [None] for the latest
+    pub version: Option<u64>,
+}
 
+impl OSPUrl {
+    /// The URL of the node itself, without any resource.
+    pub fn node(&self) -> OSPUrl {
         OSPUrl {
-            domain: value.domain().unwrap().to_string(),
-            port: value.port().unwrap()
+            domain: self.domain.clone(),
+            port: self.port,
+            resource: None,
+        }
+    }
+
+    /// The port given in the URL, or [DEFAULT_PORT].
+    pub fn port_or_default(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+impl TryFrom<Url> for OSPUrl {
+    type Error = io::Error;
+
+    fn try_from(value: Url) -> Result<Self, Self::Error> {
+        if value.scheme() != "osp" {
+            return Err(invalid(format!("Expected an osp:// URL, got {}://", value.scheme())));
+        }
+        if !value.username().is_empty() || value.password().is_some() {
+            return Err(invalid("OSP URLs can't carry credentials"));
         }
+        if value.fragment().is_some() {
+            return Err(invalid(format!("Unexpected fragment in OSP URL {value}")));
+        }
+        let resource = parse_resource(&value)?;
+
+        let domain = match value.host() {
+            Some(Host::Domain(domain)) if !domain.is_empty() => domain.to_string(),
+            Some(Host::Ipv4(ip)) => ip.to_string(),
+            Some(Host::Ipv6(ip)) => ip.to_string(),
+            _ => return Err(invalid(format!("Missing host in OSP URL {value}"))),
+        };
+
+        Ok(OSPUrl {
+            domain,
+            port: value.port(),
+            resource,
+        })
     }
 }
 
+fn parse_resource(value: &Url) -> io::Result<Option<ResourcePath>> {
+    let segments: Vec<&str> = value.path_segments().map(|segments| segments.collect()).unwrap_or_default();
+    let (data_type, object_id) = match segments.as_slice() {
+        [] | [""] => {
+            if value.query().is_some() {
+                return Err(invalid(format!("Unexpected query in OSP URL {value}")));
+            }
+            return Ok(None);
+        }
+        [data_type, object_id] if !object_id.is_empty() => (data_type, object_id),
+        _ => return Err(invalid(format!("Expected /<data-type>/<object-id> in OSP URL {value}"))),
+    };
+
+    let data_type = Uuid::parse_str(data_type)
+        .map_err(|e| invalid(format!("Invalid data type in OSP URL {value}: {e}")))?;
+    let object_id = percent_decode_str(object_id).decode_utf8()
+        .map_err(|e| invalid(format!("Invalid object id in OSP URL {value}: {e}")))?
+        .into_owned();
+
+    let mut version = None;
+    for (key, val) in value.query_pairs() {
+        match key.as_ref() {
+            "version" if version.is_none() => version = Some(val.parse::<u64>()
+                .map_err(|e| invalid(format!("Invalid version in OSP URL {value}: {e}")))?),
+            _ => return Err(invalid(format!("Unexpected query parameter {key} in OSP URL {value}"))),
+        }
+    }
+
+    Ok(Some(ResourcePath { data_type, object_id, version }))
+}
+
+impl FromStr for OSPUrl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Url resolves `.` and `..` segments away, escaped or not, so an
+        // object id of only dots is swapped for a placeholder while parsing
+        let (parsed, dots) = match dot_object_id(s) {
+            Some((start, end, dots)) => (format!("{}_{}", &s[..start], &s[end..]), Some(dots)),
+            None => (s.to_string(), None),
+        };
+        let url = Url::parse(&parsed).map_err(|e| invalid(format!("Invalid URL {s}: {e}")))?;
+        let mut url = OSPUrl::try_from(url)?;
+        if let (Some(resource), Some(dots)) = (url.resource.as_mut(), dots) {
+            resource.object_id = dots;
+        }
+        Ok(url)
+    }
+}
+
+/// Where the last path segment of `s` starts and ends, and what it decodes
+/// to, if it is `.` or `..`.
+fn dot_object_id(s: &str) -> Option<(usize, usize, String)> {
+    let authority = s.find("://")? + 3;
+    let path = authority + s[authority..].find('/')?;
+    let end = s[path..].find(['?', '#']).map_or(s.len(), |end| path + end);
+    let start = path + s[path..end].rfind('/')? + 1;
+    let segment = percent_decode_str(&s[start..end]).decode_utf8().ok()?;
+    matches!(segment.as_ref(), "." | "..").then(|| (start, end, segment.into_owned()))
+}
+
 impl Display for OSPUrl {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(format!("osp://{}:{}", self.domain, self.port).as_str())
+        if self.domain.parse::<Ipv6Addr>().is_ok() {
+            write!(f, "osp://[{}]", self.domain)?;
+        } else {
+            write!(f, "osp://{}", self.domain)?;
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        if let Some(resource) = &self.resource {
+            match resource.object_id.as_str() {
+                // Written escaped, as a bare `.` or `..` is a relative path
+                "." | ".." => write!(f, "/{}/{}", resource.data_type, "%2E".repeat(resource.object_id.len()))?,
+                object_id => write!(f, "/{}/{}", resource.data_type, utf8_percent_encode(object_id, SEGMENT))?,
+            }
+            if let Some(version) = resource.version {
+                write!(f, "?version={version}")?;
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use tokio::io;
     use url::Url;
-    use crate::OSPUrl;
+    use uuid::Uuid;
+    use crate::{OSPUrl, ResourcePath};
 
     #[test]
     fn test_url_parse() {
         let expected = OSPUrl {
             domain: "test-url.com".to_string(),
-            port: 42069,
+            port: Some(42069),
+            resource: None,
         };
 
-        let test_val = OSPUrl::from(Url::parse("osp://test-url.com:42069").unwrap());
+        let test_val = OSPUrl::try_from(Url::parse("osp://test-url.com:42069").unwrap()).unwrap();
         assert_eq!(expected, test_val);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_url_without_port() {
+        let url: OSPUrl = "osp://test-url.com".parse().unwrap();
+        assert_eq!(url.port, None);
+        assert_eq!(url.port_or_default(), 57401);
+        assert_eq!(url.to_string(), "osp://test-url.com");
+    }
+
+    #[test]
+    fn test_url_round_trip() {
+        for s in ["osp://test-url.com:42069", "osp://127.0.0.1:1", "osp://[::1]:57401", "osp://[::1]"] {
+            let url: OSPUrl = s.parse().unwrap();
+            assert_eq!(url.to_string(), s);
+        }
+        let url: OSPUrl = "osp://[::1]".parse().unwrap();
+        assert_eq!(url.domain, "::1");
+
+        // Object ids that look like relative paths aren't resolved away
+        for object_id in [".", "..", "...", "./a", "a/.."] {
+            let url = OSPUrl {
+                domain: "test-url.com".to_string(),
+                port: None,
+                resource: Some(ResourcePath { data_type: Uuid::new_v4(), object_id: object_id.to_string(), version: Some(1) }),
+            };
+            assert_eq!(url.to_string().parse::<OSPUrl>().unwrap(), url, "{url}");
+        }
+        let url: OSPUrl = "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/..".parse().unwrap();
+        assert_eq!(url.resource.unwrap().object_id, "..");
+    }
+
+    #[test]
+    fn test_url_invalid() {
+        for s in [
+            "https://test-url.com",
+            "osp://",
+            "osp://user@test-url.com",
+            "osp://test-url.com/some/path/",
+            "osp://test-url.com/not-a-uuid/object",
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/",
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/object?version=x",
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/object?other=1",
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/object#fragment",
+            "osp://test-url.com?query",
+            "not a url",
+        ] {
+            assert!(s.parse::<OSPUrl>().is_err(), "{s} should not parse");
+        }
+    }
+
+    #[test]
+    fn test_resource_url() {
+        let url: OSPUrl = "osp://test-url.com:42069/67e55044-10b1-426f-9247-bb680e5fe0c8/post%2F1%20a?version=3"
+            .parse()
+            .unwrap();
+        assert_eq!(url.resource, Some(ResourcePath {
+            data_type: Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap(),
+            object_id: "post/1 a".to_string(),
+            version: Some(3),
+        }));
+        assert_eq!(url.node().to_string(), "osp://test-url.com:42069");
+        assert_eq!(url.to_string().parse::<OSPUrl>().unwrap(), url);
+    }
+
+    #[test]
+    fn test_resource_url_round_trip() {
+        for s in [
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/object",
+            "osp://[::1]:1/67e55044-10b1-426f-9247-bb680e5fe0c8/object?version=0",
+            "osp://test-url.com/67e55044-10b1-426f-9247-bb680e5fe0c8/a%2Fb%3Fc%25d",
+        ] {
+            let url: OSPUrl = s.parse().unwrap();
+            assert_eq!(url.to_string(), s);
+        }
+    }
+}
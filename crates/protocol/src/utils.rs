@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ConnectionType {
     Unknown = 0,
     Client = 1,
@@ -22,4 +23,16 @@ impl From<&ConnectionType> for u8 {
             ConnectionType::Server => 2,
         }
     }
-}
\ No newline at end of file
+}
+/// The transport a connection runs over
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Transport {
+    /// TCP, optionally wrapped in TLS
+    #[default]
+    Tcp,
+    /// QUIC, see [quic](crate::quic). Requires TLS configs on both sides.
+    Quic,
+    /// A WebSocket over TCP, optionally wrapped in TLS, see
+    /// [websocket](crate::websocket)
+    WebSocket,
+}
@@ -0,0 +1,336 @@
+//! # WebSocket Transport
+//!
+//! For hosting setups that only forward HTTP(S), OSP can run inside a
+//! WebSocket. Every [Protocol] frame is carried in exactly one binary message,
+//! so proxies that buffer or inspect messages see whole frames. Layer it on top
+//! of a [tls] stream for `wss`.
+//!
+//! [tls]: crate::tls
+
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use futures_util::{Sink, Stream, StreamExt};
+use futures_util::stream::{SplitSink, SplitStream};
+
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+use crate::Protocol;
+use crate::packet::{DeserializePacket, SerializePacket};
+use crate::packet::encryption::ENCRYPTION_OVERHEAD;
+
+/// The path nodes serve the WebSocket upgrade on unless configured otherwise
+pub const DEFAULT_PATH: &str = "/osp";
+
+/// Every frame starts with its u32 length
+const LENGTH_HEADER: usize = 4;
+
+/// Run the client side of the WebSocket upgrade for `ws://host/path` (or
+/// `wss://` if `secure`) over `stream`, returning the wrapped [Protocol].
+/// Messages from the peer are refused once they could not hold a frame of
+/// `max_frame_length`, see [PacketDecoder::with_max_length].
+///
+/// [PacketDecoder::with_max_length]: crate::packet::PacketDecoder::with_max_length
+pub async fn connect<InPacketType, OutPacketType, S>(
+    stream: S,
+    host: &str,
+    path: &str,
+    secure: bool,
+    max_frame_length: usize,
+) -> io::Result<Protocol<InPacketType, OutPacketType>>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let scheme = if secure { "wss" } else { "ws" };
+    let url = format!("{scheme}://{host}{path}");
+    let (stream, _) = tokio_tungstenite::client_async_with_config(url, stream, Some(config(max_frame_length)))
+        .await
+        .map_err(ws_error)?;
+    Ok(with_websocket(stream))
+}
+
+/// Run the server side of the WebSocket upgrade over `stream`, rejecting
+/// upgrades for any path other than `path`, and return the wrapped [Protocol].
+/// Messages are limited by `max_frame_length` as in [connect].
+pub async fn accept<InPacketType, OutPacketType, S>(
+    stream: S,
+    path: &str,
+    max_frame_length: usize,
+) -> io::Result<Protocol<InPacketType, OutPacketType>>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The error type is set by tungstenite
+    #[allow(clippy::result_large_err)]
+    let check_path = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        if request.uri().path() == path {
+            Ok(response)
+        } else {
+            let mut error = ErrorResponse::new(None);
+            *error.status_mut() = StatusCode::NOT_FOUND;
+            Err(error)
+        }
+    };
+    let stream = tokio_tungstenite::accept_hdr_async_with_config(stream, check_path, Some(config(max_frame_length)))
+        .await
+        .map_err(ws_error)?;
+    Ok(with_websocket(stream))
+}
+
+fn with_websocket<InPacketType, OutPacketType, S>(stream: WebSocketStream<S>) -> Protocol<InPacketType, OutPacketType>
+where
+    InPacketType: DeserializePacket,
+    OutPacketType: SerializePacket,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sink, stream) = stream.split();
+    Protocol::with_halves(
+        Box::new(WebSocketRead { stream, message: Bytes::new() }),
+        Box::new(WebSocketWrite { sink, pending: BytesMut::new() }),
+    )
+}
+
+fn config(max_frame_length: usize) -> WebSocketConfig {
+    // A message holds exactly one frame, which may be wrapped in compression
+    // and encryption headers, so nothing larger is ever buffered. A message
+    // is always sent as a single WebSocket frame.
+    let max_message_size = LENGTH_HEADER + max_frame_length + 1 + ENCRYPTION_OVERHEAD;
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..Default::default()
+    }
+}
+
+fn ws_error(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => io::Error::from(io::ErrorKind::BrokenPipe),
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+/// Reads the frames out of each binary message in turn.
+struct WebSocketRead<S> {
+    stream: SplitStream<WebSocketStream<S>>,
+    /// What is left of the message currently being read
+    message: Bytes,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketRead<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.message.is_empty() {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    if data.len() < LENGTH_HEADER
+                        || u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize != data.len() - LENGTH_HEADER {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "WebSocket message does not hold exactly one frame"
+                        )));
+                    }
+                    self.message = Bytes::from(data);
+                }
+                // Pings are answered by tungstenite itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Expected a binary WebSocket message"
+                ))),
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+        }
+
+        let length = self.message.len().min(buf.remaining());
+        buf.put_slice(&self.message[..length]);
+        self.message.advance(length);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Collects written bytes and sends each complete frame as its own binary
+/// message.
+struct WebSocketWrite<S> {
+    sink: SplitSink<WebSocketStream<S>, Message>,
+    /// Bytes written since the last complete frame was sent
+    pending: BytesMut,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketWrite<S> {
+    fn next_frame_length(&self) -> Option<usize> {
+        if self.pending.len() < LENGTH_HEADER {
+            return None;
+        }
+        let length = LENGTH_HEADER
+            + u32::from_le_bytes([self.pending[0], self.pending[1], self.pending[2], self.pending[3]]) as usize;
+        (self.pending.len() >= length).then_some(length)
+    }
+
+    /// Hand every complete frame in `pending` to the sink.
+    fn poll_send_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(length) = self.next_frame_length() {
+            ready!(Pin::new(&mut self.sink).poll_ready(cx)).map_err(ws_error)?;
+            let frame = self.pending.split_to(length);
+            Pin::new(&mut self.sink).start_send(Message::Binary(frame.to_vec())).map_err(ws_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketWrite<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_send_frames(cx))?;
+        self.pending.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_frames(cx))?;
+        Pin::new(&mut self.sink).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.sink).poll_close(cx).map_err(ws_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use futures_util::{SinkExt, StreamExt};
+
+    use tokio::io;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_util::codec::Encoder;
+
+    use tokio_tungstenite::tungstenite::Message;
+    use uuid::Uuid;
+
+    use crate::Protocol;
+    use crate::packet::{PacketEncoder, PACKET_MAX_LENGTH, PACKET_MIN_MAX_LENGTH};
+    use crate::packet::transfer::TransferPacket;
+    use crate::websocket;
+
+    #[tokio::test]
+    async fn test_websocket_round_trip() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let data = vec![5u8; 300_000];
+
+        let expected = data.clone();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut protocol: Protocol<TransferPacket, TransferPacket> = websocket::accept(stream, websocket::DEFAULT_PATH, PACKET_MAX_LENGTH).await?;
+            let mut received = Vec::new();
+            loop {
+                match protocol.read_frame().await? {
+                    TransferPacket::Chunk { data, last, .. } => {
+                        received.extend_from_slice(&data);
+                        if last {
+                            break;
+                        }
+                    }
+                    _ => panic!("Expected chunk packet"),
+                }
+            }
+            assert_eq!(received, expected);
+            protocol.send_message(TransferPacket::Close { err: None }).await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut protocol: Protocol<TransferPacket, TransferPacket> =
+            websocket::connect(stream, &addr.to_string(), websocket::DEFAULT_PATH, false, PACKET_MAX_LENGTH).await?;
+        for chunk in TransferPacket::chunks(Uuid::new_v4(), &data) {
+            protocol.send_message(chunk).await?;
+        }
+        assert!(matches!(protocol.read_frame().await?, TransferPacket::Close { err: None }));
+        server.await??;
+        Ok(())
+    }
+
+    /// Each binary message holds exactly one encoded frame.
+    #[tokio::test]
+    async fn test_one_frame_per_message() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut protocol: Protocol<TransferPacket, TransferPacket> = websocket::accept(stream, websocket::DEFAULT_PATH, PACKET_MAX_LENGTH).await?;
+            protocol.send_message(TransferPacket::Ping { nonce: 1 }).await?;
+            protocol.send_message(TransferPacket::Ping { nonce: 2 }).await
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let url = format!("ws://{addr}{}", websocket::DEFAULT_PATH);
+        let (mut client, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+        for nonce in [1, 2] {
+            let mut expected = BytesMut::new();
+            PacketEncoder::<TransferPacket>::new().encode(TransferPacket::Ping { nonce }, &mut expected)?;
+            match client.next().await {
+                Some(Ok(Message::Binary(message))) => assert_eq!(message, expected.to_vec()),
+                other => panic!("Expected binary message, got {other:?}"),
+            }
+        }
+        client.close(None).await.unwrap();
+        server.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wrong_path_rejected() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            websocket::accept::<TransferPacket, TransferPacket, _>(stream, websocket::DEFAULT_PATH, PACKET_MAX_LENGTH).await.map(|_| ())
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let result = websocket::connect::<TransferPacket, TransferPacket, _>(stream, &addr.to_string(), "/elsewhere", false, PACKET_MAX_LENGTH).await;
+        assert!(result.is_err());
+        assert!(server.await?.is_err());
+        Ok(())
+    }
+
+    /// A message too large to hold a frame the reader accepts is refused
+    /// before it is buffered.
+    #[tokio::test]
+    async fn test_oversized_message_refused() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut protocol: Protocol<TransferPacket, TransferPacket> =
+                websocket::accept(stream, websocket::DEFAULT_PATH, PACKET_MIN_MAX_LENGTH).await?;
+            protocol.read_frame().await.map(|_| ())
+        });
+
+        let stream = TcpStream::connect(addr).await?;
+        let url = format!("ws://{addr}{}", websocket::DEFAULT_PATH);
+        let (mut client, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+        let mut message = vec![0u8; PACKET_MIN_MAX_LENGTH * 2];
+        message[..4].copy_from_slice(&(PACKET_MIN_MAX_LENGTH as u32 * 2 - 4).to_le_bytes());
+        client.send(Message::Binary(message)).await.unwrap();
+        let err = server.await?.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Message too long"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! The capability exchange both sides run right after the handshake.
+
+use log::info;
+
+use tokio::io;
+
+use osp_data::{DataRegistry, PeerCapabilities};
+
+use osp_protocol::Protocol;
+use osp_protocol::packet::transfer::TransferPacket;
+
+/// Send the data types in `registry` to the peer and intersect them with the
+/// ones it sends back.
+pub(crate) async fn exchange_capabilities(
+    protocol: &mut Protocol<TransferPacket, TransferPacket>,
+    registry: &DataRegistry,
+) -> io::Result<PeerCapabilities> {
+    let ours = registry.capabilities();
+    protocol.send_message(TransferPacket::Capabilities { data_types: ours.clone() }).await?;
+
+    match protocol.read_frame().await? {
+        TransferPacket::Capabilities { data_types } => {
+            let capabilities = PeerCapabilities::negotiate(&ours, &data_types);
+            info!(
+                "Peer can receive {} and send {} of our data types",
+                capabilities.sendable().count(),
+                capabilities.receivable().count()
+            );
+            Ok(capabilities)
+        }
+        TransferPacket::Close { err } => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            err.unwrap_or_else(|| "Peer closed the connection before sending its capabilities".to_string())
+        )),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Expected the peer's capabilities")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Decode;
+    use tokio::io;
+    use uuid::{uuid, Uuid};
+
+    use osp_data::{Data, DataRegistry, DataUsage};
+    use osp_protocol::Protocol;
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::connection::capabilities::exchange_capabilities;
+
+    #[derive(Decode)]
+    struct Post;
+
+    impl Data for Post {
+        fn get_id() -> Uuid {
+            uuid!("5d0c0f0e-52a4-4f0e-8b8e-8a1f2c1c9a01")
+        }
+    }
+
+    #[derive(Decode)]
+    struct Like;
+
+    impl Data for Like {
+        fn get_id() -> Uuid {
+            uuid!("9b1f3c55-4c1e-4a47-9e7a-3f0e2b6d7c02")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exchange_capabilities() -> io::Result<()> {
+        let (a, b) = io::duplex(1024);
+
+        let peer = tokio::spawn(async move {
+            let mut registry = DataRegistry::new();
+            registry.register::<Post>().unwrap();
+            registry.register_with_usage::<Like>(DataUsage::PRODUCE).unwrap();
+            let mut protocol: Protocol<TransferPacket, TransferPacket> = Protocol::with_io(b);
+            exchange_capabilities(&mut protocol, &registry).await
+        });
+
+        let mut registry = DataRegistry::new();
+        registry.register::<Post>().unwrap();
+        registry.register::<Like>().unwrap();
+        let mut protocol: Protocol<TransferPacket, TransferPacket> = Protocol::with_io(a);
+        let ours = exchange_capabilities(&mut protocol, &registry).await?;
+        let theirs = peer.await??;
+
+        assert!(ours.can_send(&Post::get_id()) && ours.can_receive(&Post::get_id()));
+        assert!(!ours.can_send(&Like::get_id()) && ours.can_receive(&Like::get_id()));
+        assert!(theirs.can_send(&Like::get_id()) && !theirs.can_receive(&Like::get_id()));
+        Ok(())
+    }
+}
@@ -1,155 +1,360 @@
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
+use openssl::pkey::Private;
 use openssl::rand::rand_bytes;
 use openssl::rsa::{Padding, Rsa};
 
-use tokio::io;
-use tokio::net::TcpStream;
+use std::sync::Arc;
 
-use trust_dns_resolver::{TokioAsyncResolver};
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use tokio::io;
+use tokio::net::{TcpStream, UnixStream};
+use tokio::net::unix::UCred;
 
 use uuid::Uuid;
 
-use osp_protocol::{ConnectionType, Protocol};
-use osp_protocol::packet::{PacketDecoder, PacketEncoder};
+use osp_data::{DataRegistry, PeerCapabilities};
+
+use osp_protocol::{ConnectionType, Protocol, ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::{PacketDecoder, PacketEncoder, PACKET_MAX_LENGTH, PACKET_MIN_MAX_LENGTH};
+use osp_protocol::packet::compression::Compression;
+use osp_protocol::packet::encryption::{EphemeralKey, SessionCipher, SessionKeys, SessionRole};
 use osp_protocol::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
 use osp_protocol::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+use osp_protocol::quic::{self, quinn::Incoming};
+use osp_protocol::tls::{self, rustls::ServerConfig};
+use osp_protocol::websocket;
+
+use crate::connection::capabilities;
+use crate::identity;
 
 pub struct InboundConnection<TState> {
     connection_type: ConnectionType,
+    /// Who is on the other end of a Unix socket connection
+    peer_credentials: Option<UCred>,
+    /// The hostname the guest proved it owns during the handshake
+    peer_hostname: Option<String>,
     state: TState
 }
 
 pub struct HandshakeState {
     nonce: Uuid,
-    protocol: Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>
+    protocol: Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>,
+    /// The TLS exporter value if the connection runs over TLS, empty otherwise
+    channel_binding: Vec<u8>,
+    /// Compression algorithms we accept, in order of preference
+    compression: Vec<Compression>,
+    /// The compression algorithm picked in response to the guest's hello
+    negotiated_compression: Compression,
+    /// The longest transfer frame we accept
+    max_frame_length: usize,
+    /// The longest transfer frame the guest accepts
+    peer_max_frame_length: usize,
+    /// Our `_osp` key, used to sign our half of the key exchange
+    private_key: Rsa<Private>,
+    /// Keys for the transfer phase, set once the guest is verified
+    session_keys: Option<SessionKeys>,
 }
 pub struct TransferState {
-    protocol: Protocol<TransferPacketGuestToHost, TransferPacketHostToGuest>
+    protocol: Protocol<TransferPacketGuestToHost, TransferPacketHostToGuest>,
+    peer_max_frame_length: usize,
+    /// What can be sent to and received from the peer, known once
+    /// capabilities have been exchanged
+    peer_capabilities: Arc<PeerCapabilities>,
 }
 
 impl From<InboundConnection<HandshakeState>> for InboundConnection<TransferState> {
     fn from(value: InboundConnection<HandshakeState>) -> Self {
+        let compression = value.state.negotiated_compression;
+        let max_frame_length = value.state.max_frame_length;
+        let peer_max_frame_length = value.state.peer_max_frame_length;
+        // Local connections skip the key exchange, everything else has keys
+        // once the handshake is complete
+        let session_keys = value.state.session_keys;
         InboundConnection {
             connection_type: value.connection_type,
+            peer_credentials: value.peer_credentials,
+            peer_hostname: value.peer_hostname,
             state: TransferState {
                 protocol: value.state.protocol.map_codecs(
                     |_| {
-                        PacketDecoder::new() // Transfer packet types implied!
+                        let decoder = PacketDecoder::new() // Transfer packet types implied!
+                            .with_max_length(max_frame_length)
+                            .with_compression(compression);
+                        match &session_keys {
+                            Some(keys) => decoder.with_encryption(SessionCipher::new(keys.receive)),
+                            None => decoder,
+                        }
                     },
                     |_| {
-                        PacketEncoder::new()
+                        let encoder = PacketEncoder::new()
+                            .with_max_length(peer_max_frame_length)
+                            .with_compression(compression);
+                        match &session_keys {
+                            Some(keys) => encoder.with_encryption(SessionCipher::new(keys.send)),
+                            None => encoder,
+                        }
                     }
                 ),
+                peer_max_frame_length,
+                peer_capabilities: Arc::default(),
             },
         }
     }
 }
 
+impl<TState> InboundConnection<TState> {
+    /// What kind of node the guest said it is. Connections over the Unix
+    /// socket are always [ConnectionType::Client].
+    pub fn connection_type(&self) -> ConnectionType {
+        self.connection_type
+    }
+
+    /// The uid, gid and pid of the process on the other end, for connections
+    /// over the Unix socket. [None] for network connections.
+    pub fn peer_credentials(&self) -> Option<UCred> {
+        self.peer_credentials
+    }
+
+    /// The hostname the guest identified as and answered the DNS challenge
+    /// for. [None] for connections over the Unix socket and until the
+    /// handshake is complete.
+    pub fn peer_hostname(&self) -> Option<&str> {
+        self.peer_hostname.as_deref()
+    }
+}
+
+impl InboundConnection<TransferState> {
+    /// The longest frame the guest agreed to accept. Anything larger has to be
+    /// split, see [TransferPacket::chunk_size_for].
+    ///
+    /// [TransferPacket::chunk_size_for]: osp_protocol::packet::transfer::TransferPacket::chunk_size_for
+    pub fn peer_max_frame_length(&self) -> usize {
+        self.state.peer_max_frame_length
+    }
+
+    /// Advertise the data types in `registry` to the guest and work out which
+    /// ones can be exchanged with it, see [InboundConnection::peer_capabilities].
+    pub async fn exchange_capabilities(&mut self, registry: &DataRegistry) -> io::Result<()> {
+        let capabilities = capabilities::exchange_capabilities(&mut self.state.protocol, registry).await?;
+        self.state.peer_capabilities = Arc::new(capabilities);
+        Ok(())
+    }
+
+    /// The data types that can be sent to and received from the guest. Empty
+    /// until capabilities have been exchanged.
+    pub fn peer_capabilities(&self) -> &Arc<PeerCapabilities> {
+        &self.state.peer_capabilities
+    }
+
+    /// Split the connection into a cloneable [ProtocolSender] that any number
+    /// of tasks can use to push packets to the peer, and a [ProtocolReceiver]
+    /// stream of the packets the peer sends.
+    pub fn split(self) -> (ProtocolSender<TransferPacketHostToGuest>, ProtocolReceiver<TransferPacketGuestToHost>) {
+        self.state.protocol.split()
+    }
+}
+
 impl InboundConnection<HandshakeState> {
-    pub fn with_stream(stream: TcpStream) -> io::Result<Self> {
-        Ok(Self {
+    pub fn with_stream(stream: TcpStream, private_key: Rsa<Private>) -> io::Result<Self> {
+        Ok(Self::with_protocol(Protocol::with_stream(stream)?, Vec::new(), private_key))
+    }
+
+    /// Accept a TLS session over `stream` before the OSP handshake, binding
+    /// the handshake to that session.
+    pub async fn with_tls_stream(stream: TcpStream, config: Arc<ServerConfig>, private_key: Rsa<Private>) -> io::Result<Self> {
+        let (protocol, channel_binding) = tls::accept(stream, config).await?;
+        Ok(Self::with_protocol(protocol, channel_binding, private_key))
+    }
+
+    /// Accept an incoming QUIC connection, running the handshake on its first
+    /// stream.
+    pub async fn with_quic(incoming: Incoming, private_key: Rsa<Private>) -> io::Result<Self> {
+        let (protocol, channel_binding) = quic::accept(incoming).await?;
+        Ok(Self::with_protocol(protocol, channel_binding, private_key))
+    }
+
+    /// Wrap a connection accepted on the node's Unix socket. The guest is a
+    /// local client, so it is trusted by its [peer credentials] instead of a
+    /// DNS challenge, and the transfer phase is not encrypted.
+    ///
+    /// [peer credentials]: InboundConnection::peer_credentials
+    pub fn with_unix_stream(stream: UnixStream, private_key: Rsa<Private>) -> io::Result<Self> {
+        let peer_credentials = stream.peer_cred()?;
+        let (read, write) = stream.into_split();
+        let mut connection = Self::with_protocol(Protocol::with_halves(Box::new(read), Box::new(write)), Vec::new(), private_key);
+        connection.connection_type = ConnectionType::Client;
+        connection.peer_credentials = Some(peer_credentials);
+        Ok(connection)
+    }
+
+    /// Accept a WebSocket upgrade at `path` over `stream`, inside a TLS
+    /// session if a config is given, before the OSP handshake. Messages are
+    /// limited to what a frame of `max_frame_length` needs.
+    pub async fn with_websocket(
+        stream: TcpStream,
+        tls_config: Option<Arc<ServerConfig>>,
+        path: &str,
+        max_frame_length: usize,
+        private_key: Rsa<Private>,
+    ) -> io::Result<Self> {
+        let (protocol, channel_binding) = match tls_config {
+            Some(config) => {
+                let (stream, channel_binding) = tls::accept_stream(stream, config).await?;
+                (websocket::accept(stream, path, max_frame_length).await?, channel_binding)
+            }
+            None => (websocket::accept(stream, path, max_frame_length).await?, Vec::new()),
+        };
+        Ok(Self::with_protocol(protocol, channel_binding, private_key))
+    }
+
+    fn with_protocol(
+        protocol: Protocol<HandshakePacketGuestToHost, HandshakePacketHostToGuest>,
+        channel_binding: Vec<u8>,
+        private_key: Rsa<Private>,
+    ) -> Self {
+        Self {
             connection_type: ConnectionType::Unknown,
+            peer_credentials: None,
+            peer_hostname: None,
             state: HandshakeState {
                 nonce: Uuid::new_v4(),
-                protocol: Protocol::with_stream(stream)?,
+                protocol,
+                channel_binding,
+                compression: Compression::SUPPORTED.to_vec(),
+                negotiated_compression: Compression::None,
+                max_frame_length: PACKET_MAX_LENGTH,
+                peer_max_frame_length: PACKET_MAX_LENGTH,
+                private_key,
+                session_keys: None,
             }
-        })
+        }
+    }
+
+    /// Set the longest transfer frame we advertise and accept. Defaults to
+    /// [PACKET_MAX_LENGTH].
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.state.max_frame_length = max_frame_length;
+    }
+
+    /// Set the compression algorithms accepted from the guest, in order of
+    /// preference. Defaults to [Compression::SUPPORTED].
+    pub fn set_compression(&mut self, compression: Vec<Compression>) {
+        self.state.compression = compression;
     }
 
     async fn send_close_err(&mut self, error_kind: io::ErrorKind, err: String) -> io::Error {
         error!("Closing connection with error: {}", err.clone());
-        self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
+        // The guest may already be gone, in which case the error we return is
+        // still the one worth reporting
+        if let Err(e) = self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
             can_continue: false,
             err: Some(err.clone()),
-        }).await.unwrap();
+        }).await {
+            warn!("Failed to send close to guest: {e}");
+        }
         io::Error::new(error_kind, err)
     }
 
     pub async fn begin(&mut self) -> io::Result<()> {
-        if let HandshakePacketGuestToHost::Hello { connection_type } = self.state.protocol.read_frame().await? {
-            self.connection_type = connection_type;
+        if let HandshakePacketGuestToHost::Hello { connection_type, compression, max_frame_length } = self.state.protocol.read_frame().await? {
+            if self.peer_credentials.is_none() {
+                self.connection_type = connection_type;
+            }
+            if (max_frame_length as usize) < PACKET_MIN_MAX_LENGTH {
+                return Err(self.send_close_err(
+                    io::ErrorKind::InvalidData,
+                    format!("Maximum frame length {max_frame_length} is below the minimum of {PACKET_MIN_MAX_LENGTH}")
+                ).await);
+            }
+            self.state.peer_max_frame_length = max_frame_length as usize;
+            self.state.negotiated_compression = Compression::negotiate(&self.state.compression, &compression);
+            debug!("Negotiated compression: {:?}", self.state.negotiated_compression);
 
             self.state.protocol.send_message(HandshakePacketHostToGuest::Acknowledge {
                 ok: true,
-                err: None
+                err: None,
+                compression: self.state.negotiated_compression,
+                max_frame_length: self.state.max_frame_length as u32,
             }).await?;
 
+            if let Some(peer_credentials) = self.peer_credentials {
+                info!("Local client connected as uid {} gid {}", peer_credentials.uid(), peer_credentials.gid());
+                self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
+                    can_continue: true,
+                    err: None,
+                }).await?;
+                debug!("Sent success packet.");
+                return Ok(());
+            }
+
             if let HandshakePacketGuestToHost::Identify { hostname } = self.state.protocol.read_frame().await? {
                 // todo: check whitelist/blacklist
-                info!("Looking up challenge record for {hostname}");
-                let resolver = TokioAsyncResolver::tokio(
-                    ResolverConfig::default(),
-                    ResolverOpts::default());
-                let txt_resp = resolver.txt_lookup(format!("_osp.{}", hostname)).await;
-                match txt_resp {
-                    Ok(txt_resp) => {
-                        if let Some(record) = txt_resp.iter().next() {
-                            info!("Challenge record found");
-                            debug!("Challenge record: {record}");
-                            let pub_key = Rsa::public_key_from_pem(record.to_string().as_bytes())?;
-
-                            info!("Generating and encrypting challenge bytes");
-                            let mut challenge_bytes = [0; 256];
-                            rand_bytes(&mut challenge_bytes).unwrap();
-                            let mut encrypted_challenge = vec![0u8; pub_key.size() as usize];
-                            pub_key.public_encrypt(&challenge_bytes, &mut encrypted_challenge, Padding::PKCS1)?;
-
-                            info!("Sending challenge bytes");
-                            self.state.protocol.send_message(HandshakePacketHostToGuest::Challenge {
-                                encrypted_challenge,
-                                nonce: self.state.nonce,
-                            }).await?;
-
-                            if let HandshakePacketGuestToHost::Verify { challenge, nonce } = self.state.protocol.read_frame().await? {
-                                info!("Received challenge verification");
-                                if nonce != self.state.nonce {
-                                    error!("Challenge response had invalid nonce. Expected: {} Actual: {}. Rejecting...", self.state.nonce, nonce);
-                                    return Err(self.send_close_err(io::ErrorKind::InvalidData, "Invalid nonce".to_string()).await);
-                                }
-
-                                if challenge == challenge_bytes {
-                                    info!("Challenge verification successful");
-                                    self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
-                                        can_continue: true,
-                                        err: None,
-                                    }).await?;
-                                    debug!("Sent success packet.");
-                                    Ok(())
-                                } else {
-                                    error!("Challenge failed as bytes did not match. Rejecting...");
-                                    return Err(self.send_close_err(io::ErrorKind::PermissionDenied, "Challenge failed".to_string()).await)
-                                }
-                            } else {
-                                return Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected challenge verification packet".to_string()).await);
-                            }
-                        } else {
-                            return Err(
-                                self.send_close_err(
-                                    io::ErrorKind::InvalidData,
-                                    format!("Failed to resolve SRV record for {}. Is it located at _osp.{}?", hostname, hostname)
-                                ).await
-                            );
-                        }
+                let pub_key = match identity::lookup_public_key(&hostname).await {
+                    Ok(pub_key) => pub_key,
+                    Err(e) => return Err(self.send_close_err(e.kind(), e.to_string()).await),
+                };
+
+                info!("Generating and encrypting challenge bytes");
+                let mut challenge_bytes = [0; 256];
+                rand_bytes(&mut challenge_bytes).unwrap();
+                let mut encrypted_challenge = vec![0u8; pub_key.size() as usize];
+                pub_key.public_encrypt(&challenge_bytes, &mut encrypted_challenge, Padding::PKCS1)?;
+
+                let ephemeral_key = EphemeralKey::generate()?;
+                let host_key = ephemeral_key.public_key()?;
+                let signature = identity::sign(
+                    &self.state.private_key,
+                    &identity::host_transcript(&self.state.nonce, &self.state.channel_binding, &hostname, &host_key)
+                )?;
+
+                info!("Sending challenge bytes");
+                self.state.protocol.send_message(HandshakePacketHostToGuest::Challenge {
+                    encrypted_challenge,
+                    nonce: self.state.nonce,
+                    ephemeral_key: host_key.clone(),
+                    signature,
+                }).await?;
+
+                if let HandshakePacketGuestToHost::Verify { challenge, nonce, ephemeral_key: guest_key, signature } = self.state.protocol.read_frame().await? {
+                    info!("Received challenge verification");
+                    if nonce != self.state.nonce {
+                        error!("Challenge response had invalid nonce. Expected: {} Actual: {}. Rejecting...", self.state.nonce, nonce);
+                        return Err(self.send_close_err(io::ErrorKind::InvalidData, "Invalid nonce".to_string()).await);
                     }
-                    Err(e) => {
-                        return Err(
-                            self.send_close_err(
-                                io::ErrorKind::Other,
-                                format!(
-                                    "Failed to resolve SRV record for {}. Is it located at _osp.{}?\n\nFurther Details: {}",
-                                    hostname, hostname, e.to_string()
-                                )
-                            ).await
-                        );
+
+                    if challenge != challenge_bytes {
+                        error!("Challenge failed as bytes did not match. Rejecting...");
+                        return Err(self.send_close_err(io::ErrorKind::PermissionDenied, "Challenge failed".to_string()).await)
+                    }
+
+                    let transcript = identity::guest_transcript(&self.state.nonce, &self.state.channel_binding, &hostname, &host_key, &guest_key);
+                    if !identity::verify(&pub_key, &transcript, &signature)? {
+                        error!("Key exchange signature did not match {hostname}'s published key. Rejecting...");
+                        return Err(self.send_close_err(io::ErrorKind::PermissionDenied, "Invalid key exchange signature".to_string()).await)
                     }
+
+                    let session_keys = match ephemeral_key.derive_session_keys(&guest_key, &self.state.nonce, SessionRole::Host) {
+                        Ok(session_keys) => session_keys,
+                        Err(e) => return Err(self.send_close_err(io::ErrorKind::InvalidData, e.to_string()).await),
+                    };
+                    self.state.session_keys = Some(session_keys);
+                    self.peer_hostname = Some(hostname);
+
+                    info!("Challenge verification successful");
+                    self.state.protocol.send_message(HandshakePacketHostToGuest::Close {
+                        can_continue: true,
+                        err: None,
+                    }).await?;
+                    debug!("Sent success packet.");
+                    Ok(())
+                } else {
+                    Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected challenge verification packet".to_string()).await)
                 }
             } else {
-                return Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected identify packet".to_string()).await);
+                Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected identify packet".to_string()).await)
             }
         } else {
-            return Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected hello packet".to_string()).await);
+            Err(self.send_close_err(io::ErrorKind::InvalidInput, "Expected hello packet".to_string()).await)
         }
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,3 @@
-use osp_protocol::packet::{DeserializePacket, SerializePacket};
-
+mod capabilities;
 pub mod inbound;
 pub mod outbound;
@@ -1,69 +1,348 @@
 use tokio::io;
+use tokio::net::{TcpStream, UnixStream};
 
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use log::{error, info};
+use log::{error, info, warn};
 
 use openssl::pkey::Private;
 use openssl::rsa::{Padding, Rsa};
 
-use trust_dns_resolver::{TokioAsyncResolver};
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use osp_data::{DataRegistry, PeerCapabilities};
 
-use osp_protocol::{ConnectionType, OSPUrl, Protocol};
+use osp_protocol::{ConnectionType, OSPUrl, Protocol, ProtocolReceiver, ProtocolSender, Transport};
+use osp_protocol::packet::{PacketDecoder, PacketEncoder, PACKET_MAX_LENGTH, PACKET_MIN_MAX_LENGTH};
+use osp_protocol::packet::compression::Compression;
+use osp_protocol::packet::encryption::{EphemeralKey, SessionCipher, SessionKeys, SessionRole};
 use osp_protocol::packet::handshake::{HandshakePacketGuestToHost, HandshakePacketHostToGuest};
+use osp_protocol::packet::transfer::{TransferPacketGuestToHost, TransferPacketHostToGuest};
+use osp_protocol::quic::{self, quinn::Endpoint};
+use osp_protocol::tls::{self, rustls::{ClientConfig, pki_types::ServerName}};
+use osp_protocol::websocket;
+
+use crate::{discovery, identity, net};
+use crate::connection::capabilities;
 
 pub struct OutboundConnection<TState> {
-    private_key: Rsa<Private>,
+    /// Our key, used to answer the host's challenge. Local clients connecting
+    /// over a Unix socket don't need one.
+    private_key: Option<Rsa<Private>>,
     hostname: String,
+    /// The host's domain, used to check its half of the key exchange against
+    /// its `_osp` record. [None] when connecting straight to an address.
+    peer_hostname: Option<String>,
+    /// The address connected to. Until the connection is open, the first of
+    /// the addresses that will be tried.
     addr: SocketAddr,
+    /// The node's Unix socket, for local clients. The host trusts those by
+    /// their credentials, so the handshake skips the challenge.
+    unix_socket_path: Option<PathBuf>,
+    compression: Vec<Compression>,
+    max_frame_length: usize,
+    transport: Transport,
+    /// The path of the WebSocket upgrade, used with [Transport::WebSocket]
+    websocket_path: String,
+    /// Wrap the connection in TLS using this config before the handshake
+    tls: Option<Arc<ClientConfig>>,
     state: TState
 }
 
-pub struct WaitingState {}
+pub struct WaitingState {
+    /// Every address the host was resolved to, in the order to try them
+    addrs: Vec<SocketAddr>,
+}
 
 pub struct HandshakeState {
     protocol: Protocol<HandshakePacketHostToGuest, HandshakePacketGuestToHost>, // packet types reversed
+    /// The TLS exporter value if the connection runs over TLS, empty otherwise
+    channel_binding: Vec<u8>,
+    compression: Compression,
+    peer_max_frame_length: usize,
+    /// Keys for the transfer phase, set once the host accepts our verification
+    session_keys: Option<SessionKeys>,
+}
+
+pub struct TransferState {
+    protocol: Protocol<TransferPacketHostToGuest, TransferPacketGuestToHost>, // packet types reversed
+    peer_max_frame_length: usize,
+    /// What can be sent to and received from the peer, known once
+    /// capabilities have been exchanged
+    peer_capabilities: Arc<PeerCapabilities>,
+}
+
+impl From<OutboundConnection<HandshakeState>> for OutboundConnection<TransferState> {
+    fn from(value: OutboundConnection<HandshakeState>) -> Self {
+        let compression = value.state.compression;
+        let max_frame_length = value.max_frame_length;
+        let peer_max_frame_length = value.state.peer_max_frame_length;
+        // Local connections skip the key exchange, everything else has keys
+        // once the handshake is complete
+        let session_keys = value.state.session_keys;
+        OutboundConnection {
+            private_key: value.private_key,
+            hostname: value.hostname,
+            peer_hostname: value.peer_hostname,
+            addr: value.addr,
+            unix_socket_path: value.unix_socket_path,
+            compression: value.compression,
+            max_frame_length,
+            transport: value.transport,
+            websocket_path: value.websocket_path,
+            tls: value.tls,
+            state: TransferState {
+                protocol: value.state.protocol.map_codecs(
+                    |_| {
+                        let decoder = PacketDecoder::new() // Transfer packet types implied!
+                            .with_max_length(max_frame_length)
+                            .with_compression(compression);
+                        match &session_keys {
+                            Some(keys) => decoder.with_encryption(SessionCipher::new(keys.receive)),
+                            None => decoder,
+                        }
+                    },
+                    |_| {
+                        let encoder = PacketEncoder::new()
+                            .with_max_length(peer_max_frame_length)
+                            .with_compression(compression);
+                        match &session_keys {
+                            Some(keys) => encoder.with_encryption(SessionCipher::new(keys.send)),
+                            None => encoder,
+                        }
+                    }
+                ),
+                peer_max_frame_length,
+                peer_capabilities: Arc::default(),
+            },
+        }
+    }
+}
+
+impl OutboundConnection<TransferState> {
+    /// The longest frame the host agreed to accept. Anything larger has to be
+    /// split, see [TransferPacket::chunk_size_for].
+    ///
+    /// [TransferPacket::chunk_size_for]: osp_protocol::packet::transfer::TransferPacket::chunk_size_for
+    pub fn peer_max_frame_length(&self) -> usize {
+        self.state.peer_max_frame_length
+    }
+
+    /// Advertise the data types in `registry` to the host and work out which
+    /// ones can be exchanged with it, see [OutboundConnection::peer_capabilities].
+    pub async fn exchange_capabilities(&mut self, registry: &DataRegistry) -> io::Result<()> {
+        let capabilities = capabilities::exchange_capabilities(&mut self.state.protocol, registry).await?;
+        self.state.peer_capabilities = Arc::new(capabilities);
+        Ok(())
+    }
+
+    /// The data types that can be sent to and received from the host. Empty
+    /// until capabilities have been exchanged.
+    pub fn peer_capabilities(&self) -> &Arc<PeerCapabilities> {
+        &self.state.peer_capabilities
+    }
+
+    /// Split the connection into a cloneable [ProtocolSender] that any number
+    /// of tasks can use to push packets to the peer, and a [ProtocolReceiver]
+    /// stream of the packets the peer sends.
+    pub fn split(self) -> (ProtocolSender<TransferPacketGuestToHost>, ProtocolReceiver<TransferPacketHostToGuest>) {
+        self.state.protocol.split()
+    }
 }
 
 impl OutboundConnection<WaitingState> {
     pub async fn create(url: OSPUrl, private_key: Rsa<Private>, hostname: String) -> io::Result<Self> {
         info!("Resolving osp connection to {url}");
-        let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
-
-        let ip_resp = resolver.ipv4_lookup(url.domain.clone()).await?;
-        if let Some(ip) = ip_resp.iter().next() {
-            info!("Lookup successful, opening connection");
-            Self::create_with_socket_addr(
-                SocketAddr::new(IpAddr::from(ip.0), url.port),
-                private_key,
-                hostname
-            )
-        } else {
-            error!("Lookup failed");
-            Err(io::Error::new(io::ErrorKind::NotConnected, format!("Failed to resolve address {}", url.domain)))
+        let addrs = discovery::resolve(&url).await.inspect_err(|_| error!("Lookup failed"))?;
+
+        info!("Lookup successful, opening connection");
+        let mut conn = Self::create_with_socket_addrs(addrs, private_key, hostname)?;
+        // Nodes reached by address have no `_osp` record to check
+        if url.domain.parse::<IpAddr>().is_err() {
+            conn.peer_hostname = Some(url.domain);
         }
+        Ok(conn)
     }
 
     pub fn create_with_socket_addr(addr: SocketAddr, private_key: Rsa<Private>, hostname: String) -> io::Result<Self> {
+        Self::create_with_socket_addrs(vec![addr], private_key, hostname)
+    }
+
+    /// Like [OutboundConnection::create_with_socket_addr], but with several
+    /// addresses for the same host. They are raced Happy Eyeballs style in
+    /// the given order when the connection begins, and the first to connect
+    /// is used.
+    pub fn create_with_socket_addrs(addrs: Vec<SocketAddr>, private_key: Rsa<Private>, hostname: String) -> io::Result<Self> {
+        let Some(&addr) = addrs.first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to"));
+        };
         info!("Opening connection to {addr}");
 
         Ok(Self {
-            private_key,
+            private_key: Some(private_key),
             hostname,
+            peer_hostname: None,
             addr,
-            state: WaitingState {}
+            unix_socket_path: None,
+            compression: Compression::SUPPORTED.to_vec(),
+            max_frame_length: PACKET_MAX_LENGTH,
+            transport: Transport::Tcp,
+            websocket_path: websocket::DEFAULT_PATH.to_string(),
+            tls: None,
+            state: WaitingState { addrs }
         })
     }
 
+    /// Connect to a node's Unix socket as a local client, see
+    /// [OSProtocolNode::set_unix_socket_path]. No key is needed, as the host
+    /// trusts local clients by their credentials, and the transfer phase is
+    /// not encrypted.
+    ///
+    /// [OSProtocolNode::set_unix_socket_path]: crate::OSProtocolNode::set_unix_socket_path
+    pub fn create_with_unix_socket(path: PathBuf, hostname: String) -> Self {
+        info!("Opening local connection to {}", path.display());
+        Self {
+            private_key: None,
+            hostname,
+            peer_hostname: None,
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            unix_socket_path: Some(path),
+            compression: Compression::SUPPORTED.to_vec(),
+            max_frame_length: PACKET_MAX_LENGTH,
+            transport: Transport::Tcp,
+            websocket_path: websocket::DEFAULT_PATH.to_string(),
+            tls: None,
+            state: WaitingState { addrs: Vec::new() }
+        }
+    }
+
+    /// Set the longest transfer frame we advertise and accept. Defaults to
+    /// [PACKET_MAX_LENGTH].
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.max_frame_length = max_frame_length;
+    }
+
+    /// Set the compression algorithms offered to the host, in order of
+    /// preference. Defaults to [Compression::SUPPORTED].
+    pub fn set_compression(&mut self, compression: Vec<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Wrap the connection in TLS before the OSP handshake, for hosts that
+    /// can only be reached through TLS. The certificate is checked against
+    /// the host's domain, or its address when connecting by address.
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>) {
+        self.tls = Some(config);
+    }
+
+    /// Set the transport to connect over. [Transport::Quic] needs a TLS
+    /// config, see [OutboundConnection::set_tls]. Defaults to [Transport::Tcp].
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Set the path of the WebSocket upgrade used with [Transport::WebSocket].
+    /// Defaults to [websocket::DEFAULT_PATH].
+    pub fn set_websocket_path(&mut self, path: String) {
+        self.websocket_path = path;
+    }
+
+    fn server_name(&self, addr: SocketAddr) -> io::Result<ServerName<'static>> {
+        match &self.peer_hostname {
+            Some(peer_hostname) => ServerName::try_from(peer_hostname.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+            None => Ok(ServerName::from(addr.ip())),
+        }
+    }
+
+    /// Connect to the first of the host's addresses to answer over the
+    /// configured transport, returning the protocol, the channel binding and
+    /// the address connected to.
+    async fn connect(&self) -> io::Result<(Protocol<HandshakePacketHostToGuest, HandshakePacketGuestToHost>, Vec<u8>, SocketAddr)> {
+        let addrs = &self.state.addrs;
+        let connected = match (self.transport, &self.tls) {
+            (Transport::Tcp, Some(config)) => {
+                let (stream, addr) = net::race(addrs, TcpStream::connect).await?;
+                info!("Starting TLS session");
+                let (protocol, channel_binding) = tls::connect(stream, config.clone(), self.server_name(addr)?).await?;
+                (protocol, channel_binding, addr)
+            }
+            (Transport::Tcp, None) => {
+                let (stream, addr) = net::race(addrs, TcpStream::connect).await?;
+                (Protocol::with_stream(stream)?, Vec::new(), addr)
+            }
+            (Transport::Quic, Some(config)) => {
+                let client_config = quic::client_config(config)?;
+                let peer_hostname = self.peer_hostname.clone();
+                info!("Starting QUIC connection");
+                let ((protocol, channel_binding), addr) = net::race(addrs, move |addr| {
+                    let client_config = client_config.clone();
+                    let server_name = peer_hostname.clone().unwrap_or_else(|| addr.ip().to_string());
+                    async move {
+                        let local_addr = match addr {
+                            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                        };
+                        let mut endpoint = Endpoint::client(local_addr)?;
+                        endpoint.set_default_client_config(client_config);
+                        quic::connect(&endpoint, addr, &server_name).await
+                    }
+                }).await?;
+                (protocol, channel_binding, addr)
+            }
+            (Transport::Quic, None) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Connecting over QUIC requires a TLS client config"
+            )),
+            (Transport::WebSocket, tls_config) => {
+                let (stream, addr) = net::race(addrs, TcpStream::connect).await?;
+                // SocketAddr puts IPv6 addresses in brackets as the Host header needs
+                let host = match &self.peer_hostname {
+                    Some(peer_hostname) => format!("{peer_hostname}:{}", addr.port()),
+                    None => addr.to_string(),
+                };
+                info!("Starting WebSocket upgrade");
+                let (protocol, channel_binding) = match tls_config {
+                    Some(config) => {
+                        let (stream, channel_binding) = tls::connect_stream(stream, config.clone(), self.server_name(addr)?).await?;
+                        (websocket::connect(stream, &host, &self.websocket_path, true, self.max_frame_length).await?, channel_binding)
+                    }
+                    None => (websocket::connect(stream, &host, &self.websocket_path, false, self.max_frame_length).await?, Vec::new()),
+                };
+                (protocol, channel_binding, addr)
+            }
+        };
+        Ok(connected)
+    }
+
     pub async fn begin(&mut self) -> io::Result<OutboundConnection<HandshakeState>> {
         info!("Starting outbound connection");
+        let (protocol, channel_binding, addr) = match &self.unix_socket_path {
+            Some(path) => {
+                let (read, write) = UnixStream::connect(path).await?.into_split();
+                (Protocol::with_halves(Box::new(read), Box::new(write)), Vec::new(), self.addr)
+            }
+            None => self.connect().await?,
+        };
+        info!("Connected to {addr}");
+        self.addr = addr;
         Ok(OutboundConnection {
             private_key: self.private_key.clone(),
             hostname: self.hostname.clone(),
-            addr: self.addr.clone(),
+            peer_hostname: self.peer_hostname.clone(),
+            addr: self.addr,
+            unix_socket_path: self.unix_socket_path.clone(),
+            compression: self.compression.clone(),
+            max_frame_length: self.max_frame_length,
+            transport: self.transport,
+            websocket_path: self.websocket_path.clone(),
+            tls: self.tls.clone(),
             state: HandshakeState {
-                protocol: Protocol::connect(self.addr).await?,
+                protocol,
+                channel_binding,
+                compression: Compression::None,
+                peer_max_frame_length: PACKET_MAX_LENGTH,
+                session_keys: None,
             },
         })
     }
@@ -84,50 +363,110 @@ impl OutboundConnection<HandshakeState> {
         }
     }
 
+    /// Run the handshake with the host. Once this succeeds the connection can
+    /// be converted into an [OutboundConnection<TransferState>].
     pub async fn handshake(&mut self) -> io::Result<()> {
         let addr = self.addr;
         info!("<{addr}> Starting outbound handshake");
         let hostname = self.hostname.clone();
-        let private_key = self.private_key.clone();
-        self.state.protocol.send_message(HandshakePacketGuestToHost::Hello { connection_type: ConnectionType::Server }).await?;
+        let local = self.unix_socket_path.is_some();
+        self.state.protocol.send_message(HandshakePacketGuestToHost::Hello {
+            connection_type: if local { ConnectionType::Client } else { ConnectionType::Server },
+            compression: self.compression.clone(),
+            max_frame_length: self.max_frame_length as u32,
+        }).await?;
 
         if let Some(HandshakePacketHostToGuest::Acknowledge {
             ok,
-            err
+            err,
+            compression,
+            max_frame_length,
         }) = self.read_frame_and_handle_err().await? {
             if ok {
                 info!("Handshake acknowledged");
+                if compression != Compression::None && !self.compression.contains(&compression) {
+                    return Err(handshake_error(format!("Host picked compression {compression:?} which was not offered")));
+                }
+                self.state.compression = compression;
+                if (max_frame_length as usize) < PACKET_MIN_MAX_LENGTH {
+                    return Err(handshake_error(format!("Host maximum frame length {max_frame_length} is below the minimum of {PACKET_MIN_MAX_LENGTH}")));
+                }
+                self.state.peer_max_frame_length = max_frame_length as usize;
+
+                // Local clients are trusted by their credentials, so the host
+                // is done once it has acknowledged
+                if local {
+                    if let Some(HandshakePacketHostToGuest::Close { can_continue: true, err: _ }) = self.read_frame_and_handle_err().await? {
+                        info!("Local handshake successful!");
+                        return Ok(());
+                    }
+                    return Err(handshake_error("Handshake rejected by host".to_string()));
+                }
+
+                let private_key = self.private_key.clone()
+                    .ok_or_else(|| handshake_error("A private key is needed to answer the host's challenge".to_string()))?;
                 self.state.protocol.send_message(HandshakePacketGuestToHost::Identify {
-                    hostname,
+                    hostname: hostname.clone(),
                 }).await?;
 
                 if let Some(HandshakePacketHostToGuest::Challenge {
                     nonce,
-                    encrypted_challenge
+                    encrypted_challenge,
+                    ephemeral_key: host_key,
+                    signature,
                 }) = self.read_frame_and_handle_err().await? {
                     info!("Challenge received, decrypting");
                     info!("Connection Nonce: {nonce}");
+                    let transcript = identity::host_transcript(&nonce, &self.state.channel_binding, &hostname, &host_key);
+                    match &self.peer_hostname {
+                        Some(peer_hostname) => {
+                            let peer_key = identity::lookup_public_key(peer_hostname).await?;
+                            if !identity::verify(&peer_key, &transcript, &signature)? {
+                                return Err(handshake_error(format!("Key exchange signature did not match {peer_hostname}'s published key")));
+                            }
+                        }
+                        None => warn!("<{addr}> Connected by address, the host's key exchange cannot be authenticated"),
+                    }
+
                     let mut decrypt_buf = vec![0u8; private_key.size() as usize];
-                    private_key.private_decrypt(&*encrypted_challenge, &mut *decrypt_buf, Padding::PKCS1)?;
+                    let decrypted_length = private_key.private_decrypt(&encrypted_challenge, &mut decrypt_buf, Padding::PKCS1)?;
+                    decrypt_buf.truncate(decrypted_length);
+
+                    let ephemeral_key = EphemeralKey::generate()?;
+                    let guest_key = ephemeral_key.public_key()?;
+                    let signature = identity::sign(
+                        &private_key,
+                        &identity::guest_transcript(&nonce, &self.state.channel_binding, &hostname, &host_key, &guest_key)
+                    )?;
+                    let session_keys = ephemeral_key.derive_session_keys(&host_key, &nonce, SessionRole::Guest)?;
 
                     info!("Sending decrypted challenge");
                     self.state.protocol.send_message(HandshakePacketGuestToHost::Verify {
                         nonce,
                         challenge: decrypt_buf,
+                        ephemeral_key: guest_key,
+                        signature,
                     }).await?;
 
                     if let Some(HandshakePacketHostToGuest::Close {
                         can_continue: true,
                         err: _,
                     }) = self.read_frame_and_handle_err().await? {
-                        info!("Handshake successful!")
+                        info!("Handshake successful!");
+                        self.state.session_keys = Some(session_keys);
+                        return Ok(());
                     }
                 }
             } else {
-                error!("Hello failed: {}", err.unwrap());
+                error!("Hello failed: {}", err.clone().unwrap_or_default());
+                return Err(handshake_error(format!("Hello failed: {}", err.unwrap_or_default())));
             }
         }
-        Ok(())
+        Err(handshake_error("Handshake rejected by host".to_string()))
     }
 
-}
\ No newline at end of file
+}
+
+fn handshake_error(err: String) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, err)
+}
@@ -0,0 +1,171 @@
+//! Finding the addresses of a node from its [OSPUrl]. A URL with a port is
+//! resolved directly. Without one, the domain's `_osp._tcp` SRV records are
+//! used, honouring their priority and weight (RFC 2782), falling back to the
+//! domain itself on [DEFAULT_PORT].
+
+use std::net::{IpAddr, SocketAddr};
+
+use log::{debug, info};
+
+use rand::Rng;
+
+use tokio::io;
+
+use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::config::{LookupIpStrategy, ResolverConfig, ResolverOpts};
+
+use osp_protocol::{OSPUrl, DEFAULT_PORT};
+
+use crate::net;
+
+/// Where an SRV record says to find the service.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub(crate) struct SrvTarget {
+    pub(crate) priority: u16,
+    pub(crate) weight: u16,
+    pub(crate) target: String,
+    pub(crate) port: u16,
+}
+
+/// Resolve `url` to the addresses to connect to, in the order to try them.
+pub(crate) async fn resolve(url: &OSPUrl) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = url.domain.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, url.port_or_default())]);
+    }
+
+    let mut opts = ResolverOpts::default();
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), opts);
+
+    let targets = match url.port {
+        Some(port) => vec![(url.domain.clone(), port)],
+        None => match srv_targets(&resolver, &url.domain).await? {
+            Some(targets) => targets,
+            None => vec![(url.domain.clone(), DEFAULT_PORT)],
+        },
+    };
+
+    let mut addrs = Vec::new();
+    for (host, port) in targets {
+        match resolver.lookup_ip(host.as_str()).await {
+            Ok(ips) => addrs.extend(net::interleave_families(
+                ips.iter().map(|ip| SocketAddr::new(ip, port)).collect()
+            )),
+            Err(e) => debug!("Failed to resolve {host}: {e}"),
+        }
+    }
+
+    if addrs.is_empty() {
+        Err(io::Error::new(io::ErrorKind::NotConnected, format!("Failed to resolve address {}", url.domain)))
+    } else {
+        Ok(addrs)
+    }
+}
+
+/// Look up the `_osp._tcp` SRV records for `domain`, returning the targets in
+/// the order to try them, or [None] if there are no records.
+async fn srv_targets(resolver: &TokioAsyncResolver, domain: &str) -> io::Result<Option<Vec<(String, u16)>>> {
+    let name = format!("_osp._tcp.{domain}.");
+    let records = match resolver.srv_lookup(name.as_str()).await {
+        Ok(records) => records,
+        Err(e) => {
+            debug!("No SRV records at {name}: {e}");
+            return Ok(None);
+        }
+    };
+
+    let records: Vec<SrvTarget> = records.iter()
+        .map(|srv| SrvTarget {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            target: srv.target().to_utf8(),
+            port: srv.port(),
+        })
+        .collect();
+    // A lone record pointing at the root means the service is decidedly not
+    // available at this domain
+    if let [SrvTarget { target, .. }] = records.as_slice() {
+        if target == "." {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("{domain} does not offer OSP")
+            ));
+        }
+    }
+
+    info!("Found {} SRV record(s) at {name}", records.len());
+    Ok(Some(
+        order_srv_targets(records, &mut rand::thread_rng())
+            .into_iter()
+            .map(|record| (record.target, record.port))
+            .collect()
+    ))
+}
+
+/// Order SRV records by ascending priority, and within each priority by a
+/// weighted random shuffle, as described in RFC 2782.
+pub(crate) fn order_srv_targets<R: Rng>(mut records: Vec<SrvTarget>, rng: &mut R) -> Vec<SrvTarget> {
+    // Zero weights go first so they only get picked when the draw is zero
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let group_len = records.iter().take_while(|record| record.priority == priority).count();
+        let mut group: Vec<SrvTarget> = records.drain(..group_len).collect();
+
+        while !group.is_empty() {
+            let total: u32 = group.iter().map(|record| record.weight as u32).sum();
+            let draw = rng.gen_range(0..=total);
+            let mut running = 0;
+            let index = group.iter()
+                .position(|record| {
+                    running += record.weight as u32;
+                    running >= draw
+                })
+                .unwrap_or(0);
+            ordered.push(group.remove(index));
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use crate::discovery::{order_srv_targets, SrvTarget};
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SrvTarget {
+        SrvTarget { priority, weight, target: target.to_string(), port: 57401 }
+    }
+
+    fn targets(records: &[SrvTarget]) -> Vec<&str> {
+        records.iter().map(|record| record.target.as_str()).collect()
+    }
+
+    #[test]
+    fn test_srv_priority_order() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let records = vec![srv(20, 5, "c"), srv(10, 0, "a"), srv(30, 1, "d"), srv(15, 100, "b")];
+        assert_eq!(targets(&order_srv_targets(records, &mut rng)), ["a", "b", "c", "d"]);
+    }
+
+    /// Within a priority, records come first in proportion to their weight.
+    #[test]
+    fn test_srv_weight_order() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut heavy_first = 0;
+        for _ in 0..1000 {
+            let records = vec![srv(10, 10, "light"), srv(10, 90, "heavy"), srv(20, 50, "backup")];
+            let ordered = order_srv_targets(records, &mut rng);
+            assert_eq!(ordered.len(), 3);
+            assert_eq!(ordered[2].target, "backup");
+            if ordered[0].target == "heavy" {
+                heavy_first += 1;
+            }
+        }
+        assert!((850..950).contains(&heavy_first), "heavy first {heavy_first} times");
+    }
+}
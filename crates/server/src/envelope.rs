@@ -0,0 +1,124 @@
+//! Signing [SignedData] envelopes with the node's key and checking them
+//! against the origin's published key.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::error;
+
+use openssl::pkey::{Private, Public};
+use openssl::rsa::Rsa;
+
+use tokio::io;
+
+use osp_data::{RawData, SignedData};
+
+use crate::identity;
+
+/// Wrap `data` in an envelope from `origin`, created now and signed with
+/// `private_key`.
+pub(crate) fn sign(private_key: &Rsa<Private>, origin: String, object_id: String, data: RawData) -> io::Result<SignedData> {
+    let mut envelope = SignedData::unsigned(origin, object_id, data);
+    envelope.signature = identity::sign(private_key, &envelope.transcript())?;
+    Ok(envelope)
+}
+
+/// How long a key looked up for an origin is used before looking it up again
+const KEY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How old a key has to be before an envelope that doesn't match it has it
+/// looked up again, so a stream of forged envelopes can't cause a lookup each
+const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checks envelopes against the keys their origins publish in DNS, whichever
+/// peer delivered them. Keys are kept for [KEY_TTL], so a stream of objects
+/// from one origin isn't a lookup each.
+#[derive(Default)]
+pub(crate) struct Verifier {
+    keys: Mutex<HashMap<String, (Rsa<Public>, Instant)>>,
+}
+
+impl Verifier {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check `envelope` against its origin's key, see [verify_with_key]. An
+    /// envelope that doesn't match a key kept for a while has the key looked
+    /// up again, in case the origin replaced it.
+    pub(crate) async fn verify(&self, envelope: &SignedData) -> io::Result<()> {
+        let kept = self.keys.lock().unwrap().get(&envelope.origin)
+            .filter(|(_, looked_up)| looked_up.elapsed() < KEY_TTL)
+            .cloned();
+        if let Some((public_key, looked_up)) = kept {
+            if matches(&public_key, envelope) || looked_up.elapsed() < KEY_REFRESH_INTERVAL {
+                return verify_with_key(&public_key, envelope);
+            }
+        }
+        let public_key = identity::lookup_public_key(&envelope.origin).await?;
+        self.insert(envelope.origin.clone(), public_key.clone());
+        verify_with_key(&public_key, envelope)
+    }
+
+    /// Use `public_key` for `origin` from now on.
+    pub(crate) fn insert(&self, origin: String, public_key: Rsa<Public>) {
+        self.keys.lock().unwrap().insert(origin, (public_key, Instant::now()));
+    }
+}
+
+/// Check `envelope` against its origin's key, failing with
+/// [io::ErrorKind::PermissionDenied] if the signature doesn't match.
+pub(crate) fn verify_with_key(public_key: &Rsa<Public>, envelope: &SignedData) -> io::Result<()> {
+    if matches(public_key, envelope) {
+        Ok(())
+    } else {
+        error!("Envelope for {} doesn't match {}'s published key", envelope.object_id, envelope.origin);
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("Invalid signature on {} from {}", envelope.object_id, envelope.origin)
+        ))
+    }
+}
+
+fn matches(public_key: &Rsa<Public>, envelope: &SignedData) -> bool {
+    // An empty or malformed signature fails to verify rather than erroring
+    identity::verify(public_key, &envelope.transcript(), &envelope.signature).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::rsa::Rsa;
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_data::{DataCodec, RawData};
+
+    use crate::envelope::{sign, verify_with_key};
+
+    #[test]
+    fn test_sign_and_verify() -> io::Result<()> {
+        let private_key = Rsa::generate(2048)?;
+        let public_key = Rsa::public_key_from_pem(&private_key.public_key_to_pem()?)?;
+        let data = RawData::new(Uuid::new_v4(), 1, DataCodec::Bincode, vec![1, 2, 3]);
+        let envelope = sign(&private_key, "a.example".to_string(), "post/1".to_string(), data)?;
+        verify_with_key(&public_key, &envelope)?;
+
+        let mut tampered = envelope.clone();
+        tampered.data.bytes = vec![1, 2, 4].into();
+        assert_eq!(verify_with_key(&public_key, &tampered).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let mut relabelled = envelope.clone();
+        relabelled.origin = "b.example".to_string();
+        assert!(verify_with_key(&public_key, &relabelled).is_err());
+
+        let other_key = Rsa::generate(2048)?;
+        let other_public = Rsa::public_key_from_pem(&other_key.public_key_to_pem()?)?;
+        assert!(verify_with_key(&other_public, &envelope).is_err());
+
+        let mut unsigned = envelope;
+        unsigned.signature.clear();
+        assert!(verify_with_key(&public_key, &unsigned).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,804 @@
+//! # Feed
+//!
+//! Every object a node publishes is also appended to its [Feed], kept in the
+//! node's [Storage], so a follower that was offline can pull what it missed.
+//! The follower sends a [TransferPacket::SyncRequest] with the cursor it got
+//! last time and is answered with pages of objects in the order they were
+//! published, each carrying the cursor to continue from. Cursors mean nothing
+//! to the follower, which keeps the last one it got from each peer for each
+//! set of types it synced in its [Cursors].
+//!
+//! Only followers are answered, and only with the types they follow, see
+//! [Feed::handle].
+//!
+//! Feeds keep every object unless given a [FeedRetention]. Followers that
+//! were away for longer than it allows continue from the oldest object left.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bincode::{Decode, Encode};
+
+use log::{debug, warn};
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_data::{PeerCapabilities, SignedData};
+use osp_protocol::{ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::{TransferPacket, SYNC_PAGE_OVERHEAD};
+
+use crate::envelope::Verifier;
+use crate::follow::Follows;
+use crate::outbox::now_millis;
+use crate::request::{close_quietly, request_reply};
+use crate::storage::{blocking, decode, encode, quarantine, Storage};
+use crate::subscription::Publication;
+
+const KEY_PREFIX: &str = "feed/";
+const CURSOR_PREFIX: &str = "sync-cursors/";
+
+/// The most objects sent in one page, whatever the follower asks for
+pub const MAX_PAGE_OBJECTS: u16 = 100;
+
+/// A page stops growing before its objects take up more than this many bytes,
+/// or more than fit in one of the peer's frames. It always holds at least one
+/// object, unless the object doesn't fit in a frame by itself.
+pub const MAX_PAGE_BYTES: usize = 256 * 1024;
+
+/// How long the cursors handed out are
+const CURSOR_LEN: usize = 16;
+
+/// How many keys are read from storage at a time while making a page or
+/// dropping old objects
+const SCAN_BATCH: usize = 128;
+
+/// Which objects a [Feed] drops as new ones are appended. Objects are kept
+/// while they are within both limits; the default keeps every object.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FeedRetention {
+    /// Keep only this many of the newest objects
+    pub max_objects: Option<u64>,
+    /// Keep only objects created less than this long ago
+    pub max_age: Option<Duration>,
+}
+
+/// What is kept in storage for an object in the feed, whose key holds its
+/// position and type
+#[derive(Encode, Decode)]
+struct StoredEntry {
+    topic: Option<String>,
+    envelopes: Vec<SignedData>,
+}
+
+/// Part of a [Feed], see [Feed::page]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FeedPage {
+    pub objects: Vec<Publication>,
+    /// Where the next page starts
+    pub cursor: String,
+    /// Whether there were more objects when the page was made
+    pub more: bool,
+}
+
+/// The objects this node has published, in order.
+pub struct Feed {
+    storage: Arc<dyn Storage>,
+    /// The position of the next object. Held while an object is written, so
+    /// objects become visible in order and a cursor never skips one.
+    next_sequence: tokio::sync::Mutex<u64>,
+    retention: FeedRetention,
+}
+
+impl Feed {
+    /// The feed kept in `storage`, continuing after the objects already in
+    /// it.
+    pub fn open(storage: Arc<dyn Storage>) -> io::Result<Self> {
+        let last_sequence = storage.keys(KEY_PREFIX)?.iter()
+            .filter_map(|key| parse_key(key))
+            .map(|(sequence, _)| sequence)
+            .max()
+            .unwrap_or(0);
+        Ok(Feed { storage, next_sequence: tokio::sync::Mutex::new(last_sequence + 1), retention: FeedRetention::default() })
+    }
+
+    /// Drop objects outside of `retention` from now on, starting with the
+    /// next one appended.
+    pub fn with_retention(mut self, retention: FeedRetention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Add an object published under `topic`, given in each codec its type
+    /// supports.
+    pub async fn append(self: &Arc<Self>, topic: Option<String>, envelopes: Vec<SignedData>) -> io::Result<()> {
+        let Some(first) = envelopes.first() else {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "An object needs at least one envelope"));
+        };
+        let mut next_sequence = self.next_sequence.lock().await;
+        let key = format!("{KEY_PREFIX}{:020}/{}", *next_sequence, first.type_id());
+        let contents = encode(StoredEntry { topic, envelopes })?;
+        blocking(&self.storage, move |storage| storage.put(&key, &contents)).await?;
+        *next_sequence += 1;
+        let oldest_kept = self.retention.max_objects.map(|max_objects| next_sequence.saturating_sub(max_objects));
+        drop(next_sequence);
+        blocking(self, move |feed| feed.expire(oldest_kept)).await
+    }
+
+    /// Drop the objects before `oldest_kept`, and those older than the
+    /// retention's `max_age`. Objects are appended in the order they were
+    /// created, so each of these stops at the first object that is kept.
+    fn expire(&self, oldest_kept: Option<u64>) -> io::Result<()> {
+        if let Some(oldest_kept) = oldest_kept {
+            self.expire_while(|sequence, _| Ok(sequence < oldest_kept))?;
+        }
+        if let Some(max_age) = self.retention.max_age {
+            let created_after = now_millis().saturating_sub(max_age.as_millis() as u64);
+            self.expire_while(|_, key| Ok(match self.load(key)? {
+                Some(entry) => entry.envelopes.first().is_none_or(|envelope| envelope.created_at < created_after),
+                None => true,
+            }))?;
+        }
+        Ok(())
+    }
+
+    /// Drop objects from the oldest on, for as long as `expired` says so
+    /// given their position and key.
+    fn expire_while(&self, mut expired: impl FnMut(u64, &str) -> io::Result<bool>) -> io::Result<()> {
+        loop {
+            let keys = self.storage.keys_after(KEY_PREFIX, None, SCAN_BATCH)?;
+            for key in &keys {
+                match parse_key(key) {
+                    Some((sequence, _)) if !expired(sequence, key)? => return Ok(()),
+                    Some(_) => {}
+                    None => warn!("Dropping feed object with malformed key {key}"),
+                }
+                self.storage.delete(key)?;
+            }
+            if keys.len() < SCAN_BATCH {
+                return Ok(());
+            }
+        }
+    }
+
+    /// The object under `key`, if it is still there and can be decoded.
+    /// Objects that can't be, or that have no envelopes, are quarantined, see
+    /// [crate::storage].
+    fn load(&self, key: &str) -> io::Result<Option<StoredEntry>> {
+        let Some(contents) = self.storage.get(key)? else {
+            return Ok(None);
+        };
+        let entry = decode::<StoredEntry>(&contents).and_then(|entry| match entry.envelopes.is_empty() {
+            true => Err(io::Error::new(io::ErrorKind::InvalidData, "Object has no envelopes")),
+            false => Ok(entry),
+        });
+        match entry {
+            Ok(entry) => Ok(Some(entry)),
+            Err(e) => {
+                quarantine(self.storage.as_ref(), key, &contents, &e)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Up to `limit` of the objects published after `cursor`, or from the
+    /// start without one. Only objects of `data_types` are included, or of
+    /// every type if it is empty, and of those only the ones `capabilities`
+    /// says the peer can receive, in the codec negotiated with it. The page
+    /// fits in a [TransferPacket::SyncPage] of `max_frame_length` bytes, the
+    /// longest frame the peer accepts. Fails with
+    /// [io::ErrorKind::InvalidInput] if `cursor` wasn't issued by a feed.
+    pub fn page(&self, cursor: Option<&str>, data_types: &[Uuid], capabilities: &PeerCapabilities, limit: u16, max_frame_length: usize) -> io::Result<FeedPage> {
+        let after = match cursor {
+            Some(cursor) => parse_cursor(cursor)?,
+            None => 0,
+        };
+        let limit = limit.clamp(1, MAX_PAGE_OBJECTS) as usize;
+        let max_bytes = MAX_PAGE_BYTES.min(max_frame_length.saturating_sub(SYNC_PAGE_OVERHEAD + CURSOR_LEN));
+        let mut objects = Vec::new();
+        let mut bytes = 0;
+        let mut last = after;
+        let mut more = false;
+        // Sorts after every key of the object at `after`
+        let mut scanned = format!("{KEY_PREFIX}{after:020}/~");
+        'scan: loop {
+            let keys = self.storage.keys_after(KEY_PREFIX, Some(&scanned), SCAN_BATCH)?;
+            for key in &keys {
+                let Some((sequence, data_type)) = parse_key(key) else {
+                    continue;
+                };
+                let wanted = data_types.is_empty() || data_types.contains(&data_type);
+                let Some(codec) = capabilities.send_codec(&data_type).filter(|_| wanted) else {
+                    last = sequence;
+                    continue;
+                };
+                if objects.len() >= limit {
+                    more = true;
+                    break 'scan;
+                }
+                let Some(entry) = self.load(key)? else {
+                    last = sequence;
+                    continue;
+                };
+                let Some(envelope) = entry.envelopes.into_iter().find(|envelope| envelope.data.codec == codec) else {
+                    last = sequence;
+                    continue;
+                };
+                let len = TransferPacket::sync_object_len(&entry.topic, &envelope);
+                if len > max_bytes {
+                    warn!("Leaving {} out of sync pages, as it doesn't fit in a frame of {max_frame_length} bytes", envelope.object_id);
+                    last = sequence;
+                    continue;
+                }
+                if bytes + len > max_bytes {
+                    more = true;
+                    break 'scan;
+                }
+                last = sequence;
+                bytes += len;
+                objects.push(Publication { topic: entry.topic, envelope });
+            }
+            match keys.last() {
+                Some(key) if keys.len() == SCAN_BATCH => scanned = key.clone(),
+                _ => break,
+            }
+        }
+        Ok(FeedPage { objects, cursor: format!("{last:016x}"), more })
+    }
+
+    /// Answer `packet` if it is a [TransferPacket::SyncRequest] from
+    /// `follower`, the hostname of the peer, returning whether it was.
+    /// `capabilities` and `max_frame_length` are the ones negotiated with the
+    /// peer, as for [Feed::page].
+    ///
+    /// The follower only gets the types it follows in `follows`, all of them
+    /// if it asks for none, and is refused if it asks for any other. Peers
+    /// without a verified hostname are always refused.
+    pub async fn handle(
+        self: &Arc<Self>,
+        follower: Option<&str>,
+        follows: &Follows,
+        packet: &TransferPacket,
+        sender: &ProtocolSender<TransferPacket>,
+        capabilities: &PeerCapabilities,
+        max_frame_length: usize,
+    ) -> io::Result<bool> {
+        let TransferPacket::SyncRequest { request_id, cursor, data_types, limit } = packet else {
+            return Ok(false);
+        };
+        let page = match followed_types(follower, follows, data_types) {
+            Ok(data_types) => {
+                let (cursor, capabilities, limit) = (cursor.clone(), capabilities.clone(), *limit);
+                blocking(self, move |feed| feed.page(cursor.as_deref(), &data_types, &capabilities, limit, max_frame_length)).await
+            }
+            Err(e) => Err(e),
+        };
+        let reply = match page {
+            Ok(page) => TransferPacket::SyncPage {
+                request_id: *request_id,
+                objects: page.objects.into_iter().map(|object| (object.topic, object.envelope)).collect(),
+                cursor: page.cursor,
+                more: page.more,
+            },
+            Err(e) => {
+                debug!("Failed to answer sync request: {e}");
+                TransferPacket::SyncFailed { request_id: *request_id, err: e.to_string() }
+            }
+        };
+        sender.send(reply).await?;
+        Ok(true)
+    }
+}
+
+/// The types `follower` may sync out of the ones it asked for, all the ones
+/// it follows if it asked for none. Fails with
+/// [io::ErrorKind::PermissionDenied] if it asked for one it doesn't follow.
+fn followed_types(follower: Option<&str>, follows: &Follows, data_types: &[Uuid]) -> io::Result<Vec<Uuid>> {
+    let Some(follower) = follower else {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Only nodes with a verified hostname can sync"));
+    };
+    if let Some(data_type) = data_types.iter().find(|data_type| !follows.is_following(follower, data_type)) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{follower} doesn't follow {data_type}")));
+    }
+    match data_types.is_empty() {
+        true => match follows.followed(follower) {
+            followed if followed.is_empty() => {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{follower} doesn't follow any data types")))
+            }
+            followed => Ok(followed),
+        },
+        false => Ok(data_types.to_vec()),
+    }
+}
+
+/// The position and type of the object under a feed key
+fn parse_key(key: &str) -> Option<(u64, Uuid)> {
+    let (sequence, data_type) = key.strip_prefix(KEY_PREFIX)?.split_once('/')?;
+    Some((sequence.parse().ok()?, data_type.parse().ok()?))
+}
+
+fn parse_cursor(cursor: &str) -> io::Result<u64> {
+    match cursor.len() {
+        16 => u64::from_str_radix(cursor, 16).ok(),
+        _ => None,
+    }.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid cursor {cursor}")))
+}
+
+/// The last cursor this node got from each peer it synced with, kept in the
+/// node's [Storage]. Pages only hold the types asked for but their cursors
+/// move past the objects of every type, so a cursor is kept for each set of
+/// types synced, in any order. An empty set stands for every type.
+pub struct Cursors {
+    storage: Arc<dyn Storage>,
+}
+
+impl Cursors {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Cursors { storage }
+    }
+
+    pub fn get(&self, peer: &str, data_types: &[Uuid]) -> io::Result<Option<String>> {
+        self.storage.get(&cursor_key(peer, data_types))?
+            .map(|cursor| String::from_utf8(cursor).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+            .transpose()
+    }
+
+    pub fn set(&self, peer: &str, data_types: &[Uuid], cursor: &str) -> io::Result<()> {
+        self.storage.put(&cursor_key(peer, data_types), cursor.as_bytes())
+    }
+
+    /// Forget every cursor for `peer`, so the next sync starts from its first
+    /// object whichever types it is for.
+    pub fn remove(&self, peer: &str) -> io::Result<()> {
+        for key in self.storage.keys(&format!("{CURSOR_PREFIX}{peer}/"))? {
+            self.storage.delete(&key)?;
+        }
+        Ok(())
+    }
+}
+
+fn cursor_key(peer: &str, data_types: &[Uuid]) -> String {
+    let mut data_types = data_types.to_vec();
+    data_types.sort();
+    data_types.dedup();
+    let data_types: Vec<_> = data_types.iter().map(Uuid::to_string).collect();
+    format!("{CURSOR_PREFIX}{peer}/{}", data_types.join(","))
+}
+
+/// Ask the peer for the page after `cursor` and wait for it, see
+/// [request_reply]. Fails with [io::ErrorKind::InvalidInput] if the peer
+/// doesn't accept the cursor.
+pub(crate) async fn request_page(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    cursor: Option<String>,
+    data_types: Vec<Uuid>,
+    limit: u16,
+) -> io::Result<FeedPage> {
+    let request_id = Uuid::new_v4();
+    let request = TransferPacket::SyncRequest { request_id, cursor, data_types, limit };
+    request_reply(sender, receiver, request, "a sync", |packet| match packet {
+        TransferPacket::SyncPage { request_id: id, objects, cursor, more } if id == request_id => {
+            let objects = objects.into_iter().map(|(topic, envelope)| Publication { topic, envelope }).collect();
+            Some(Ok(FeedPage { objects, cursor, more }))
+        }
+        TransferPacket::SyncFailed { request_id: id, err } if id == request_id => {
+            Some(Err(io::Error::new(io::ErrorKind::InvalidInput, err)))
+        }
+        _ => None,
+    }).await
+}
+
+/// Catching up on what a peer published since the last sync with it, over a
+/// connection of its own.
+pub struct CatchUp {
+    peer: String,
+    data_types: Vec<Uuid>,
+    limit: u16,
+    cursors: Arc<Cursors>,
+    sender: ProtocolSender<TransferPacket>,
+    receiver: ProtocolReceiver<TransferPacket>,
+    verifier: Arc<Verifier>,
+    /// What is left of the current page
+    objects: VecDeque<Publication>,
+    /// Where the current page ends
+    cursor: Option<String>,
+    /// Whether `cursor` still has to be saved, which happens once the whole
+    /// page has been handed out
+    unsaved: bool,
+    more: bool,
+}
+
+impl CatchUp {
+    /// Catch up on `data_types` from `peer` over an established connection,
+    /// starting after the cursor kept for it in `cursors`.
+    pub(crate) async fn open(
+        peer: String,
+        data_types: Vec<Uuid>,
+        limit: u16,
+        cursors: Arc<Cursors>,
+        sender: ProtocolSender<TransferPacket>,
+        receiver: ProtocolReceiver<TransferPacket>,
+        verifier: Arc<Verifier>,
+    ) -> io::Result<Self> {
+        let cursor = {
+            let (peer, data_types) = (peer.clone(), data_types.clone());
+            blocking(&cursors, move |cursors| cursors.get(&peer, &data_types)).await?
+        };
+        Ok(CatchUp {
+            peer,
+            data_types,
+            limit,
+            cursors,
+            sender,
+            receiver,
+            verifier,
+            objects: VecDeque::new(),
+            cursor,
+            unsaved: false,
+            more: true,
+        })
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// The next object the peer published, asking it for more as needed.
+    /// Returns `None` once caught up. Objects whose signature doesn't match
+    /// their origin's key are dropped, see [OSProtocolNode::verify_data].
+    ///
+    /// The cursor is saved each time a page has been handed out in full, so
+    /// a catch-up that is cut short starts the next one at the beginning of
+    /// the page it stopped in. Objects may be seen twice because of that, see
+    /// [Inbox].
+    ///
+    /// [OSProtocolNode::verify_data]: crate::OSProtocolNode::verify_data
+    /// [Inbox]: crate::inbox::Inbox
+    pub async fn next(&mut self) -> io::Result<Option<Publication>> {
+        loop {
+            if let Some(object) = self.objects.front() {
+                match self.verifier.verify(&object.envelope).await {
+                    Ok(()) => return Ok(self.objects.pop_front()),
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        warn!("Dropping {}: {e}", object.envelope.object_id);
+                        self.objects.pop_front();
+                    }
+                    Err(e) => return Err(e),
+                }
+                continue;
+            }
+            if self.unsaved {
+                if let Some(cursor) = self.cursor.clone() {
+                    let (peer, data_types) = (self.peer.clone(), self.data_types.clone());
+                    blocking(&self.cursors, move |cursors| cursors.set(&peer, &data_types, &cursor)).await?;
+                }
+                self.unsaved = false;
+            }
+            if !self.more {
+                return Ok(None);
+            }
+            let page = request_page(&self.sender, &mut self.receiver, self.cursor.clone(), self.data_types.clone(), self.limit).await?;
+            self.objects = page.objects.into();
+            self.cursor = Some(page.cursor);
+            self.unsaved = true;
+            self.more = page.more;
+        }
+    }
+
+    /// Close the connection, whether or not everything was handed out.
+    pub async fn close(self) -> io::Result<()> {
+        close_quietly(&self.sender, self.receiver).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use std::time::Duration;
+
+    use bytes::BytesMut;
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_data::{DataCapability, DataCodec, DataUsage, PeerCapabilities, RawData, SignedData};
+    use osp_protocol::packet::{SerializePacket, PACKET_MAX_LENGTH};
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::feed::{request_page, CatchUp, Cursors, Feed, FeedRetention, StoredEntry};
+    use crate::follow::Follows;
+    use crate::storage::{encode, MemoryStorage, Storage};
+    use crate::testing;
+
+    fn envelopes(data_type: Uuid, object_id: &str) -> Vec<SignedData> {
+        [DataCodec::Bincode, DataCodec::Json].into_iter()
+            .map(|codec| testing::signed(object_id, RawData::new(data_type, 1, codec, vec![1])))
+            .collect()
+    }
+
+    fn capabilities(data_types: &[Uuid], codecs: Vec<DataCodec>) -> PeerCapabilities {
+        let capabilities: Vec<_> = data_types.iter()
+            .map(|data_type| DataCapability { data_type: *data_type, min_version: 1, max_version: 1, usage: DataUsage::BOTH, codecs: codecs.clone() })
+            .collect();
+        PeerCapabilities::negotiate(&capabilities, &capabilities)
+    }
+
+    fn object_ids(objects: &[crate::subscription::Publication]) -> Vec<&str> {
+        objects.iter().map(|object| object.envelope.object_id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_pages() -> io::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let feed = Arc::new(Feed::open(storage.clone())?);
+        let (posts, likes, unknown) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        for i in 1..=5 {
+            feed.append(Some("rust".to_string()), envelopes(posts, &format!("post/{i}"))).await?;
+            feed.append(None, envelopes(likes, &format!("like/{i}"))).await?;
+        }
+        feed.append(None, envelopes(unknown, "other/1")).await?;
+        let capabilities = capabilities(&[posts, likes], vec![DataCodec::Json]);
+
+        let first = feed.page(None, &[posts], &capabilities, 2, PACKET_MAX_LENGTH)?;
+        assert_eq!(object_ids(&first.objects), vec!["post/1", "post/2"]);
+        assert_eq!(first.objects[0].topic.as_deref(), Some("rust"));
+        assert_eq!(first.objects[0].envelope.data.codec, DataCodec::Json);
+        assert!(first.more);
+        let second = feed.page(Some(&first.cursor), &[posts], &capabilities, 100, PACKET_MAX_LENGTH)?;
+        assert_eq!(object_ids(&second.objects), vec!["post/3", "post/4", "post/5"]);
+        // Types the peer can't receive are passed over
+        assert!(!second.more);
+        assert!(feed.page(Some(&second.cursor), &[], &capabilities, 100, PACKET_MAX_LENGTH)?.objects.is_empty());
+
+        // Objects of other types were passed over as well
+        let everything = feed.page(Some(&first.cursor), &[], &capabilities, 3, PACKET_MAX_LENGTH)?;
+        assert_eq!(object_ids(&everything.objects), vec!["post/3", "like/3", "post/4"]);
+
+        assert_eq!(feed.page(Some("post/1"), &[], &capabilities, 1, PACKET_MAX_LENGTH).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // Reopening continues after the objects already there
+        let reopened = Arc::new(Feed::open(storage)?);
+        reopened.append(None, envelopes(posts, "post/6")).await?;
+        assert_eq!(object_ids(&reopened.page(Some(&second.cursor), &[posts], &capabilities, 100, PACKET_MAX_LENGTH)?.objects), vec!["post/6"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_undecodable_objects_quarantined() -> io::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let feed = Arc::new(Feed::open(storage.clone())?);
+        let posts = Uuid::new_v4();
+        for i in 1..=3 {
+            feed.append(None, envelopes(posts, &format!("post/{i}"))).await?;
+        }
+        let keys = storage.keys("feed/")?;
+        storage.put(&keys[1], b"\xff")?;
+        storage.put(&keys[2], &encode(StoredEntry { topic: None, envelopes: vec![] })?)?;
+
+        let capabilities = capabilities(&[posts], vec![DataCodec::Bincode]);
+        let page = feed.page(None, &[], &capabilities, 100, PACKET_MAX_LENGTH)?;
+        assert_eq!(object_ids(&page.objects), vec!["post/1"]);
+        assert_eq!(storage.keys("quarantine/")?, vec![format!("quarantine/{}", keys[1]), format!("quarantine/{}", keys[2])]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pages_fit_in_a_frame() -> io::Result<()> {
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?);
+        let data_type = Uuid::new_v4();
+        let envelope = |object_id: &str, len: usize| {
+            SignedData::unsigned("a.example".to_string(), object_id.to_string(), RawData::new(data_type, 1, DataCodec::Bincode, vec![1; len]))
+        };
+        for i in 1..=4 {
+            feed.append(Some("rust".to_string()), vec![envelope(&format!("post/{i}"), 1000)]).await?;
+        }
+        feed.append(None, vec![envelope("huge", 10_000)]).await?;
+        feed.append(None, vec![envelope("post/5", 1000)]).await?;
+        let capabilities = capabilities(&[data_type], vec![DataCodec::Bincode]);
+
+        // Pages are cut short to fit, and objects that never fit are passed over
+        let max_frame_length = 2500;
+        let mut cursor = None;
+        let mut pages = Vec::new();
+        loop {
+            let page = feed.page(cursor.as_deref(), &[], &capabilities, 100, max_frame_length)?;
+            let packet = TransferPacket::SyncPage {
+                request_id: Uuid::new_v4(),
+                objects: page.objects.iter().map(|object| (object.topic.clone(), object.envelope.clone())).collect(),
+                cursor: page.cursor.clone(),
+                more: page.more,
+            };
+            assert!(packet.serialize(&mut BytesMut::new())? <= max_frame_length);
+            pages.push(object_ids(&page.objects).join(","));
+            cursor = Some(page.cursor);
+            if !page.more {
+                break;
+            }
+        }
+        assert_eq!(pages, vec!["post/1,post/2", "post/3,post/4", "post/5"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retention() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let capabilities = capabilities(&[data_type], vec![DataCodec::Bincode]);
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?.with_retention(FeedRetention { max_objects: Some(3), max_age: None }));
+        for i in 1..=5 {
+            feed.append(None, envelopes(data_type, &format!("post/{i}"))).await?;
+        }
+        let page = feed.page(None, &[], &capabilities, 100, PACKET_MAX_LENGTH)?;
+        assert_eq!(object_ids(&page.objects), vec!["post/3", "post/4", "post/5"]);
+
+        // Objects created too long ago go as well, however many there are
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?
+            .with_retention(FeedRetention { max_objects: None, max_age: Some(Duration::from_secs(60)) }));
+        for object_id in ["post/1", "post/2"] {
+            let mut old = envelopes(data_type, object_id);
+            for envelope in &mut old {
+                envelope.created_at -= 60 * 60 * 1000;
+            }
+            feed.append(None, old).await?;
+        }
+        feed.append(None, envelopes(data_type, "post/3")).await?;
+        let page = feed.page(None, &[], &capabilities, 100, PACKET_MAX_LENGTH)?;
+        assert_eq!(object_ids(&page.objects), vec!["post/3"]);
+
+        // Objects without envelopes or with malformed keys don't hold up the
+        // ones after them
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        storage.put("feed/00000000000000000000", b"")?;
+        storage.put(&format!("feed/00000000000000000001/{data_type}"), &encode(StoredEntry { topic: None, envelopes: vec![] })?)?;
+        let feed = Arc::new(Feed::open(storage.clone())?
+            .with_retention(FeedRetention { max_objects: None, max_age: Some(Duration::from_secs(60)) }));
+        let mut old = envelopes(data_type, "post/1");
+        old.iter_mut().for_each(|envelope| envelope.created_at -= 60 * 60 * 1000);
+        feed.append(None, old).await?;
+        feed.append(None, envelopes(data_type, "post/2")).await?;
+        assert_eq!(object_ids(&feed.page(None, &[], &capabilities, 100, PACKET_MAX_LENGTH)?.objects), vec!["post/2"]);
+        assert_eq!(storage.keys("feed/")?.len(), 1);
+        assert_eq!(storage.keys("quarantine/")?.len(), 1);
+        Ok(())
+    }
+
+    async fn catch_up(feed: &Arc<Feed>, cursors: &Arc<Cursors>, data_type: Uuid) -> io::Result<CatchUp> {
+        catch_up_on(feed, cursors, &[data_type], vec![data_type]).await
+    }
+
+    async fn catch_up_on(feed: &Arc<Feed>, cursors: &Arc<Cursors>, receivable: &[Uuid], data_types: Vec<Uuid>) -> io::Result<CatchUp> {
+        let follows = Follows::new(None);
+        follows.add("a.example".to_string(), receivable).await?;
+        let (sender, receiver) = serve(feed, Arc::new(follows), Some("a.example"), receivable);
+        CatchUp::open("b.example".to_string(), data_types, 2, cursors.clone(), sender, receiver, testing::verifier()).await
+    }
+
+    fn serve(feed: &Arc<Feed>, follows: Arc<Follows>, follower: Option<&'static str>, receivable: &[Uuid]) -> testing::Connection {
+        let feed = feed.clone();
+        let capabilities = Arc::new(capabilities(receivable, vec![DataCodec::Bincode]));
+        testing::serve(move |packet, sender| {
+            let (feed, follows, capabilities) = (feed.clone(), follows.clone(), capabilities.clone());
+            async move { feed.handle(follower, &follows, &packet, &sender, &capabilities, PACKET_MAX_LENGTH).await.map(drop) }
+        })
+    }
+
+    async fn drain(catch_up: &mut CatchUp) -> io::Result<Vec<String>> {
+        let mut object_ids = Vec::new();
+        while let Some(object) = catch_up.next().await? {
+            object_ids.push(object.envelope.object_id);
+        }
+        Ok(object_ids)
+    }
+
+    #[tokio::test]
+    async fn test_catch_up_resumes() -> io::Result<()> {
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?);
+        let cursors = Arc::new(Cursors::new(Arc::new(MemoryStorage::new())));
+        let data_type = Uuid::new_v4();
+        for i in 1..=5 {
+            feed.append(None, envelopes(data_type, &format!("post/{i}"))).await?;
+        }
+
+        // Stopping in the middle of the second page resumes at its start
+        let mut interrupted = catch_up(&feed, &cursors, data_type).await?;
+        for i in 1..=3 {
+            assert_eq!(interrupted.next().await?.unwrap().envelope.object_id, format!("post/{i}"));
+        }
+        interrupted.close().await?;
+        let mut resumed = catch_up(&feed, &cursors, data_type).await?;
+        assert_eq!(drain(&mut resumed).await?, vec!["post/3", "post/4", "post/5"]);
+        resumed.close().await?;
+
+        let mut caught_up = catch_up(&feed, &cursors, data_type).await?;
+        assert!(drain(&mut caught_up).await?.is_empty());
+        caught_up.close().await?;
+
+        feed.append(None, envelopes(data_type, "post/6")).await?;
+        let mut later = catch_up(&feed, &cursors, data_type).await?;
+        assert_eq!(drain(&mut later).await?, vec!["post/6"]);
+        later.close().await?;
+
+        cursors.remove("b.example")?;
+        let mut again = catch_up(&feed, &cursors, data_type).await?;
+        assert_eq!(drain(&mut again).await?.len(), 6);
+        again.close().await
+    }
+    #[tokio::test]
+    async fn test_cursor_per_data_types() -> io::Result<()> {
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?);
+        let cursors = Arc::new(Cursors::new(Arc::new(MemoryStorage::new())));
+        let (posts, comments) = (Uuid::new_v4(), Uuid::new_v4());
+        for i in 1..=3 {
+            feed.append(None, envelopes(posts, &format!("post/{i}"))).await?;
+            feed.append(None, envelopes(comments, &format!("comment/{i}"))).await?;
+        }
+        let types = [posts, comments];
+
+        let mut first = catch_up_on(&feed, &cursors, &types, vec![posts]).await?;
+        assert_eq!(drain(&mut first).await?, vec!["post/1", "post/2", "post/3"]);
+        first.close().await?;
+
+        // Syncing posts didn't move past the comments in between them
+        let mut second = catch_up_on(&feed, &cursors, &types, vec![comments]).await?;
+        assert_eq!(drain(&mut second).await?, vec!["comment/1", "comment/2", "comment/3"]);
+        second.close().await?;
+
+        // Nor past anything for the types together, in whichever order
+        feed.append(None, envelopes(posts, "post/4")).await?;
+        let mut both = catch_up_on(&feed, &cursors, &types, vec![comments, posts]).await?;
+        assert_eq!(drain(&mut both).await?.len(), 7);
+        both.close().await?;
+        let mut again = catch_up_on(&feed, &cursors, &types, vec![posts, comments]).await?;
+        assert!(drain(&mut again).await?.is_empty());
+        again.close().await?;
+
+        let mut posts_only = catch_up_on(&feed, &cursors, &types, vec![posts]).await?;
+        assert_eq!(drain(&mut posts_only).await?, vec!["post/4"]);
+        posts_only.close().await
+    }
+    #[tokio::test]
+    async fn test_only_followers_sync() -> io::Result<()> {
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?);
+        let (posts, secrets) = (Uuid::new_v4(), Uuid::new_v4());
+        feed.append(None, envelopes(posts, "post/1")).await?;
+        feed.append(None, envelopes(secrets, "secret/1")).await?;
+        let follows = Arc::new(Follows::new(None));
+        follows.add("a.example".to_string(), &[posts]).await?;
+        let types = [posts, secrets];
+
+        let (sender, mut receiver) = serve(&feed, follows.clone(), Some("a.example"), &types);
+        // Asking for nothing in particular only gets the followed types
+        let page = request_page(&sender, &mut receiver, None, vec![], 100).await?;
+        assert_eq!(object_ids(&page.objects), vec!["post/1"]);
+        for data_types in [vec![secrets], vec![posts, secrets]] {
+            let err = request_page(&sender, &mut receiver, None, data_types, 100).await.err().unwrap();
+            assert!(err.to_string().contains("doesn't follow"));
+        }
+        receiver.shutdown().await?;
+
+        for follower in [Some("c.example"), None] {
+            let (sender, mut receiver) = serve(&feed, follows.clone(), follower, &types);
+            assert!(request_page(&sender, &mut receiver, None, vec![], 100).await.is_err());
+            assert!(request_page(&sender, &mut receiver, None, vec![posts], 100).await.is_err());
+            receiver.shutdown().await?;
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_catch_up_drops_forged_objects() -> io::Result<()> {
+        let feed = Arc::new(Feed::open(Arc::new(MemoryStorage::new()))?);
+        let cursors = Arc::new(Cursors::new(Arc::new(MemoryStorage::new())));
+        let data_type = Uuid::new_v4();
+        feed.append(None, envelopes(data_type, "post/1")).await?;
+        let mut forged = envelopes(data_type, "post/2");
+        forged.iter_mut().for_each(|envelope| envelope.data.bytes = vec![2].into());
+        feed.append(None, forged).await?;
+        feed.append(None, envelopes(data_type, "post/3")).await?;
+
+        let mut catch_up = catch_up(&feed, &cursors, data_type).await?;
+        assert_eq!(drain(&mut catch_up).await?, vec!["post/1", "post/3"]);
+        catch_up.close().await
+    }
+}
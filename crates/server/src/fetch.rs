@@ -0,0 +1,154 @@
+//! Fetching the object a resource [OSPUrl] points at over a connection, and
+//! serving the objects of this node to peers that fetch them.
+//!
+//! [OSPUrl]: osp_protocol::OSPUrl
+
+use std::sync::Arc;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::{ProtocolReceiver, ProtocolSender, ResourcePath};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::request::request_reply;
+
+/// Finds the object a [TransferPacket::Fetch] asks for, encoded as
+/// [OSProtocolNode::fetch] decodes it, returning why it can't be served if
+/// it isn't found.
+///
+/// [OSProtocolNode::fetch]: crate::OSProtocolNode::fetch
+pub(crate) type ResourceProvider = Arc<dyn Fn(&ResourcePath) -> Result<Vec<u8>, String> + Send + Sync>;
+
+/// The answer to a [TransferPacket::Fetch] for `resource`, the object from
+/// `provider` in chunks that fit in frames of `max_frame_length` bytes.
+/// Without a provider every fetch fails. The provider may well read from
+/// disk, so it is run on the blocking thread pool.
+pub(crate) async fn fetch_response(
+    provider: Option<ResourceProvider>,
+    transfer_id: Uuid,
+    resource: ResourcePath,
+    max_frame_length: usize,
+) -> Vec<TransferPacket> {
+    let found = match provider {
+        Some(provider) => tokio::task::spawn_blocking(move || provider(&resource)).await
+            .unwrap_or_else(|e| Err(format!("Failed to find the object: {e}"))),
+        None => Err("Objects aren't served".to_string()),
+    };
+    match found {
+        Ok(object) => TransferPacket::chunks_of(transfer_id, &object, TransferPacket::chunk_size_for(max_frame_length)),
+        Err(err) => vec![TransferPacket::FetchFailed { transfer_id, err }],
+    }
+}
+
+/// Ask the peer for `resource` and collect the chunks of its answer, see
+/// [request_reply]. Fails with [io::ErrorKind::InvalidData] once the object
+/// grows past `max_length` bytes.
+pub(crate) async fn fetch_bytes(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    resource: &ResourcePath,
+    max_length: usize,
+) -> io::Result<Vec<u8>> {
+    let transfer_id = Uuid::new_v4();
+    let request = TransferPacket::Fetch {
+        transfer_id,
+        data_type: resource.data_type,
+        object_id: resource.object_id.clone(),
+        version: resource.version,
+    };
+
+    let mut data = Vec::new();
+    request_reply(sender, receiver, request, "a fetch", |packet| match packet {
+        TransferPacket::Chunk { transfer_id: id, last, data: chunk } if id == transfer_id => {
+            if data.len() + chunk.len() > max_length {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Object is longer than {max_length} bytes"),
+                )));
+            }
+            data.extend_from_slice(&chunk);
+            last.then(|| Ok(std::mem::take(&mut data)))
+        }
+        TransferPacket::FetchFailed { transfer_id: id, err } if id == transfer_id => {
+            Some(Err(io::Error::new(io::ErrorKind::NotFound, err)))
+        }
+        _ => None,
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::{ProtocolSender, ResourcePath};
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::fetch::{fetch_bytes, fetch_response, ResourceProvider};
+    use crate::testing;
+
+    /// Serve one object, failing fetches for anything else.
+    fn provider(object: Vec<u8>) -> ResourceProvider {
+        Arc::new(move |resource| match (resource.object_id.as_str(), resource.version) {
+            ("post", Some(2)) => Ok(object.clone()),
+            (object_id, _) => Err(format!("No object {object_id}")),
+        })
+    }
+
+    async fn answer(packet: TransferPacket, sender: ProtocolSender<TransferPacket>, provider: ResourceProvider) -> io::Result<()> {
+        if let TransferPacket::Fetch { transfer_id, data_type, object_id, version } = packet {
+            sender.send(TransferPacket::Ping { nonce: 9 }).await?;
+            let resource = ResourcePath { data_type, object_id, version };
+            for packet in fetch_response(Some(provider), transfer_id, resource, 1000).await {
+                sender.send(packet).await?;
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_bytes() -> io::Result<()> {
+        let object: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let served = provider(object.clone());
+        let (sender, mut receiver) = testing::serve(move |packet, sender| answer(packet, sender, served.clone()));
+        let mut resource = ResourcePath {
+            data_type: Uuid::new_v4(),
+            object_id: "post".to_string(),
+            version: Some(2),
+        };
+        assert_eq!(fetch_bytes(&sender, &mut receiver, &resource, 5000).await?, object);
+
+        // Objects longer than we take are refused
+        let err = fetch_bytes(&sender, &mut receiver, &resource, 4999).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        resource.object_id = "missing".to_string();
+        let err = fetch_bytes(&sender, &mut receiver, &resource, 5000).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        receiver.shutdown().await
+    }
+
+    #[tokio::test]
+    async fn test_fetch_response() {
+        let resource = ResourcePath { data_type: Uuid::new_v4(), object_id: "post".to_string(), version: Some(2) };
+        let transfer_id = Uuid::new_v4();
+        let chunks = fetch_response(Some(provider(vec![1; 5000])), transfer_id, resource.clone(), 1000).await;
+        assert_eq!(chunks.len(), 5000usize.div_ceil(TransferPacket::chunk_size_for(1000)));
+        assert!(chunks.iter().all(|chunk| matches!(
+            chunk,
+            TransferPacket::Chunk { data, .. } if data.len() <= TransferPacket::chunk_size_for(1000)
+        )));
+
+        for provider in [None, Some(provider(vec![]))] {
+            let missing = ResourcePath { object_id: "missing".to_string(), ..resource.clone() };
+            assert!(matches!(
+                &fetch_response(provider, transfer_id, missing, 1000).await[..],
+                [TransferPacket::FetchFailed { transfer_id: id, .. }] if *id == transfer_id
+            ));
+        }
+    }
+}
@@ -0,0 +1,291 @@
+//! # Follows
+//!
+//! A follow is a standing subscription from one node to some of another
+//! node's data types. Unlike a [Subscription] it outlives the connection it
+//! was asked for on: the followed node's application approves each request,
+//! approved follows are kept in a file, and new objects are pushed to
+//! followers over outbound connections whether they are connected or not.
+//!
+//! [Subscription]: crate::subscription::Subscription
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_protocol::{ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::request::request_reply;
+
+/// A node asking to follow some of our data types
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FollowRequest {
+    /// The hostname the follower proved it owns during the handshake
+    pub follower: String,
+    pub data_types: Vec<Uuid>,
+}
+
+/// Decides whether a [FollowRequest] is approved, returning the reason to
+/// give the follower if it isn't.
+pub type FollowApproval = Arc<dyn Fn(&FollowRequest) -> Result<(), String> + Send + Sync>;
+
+/// The nodes following this one and which data types they follow.
+pub struct Follows {
+    followers: Mutex<HashMap<String, HashSet<Uuid>>>,
+    /// Where the follows are kept, if anywhere
+    path: Option<PathBuf>,
+    /// Held while the file is written, so writes land in the order the
+    /// changes were made
+    saving: tokio::sync::Mutex<()>,
+    approval: Option<FollowApproval>,
+}
+
+impl Follows {
+    /// Follows kept in memory only. Without `approval` every request is
+    /// rejected.
+    pub fn new(approval: Option<FollowApproval>) -> Self {
+        Follows { followers: Mutex::new(HashMap::new()), path: None, saving: tokio::sync::Mutex::new(()), approval }
+    }
+
+    /// Follows kept in the file at `path`, starting with the ones already in
+    /// it. The file is created once the first follow is approved.
+    pub fn open(path: PathBuf, approval: Option<FollowApproval>) -> io::Result<Self> {
+        let followers = match fs::read(&path) {
+            Ok(contents) => decode_followers(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Follows { followers: Mutex::new(followers), path: Some(path), saving: tokio::sync::Mutex::new(()), approval })
+    }
+
+    /// Every node following `data_type`
+    pub fn followers(&self, data_type: &Uuid) -> Vec<String> {
+        self.followers.lock().unwrap().iter()
+            .filter(|(_, data_types)| data_types.contains(data_type))
+            .map(|(follower, _)| follower.clone())
+            .collect()
+    }
+
+    /// Every data type `follower` follows
+    pub fn followed(&self, follower: &str) -> Vec<Uuid> {
+        self.followers.lock().unwrap().get(follower)
+            .map(|data_types| data_types.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_following(&self, follower: &str, data_type: &Uuid) -> bool {
+        self.followers.lock().unwrap().get(follower).is_some_and(|data_types| data_types.contains(data_type))
+    }
+
+    /// Have `follower` follow `data_types` without asking for approval.
+    pub async fn add(&self, follower: String, data_types: &[Uuid]) -> io::Result<()> {
+        self.update(|followers| followers.entry(follower).or_default().extend(data_types)).await
+    }
+
+    /// Stop pushing `data_types` to `follower`.
+    pub async fn remove(&self, follower: &str, data_types: &[Uuid]) -> io::Result<()> {
+        self.update(|followers| {
+            if let Some(followed) = followers.get_mut(follower) {
+                followed.retain(|data_type| !data_types.contains(data_type));
+                if followed.is_empty() {
+                    followers.remove(follower);
+                }
+            }
+        }).await
+    }
+
+    /// Apply `packet` if it is a [TransferPacket::Follow] or
+    /// [TransferPacket::Unfollow] from `follower`, the hostname of the peer,
+    /// returning whether it was. Follow requests are answered through
+    /// `sender`. Peers without a verified hostname can't follow.
+    pub async fn handle(&self, follower: Option<&str>, packet: &TransferPacket, sender: &ProtocolSender<TransferPacket>) -> io::Result<bool> {
+        match packet {
+            TransferPacket::Follow { request_id, data_types } => {
+                let err = match follower {
+                    Some(follower) => self.request(follower, data_types).await?.err(),
+                    None => Some("Only nodes with a verified hostname can follow".to_string()),
+                };
+                sender.send(TransferPacket::FollowResponse { request_id: *request_id, err }).await?;
+                Ok(true)
+            }
+            TransferPacket::Unfollow { data_types } => {
+                if let Some(follower) = follower {
+                    info!("{follower} unfollowed {} data types", data_types.len());
+                    self.remove(follower, data_types).await?;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Ask the approval callback about a follow request, recording the
+    /// follow if it is approved.
+    async fn request(&self, follower: &str, data_types: &[Uuid]) -> io::Result<Result<(), String>> {
+        if data_types.is_empty() {
+            return Ok(Err("No data types to follow".to_string()));
+        }
+        let request = FollowRequest { follower: follower.to_string(), data_types: data_types.to_vec() };
+        let decision = match &self.approval {
+            Some(approval) => approval(&request),
+            None => Err("Follow requests aren't accepted".to_string()),
+        };
+        match &decision {
+            Ok(()) => {
+                info!("Approved {follower} following {} data types", data_types.len());
+                self.add(request.follower, data_types).await?;
+            }
+            Err(reason) => info!("Rejected {follower}'s follow request: {reason}"),
+        }
+        Ok(decision)
+    }
+
+    /// Apply `change` and write the result to the file, without holding the
+    /// lock on the follows or blocking the runtime while writing. The change
+    /// only takes effect once it is written, so a failed write leaves the
+    /// follows as they were.
+    async fn update(&self, change: impl FnOnce(&mut HashMap<String, HashSet<Uuid>>)) -> io::Result<()> {
+        let _saving = self.saving.lock().await;
+        let mut followers = self.followers.lock().unwrap().clone();
+        change(&mut followers);
+        if let Some(path) = self.path.clone() {
+            let contents = encode_followers(&followers)?;
+            tokio::task::spawn_blocking(move || write_file(&path, &contents)).await??;
+        }
+        *self.followers.lock().unwrap() = followers;
+        Ok(())
+    }
+}
+
+/// Replace the file at `path` with `contents`. It is written next to the file
+/// and renamed over it, so a crash never leaves it half written, and synced
+/// along with its directory before returning.
+fn write_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(temporary, path)?;
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+fn encode_followers(followers: &HashMap<String, HashSet<Uuid>>) -> io::Result<Vec<u8>> {
+    let entries: Vec<(String, Vec<u128>)> = followers.iter()
+        .map(|(follower, data_types)| (follower.clone(), data_types.iter().map(Uuid::as_u128).collect()))
+        .collect();
+    bincode::encode_to_vec(entries, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn decode_followers(contents: &[u8]) -> io::Result<HashMap<String, HashSet<Uuid>>> {
+    let (entries, _): (Vec<(String, Vec<u128>)>, _) = bincode::decode_from_slice(contents, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(entries.into_iter()
+        .map(|(follower, data_types)| (follower, data_types.into_iter().map(Uuid::from_u128).collect()))
+        .collect())
+}
+
+/// Ask the peer to let us follow `data_types` and wait for its answer, see
+/// [request_reply]. Fails with [io::ErrorKind::PermissionDenied] if the
+/// request is rejected.
+pub(crate) async fn request_follow(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    data_types: Vec<Uuid>,
+) -> io::Result<()> {
+    let request_id = Uuid::new_v4();
+    let request = TransferPacket::Follow { request_id, data_types };
+    request_reply(sender, receiver, request, "a follow request", |packet| match packet {
+        TransferPacket::FollowResponse { request_id: id, err } if id == request_id => Some(match err {
+            Some(err) => Err(io::Error::new(io::ErrorKind::PermissionDenied, err)),
+            None => Ok(()),
+        }),
+        _ => None,
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::follow::{request_follow, FollowApproval, Follows};
+    use crate::testing::{self, Connection};
+
+    fn serve(follows: Arc<Follows>, follower: Option<&'static str>) -> Connection {
+        testing::serve(move |packet, sender| {
+            let follows = follows.clone();
+            async move { follows.handle(follower, &packet, &sender).await.map(drop) }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_follow_approval() -> io::Result<()> {
+        let allowed = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        let approval: FollowApproval = Arc::new(move |request| match request.data_types.contains(&allowed) {
+            true => Ok(()),
+            false => Err("Not shared".to_string()),
+        });
+        let path = std::env::temp_dir().join(format!("osp-follows-{}", Uuid::new_v4()));
+        let follows = Arc::new(Follows::open(path.clone(), Some(approval.clone()))?);
+
+        let (sender, mut receiver) = serve(follows.clone(), Some("a.example"));
+        let err = request_follow(&sender, &mut receiver, vec![other]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(request_follow(&sender, &mut receiver, vec![]).await.is_err());
+        request_follow(&sender, &mut receiver, vec![allowed]).await?;
+        assert_eq!(follows.followers(&allowed), vec!["a.example".to_string()]);
+        assert!(follows.followers(&other).is_empty());
+
+        // Approved follows survive a restart
+        let reopened = Follows::open(path.clone(), Some(approval))?;
+        assert!(reopened.is_following("a.example", &allowed));
+
+        sender.send(TransferPacket::Unfollow { data_types: vec![allowed] }).await?;
+        sender.send(TransferPacket::Close { err: None }).await?;
+        receiver.shutdown().await?;
+        // The follow is gone from memory once the file is written
+        while follows.is_following("a.example", &allowed) {
+            tokio::task::yield_now().await;
+        }
+        assert!(!Follows::open(path.clone(), None)?.is_following("a.example", &allowed));
+        std::fs::remove_file(path)
+    }
+
+    #[tokio::test]
+    async fn test_unsaved_follows_dropped() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("osp-missing-{}", Uuid::new_v4())).join("follows");
+        let follows = Follows::open(path, None)?;
+        let data_type = Uuid::new_v4();
+        assert!(follows.add("a.example".to_string(), &[data_type]).await.is_err());
+        assert!(!follows.is_following("a.example", &data_type));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unverified_peers_cant_follow() -> io::Result<()> {
+        let follows = Arc::new(Follows::new(Some(Arc::new(|_| Ok(())))));
+        let (sender, mut receiver) = serve(follows.clone(), None);
+        let data_type = Uuid::new_v4();
+        assert!(request_follow(&sender, &mut receiver, vec![data_type]).await.is_err());
+        assert!(follows.followers(&data_type).is_empty());
+        receiver.shutdown().await
+    }
+}
@@ -0,0 +1,112 @@
+//! # Handler
+//!
+//! The connection handler most nodes need. Pass [handle] to
+//! [OSProtocolNode::listen] and every packet the SDK knows about is answered:
+//! subscriptions, follows, sync requests, schema requests, fetches, which
+//! reach [OSProtocolNode::set_resource_provider], pings, and objects pushed
+//! to the node, which reach [OSProtocolNode::set_publish_handler].
+//! Nodes that need more can write their own handler from the same parts of
+//! the `ConnectionState` it is given.
+//!
+//! [OSProtocolNode::listen]: crate::OSProtocolNode::listen
+//! [OSProtocolNode::set_resource_provider]: crate::OSProtocolNode::set_resource_provider
+//! [OSProtocolNode::set_publish_handler]: crate::OSProtocolNode::set_publish_handler
+
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+
+use tokio::io;
+use tokio_stream::StreamExt;
+
+use osp_protocol::{ProtocolSender, ResourcePath};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::connection::inbound::{InboundConnection, TransferState};
+use crate::node::ConnectionState;
+
+/// Answer the peer on `connection` until it closes.
+pub fn handle(
+    connection: InboundConnection<TransferState>,
+    state: &Arc<Mutex<ConnectionState>>,
+) -> impl Future<Output = Result<(), ()>> + Send + 'static {
+    let state = state.clone();
+    async move {
+        let capabilities = connection.peer_capabilities().clone();
+        let peer_hostname = connection.peer_hostname().map(str::to_string);
+        let max_frame_length = connection.peer_max_frame_length();
+        let (sender, mut receiver) = connection.split();
+        let (registry, subscriptions, follows, inbox, feed) = {
+            let state = state.lock().unwrap();
+            (state.registry().clone(), state.subscriptions().clone(), state.follows().clone(), state.inbox().clone(), state.feed().clone())
+        };
+        let peer = subscriptions.add_peer(sender.clone(), capabilities.clone());
+
+        while let Some(packet) = receiver.next().await {
+            let packet = match packet {
+                Ok(packet) => packet,
+                Err(e) => {
+                    error!("Connection failed: {e}");
+                    break;
+                }
+            };
+            if subscriptions.handle(peer, &packet) {
+                continue;
+            }
+            match follows.handle(peer_hostname.as_deref(), &packet, &sender).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to handle follow packet: {e}");
+                    continue;
+                }
+            }
+            match feed.handle(peer_hostname.as_deref(), &follows, &packet, &sender, &capabilities, max_frame_length).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(e) => {
+                    error!("Failed to answer sync request: {e}");
+                    break;
+                }
+            }
+            let reply = match packet {
+                TransferPacket::Ping { nonce } => TransferPacket::Pong { nonce },
+                TransferPacket::SchemaRequest { data_type } => state.lock().unwrap().schema_response(data_type),
+                TransferPacket::Fetch { transfer_id, data_type, object_id, version } => {
+                    let resource = ResourcePath { data_type, object_id, version };
+                    let response = state.lock().unwrap().fetch_response(transfer_id, resource, max_frame_length);
+                    if send_all(&sender, response.await).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                TransferPacket::Publish { .. } => {
+                    if let Err(e) = inbox.handle(packet, &sender, &registry).await {
+                        error!("Failed to answer pushed object: {e}");
+                    }
+                    continue;
+                }
+                TransferPacket::Close { .. } => break,
+                _ => {
+                    info!("Ignoring unsupported packet");
+                    continue;
+                }
+            };
+            if sender.send(reply).await.is_err() {
+                break;
+            }
+        }
+
+        subscriptions.remove_peer(peer);
+        Ok(())
+    }
+}
+
+/// Send `packets` in order, stopping at the first that can't be sent.
+async fn send_all(sender: &ProtocolSender<TransferPacket>, packets: Vec<TransferPacket>) -> io::Result<()> {
+    for packet in packets {
+        sender.send(packet).await?;
+    }
+    Ok(())
+}
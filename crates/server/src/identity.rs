@@ -0,0 +1,117 @@
+//! Helpers for proving and checking a node's identity with the RSA key it
+//! publishes in its `_osp` DNS TXT record.
+
+use log::{debug, info};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+
+use tokio::io;
+
+use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+
+use uuid::Uuid;
+
+const HOST_TRANSCRIPT_LABEL: &[u8] = b"osp handshake host v2";
+const GUEST_TRANSCRIPT_LABEL: &[u8] = b"osp handshake guest v2";
+
+/// Look up the public key a node publishes at `_osp.<hostname>`.
+pub(crate) async fn lookup_public_key(hostname: &str) -> io::Result<Rsa<Public>> {
+    info!("Looking up challenge record for {hostname}");
+    let resolver = TokioAsyncResolver::tokio(
+        ResolverConfig::default(),
+        ResolverOpts::default());
+    let txt_resp = resolver.txt_lookup(format!("_osp.{}", hostname)).await.map_err(|e| io::Error::other(
+        format!(
+            "Failed to resolve TXT record for {}. Is it located at _osp.{}?\n\nFurther Details: {}",
+            hostname, hostname, e
+        )
+    ))?;
+
+    if let Some(record) = txt_resp.iter().next() {
+        info!("Challenge record found");
+        debug!("Challenge record: {record}");
+        Ok(Rsa::public_key_from_pem(record.to_string().as_bytes())?)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to resolve TXT record for {}. Is it located at _osp.{}?", hostname, hostname)
+        ))
+    }
+}
+
+/// Sign `data` with a node's private key using RSA and SHA-256.
+pub(crate) fn sign(private_key: &Rsa<Private>, data: &[u8]) -> io::Result<Vec<u8>> {
+    let key = PKey::from_rsa(private_key.clone())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Check a signature made by [sign].
+pub(crate) fn verify(public_key: &Rsa<Public>, data: &[u8], signature: &[u8]) -> io::Result<bool> {
+    let key = PKey::from_rsa(public_key.clone())?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.update(data)?;
+    Ok(verifier.verify(signature)?)
+}
+
+/// What the host signs to bind its ephemeral key to this handshake.
+/// `channel_binding` is the TLS exporter value when the connection runs over
+/// TLS, and empty otherwise.
+pub(crate) fn host_transcript(nonce: &Uuid, channel_binding: &[u8], guest_hostname: &str, host_key: &[u8]) -> Vec<u8> {
+    let mut transcript = HOST_TRANSCRIPT_LABEL.to_vec();
+    transcript.extend_from_slice(nonce.as_bytes());
+    put_field(&mut transcript, channel_binding);
+    put_field(&mut transcript, guest_hostname.as_bytes());
+    put_field(&mut transcript, host_key);
+    transcript
+}
+
+/// What the guest signs to bind its ephemeral key to this handshake and to
+/// the host's half of the exchange.
+pub(crate) fn guest_transcript(nonce: &Uuid, channel_binding: &[u8], guest_hostname: &str, host_key: &[u8], guest_key: &[u8]) -> Vec<u8> {
+    let mut transcript = GUEST_TRANSCRIPT_LABEL.to_vec();
+    transcript.extend_from_slice(nonce.as_bytes());
+    put_field(&mut transcript, channel_binding);
+    put_field(&mut transcript, guest_hostname.as_bytes());
+    put_field(&mut transcript, host_key);
+    put_field(&mut transcript, guest_key);
+    transcript
+}
+
+/// Append a variable length transcript field behind a `u32` length, so bytes
+/// can't be moved from one field into its neighbour without changing what
+/// was signed.
+fn put_field(transcript: &mut Vec<u8>, field: &[u8]) {
+    transcript.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    transcript.extend_from_slice(field);
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use crate::identity::{guest_transcript, host_transcript};
+
+    #[test]
+    fn test_transcript_fields_are_delimited() {
+        let nonce = Uuid::new_v4();
+        // Shifting bytes between neighbouring fields changes the transcript
+        assert_ne!(
+            host_transcript(&nonce, &[], "a.example", b"key"),
+            host_transcript(&nonce, &[], "a.exampl", b"ekey"),
+        );
+        assert_ne!(
+            guest_transcript(&nonce, &[1; 32], "a.example", b"host", b"guest"),
+            guest_transcript(&nonce, &[1; 31], "\x01a.example", b"host", b"guest"),
+        );
+        assert_ne!(
+            guest_transcript(&nonce, &[], "a.example", b"host", b"guest"),
+            guest_transcript(&nonce, &[], "a.example", b"hostg", b"uest"),
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! # Inbox
+//!
+//! Objects pushed from an [Outbox] arrive at least once, so the receiving
+//! side keeps track of what it has already handled. An object counts as seen
+//! once an envelope with its origin and id has been acknowledged, unless the
+//! new envelope was created later, in which case it is an update. Handled
+//! objects are remembered for [INBOX_RETENTION].
+//!
+//! [Inbox::handle] does all of it for a [TransferPacket::Publish], checking
+//! the envelope's signature and handing new objects to the node's
+//! [PublishHandler] before acknowledging them.
+//!
+//! [Outbox]: crate::outbox::Outbox
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+
+use tokio::io;
+
+use osp_data::{DataRegistry, SignedData};
+use osp_protocol::ProtocolSender;
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::envelope::Verifier;
+use crate::outbox::now_millis;
+use crate::storage::{blocking, Storage};
+use crate::subscription::Publication;
+
+const KEY_PREFIX: &str = "inbox/";
+const RECEIVED_AT_PREFIX: &str = "inbox-at/";
+
+/// How many records are read from storage at a time while dropping old ones
+const EXPIRE_BATCH: usize = 128;
+
+/// How long an object is remembered once handled. Far longer than an outbox
+/// keeps retrying, see [MAX_ATTEMPTS], so repeats arrive well within it.
+///
+/// [MAX_ATTEMPTS]: crate::outbox::MAX_ATTEMPTS
+pub const INBOX_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Handles an object pushed to this node, returning the reason to reject it
+/// with if it is refused. Repeats of objects already handled don't reach it.
+pub type PublishHandler = Arc<dyn Fn(&Publication) -> Result<(), String> + Send + Sync>;
+
+/// The objects this node has received, kept in the node's [Storage].
+pub struct Inbox {
+    storage: Arc<dyn Storage>,
+    handler: Option<PublishHandler>,
+    verifier: Arc<Verifier>,
+}
+
+impl Inbox {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Inbox { storage, handler: None, verifier: Arc::new(Verifier::new()) }
+    }
+
+    /// Check signatures with `verifier`, sharing the keys it already knows.
+    pub(crate) fn with_verifier(mut self, verifier: Arc<Verifier>) -> Self {
+        self.verifier = verifier;
+        self
+    }
+
+    /// Hand new objects to `handler` in [Inbox::handle].
+    pub fn with_handler(mut self, handler: Option<PublishHandler>) -> Self {
+        self.handler = handler;
+        self
+    }
+
+    /// Whether `envelope` repeats an object that was already acknowledged.
+    pub fn is_duplicate(&self, envelope: &SignedData) -> io::Result<bool> {
+        Ok(self.seen(&key(envelope))?.is_some_and(|(created_at, _)| envelope.created_at <= created_at))
+    }
+
+    /// Record `envelope` as handled and acknowledge it to the peer that sent
+    /// it. Duplicates have to be acknowledged as well, or the peer keeps
+    /// retrying them.
+    pub async fn acknowledge(self: &Arc<Self>, envelope: &SignedData, sender: &ProtocolSender<TransferPacket>) -> io::Result<()> {
+        let handled = envelope.clone();
+        blocking(self, move |inbox| inbox.remember(&handled)).await?;
+        sender.send(TransferPacket::Ack {
+            object_id: envelope.object_id.clone(),
+            created_at: envelope.created_at,
+        }).await
+    }
+
+    /// Refuse `envelope` for good, for instance because it doesn't decode
+    /// as its type. The peer won't retry it.
+    pub async fn reject(&self, envelope: &SignedData, reason: String, sender: &ProtocolSender<TransferPacket>) -> io::Result<()> {
+        sender.send(TransferPacket::Reject {
+            object_id: envelope.object_id.clone(),
+            created_at: envelope.created_at,
+            reason,
+        }).await
+    }
+
+    /// Answer `packet` if it is a [TransferPacket::Publish], returning whether
+    /// it was. Objects whose signature doesn't match their origin's key are
+    /// rejected, as are objects of types in `registry` that don't decode as
+    /// them. Repeats are acknowledged again, and new objects are acknowledged
+    /// once the [PublishHandler] accepts them. Types this node doesn't know
+    /// are accepted as they are.
+    ///
+    /// Fails without answering if the origin's key can't be looked up, so the
+    /// peer tries again later.
+    pub async fn handle(self: &Arc<Self>, packet: TransferPacket, sender: &ProtocolSender<TransferPacket>, registry: &DataRegistry) -> io::Result<bool> {
+        let TransferPacket::Publish { topic, envelope } = packet else {
+            return Ok(false);
+        };
+        match self.verifier.verify(&envelope).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                self.reject(&envelope, e.to_string(), sender).await?;
+                return Ok(true);
+            }
+            Err(e) => return Err(e),
+        }
+        if registry.contains(&envelope.type_id()) {
+            if let Err(e) = envelope.data.decode_any(registry) {
+                self.reject(&envelope, e.to_string(), sender).await?;
+                return Ok(true);
+            }
+        }
+        let received = envelope.clone();
+        if blocking(self, move |inbox| inbox.is_duplicate(&received)).await? {
+            info!("Skipping repeated {} from {}", envelope.object_id, envelope.origin);
+            self.acknowledge(&envelope, sender).await?;
+            return Ok(true);
+        }
+        let publication = Publication { topic, envelope };
+        match self.handler.as_ref().map_or(Ok(()), |handler| handler(&publication)) {
+            Ok(()) => self.acknowledge(&publication.envelope, sender).await?,
+            Err(reason) => self.reject(&publication.envelope, reason, sender).await?,
+        }
+        Ok(true)
+    }
+
+    /// Record `envelope` as handled now, unless it is a duplicate, and forget
+    /// the objects handled longer than [INBOX_RETENTION] ago.
+    fn remember(&self, envelope: &SignedData) -> io::Result<()> {
+        if self.is_duplicate(envelope)? {
+            return Ok(());
+        }
+        let now = now_millis();
+        self.record(envelope, now)?;
+        self.expire(now.saturating_sub(INBOX_RETENTION.as_millis() as u64))
+    }
+
+    /// When the object under `key` was created and when it was handled.
+    fn seen(&self, key: &str) -> io::Result<Option<(u64, u64)>> {
+        Ok(self.storage.get(key)?.and_then(|seen| {
+            let created_at = u64::from_be_bytes(seen.get(..8)?.try_into().ok()?);
+            let received_at = u64::from_be_bytes(seen.get(8..16)?.try_into().ok()?);
+            Some((created_at, received_at))
+        }))
+    }
+
+    /// Remember `envelope` as handled at `at`, under the object and the time
+    /// so old records can be dropped in order.
+    fn record(&self, envelope: &SignedData, at: u64) -> io::Result<()> {
+        let value = [envelope.created_at.to_be_bytes(), at.to_be_bytes()].concat();
+        self.storage.put(&key(envelope), &value)?;
+        self.storage.put(&format!("{RECEIVED_AT_PREFIX}{at:020}/{}/{}", envelope.origin, envelope.object_id), &[])
+    }
+
+    /// Forget the objects handled before `before`. A record replaced by a
+    /// later update of the object is kept.
+    fn expire(&self, before: u64) -> io::Result<()> {
+        loop {
+            let keys = self.storage.keys_after(RECEIVED_AT_PREFIX, None, EXPIRE_BATCH)?;
+            for key in &keys {
+                let Some((at, object)) = key[RECEIVED_AT_PREFIX.len()..].split_once('/')
+                    .and_then(|(at, object)| Some((at.parse::<u64>().ok()?, object))) else {
+                    warn!("Dropping inbox record with malformed key {key}");
+                    self.storage.delete(key)?;
+                    continue;
+                };
+                if at >= before {
+                    return Ok(());
+                }
+                let seen = format!("{KEY_PREFIX}{object}");
+                if self.seen(&seen)?.is_some_and(|(_, received_at)| received_at == at) {
+                    self.storage.delete(&seen)?;
+                }
+                self.storage.delete(key)?;
+            }
+            if keys.len() < EXPIRE_BATCH {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn key(envelope: &SignedData) -> String {
+    format!("{KEY_PREFIX}{}/{}", envelope.origin, envelope.object_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use osp_data::{DataCodec, DataRegistry, RawData, SignedData};
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::inbox::{Inbox, PublishHandler};
+use crate::outbox::now_millis;
+    use crate::storage::MemoryStorage;
+    use crate::testing;
+
+    fn envelope(object_id: &str) -> SignedData {
+        testing::signed(object_id, RawData::new(Uuid::new_v4(), 1, DataCodec::Bincode, vec![1]))
+    }
+
+    #[tokio::test]
+    async fn test_duplicates() -> io::Result<()> {
+        let ((_sender, mut acks), (sender, _receiver)) = testing::pipe();
+
+        let inbox = Arc::new(Inbox::new(Arc::new(MemoryStorage::new())));
+        let envelope = envelope("post/1");
+        assert!(!inbox.is_duplicate(&envelope)?);
+        inbox.acknowledge(&envelope, &sender).await?;
+        assert!(inbox.is_duplicate(&envelope)?);
+        inbox.acknowledge(&envelope, &sender).await?;
+        for _ in 0..2 {
+            assert!(matches!(
+                acks.next().await.unwrap()?,
+                TransferPacket::Ack { object_id, created_at } if object_id == "post/1" && created_at == envelope.created_at
+            ));
+        }
+
+        let mut update = envelope.clone();
+        update.created_at += 1;
+        assert!(!inbox.is_duplicate(&update)?);
+        let mut elsewhere = envelope.clone();
+        elsewhere.origin = "b.example".to_string();
+        assert!(!inbox.is_duplicate(&elsewhere)?);
+
+        inbox.reject(&update, "Invalid".to_string(), &sender).await?;
+        assert!(matches!(acks.next().await.unwrap()?, TransferPacket::Reject { reason, .. } if reason == "Invalid"));
+        assert!(!inbox.is_duplicate(&update)?);
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_handle() -> io::Result<()> {
+        let ((_sender, mut replies), (sender, _receiver)) = testing::pipe();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let seen = handled.clone();
+        let handler: PublishHandler = Arc::new(move |publication| match publication.envelope.object_id.as_str() {
+            "spam" => Err("Spam".to_string()),
+            object_id => {
+                seen.lock().unwrap().push(object_id.to_string());
+                Ok(())
+            }
+        });
+        let inbox = Arc::new(Inbox::new(Arc::new(MemoryStorage::new())).with_handler(Some(handler)).with_verifier(testing::verifier()));
+        let registry = DataRegistry::new();
+
+        let post = envelope("post/1");
+        let mut forged = envelope("post/2");
+        forged.data.bytes = vec![2].into();
+        for envelope in [post.clone(), post, envelope("spam"), forged] {
+            assert!(inbox.handle(TransferPacket::Publish { topic: None, envelope }, &sender, &registry).await?);
+        }
+        assert!(!inbox.handle(TransferPacket::Ping { nonce: 1 }, &sender, &registry).await?);
+        for _ in 0..2 {
+            assert!(matches!(replies.next().await.unwrap()?, TransferPacket::Ack { object_id, .. } if object_id == "post/1"));
+        }
+        assert!(matches!(replies.next().await.unwrap()?, TransferPacket::Reject { reason, .. } if reason == "Spam"));
+        assert!(matches!(
+            replies.next().await.unwrap()?,
+            TransferPacket::Reject { object_id, reason, .. } if object_id == "post/2" && reason.starts_with("Invalid signature")
+        ));
+        // Repeats aren't handled again
+        assert_eq!(*handled.lock().unwrap(), vec!["post/1"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_records_expire() -> io::Result<()> {
+        let ((_sender, _acks), (sender, _receiver)) = testing::pipe();
+        let inbox = Arc::new(Inbox::new(Arc::new(MemoryStorage::new())));
+        let day = 24 * 60 * 60 * 1000;
+        let (old, updated) = (envelope("post/1"), envelope("post/2"));
+        inbox.record(&old, now_millis() - 40 * day)?;
+        inbox.record(&updated, now_millis() - 40 * day)?;
+        let mut update = updated.clone();
+        update.created_at += 1;
+        inbox.record(&update, now_millis() - day)?;
+
+        inbox.acknowledge(&envelope("post/3"), &sender).await?;
+        assert!(!inbox.is_duplicate(&old)?);
+        assert!(inbox.is_duplicate(&update)?);
+        Ok(())
+    }
+}
@@ -1,4 +1,21 @@
+mod discovery;
+mod envelope;
+mod fetch;
+mod identity;
+mod net;
+mod schema;
 mod node;
+mod request;
 pub mod connection;
+pub mod feed;
+pub mod follow;
+pub mod handler;
+pub mod inbox;
+pub mod outbox;
+pub mod storage;
+pub mod subscription;
 
-pub use {node::OSProtocolNode};
\ No newline at end of file
+#[cfg(test)]
+mod testing;
+
+pub use {node::{OSProtocolNode, DEFAULT_MAX_OBJECT_LENGTH, DEFAULT_UNIX_SOCKET_MODE}};
\ No newline at end of file
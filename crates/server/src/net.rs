@@ -0,0 +1,183 @@
+//! Dual-stack listening and Happy Eyeballs (RFC 8305) connection racing, so
+//! nodes can be reached over IPv4, IPv6 or both.
+
+use std::future::Future;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use log::debug;
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+
+/// How long to wait on a connection attempt before also trying the next
+/// address, as recommended by RFC 8305.
+pub(crate) const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Order resolved addresses for racing, alternating between IPv6 and IPv4
+/// starting with IPv6, and otherwise keeping the resolver's order.
+pub(crate) fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    v6.reverse();
+    v4.reverse();
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop());
+        ordered.extend(v4.pop());
+    }
+    ordered
+}
+
+/// Race `connect` across `addrs` in order, starting the next attempt whenever
+/// the previous one fails or has been running for [CONNECTION_ATTEMPT_DELAY].
+/// The first attempt to succeed wins and the rest are cancelled.
+pub(crate) async fn race<T, F, Fut>(addrs: &[SocketAddr], connect: F) -> io::Result<(T, SocketAddr)>
+where
+    T: Send + 'static,
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+{
+    let mut attempts = JoinSet::new();
+    let mut remaining = addrs.iter();
+    let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No addresses to connect to");
+
+    loop {
+        if let Some(addr) = remaining.next() {
+            debug!("Trying {addr}");
+            let attempt = connect(*addr);
+            let addr = *addr;
+            attempts.spawn(async move { (attempt.await, addr) });
+        } else if attempts.is_empty() {
+            return Err(last_error);
+        }
+
+        // Wait for the next attempt to finish, or give up waiting after the
+        // delay if there is another address to try
+        let finished = tokio::select! {
+            finished = attempts.join_next(), if !attempts.is_empty() => finished,
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if remaining.len() > 0 => None,
+        };
+        match finished {
+            Some(Ok((Ok(connection), addr))) => return Ok((connection, addr)),
+            Some(Ok((Err(e), addr))) => {
+                debug!("Connecting to {addr} failed: {e}");
+                last_error = e;
+            }
+            Some(Err(e)) => last_error = io::Error::other(e),
+            None => {}
+        }
+    }
+}
+
+fn socket_for(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        // Listening on [::] accepts IPv4 too, whatever the system default
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Bind a TCP listener to `addr`. Binding to the unspecified IPv6 address
+/// listens on both IPv6 and IPv4.
+pub(crate) fn bind_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket_for(addr, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a UDP socket to `addr`, dual-stack like [bind_tcp_listener].
+pub(crate) fn bind_udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket_for(addr, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Instant;
+
+    use tokio::io;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::net::{bind_tcp_listener, interleave_families, race, CONNECTION_ATTEMPT_DELAY};
+
+    fn v4(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn v6(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv6Addr::LOCALHOST, port))
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        assert_eq!(
+            interleave_families(vec![v4(1), v4(2), v4(3), v6(4), v6(5)]),
+            vec![v6(4), v4(1), v6(5), v4(2), v4(3)]
+        );
+    }
+
+    /// An address that never answers only delays the connection by
+    /// [CONNECTION_ATTEMPT_DELAY] before the next one is tried.
+    #[tokio::test]
+    async fn test_race_past_unresponsive_address() -> io::Result<()> {
+        let listener = TcpListener::bind(v6(0)).await?;
+        let reachable = listener.local_addr()?;
+        let unresponsive = v6(1);
+
+        let start = Instant::now();
+        let (_, addr) = race(&[unresponsive, reachable], move |addr| async move {
+            if addr == unresponsive {
+                pending().await
+            } else {
+                TcpStream::connect(addr).await
+            }
+        }).await?;
+        assert_eq!(addr, reachable);
+        assert!(start.elapsed() >= CONNECTION_ATTEMPT_DELAY);
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY * 4);
+        Ok(())
+    }
+
+    /// A refused address moves straight on to the next one.
+    #[tokio::test]
+    async fn test_race_past_refused_address() -> io::Result<()> {
+        let refused = TcpListener::bind(v6(0)).await?.local_addr()?;
+        let listener = TcpListener::bind(v6(0)).await?;
+        let reachable = listener.local_addr()?;
+
+        let start = Instant::now();
+        let (_, addr) = race(&[refused, reachable], TcpStream::connect).await?;
+        assert_eq!(addr, reachable);
+        assert!(start.elapsed() < CONNECTION_ATTEMPT_DELAY);
+
+        assert!(race(&[refused], TcpStream::connect).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener() -> io::Result<()> {
+        let listener = bind_tcp_listener(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)))?;
+        let port = listener.local_addr()?.port();
+
+        for addr in [v6(port), v4(port)] {
+            let _client = TcpStream::connect(addr).await?;
+            let (_, peer) = listener.accept().await?;
+            // IPv4 peers show up as IPv4-mapped IPv6 addresses
+            assert_eq!(peer.ip().to_canonical(), addr.ip());
+        }
+        Ok(())
+    }
+}
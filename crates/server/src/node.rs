@@ -1,47 +1,216 @@
 use std::{fs, net::{SocketAddr, IpAddr, Ipv4Addr}};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::future::Future;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::{error, info};
+use bincode::{Decode, Encode};
+
+use bytes::Bytes;
+
+use log::{error, info, warn};
 
 use openssl::pkey::Private;
 use openssl::rsa::Rsa;
 
 use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{UnixListener, UnixStream};
+
+use uuid::Uuid;
+
+use osp_data::{AnyData, Data, DataCodec, DataRegistry, DataSchema, RawData, SignedData};
 
-use osp_protocol::OSPUrl;
+use osp_protocol::{OSPUrl, ResourcePath, Transport, DEFAULT_PORT};
+use osp_protocol::packet::{PACKET_MAX_LENGTH, PACKET_MIN_MAX_LENGTH};
+use osp_protocol::packet::compression::Compression;
+use osp_protocol::packet::transfer::TransferPacket;
+use osp_protocol::quic::{self, quinn::{self, Endpoint, EndpointConfig}};
+use osp_protocol::tls::rustls::{ClientConfig, ServerConfig};
+use osp_protocol::websocket;
 
-use crate::connection::inbound::{InboundConnection, TransferState};
-use crate::connection::outbound::OutboundConnection;
+use crate::connection::inbound::{HandshakeState, InboundConnection, TransferState};
+use crate::connection::outbound::{self, OutboundConnection};
+use crate::envelope::Verifier;
+use crate::{envelope, fetch, follow, net, outbox, schema};
+use crate::request::close_quietly;
+use crate::fetch::ResourceProvider;
+use crate::feed::{CatchUp, Cursors, Feed, FeedRetention, MAX_PAGE_OBJECTS};
+use crate::follow::{FollowApproval, FollowRequest, Follows};
+use crate::inbox::{Inbox, PublishHandler};
+use crate::outbox::{Claim, DeadLetter, DeliveryStatus, Outbox, RETRY_MAX};
+use crate::storage::{blocking, MemoryStorage, Storage};
+use crate::subscription::{Publication, Subscription, Subscriptions};
 
 pub struct InitState {
     private_key: Option<Rsa<Private>>,
+    follows_file: Option<PathBuf>,
+    follow_approval: Option<FollowApproval>,
+    publish_handler: Option<PublishHandler>,
+    resource_provider: Option<ResourceProvider>,
+    storage: Option<Arc<dyn Storage>>,
+    feed_retention: FeedRetention,
 }
 
 pub struct ConnectionState {
     private_key: Rsa<Private>,
+    registry: Arc<DataRegistry>,
+    subscriptions: Arc<Subscriptions>,
+    follows: Arc<Follows>,
+    outbox: Arc<Outbox>,
+    inbox: Arc<Inbox>,
+    feed: Arc<Feed>,
+    cursors: Arc<Cursors>,
+    verifier: Arc<Verifier>,
+    resource_provider: Option<ResourceProvider>,
+}
+
+impl ConnectionState {
+    /// The data types this node understands, see
+    /// [OSProtocolNode::set_registry].
+    pub fn registry(&self) -> &Arc<DataRegistry> {
+        &self.registry
+    }
+
+    /// What connected peers are subscribed to. Handlers add their peer to it
+    /// so [OSProtocolNode::publish] reaches them.
+    pub fn subscriptions(&self) -> &Arc<Subscriptions> {
+        &self.subscriptions
+    }
+
+    /// The nodes following this one. Handlers pass it their peer's packets so
+    /// follow requests reach [OSProtocolNode::set_follow_approval].
+    pub fn follows(&self) -> &Arc<Follows> {
+        &self.follows
+    }
+
+    /// Objects waiting to be delivered to followers
+    pub fn outbox(&self) -> &Arc<Outbox> {
+        &self.outbox
+    }
+
+    /// The objects pushed to this node so far. Handlers pass it their peer's
+    /// packets so each [TransferPacket::Publish] is acknowledged once
+    /// handled, see [Inbox::handle].
+    pub fn inbox(&self) -> &Arc<Inbox> {
+        &self.inbox
+    }
+
+    /// Everything this node has published. Handlers pass it their peer's
+    /// packets so followers can catch up with [OSProtocolNode::sync].
+    pub fn feed(&self) -> &Arc<Feed> {
+        &self.feed
+    }
+
+    /// The answer to a [TransferPacket::SchemaRequest] for `data_type`, from
+    /// the node's registry.
+    pub fn schema_response(&self, data_type: Uuid) -> TransferPacket {
+        schema::schema_response(&self.registry, data_type)
+    }
+
+    /// The answer to a [TransferPacket::Fetch] for `resource`, from
+    /// [OSProtocolNode::set_resource_provider]: the object in chunks that fit
+    /// in the peer's frames of `max_frame_length` bytes, or a
+    /// [TransferPacket::FetchFailed]. The returned future doesn't borrow the
+    /// state, so it can be awaited after unlocking it.
+    pub fn fetch_response(
+        &self,
+        transfer_id: Uuid,
+        resource: ResourcePath,
+        max_frame_length: usize,
+    ) -> impl Future<Output = Vec<TransferPacket>> + Send + 'static {
+        fetch::fetch_response(self.resource_provider.clone(), transfer_id, resource, max_frame_length)
+    }
 }
 
-#[derive(Clone)]
+/// Who can connect to the Unix socket unless configured otherwise: only the
+/// node's own user.
+pub const DEFAULT_UNIX_SOCKET_MODE: u32 = 0o600;
+
+/// The longest object [OSProtocolNode::fetch] takes unless configured
+/// otherwise.
+pub const DEFAULT_MAX_OBJECT_LENGTH: usize = 64 * 1024 * 1024;
+
 pub struct OSProtocolNode<TState> {
     bind_addr: SocketAddr,
     hostname: String,
+    compression: Vec<Compression>,
+    max_frame_length: usize,
+    max_object_length: usize,
+    transport: Transport,
+    websocket_path: String,
+    /// The transport, and WebSocket path, to connect to each peer over
+    peer_transports: HashMap<String, (Transport, String)>,
+    unix_socket_path: Option<PathBuf>,
+    unix_socket_mode: u32,
+    tls_server_config: Option<Arc<ServerConfig>>,
+    tls_client_config: Option<Arc<ClientConfig>>,
+    registry: Arc<DataRegistry>,
+    subscriptions: Arc<Subscriptions>,
     state: Arc<Mutex<TState>>,
 }
 
+// Not derived, as that would require the state itself to be Clone
+impl<TState> Clone for OSProtocolNode<TState> {
+    fn clone(&self) -> Self {
+        OSProtocolNode {
+            bind_addr: self.bind_addr,
+            hostname: self.hostname.clone(),
+            compression: self.compression.clone(),
+            max_frame_length: self.max_frame_length,
+            max_object_length: self.max_object_length,
+            transport: self.transport,
+            websocket_path: self.websocket_path.clone(),
+            peer_transports: self.peer_transports.clone(),
+            unix_socket_path: self.unix_socket_path.clone(),
+            unix_socket_mode: self.unix_socket_mode,
+            tls_server_config: self.tls_server_config.clone(),
+            tls_client_config: self.tls_client_config.clone(),
+            registry: self.registry.clone(),
+            subscriptions: self.subscriptions.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl Default for OSProtocolNode<InitState> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OSProtocolNode<InitState> {
     pub fn new() -> Self {
         OSProtocolNode::<InitState> {
-            bind_addr: SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), 57401),
+            bind_addr: SocketAddr::new(IpAddr::from(Ipv4Addr::LOCALHOST), DEFAULT_PORT),
             hostname: "".to_string(),
+            compression: Compression::SUPPORTED.to_vec(),
+            max_frame_length: PACKET_MAX_LENGTH,
+            max_object_length: DEFAULT_MAX_OBJECT_LENGTH,
+            transport: Transport::Tcp,
+            websocket_path: websocket::DEFAULT_PATH.to_string(),
+            peer_transports: HashMap::new(),
+            unix_socket_path: None,
+            unix_socket_mode: DEFAULT_UNIX_SOCKET_MODE,
+            tls_server_config: None,
+            tls_client_config: None,
+            registry: Arc::new(DataRegistry::new()),
+            subscriptions: Arc::new(Subscriptions::new()),
             state: Arc::new(Mutex::new(InitState {
                 private_key: None,
+                follows_file: None,
+                follow_approval: None,
+                publish_handler: None,
+                resource_provider: None,
+                storage: None,
+                feed_retention: FeedRetention::default(),
             })),
         }
     }
 
+    /// Set the address to listen on. Binding to `[::]` listens on both IPv6
+    /// and IPv4.
     pub fn set_addr(&mut self, addr: SocketAddr) {
         self.bind_addr = addr;
     }
@@ -50,33 +219,279 @@ impl OSProtocolNode<InitState> {
         self.hostname = hostname;
     }
 
+    /// Set the compression algorithms this node will negotiate for transfer
+    /// frames, in order of preference. An empty list disables compression.
+    pub fn set_compression(&mut self, compression: Vec<Compression>) {
+        self.compression = compression;
+    }
+
+    /// Set the longest transfer frame this node advertises and accepts.
+    /// Constrained nodes can lower this, archive nodes can raise it. Defaults
+    /// to [PACKET_MAX_LENGTH]. Fails with [io::ErrorKind::InvalidInput] if it
+    /// is below [PACKET_MIN_MAX_LENGTH] or doesn't fit in a `u32`.
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) -> io::Result<()> {
+        if max_frame_length < PACKET_MIN_MAX_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Maximum frame length must be at least {PACKET_MIN_MAX_LENGTH}")
+            ));
+        }
+        if max_frame_length > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Maximum frame length must fit in a u32"));
+        }
+        self.max_frame_length = max_frame_length;
+        Ok(())
+    }
+
+    /// Set the longest object this node fetches from peers, in bytes.
+    /// Fetches of longer objects fail with [io::ErrorKind::InvalidData].
+    /// Defaults to [DEFAULT_MAX_OBJECT_LENGTH].
+    pub fn set_max_object_length(&mut self, max_object_length: usize) {
+        self.max_object_length = max_object_length;
+    }
+
+    /// Set the transport used for listening. [Transport::Quic] needs the TLS
+    /// server config to be set as well. Defaults to [Transport::Tcp].
+    ///
+    /// Outbound connections don't follow this, as each peer picks its own,
+    /// see [OSProtocolNode::set_peer_transport].
+    pub fn set_transport(&mut self, transport: Transport) {
+        self.transport = transport;
+    }
+
+    /// Set the path WebSocket upgrades are served on with
+    /// [Transport::WebSocket]. Defaults to [websocket::DEFAULT_PATH].
+    pub fn set_websocket_path(&mut self, path: String) {
+        self.websocket_path = path;
+    }
+
+    /// Connect to `peer` over `transport`, requesting WebSocket upgrades at
+    /// [websocket::DEFAULT_PATH]. [Transport::Quic] needs the TLS client
+    /// config to be set as well. Peers without a transport of their own are
+    /// connected to over [Transport::Tcp].
+    pub fn set_peer_transport(&mut self, peer: String, transport: Transport) {
+        self.peer_transports.insert(peer, (transport, websocket::DEFAULT_PATH.to_string()));
+    }
+
+    /// Connect to `peer` over [Transport::WebSocket], requesting the upgrade
+    /// at `path`.
+    pub fn set_peer_websocket_path(&mut self, peer: String, path: String) {
+        self.peer_transports.insert(peer, (Transport::WebSocket, path));
+    }
+
+    /// Also listen for local clients on a Unix socket at `path`. Connections
+    /// there skip the DNS challenge and are always [ConnectionType::Client],
+    /// with the peer's credentials available through
+    /// [InboundConnection::peer_credentials] for authorization.
+    ///
+    /// [ConnectionType::Client]: osp_protocol::ConnectionType::Client
+    pub fn set_unix_socket_path(&mut self, path: PathBuf) {
+        self.unix_socket_path = Some(path);
+    }
+
+    /// Set the permissions of the Unix socket, which decide who can connect
+    /// as a local client. Defaults to [DEFAULT_UNIX_SOCKET_MODE], so only the
+    /// node's own user can.
+    pub fn set_unix_socket_mode(&mut self, mode: u32) {
+        self.unix_socket_mode = mode;
+    }
+
+    /// Only accept connections over TLS, using this config. The OSP handshake
+    /// still runs inside the TLS session and is bound to it.
+    pub fn set_tls_server_config(&mut self, config: Arc<ServerConfig>) {
+        self.tls_server_config = Some(config);
+    }
+
+    /// Wrap outbound connections in TLS, using this config.
+    pub fn set_tls_client_config(&mut self, config: Arc<ClientConfig>) {
+        self.tls_client_config = Some(config);
+    }
+
+    /// Set the data types this node understands. Incoming data is decoded
+    /// through it, see [OSProtocolNode::fetch_any].
+    pub fn set_registry(&mut self, registry: DataRegistry) {
+        self.registry = Arc::new(registry);
+    }
+
     pub fn set_private_key_file(&mut self, path: String) {
-        let key_contents = fs::read_to_string(path.clone()).expect(format!("Unable to open private key file {}", path).as_str());
+        let key_contents = fs::read_to_string(path.clone()).unwrap_or_else(|_| panic!("Unable to open private key file {}", path));
         self.state.lock().unwrap().private_key = Some(Rsa::private_key_from_pem(key_contents.as_bytes()).unwrap());
     }
 
-    pub fn init(&mut self) -> OSProtocolNode<ConnectionState> {
-        let bind_addr = self.bind_addr.clone();
+    /// Keep approved follows in the file at `path` so they survive restarts.
+    /// Without one they are only kept in memory.
+    pub fn set_follows_file(&mut self, path: PathBuf) {
+        self.state.lock().unwrap().follows_file = Some(path);
+    }
+
+    /// Decide whether other nodes may follow this one, returning the reason
+    /// to give them if not. Without it every follow request is rejected.
+    pub fn set_follow_approval<F>(&mut self, approval: F)
+    where
+        F: Fn(&FollowRequest) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.state.lock().unwrap().follow_approval = Some(Arc::new(approval));
+    }
+
+    /// Handle objects other nodes push to this one, returning the reason to
+    /// reject them with if refused, see [Inbox::handle]. Without it every
+    /// object that decodes is accepted.
+    pub fn set_publish_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&Publication) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.state.lock().unwrap().publish_handler = Some(Arc::new(handler));
+    }
+
+    /// Serve this node's objects to peers that fetch them, see
+    /// [OSProtocolNode::fetch]. `provider` returns the object a resource
+    /// names, encoded with bincode, or the reason the fetch fails. Without it
+    /// every fetch fails.
+    pub fn set_resource_provider<F>(&mut self, provider: F)
+    where
+        F: Fn(&ResourcePath) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    {
+        self.state.lock().unwrap().resource_provider = Some(Arc::new(provider));
+    }
+
+    /// Keep node state that has to survive restarts, such as objects waiting
+    /// to be delivered, in `storage`. Defaults to a [MemoryStorage].
+    pub fn set_storage(&mut self, storage: impl Storage + 'static) {
+        self.state.lock().unwrap().storage = Some(Arc::new(storage));
+    }
+
+    /// Drop published objects outside of `retention` from the feed followers
+    /// catch up from. Defaults to keeping every object.
+    pub fn set_feed_retention(&mut self, retention: FeedRetention) {
+        self.state.lock().unwrap().feed_retention = retention;
+    }
+
+    /// The node as configured, ready to listen. Fails if no private key was
+    /// set, or if the follows file or the state kept in storage can't be
+    /// loaded.
+    pub fn init(&mut self) -> io::Result<OSProtocolNode<ConnectionState>> {
+        let bind_addr = self.bind_addr;
         let hostname = self.hostname.clone();
-        let private_key = self.state.lock().unwrap().private_key.clone().unwrap();
-        OSProtocolNode::<ConnectionState> {
+        let state = self.state.lock().unwrap();
+        let private_key = state.private_key.clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No private key was set"))?;
+        let approval = state.follow_approval.clone();
+        let follows = match &state.follows_file {
+            Some(path) => Follows::open(path.clone(), approval)
+                .map_err(|e| io::Error::new(e.kind(), format!("Unable to open follows file {}: {e}", path.display())))?,
+            None => Follows::new(approval),
+        };
+        let storage = state.storage.clone().unwrap_or_else(|| Arc::new(MemoryStorage::new()));
+        let outbox = Outbox::open(storage.clone())
+            .map_err(|e| io::Error::new(e.kind(), format!("Unable to load outbox: {e}")))?;
+        let feed = Feed::open(storage.clone())
+            .map_err(|e| io::Error::new(e.kind(), format!("Unable to load feed: {e}")))?
+            .with_retention(state.feed_retention);
+        let verifier = Arc::new(Verifier::new());
+        Ok(OSProtocolNode::<ConnectionState> {
             bind_addr,
             hostname,
+            compression: self.compression.clone(),
+            max_frame_length: self.max_frame_length,
+            max_object_length: self.max_object_length,
+            transport: self.transport,
+            websocket_path: self.websocket_path.clone(),
+            peer_transports: self.peer_transports.clone(),
+            unix_socket_path: self.unix_socket_path.clone(),
+            unix_socket_mode: self.unix_socket_mode,
+            tls_server_config: self.tls_server_config.clone(),
+            tls_client_config: self.tls_client_config.clone(),
+            registry: self.registry.clone(),
+            subscriptions: self.subscriptions.clone(),
             state: Arc::new(Mutex::new(ConnectionState {
                 private_key,
+                registry: self.registry.clone(),
+                subscriptions: self.subscriptions.clone(),
+                follows: Arc::new(follows),
+                outbox: Arc::new(outbox),
+                inbox: Arc::new(Inbox::new(storage.clone()).with_handler(state.publish_handler.clone()).with_verifier(verifier.clone())),
+                feed: Arc::new(feed),
+                cursors: Arc::new(Cursors::new(storage)),
+                verifier,
+                resource_provider: state.resource_provider.clone(),
             })),
-        }
+        })
+    }
+}
+
+impl<TState> OSProtocolNode<TState> {
+    /// The data types this node understands
+    pub fn registry(&self) -> &Arc<DataRegistry> {
+        &self.registry
+    }
+
+    /// What connected peers are subscribed to
+    pub fn subscriptions(&self) -> &Arc<Subscriptions> {
+        &self.subscriptions
     }
 }
 
 impl OSProtocolNode<ConnectionState> {
-    pub async fn listen<'a, F, Fut>(&'a mut self, conn_handler: F) -> io::Result<()>
+    /// Accept connections, handing each one to `conn_handler` once the
+    /// handshake is done, until listening fails. [handler::handle] answers
+    /// everything the SDK knows about.
+    ///
+    /// Outbox entries, such as objects published to followers, are only
+    /// delivered while this runs. A node that doesn't listen keeps them
+    /// queued until it does.
+    ///
+    /// [handler::handle]: crate::handler::handle
+    pub async fn listen<F, Fut>(&mut self, conn_handler: F) -> io::Result<()>
+    where
+        F: Fn(InboundConnection<TransferState>, &Arc<Mutex<ConnectionState>>) -> Fut + Send + Copy + 'static,
+        Fut: Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let network = async {
+            match self.transport {
+                Transport::Tcp | Transport::WebSocket => self.listen_tcp(conn_handler).await,
+                Transport::Quic => self.listen_quic(conn_handler).await,
+            }
+        };
+        let listeners = async {
+            match &self.unix_socket_path {
+                Some(path) => tokio::try_join!(network, self.listen_unix(path, conn_handler)).map(|_| ()),
+                None => network.await,
+            }
+        };
+        // Objects for followers are retried for as long as the node listens
+        tokio::select! {
+            result = listeners => result,
+            result = self.run_outbox() => result,
+        }
+    }
+
+    /// Deliver outbox entries as they become due.
+    async fn run_outbox(&self) -> io::Result<()> {
+        let outbox = self.state.lock().unwrap().outbox.clone();
+        loop {
+            let now = outbox::now_millis();
+            for peer in outbox.due_peers(now) {
+                if let Some(claim) = outbox.claim(&peer) {
+                    self.spawn_delivery(claim);
+                }
+            }
+            let wait = outbox.next_due()
+                .map(|due| Duration::from_millis(due.saturating_sub(now)))
+                .unwrap_or(RETRY_MAX);
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = outbox.changed() => {}
+            }
+        }
+    }
+
+    async fn listen_tcp<F, Fut>(&self, conn_handler: F) -> io::Result<()>
     where
         F: Fn(InboundConnection<TransferState>, &Arc<Mutex<ConnectionState>>) -> Fut + Send + Copy + 'static,
         Fut: Future<Output = Result<(), ()>> + Send + 'static,
     {
         let port = self.bind_addr.port();
-        let listener = TcpListener::bind(self.bind_addr).await?;
+        let listener = net::bind_tcp_listener(self.bind_addr)?;
         info!("Listening started on port {port}, ready to accept connections");
 
         loop {
@@ -91,34 +506,542 @@ impl OSProtocolNode<ConnectionState> {
                     .unwrap_or("unknown address".to_string())
             );
 
-            let state_rc = self.state.clone();
-            tokio::spawn(async move {
-                let mut connection_handshake = InboundConnection::with_stream(stream).unwrap();
-                match connection_handshake.begin().await {
-                    Ok(_) => {
-                        let connection_transfer = InboundConnection::<TransferState>::from(connection_handshake);
+            let tls_server_config = self.tls_server_config.clone();
+            let websocket_path = (self.transport == Transport::WebSocket).then(|| self.websocket_path.clone());
+            let max_frame_length = self.max_frame_length;
+            self.spawn_connection(conn_handler, |private_key| async move {
+                match (websocket_path, tls_server_config) {
+                    (Some(path), config) => InboundConnection::with_websocket(stream, config, &path, max_frame_length, private_key).await,
+                    (None, Some(config)) => InboundConnection::with_tls_stream(stream, config, private_key).await,
+                    (None, None) => InboundConnection::with_stream(stream, private_key),
+                }
+            });
+        }
+    }
 
-                        match conn_handler(connection_transfer, &state_rc).await {
-                            Ok(_) => {}
-                            Err(_) => {}
-                        }
+    async fn listen_quic<F, Fut>(&self, conn_handler: F) -> io::Result<()>
+    where
+        F: Fn(InboundConnection<TransferState>, &Arc<Mutex<ConnectionState>>) -> Fut + Send + Copy + 'static,
+        Fut: Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        let tls_server_config = self.tls_server_config.as_ref().ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Listening over QUIC requires a TLS server config"
+        ))?;
+        let port = self.bind_addr.port();
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(quic::server_config(tls_server_config)?),
+            net::bind_udp_socket(self.bind_addr)?,
+            Arc::new(quinn::TokioRuntime),
+        )?;
+        info!("Listening started on port {port} over QUIC, ready to accept connections");
+
+        while let Some(incoming) = endpoint.accept().await {
+            info!("Accepting a new connection from {}", incoming.remote_address());
+            self.spawn_connection(conn_handler, |private_key| InboundConnection::with_quic(incoming, private_key));
+        }
+        Ok(())
+    }
+
+    async fn listen_unix<F, Fut>(&self, path: &Path, conn_handler: F) -> io::Result<()>
+    where
+        F: Fn(InboundConnection<TransferState>, &Arc<Mutex<ConnectionState>>) -> Fut + Send + Copy + 'static,
+        Fut: Future<Output = Result<(), ()>> + Send + 'static,
+    {
+        // Anything else at the path was left behind by a node that didn't
+        // shut down cleanly, and is replaced
+        if fs::symlink_metadata(path).is_ok() && UnixStream::connect(path).await.is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use by another node", path.display())));
+        }
+        let listener = bind_unix(path, self.unix_socket_mode)?;
+        info!("Listening started on {}, ready to accept local connections", path.display());
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            info!("Accepting a new local connection");
+            self.spawn_connection(conn_handler, |private_key| async move {
+                InboundConnection::with_unix_stream(stream, private_key)
+            });
+        }
+    }
+
+    /// Run the handshake for a new connection on its own task, then hand it to
+    /// `conn_handler`.
+    fn spawn_connection<F, Fut, O, OFut>(&self, conn_handler: F, open: O)
+    where
+        F: Fn(InboundConnection<TransferState>, &Arc<Mutex<ConnectionState>>) -> Fut + Send + Copy + 'static,
+        Fut: Future<Output = Result<(), ()>> + Send + 'static,
+        O: FnOnce(Rsa<Private>) -> OFut,
+        OFut: Future<Output = io::Result<InboundConnection<HandshakeState>>> + Send + 'static,
+    {
+        let state_rc = self.state.clone();
+        let private_key = state_rc.lock().unwrap().private_key.clone();
+        let compression = self.compression.clone();
+        let max_frame_length = self.max_frame_length;
+        let registry = self.registry.clone();
+        let outbox = state_rc.lock().unwrap().outbox.clone();
+        let connection_handshake = open(private_key);
+        tokio::spawn(async move {
+            let mut connection_handshake = match connection_handshake.await {
+                Ok(connection_handshake) => connection_handshake,
+                Err(e) => {
+                    error!("Failed to open connection: {e}");
+                    return;
+                }
+            };
+            connection_handshake.set_compression(compression);
+            connection_handshake.set_max_frame_length(max_frame_length);
+            match connection_handshake.begin().await {
+                Ok(_) => {
+                    let mut connection_transfer = InboundConnection::<TransferState>::from(connection_handshake);
+                    if let Err(e) = connection_transfer.exchange_capabilities(&registry).await {
+                        error!("Capability exchange failed: {e}");
+                        return;
                     }
-                    Err(e) => {
-                        error!("Handshake failed: {e}");
+                    // A follower that was unreachable is likely back
+                    if let Some(peer) = connection_transfer.peer_hostname() {
+                        let rescheduled = peer.to_string();
+                        if let Err(e) = blocking(&outbox, move |outbox| outbox.retry_now(&rescheduled)).await {
+                            error!("Failed to reschedule deliveries to {peer}: {e}");
+                        }
                     }
+
+                    let _ = conn_handler(connection_transfer, &state_rc).await;
                 }
-            });
-        }
+                Err(e) => {
+                    error!("Handshake failed: {e}");
+                }
+            }
+        });
     }
 
-    pub async fn create_outbound(&self, url: OSPUrl) -> io::Result<()> {
+    pub async fn create_outbound(&self, url: OSPUrl) -> io::Result<OutboundConnection<outbound::TransferState>> {
         info!("Starting outbound connection to {url}");
+        let private_key = self.state.lock().unwrap().private_key.clone();
+        let (transport, websocket_path) = self.peer_transports.get(&url.domain)
+            .cloned()
+            .unwrap_or((Transport::Tcp, websocket::DEFAULT_PATH.to_string()));
         let mut conn = OutboundConnection::create(
             url,
-            self.state.lock().unwrap().private_key.clone(),
+            private_key,
             self.hostname.clone()
         ).await?;
+        conn.set_compression(self.compression.clone());
+        conn.set_max_frame_length(self.max_frame_length);
+        conn.set_transport(transport);
+        conn.set_websocket_path(websocket_path);
+        if let Some(config) = &self.tls_client_config {
+            conn.set_tls(config.clone());
+        }
         let mut conn_in_handshake = conn.begin().await?;
-        conn_in_handshake.handshake().await
+        conn_in_handshake.handshake().await?;
+        let mut conn = OutboundConnection::<outbound::TransferState>::from(conn_in_handshake);
+        conn.exchange_capabilities(&self.registry).await?;
+        Ok(conn)
+    }
+
+    /// Encode `value` with `codec` and sign it as object `object_id` created
+    /// on this node, so receivers can check it wasn't changed on the way.
+    pub fn sign_data<T: Data + Encode>(&self, object_id: String, value: &T, codec: DataCodec) -> io::Result<SignedData> {
+        let data = RawData::encode(value, codec).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.sign_raw(object_id, data)
+    }
+
+    /// Sign an already encoded payload as object `object_id` created on this
+    /// node.
+    pub fn sign_raw(&self, object_id: String, data: RawData) -> io::Result<SignedData> {
+        let private_key = self.state.lock().unwrap().private_key.clone();
+        envelope::sign(&private_key, self.hostname.clone(), object_id, data)
+    }
+
+    /// Sign `value` as object `object_id` and send it to every connected peer
+    /// subscribed to its type, either without a topic or under `topic`. Each
+    /// peer gets it in the codec negotiated with it, peers that can't receive
+    /// the type are skipped. Returns how many peers it was sent to.
+    ///
+    /// Nodes following the type get it through the outbox, which keeps
+    /// retrying while the node listens until each of them acknowledges it,
+    /// see [OSProtocolNode::listen]. It is
+    /// also added to the node's feed, for followers catching up with
+    /// [OSProtocolNode::sync].
+    pub async fn publish<T: Data + Encode>(&self, object_id: String, topic: Option<String>, value: &T) -> io::Result<usize> {
+        // Which codec a follower takes is only known once connected, so the
+        // feed and the outbox keep the object in all of them
+        let envelopes = T::codecs().iter()
+            .map(|codec| self.sign_data(object_id.clone(), value, *codec))
+            .collect::<io::Result<Vec<_>>>()?;
+        let (feed, followers, outbox) = {
+            let state = self.state.lock().unwrap();
+            (state.feed.clone(), state.follows.followers(&T::get_id()), state.outbox.clone())
+        };
+        feed.append(topic.clone(), envelopes.clone()).await?;
+
+        let envelope = |codec| match envelopes.iter().find(|envelope| envelope.data.codec == codec) {
+            Some(envelope) => Ok(envelope.clone()),
+            None => self.sign_data(object_id.clone(), value, codec),
+        };
+        let sent = self.subscriptions.publish(T::get_id(), topic.clone(), envelope).await?;
+
+        for follower in followers {
+            let (queued, topic, envelopes) = (follower.clone(), topic.clone(), envelopes.clone());
+            blocking(&outbox, move |outbox| outbox.push(&queued, topic, envelopes)).await?;
+            if let Some(claim) = outbox.claim(&follower) {
+                self.spawn_delivery(claim);
+            }
+        }
+        Ok(sent)
+    }
+
+    fn spawn_delivery(&self, claim: Claim) {
+        let node = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = node.deliver(&claim).await {
+                warn!("Failed to deliver to {}: {e}", claim.peer());
+            }
+        });
+    }
+
+    /// Connect to the claimed peer and deliver its due outbox entries,
+    /// including any queued in the meantime. Whatever isn't acknowledged is
+    /// retried later.
+    async fn deliver(&self, claim: &Claim) -> io::Result<()> {
+        let entries = claim.due();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let outbox = self.state.lock().unwrap().outbox.clone();
+        let url = OSPUrl { domain: claim.peer().to_string(), port: None, resource: None };
+        let conn = match self.create_outbound(url).await {
+            Ok(conn) => conn,
+            Err(e) => {
+                let reason = e.to_string();
+                blocking(&outbox, move |outbox| outbox.retry_all_later(&entries, reason)).await?;
+                return Err(e);
+            }
+        };
+        let capabilities = conn.peer_capabilities().clone();
+        let (sender, mut receiver) = conn.split();
+        let mut delivered = outbox::deliver(&outbox, &sender, &mut receiver, &capabilities, entries).await;
+        while delivered.is_ok() {
+            let entries = claim.due();
+            if entries.is_empty() {
+                break;
+            }
+            delivered = outbox::deliver(&outbox, &sender, &mut receiver, &capabilities, entries).await;
+        }
+        close_quietly(&sender, receiver).await?;
+        delivered
+    }
+
+    /// Where the delivery of `object_id` to each of the followers it was
+    /// published to stands, including why it failed for those it didn't
+    /// reach.
+    pub fn delivery_status(&self, object_id: &str) -> io::Result<Vec<DeliveryStatus>> {
+        let outbox = self.state.lock().unwrap().outbox.clone();
+        outbox.object_status(object_id)
+    }
+
+    /// Where the delivery of each object published to `peer` stands.
+    pub fn peer_delivery_status(&self, peer: &str) -> io::Result<Vec<DeliveryStatus>> {
+        let outbox = self.state.lock().unwrap().outbox.clone();
+        outbox.peer_status(peer)
+    }
+
+    /// Deliveries that were given up on, with the reason and attempt count.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.state.lock().unwrap().outbox.dead_letters()
+    }
+
+    /// Try delivering a dead letter again, starting over its attempts.
+    pub fn retry_dead_letter(&self, dead_letter: &DeadLetter) -> io::Result<()> {
+        let outbox = self.state.lock().unwrap().outbox.clone();
+        outbox.requeue(dead_letter)?;
+        if let Some(claim) = outbox.claim(&dead_letter.peer) {
+            self.spawn_delivery(claim);
+        }
+        Ok(())
+    }
+
+    /// Ask the node at `url` to push new objects of `data_types` to this node
+    /// from now on, over a new connection. Fails with
+    /// [io::ErrorKind::PermissionDenied] if it rejects the request.
+    pub async fn follow(&self, url: &OSPUrl, data_types: Vec<Uuid>) -> io::Result<()> {
+        let conn = self.create_outbound(url.node()).await?;
+        let unsupported = data_types.iter().find(|data_type| !conn.peer_capabilities().can_receive(data_type)).copied();
+        let (sender, mut receiver) = conn.split();
+        let followed = match unsupported {
+            Some(data_type) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{url} can't send objects of type {data_type}")
+            )),
+            None => follow::request_follow(&sender, &mut receiver, data_types).await,
+        };
+        close_quietly(&sender, receiver).await?;
+        followed
+    }
+
+    /// Stop following `data_types` on the node at `url`.
+    pub async fn unfollow(&self, url: &OSPUrl, data_types: Vec<Uuid>) -> io::Result<()> {
+        let (sender, receiver) = self.create_outbound(url.node()).await?.split();
+        sender.send(TransferPacket::Unfollow { data_types }).await?;
+        close_quietly(&sender, receiver).await
+    }
+
+    /// Catch up on the objects of `data_types` the node at `url` published
+    /// since the last sync with it, or on every type we follow there if
+    /// `data_types` is empty, over a new connection. Fails with
+    /// [io::ErrorKind::Unsupported] if the node can't send us one of the
+    /// types. The node refuses to sync types we don't follow.
+    ///
+    /// A cursor is kept per node for each set of types synced, so syncing
+    /// some types doesn't move past the objects of the others.
+    pub async fn sync(&self, url: &OSPUrl, data_types: Vec<Uuid>) -> io::Result<CatchUp> {
+        let conn = self.create_outbound(url.node()).await?;
+        let unsupported = data_types.iter().find(|data_type| !conn.peer_capabilities().can_receive(data_type)).copied();
+        let (sender, receiver) = conn.split();
+        if let Some(data_type) = unsupported {
+            close_quietly(&sender, receiver).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{url} can't send objects of type {data_type}")
+            ));
+        }
+        let (cursors, verifier) = {
+            let state = self.state.lock().unwrap();
+            (state.cursors.clone(), state.verifier.clone())
+        };
+        CatchUp::open(url.domain.clone(), data_types, MAX_PAGE_OBJECTS, cursors, sender, receiver, verifier).await
+    }
+
+    /// Subscribe to new objects of `data_type` on the node at `url`, or only
+    /// to those published under `topic`, over a new connection. Fails with
+    /// [io::ErrorKind::Unsupported] if the node can't send us the type.
+    pub async fn subscribe(&self, url: &OSPUrl, data_type: Uuid, topic: Option<String>) -> io::Result<Subscription> {
+        let conn = self.create_outbound(url.node()).await?;
+        let receivable = conn.peer_capabilities().can_receive(&data_type);
+        let (sender, receiver) = conn.split();
+        if !receivable {
+            close_quietly(&sender, receiver).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{url} can't send objects of type {data_type}")
+            ));
+        }
+        let verifier = self.state.lock().unwrap().verifier.clone();
+        Subscription::open(sender, receiver, data_type, topic, verifier).await
+    }
+
+    /// Check `envelope`'s signature against the key its origin publishes at
+    /// `_osp.<origin>`, whichever peer it arrived from. Fails with
+    /// [io::ErrorKind::PermissionDenied] if it was tampered with.
+    pub async fn verify_data(&self, envelope: &SignedData) -> io::Result<()> {
+        let verifier = self.state.lock().unwrap().verifier.clone();
+        verifier.verify(envelope).await
+    }
+
+    /// Dereference `url`, which must name an object of type `T`, by fetching
+    /// the object from its node over a new connection and decoding it. The
+    /// node answers from its [OSProtocolNode::set_resource_provider].
+    pub async fn fetch<T: Data + Decode>(&self, url: &OSPUrl) -> io::Result<T> {
+        let resource = resource_of(url)?;
+        if resource.data_type != T::get_id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{url} names an object of type {}, not {}", resource.data_type, T::get_id())
+            ));
+        }
+
+        let (value, _) = T::decode_from_bytes(&self.fetch_bytes(url, resource).await?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(value)
+    }
+
+    /// Like [OSProtocolNode::fetch], but decodes the object with whichever
+    /// type is registered for it in the node's [DataRegistry].
+    pub async fn fetch_any(&self, url: &OSPUrl) -> io::Result<AnyData> {
+        let resource = resource_of(url)?;
+        if !self.registry.contains(&resource.data_type) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{url} names an object of unregistered type {}", resource.data_type)
+            ));
+        }
+
+        let data = self.fetch_bytes(url, resource).await?;
+        self.registry.decode(resource.data_type, &data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Ask the node at `url` what `data_type` contains, over a new connection.
+    /// Fails with [io::ErrorKind::NotFound] if it has no schema for the type.
+    ///
+    /// Schema requests reach the peer's connection handler like any other
+    /// packet. [handler::handle] answers them, but a peer with a handler of
+    /// its own that ignores them leaves this waiting until the connection
+    /// closes, so wrap it in a timeout when the peer isn't known to answer.
+    ///
+    /// [handler::handle]: crate::handler::handle
+    pub async fn fetch_schema(&self, url: &OSPUrl, data_type: Uuid) -> io::Result<DataSchema> {
+        let (sender, mut receiver) = self.create_outbound(url.node()).await?.split();
+        let fetched = schema::fetch_schema(&sender, &mut receiver, data_type).await;
+        close_quietly(&sender, receiver).await?;
+        fetched
+    }
+
+    async fn fetch_bytes(&self, url: &OSPUrl, resource: &ResourcePath) -> io::Result<Bytes> {
+        let (sender, mut receiver) = self.create_outbound(url.node()).await?.split();
+        let fetched = fetch::fetch_bytes(&sender, &mut receiver, resource, self.max_object_length).await;
+        close_quietly(&sender, receiver).await?;
+        Ok(Bytes::from(fetched?))
+    }
+}
+
+/// Bind a Unix socket at `path` that only `mode` allows connecting to. The
+/// socket is bound inside a directory only we can enter and moved into place
+/// once its mode is set, so it is never reachable with the permissions the
+/// umask gave it.
+fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let private = parent.join(format!(".osp-{}", &Uuid::new_v4().simple().to_string()[..8]));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join("s");
+    let listener = UnixListener::bind(&bound)
+        .and_then(|listener| fs::set_permissions(&bound, fs::Permissions::from_mode(mode)).map(|()| listener))
+        .and_then(|listener| fs::rename(&bound, path).map(|()| listener));
+    if listener.is_err() {
+        let _ = fs::remove_file(&bound);
+    }
+    fs::remove_dir(&private)?;
+    listener
+}
+
+fn resource_of(url: &OSPUrl) -> io::Result<&ResourcePath> {
+    url.resource.as_ref().ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{url} does not name an object")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+    use std::time::Duration;
+
+    use openssl::rsa::Rsa;
+
+    use tokio::io;
+    use tokio::net::UnixStream;
+    use tokio_stream::StreamExt;
+
+    use uuid::Uuid;
+
+    use osp_data::DataRegistry;
+    use osp_protocol::{ConnectionType, ResourcePath};
+    use osp_protocol::packet::{PACKET_MAX_LENGTH, PACKET_MIN_MAX_LENGTH};
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::{fetch, handler, OSProtocolNode, DEFAULT_MAX_OBJECT_LENGTH};
+    use crate::connection::outbound::{self, OutboundConnection};
+
+    /// Connect to the node listening on the Unix socket at `path` once it is
+    /// there, accepting frames of up to `max_frame_length` bytes.
+    async fn connect_local(path: &Path, max_frame_length: usize) -> io::Result<OutboundConnection<outbound::TransferState>> {
+        // Wait until the node is listening, rather than for anything at all
+        // to be at the path
+        while UnixStream::connect(path).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut connection = OutboundConnection::create_with_unix_socket(path.to_path_buf(), "client".to_string());
+        connection.set_max_frame_length(max_frame_length);
+        let mut handshake = connection.begin().await?;
+        handshake.handshake().await?;
+        let mut connection = OutboundConnection::<outbound::TransferState>::from(handshake);
+        connection.exchange_capabilities(&DataRegistry::new()).await?;
+        Ok(connection)
+    }
+
+    /// A local client connects through the Unix socket without a key, and
+    /// the handler sees it as a client with its credentials. Only the node's
+    /// own user can use the socket.
+    #[tokio::test]
+    async fn test_unix_socket_round_trip() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("osp-{}.sock", Uuid::new_v4()));
+        // A socket left behind by a node that didn't shut down cleanly
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        let mut node = OSProtocolNode::new();
+        node.set_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+        node.set_unix_socket_path(path.clone());
+        node.state.lock().unwrap().private_key = Some(Rsa::generate(2048)?);
+        let mut node = node.init()?;
+        let listener = tokio::spawn(async move {
+            node.listen(|connection, _| async move {
+                assert_eq!(connection.connection_type(), ConnectionType::Client);
+                let uid = connection.peer_credentials().expect("Local connections have credentials").uid();
+                let (sender, mut receiver) = connection.split();
+                while let Some(Ok(packet)) = receiver.next().await {
+                    if let TransferPacket::Ping { .. } = packet {
+                        // Tell the client who it was seen as
+                        sender.send(TransferPacket::Pong { nonce: uid as u64 }).await.map_err(|_| ())?;
+                    }
+                }
+                Ok(())
+            }).await
+        });
+
+        let (sender, mut receiver) = connect_local(&path, PACKET_MAX_LENGTH).await?.split();
+        let metadata = std::fs::metadata(&path)?;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        sender.send(TransferPacket::Ping { nonce: 1 }).await?;
+        match receiver.next().await.unwrap()? {
+            TransferPacket::Pong { nonce } => assert_eq!(nonce, metadata.uid() as u64),
+            _ => panic!("Expected a pong"),
+        }
+
+        listener.abort();
+        std::fs::remove_file(path)
+    }
+    #[test]
+    fn test_max_frame_length_bounds() -> io::Result<()> {
+        let mut node = OSProtocolNode::new();
+        node.set_max_frame_length(PACKET_MIN_MAX_LENGTH)?;
+        for invalid in [PACKET_MIN_MAX_LENGTH - 1, u32::MAX as usize + 1] {
+            assert_eq!(node.set_max_frame_length(invalid).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(node.max_frame_length, PACKET_MIN_MAX_LENGTH);
+        Ok(())
+    }
+
+    /// Objects the node's resource provider has are fetched in frame sized
+    /// chunks through the default handler, others fail to fetch.
+    #[tokio::test]
+    async fn test_fetch_from_listening_node() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("osp-{}.sock", Uuid::new_v4()));
+        let object: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let served = object.clone();
+        let mut node = OSProtocolNode::new();
+        node.set_addr(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+        node.set_unix_socket_path(path.clone());
+        node.set_resource_provider(move |resource| match resource.object_id.as_str() {
+            "post/1" => Ok(served.clone()),
+            object_id => Err(format!("No object {object_id}")),
+        });
+        node.state.lock().unwrap().private_key = Some(Rsa::generate(2048)?);
+        let mut node = node.init()?;
+        let listener = tokio::spawn(async move { node.listen(handler::handle).await });
+
+        // Chunks have to fit in the client's frames, not the node's
+        let connection = connect_local(&path, PACKET_MIN_MAX_LENGTH).await?;
+        let (sender, mut receiver) = connection.split();
+        let mut resource = ResourcePath { data_type: Uuid::new_v4(), object_id: "post/1".to_string(), version: None };
+        assert_eq!(fetch::fetch_bytes(&sender, &mut receiver, &resource, DEFAULT_MAX_OBJECT_LENGTH).await?, object);
+        resource.object_id = "post/2".to_string();
+        let err = fetch::fetch_bytes(&sender, &mut receiver, &resource, DEFAULT_MAX_OBJECT_LENGTH).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        listener.abort();
+        std::fs::remove_file(path)
     }
 }
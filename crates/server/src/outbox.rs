@@ -0,0 +1,854 @@
+//! # Outbox
+//!
+//! Objects pushed to followers go through a per-peer [Outbox] kept in the
+//! node's [Storage]. An entry stays until the follower answers its
+//! [TransferPacket::Publish] with a [TransferPacket::Ack], and is retried with
+//! exponential backoff while the follower can't be reached, across reconnects
+//! and restarts. Followers may see an object more than once and drop the
+//! repeats, see [Inbox].
+//!
+//! Entries the follower rejects with a [TransferPacket::Reject], or that
+//! still fail after [MAX_ATTEMPTS], are moved to the dead letters along with
+//! the reason, where they stay until requeued or discarded. The outbox also
+//! remembers when each object was delivered, for [DELIVERED_RETENTION], so
+//! [Outbox::object_status] and [Outbox::peer_status] can tell what became of
+//! it.
+//!
+//! [Inbox]: crate::inbox::Inbox
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bincode::{Decode, Encode};
+
+use log::{debug, warn};
+
+use tokio::io;
+use tokio::sync::Notify;
+
+use uuid::Uuid;
+
+use osp_data::{DataCodec, PeerCapabilities, SignedData};
+use osp_protocol::{ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::request::request_reply;
+use crate::storage::{blocking, decode, encode, quarantine, Storage};
+
+const KEY_PREFIX: &str = "outbox/";
+const DEAD_LETTER_PREFIX: &str = "dead-letters/";
+const DELIVERED_PREFIX: &str = "delivered/";
+const DELIVERED_BY_OBJECT_PREFIX: &str = "delivered-by-object/";
+const DELIVERED_AT_PREFIX: &str = "delivered-at/";
+
+/// How many delivery records are read from storage at a time while dropping
+/// old ones
+const EXPIRE_BATCH: usize = 128;
+
+/// How long to wait before the first retry. Each further retry waits twice
+/// as long as the one before, up to [RETRY_MAX].
+pub const RETRY_INITIAL: Duration = Duration::from_secs(5);
+pub const RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+
+/// How long a peer has to acknowledge an object before it counts as a
+/// failed attempt.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many failed deliveries an entry gets before it becomes a dead letter.
+/// With the backoff above that is about 14 hours of trying.
+pub const MAX_ATTEMPTS: u32 = 24;
+
+/// How long the outbox remembers that an object was delivered, for
+/// [Outbox::object_status] and [Outbox::peer_status].
+pub const DELIVERED_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// One object waiting to be delivered to one peer.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OutboxEntry {
+    key: String,
+    pub peer: String,
+    pub topic: Option<String>,
+    /// The object in each codec its type supports. The peer is sent the one
+    /// negotiated with it.
+    pub envelopes: Vec<SignedData>,
+    /// How many deliveries have failed so far
+    pub attempts: u32,
+    /// When to try next, in milliseconds since the Unix epoch
+    pub next_attempt: u64,
+    /// Why the last delivery failed
+    pub last_error: Option<String>,
+}
+
+impl OutboxEntry {
+    pub fn data_type(&self) -> Uuid {
+        self.envelopes[0].type_id()
+    }
+
+    pub fn object_id(&self) -> &str {
+        &self.envelopes[0].object_id
+    }
+
+    pub fn envelope(&self, codec: DataCodec) -> Option<&SignedData> {
+        self.envelopes.iter().find(|envelope| envelope.data.codec == codec)
+    }
+}
+
+/// What is kept in storage for an [OutboxEntry], whose key holds the peer
+#[derive(Encode, Decode)]
+struct StoredEntry {
+    topic: Option<String>,
+    envelopes: Vec<SignedData>,
+    attempts: u32,
+    next_attempt: u64,
+    last_error: Option<String>,
+}
+
+/// An entry that was given up on.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DeadLetter {
+    key: String,
+    pub peer: String,
+    pub topic: Option<String>,
+    pub envelopes: Vec<SignedData>,
+    /// How many deliveries were tried
+    pub attempts: u32,
+    /// Why the peer rejected it, or why the last delivery failed
+    pub reason: String,
+    /// When it was given up on, in milliseconds since the Unix epoch
+    pub failed_at: u64,
+}
+
+impl DeadLetter {
+    pub fn object_id(&self) -> &str {
+        &self.envelopes[0].object_id
+    }
+}
+
+/// What is kept in storage for a [DeadLetter], whose key holds the peer
+#[derive(Encode, Decode)]
+struct StoredDeadLetter {
+    topic: Option<String>,
+    envelopes: Vec<SignedData>,
+    attempts: u32,
+    reason: String,
+    failed_at: u64,
+}
+
+/// Where the delivery of one object to one peer stands.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DeliveryStatus {
+    pub peer: String,
+    pub object_id: String,
+    pub state: DeliveryState,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DeliveryState {
+    /// Waiting for its first delivery or a retry
+    Pending {
+        attempts: u32,
+        next_attempt: u64,
+        last_error: Option<String>,
+    },
+    /// Acknowledged by the peer at `at`
+    Delivered {
+        at: u64,
+    },
+    /// Given up on, see [Outbox::dead_letters]
+    Failed {
+        attempts: u32,
+        reason: String,
+        at: u64,
+    },
+}
+
+/// The objects waiting to be delivered to each peer. Entries are kept in
+/// memory as well as in storage, which is only read when opening.
+pub struct Outbox {
+    storage: Arc<dyn Storage>,
+    /// Entries for each peer, oldest first
+    peers: Mutex<BTreeMap<String, Vec<OutboxEntry>>>,
+    /// Dead letters by key, so by peer and then oldest first
+    dead_letters: Mutex<BTreeMap<String, DeadLetter>>,
+    /// Numbers entries in the order they were queued, carrying on from the
+    /// entries in storage
+    next_sequence: AtomicU64,
+    /// Peers a delivery is currently running for
+    delivering: Mutex<HashSet<String>>,
+    changed: Notify,
+}
+
+impl Outbox {
+    /// Load the entries already in `storage`. Entries that can't be decoded
+    /// are quarantined, see [crate::storage].
+    pub fn open(storage: Arc<dyn Storage>) -> io::Result<Self> {
+        let mut peers: BTreeMap<String, Vec<OutboxEntry>> = BTreeMap::new();
+        let mut last_sequence = 0;
+        for key in storage.keys(KEY_PREFIX)? {
+            let Some(contents) = storage.get(&key)? else {
+                continue;
+            };
+            let Some((peer, sequence)) = key[KEY_PREFIX.len()..].split_once('/') else {
+                warn!("Skipping outbox entry with malformed key {key}");
+                continue;
+            };
+            last_sequence = last_sequence.max(sequence.parse().unwrap_or(0));
+            let stored = decode::<StoredEntry>(&contents)
+                .and_then(|stored| check_envelopes(&stored.envelopes).map(|()| stored));
+            let stored = match stored {
+                Ok(stored) => stored,
+                Err(e) => {
+                    quarantine(storage.as_ref(), &key, &contents, &e)?;
+                    continue;
+                }
+            };
+            peers.entry(peer.to_string()).or_default().push(OutboxEntry {
+                peer: peer.to_string(),
+                key,
+                topic: stored.topic,
+                envelopes: stored.envelopes,
+                attempts: stored.attempts,
+                next_attempt: stored.next_attempt,
+                last_error: stored.last_error,
+            });
+        }
+
+        let mut dead_letters = BTreeMap::new();
+        for key in storage.keys(DEAD_LETTER_PREFIX)? {
+            let Some(contents) = storage.get(&key)? else {
+                continue;
+            };
+            let Some((peer, sequence)) = key[DEAD_LETTER_PREFIX.len()..].split_once('/') else {
+                warn!("Skipping dead letter with malformed key {key}");
+                continue;
+            };
+            last_sequence = last_sequence.max(sequence.parse().unwrap_or(0));
+            let stored = decode::<StoredDeadLetter>(&contents)
+                .and_then(|stored| check_envelopes(&stored.envelopes).map(|()| stored));
+            let stored = match stored {
+                Ok(stored) => stored,
+                Err(e) => {
+                    quarantine(storage.as_ref(), &key, &contents, &e)?;
+                    continue;
+                }
+            };
+            dead_letters.insert(key.clone(), DeadLetter {
+                peer: peer.to_string(),
+                key,
+                topic: stored.topic,
+                envelopes: stored.envelopes,
+                attempts: stored.attempts,
+                reason: stored.reason,
+                failed_at: stored.failed_at,
+            });
+        }
+
+        Ok(Outbox {
+            storage,
+            peers: Mutex::new(peers),
+            dead_letters: Mutex::new(dead_letters),
+            next_sequence: AtomicU64::new(last_sequence + 1),
+            delivering: Mutex::new(HashSet::new()),
+            changed: Notify::new(),
+        })
+    }
+
+    /// Queue an object for `peer`, due right away. `envelopes` holds the
+    /// object in each codec its type supports.
+    pub fn push(&self, peer: &str, topic: Option<String>, envelopes: Vec<SignedData>) -> io::Result<()> {
+        if envelopes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "No envelopes to queue"));
+        }
+        let now = now_millis();
+        // Padded so keys sort in the order entries were queued
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let key = format!("{KEY_PREFIX}{peer}/{sequence:020}");
+        let entry = OutboxEntry { key, peer: peer.to_string(), topic, envelopes, attempts: 0, next_attempt: now, last_error: None };
+        self.store(&entry)?;
+        self.peers.lock().unwrap().entry(peer.to_string()).or_default().push(entry);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Every entry waiting for `peer`, oldest first
+    pub fn pending(&self, peer: &str) -> Vec<OutboxEntry> {
+        self.peers.lock().unwrap().get(peer).cloned().unwrap_or_default()
+    }
+
+    /// Every peer with entries waiting for it
+    pub fn peers(&self) -> Vec<String> {
+        self.peers.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Make all of `peer`'s entries due now, for instance because it just
+    /// connected to us.
+    pub fn retry_now(&self, peer: &str) -> io::Result<()> {
+        let now = now_millis();
+        let rescheduled: Vec<_> = self.peers.lock().unwrap().get_mut(peer).into_iter().flatten()
+            .filter(|entry| entry.next_attempt > now)
+            .map(|entry| {
+                entry.next_attempt = now;
+                entry.clone()
+            })
+            .collect();
+        for entry in &rescheduled {
+            self.store(entry)?;
+        }
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Every dead letter, by peer and then oldest first
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.dead_letters.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Queue a dead letter for its peer again, with a fresh set of attempts.
+    pub fn requeue(&self, dead_letter: &DeadLetter) -> io::Result<()> {
+        self.discard(dead_letter)?;
+        self.push(&dead_letter.peer, dead_letter.topic.clone(), dead_letter.envelopes.clone())
+    }
+
+    /// Forget a dead letter.
+    pub fn discard(&self, dead_letter: &DeadLetter) -> io::Result<()> {
+        self.storage.delete(&dead_letter.key)?;
+        self.dead_letters.lock().unwrap().remove(&dead_letter.key);
+        Ok(())
+    }
+
+    /// Where the delivery of `object_id` stands for each peer it was queued
+    /// for.
+    pub fn object_status(&self, object_id: &str) -> io::Result<Vec<DeliveryStatus>> {
+        let mut statuses = self.statuses(|_, queued_object_id| queued_object_id == object_id);
+        let prefix = format!("{DELIVERED_BY_OBJECT_PREFIX}{object_id}/");
+        for key in self.storage.keys(&prefix)? {
+            // Object ids can contain `/`, peers can't, so anything with one
+            // left belongs to a longer object id
+            let peer = &key[prefix.len()..];
+            if !peer.contains('/') {
+                statuses.extend(self.delivered(peer, object_id)?);
+            }
+        }
+        Ok(statuses)
+    }
+
+    /// Where the delivery of each object queued for `peer` stands.
+    pub fn peer_status(&self, peer: &str) -> io::Result<Vec<DeliveryStatus>> {
+        let mut statuses = self.statuses(|queued_peer, _| queued_peer == peer);
+        for key in self.storage.keys(&format!("{DELIVERED_PREFIX}{peer}/"))? {
+            let object_id = &key[DELIVERED_PREFIX.len() + peer.len() + 1..];
+            statuses.extend(self.delivered(peer, object_id)?);
+        }
+        Ok(statuses)
+    }
+
+    /// The pending entries and dead letters matching `filter`, which is
+    /// given the peer and object id
+    fn statuses(&self, filter: impl Fn(&str, &str) -> bool) -> Vec<DeliveryStatus> {
+        let pending: Vec<_> = self.peers.lock().unwrap().values().flatten()
+            .filter(|entry| filter(&entry.peer, entry.object_id()))
+            .map(|entry| DeliveryStatus {
+                peer: entry.peer.clone(),
+                object_id: entry.object_id().to_string(),
+                state: DeliveryState::Pending {
+                    attempts: entry.attempts,
+                    next_attempt: entry.next_attempt,
+                    last_error: entry.last_error.clone(),
+                },
+            })
+            .collect();
+        let failed: Vec<_> = self.dead_letters.lock().unwrap().values()
+            .filter(|dead_letter| filter(&dead_letter.peer, dead_letter.object_id()))
+            .map(|dead_letter| DeliveryStatus {
+                peer: dead_letter.peer.clone(),
+                object_id: dead_letter.object_id().to_string(),
+                state: DeliveryState::Failed {
+                    attempts: dead_letter.attempts,
+                    reason: dead_letter.reason.clone(),
+                    at: dead_letter.failed_at,
+                },
+            })
+            .collect();
+        pending.into_iter().chain(failed).collect()
+    }
+
+    fn delivered(&self, peer: &str, object_id: &str) -> io::Result<Option<DeliveryStatus>> {
+        let at = self.storage.get(&format!("{DELIVERED_PREFIX}{peer}/{object_id}"))?
+            .and_then(|at| at.try_into().ok())
+            .map(u64::from_be_bytes);
+        Ok(at.map(|at| DeliveryStatus {
+            peer: peer.to_string(),
+            object_id: object_id.to_string(),
+            state: DeliveryState::Delivered { at },
+        }))
+    }
+
+    /// Remove an entry the peer acknowledged.
+    pub(crate) fn acknowledge(&self, entry: &OutboxEntry) -> io::Result<()> {
+        let now = now_millis();
+        self.record_delivery(&entry.peer, entry.object_id(), now)?;
+        self.remove(entry)?;
+        self.expire_deliveries(now.saturating_sub(DELIVERED_RETENTION.as_millis() as u64))
+    }
+
+    /// Remember that `object_id` reached `peer` at `at`, under the peer, the
+    /// object, and the time so old records can be dropped in order.
+    fn record_delivery(&self, peer: &str, object_id: &str, at: u64) -> io::Result<()> {
+        self.storage.put(&format!("{DELIVERED_PREFIX}{peer}/{object_id}"), &at.to_be_bytes())?;
+        self.storage.put(&format!("{DELIVERED_BY_OBJECT_PREFIX}{object_id}/{peer}"), &at.to_be_bytes())?;
+        self.storage.put(&format!("{DELIVERED_AT_PREFIX}{at:020}/{peer}/{object_id}"), &[])
+    }
+
+    /// Forget the deliveries made before `before`. A record replaced by a
+    /// later delivery of the same object to the same peer is kept.
+    fn expire_deliveries(&self, before: u64) -> io::Result<()> {
+        loop {
+            let keys = self.storage.keys_after(DELIVERED_AT_PREFIX, None, EXPIRE_BATCH)?;
+            for key in &keys {
+                let Some((at, peer, object_id)) = key[DELIVERED_AT_PREFIX.len()..].split_once('/')
+                    .and_then(|(at, rest)| Some((at.parse::<u64>().ok()?, rest.split_once('/')?)))
+                    .map(|(at, (peer, object_id))| (at, peer, object_id)) else {
+                    warn!("Dropping delivery record with malformed key {key}");
+                    self.storage.delete(key)?;
+                    continue;
+                };
+                if at >= before {
+                    return Ok(());
+                }
+                if self.delivered(peer, object_id)?.is_some_and(|status| status.state == DeliveryState::Delivered { at }) {
+                    self.storage.delete(&format!("{DELIVERED_PREFIX}{peer}/{object_id}"))?;
+                    self.storage.delete(&format!("{DELIVERED_BY_OBJECT_PREFIX}{object_id}/{peer}"))?;
+                }
+                self.storage.delete(key)?;
+            }
+            if keys.len() < EXPIRE_BATCH {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Move an entry the peer rejected to the dead letters.
+    pub(crate) fn reject(&self, entry: &OutboxEntry, reason: String) -> io::Result<()> {
+        warn!("{} rejected {}: {reason}", entry.peer, entry.object_id());
+        self.kill(entry, entry.attempts + 1, reason)
+    }
+
+    /// Count a failed delivery of `entry` and schedule the next attempt, or
+    /// move it to the dead letters once it has run out of attempts.
+    pub(crate) fn retry_later(&self, entry: &OutboxEntry, reason: String) -> io::Result<()> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(queued) = peers.get_mut(&entry.peer).and_then(|entries| entries.iter_mut().find(|queued| queued.key == entry.key)) else {
+            return Ok(());
+        };
+        queued.attempts += 1;
+        if queued.attempts >= MAX_ATTEMPTS {
+            let attempts = queued.attempts;
+            drop(peers);
+            warn!("Giving up on {} for {} after {attempts} attempts: {reason}", entry.object_id(), entry.peer);
+            return self.kill(entry, attempts, reason);
+        }
+        queued.next_attempt = now_millis() + backoff(queued.attempts).as_millis() as u64;
+        queued.last_error = Some(reason);
+        debug!("Retrying {} for {} in {:?}", queued.object_id(), queued.peer, backoff(queued.attempts));
+        let queued = queued.clone();
+        drop(peers);
+        self.store(&queued)
+    }
+
+    /// Count a failed delivery of each of `entries`, see
+    /// [Outbox::retry_later].
+    pub(crate) fn retry_all_later(&self, entries: &[OutboxEntry], reason: String) -> io::Result<()> {
+        for entry in entries {
+            self.retry_later(entry, reason.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Replace `entry` with a dead letter.
+    fn kill(&self, entry: &OutboxEntry, attempts: u32, reason: String) -> io::Result<()> {
+        let dead_letter = DeadLetter {
+            key: format!("{DEAD_LETTER_PREFIX}{}", &entry.key[KEY_PREFIX.len()..]),
+            peer: entry.peer.clone(),
+            topic: entry.topic.clone(),
+            envelopes: entry.envelopes.clone(),
+            attempts,
+            reason,
+            failed_at: now_millis(),
+        };
+        let stored = StoredDeadLetter {
+            topic: dead_letter.topic.clone(),
+            envelopes: dead_letter.envelopes.clone(),
+            attempts,
+            reason: dead_letter.reason.clone(),
+            failed_at: dead_letter.failed_at,
+        };
+        self.storage.put(&dead_letter.key, &encode(stored)?)?;
+        self.dead_letters.lock().unwrap().insert(dead_letter.key.clone(), dead_letter);
+        self.remove(entry)
+    }
+
+    fn remove(&self, entry: &OutboxEntry) -> io::Result<()> {
+        self.storage.delete(&entry.key)?;
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(entries) = peers.get_mut(&entry.peer) {
+            entries.retain(|queued| queued.key != entry.key);
+            if entries.is_empty() {
+                peers.remove(&entry.peer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start delivering to `peer`, unless a delivery to it is already running.
+    pub(crate) fn claim(self: &Arc<Self>, peer: &str) -> Option<Claim> {
+        self.delivering.lock().unwrap().insert(peer.to_string())
+            .then(|| Claim { outbox: self.clone(), peer: peer.to_string() })
+    }
+
+    /// The peers with entries due at `now` that no delivery is running for
+    pub(crate) fn due_peers(&self, now: u64) -> Vec<String> {
+        let delivering = self.delivering.lock().unwrap();
+        self.peers.lock().unwrap().iter()
+            .filter(|(peer, entries)| !delivering.contains(*peer) && entries.iter().any(|entry| entry.next_attempt <= now))
+            .map(|(peer, _)| peer.clone())
+            .collect()
+    }
+
+    /// When the next entry no delivery is running for is due
+    pub(crate) fn next_due(&self) -> Option<u64> {
+        let delivering = self.delivering.lock().unwrap();
+        self.peers.lock().unwrap().iter()
+            .filter(|(peer, _)| !delivering.contains(*peer))
+            .flat_map(|(_, entries)| entries.iter().map(|entry| entry.next_attempt))
+            .min()
+    }
+
+    /// Wait until entries are queued, made due, or a delivery finishes.
+    pub(crate) async fn changed(&self) {
+        self.changed.notified().await
+    }
+
+    fn due(&self, peer: &str, now: u64) -> Vec<OutboxEntry> {
+        self.peers.lock().unwrap().get(peer).into_iter().flatten()
+            .filter(|entry| entry.next_attempt <= now)
+            .cloned()
+            .collect()
+    }
+
+    fn store(&self, entry: &OutboxEntry) -> io::Result<()> {
+        let stored = StoredEntry {
+            topic: entry.topic.clone(),
+            envelopes: entry.envelopes.clone(),
+            attempts: entry.attempts,
+            next_attempt: entry.next_attempt,
+            last_error: entry.last_error.clone(),
+        };
+        self.storage.put(&entry.key, &encode(stored)?)
+    }
+}
+
+/// A running delivery to one peer, see [Outbox::claim]. Dropping it lets the
+/// next delivery to the peer start.
+pub(crate) struct Claim {
+    outbox: Arc<Outbox>,
+    peer: String,
+}
+
+impl Claim {
+    pub(crate) fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// The claimed peer's entries that are due now
+    pub(crate) fn due(&self) -> Vec<OutboxEntry> {
+        self.outbox.due(&self.peer, now_millis())
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.outbox.delivering.lock().unwrap().remove(&self.peer);
+        self.outbox.changed.notify_one();
+    }
+}
+
+/// Send `entries` to the peer one at a time, each time waiting for it to be
+/// acknowledged. Entries the peer can't receive or rejects become dead
+/// letters, as retrying won't change its mind. On a connection error the entries that
+/// weren't acknowledged are all retried later and the error is returned.
+pub(crate) async fn deliver(
+    outbox: &Arc<Outbox>,
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    capabilities: &PeerCapabilities,
+    entries: Vec<OutboxEntry>,
+) -> io::Result<()> {
+    let mut entries = entries.into_iter();
+    while let Some(entry) = entries.next() {
+        let codec = capabilities.send_codec(&entry.data_type());
+        let Some(envelope) = codec.and_then(|codec| entry.envelope(codec)) else {
+            let reason = format!("{} can't receive objects of type {}", entry.peer, entry.data_type());
+            warn!("Not sending {}: {reason}", entry.object_id());
+            blocking(outbox, move |outbox| outbox.kill(&entry, entry.attempts + 1, reason)).await?;
+            continue;
+        };
+
+        let sent = tokio::time::timeout(ACK_TIMEOUT, publish_and_wait(sender, receiver, entry.topic.clone(), envelope.clone())).await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} wasn't acknowledged in time", entry.object_id()))));
+        match sent {
+            Ok(Ok(())) => blocking(outbox, move |outbox| outbox.acknowledge(&entry)).await?,
+            Ok(Err(reason)) => blocking(outbox, move |outbox| outbox.reject(&entry, reason)).await?,
+            Err(e) => {
+                let unsent: Vec<_> = std::iter::once(entry).chain(entries).collect();
+                let reason = e.to_string();
+                blocking(outbox, move |outbox| outbox.retry_all_later(&unsent, reason)).await?;
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Push `envelope` to the peer and wait for it to be acknowledged or
+/// rejected, see [request_reply]. A rejection comes with the peer's reason.
+async fn publish_and_wait(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    topic: Option<String>,
+    envelope: SignedData,
+) -> io::Result<Result<(), String>> {
+    let (object_id, created_at) = (envelope.object_id.clone(), envelope.created_at);
+    let request = TransferPacket::Publish { topic, envelope };
+    request_reply(sender, receiver, request, "a delivery", |packet| match packet {
+        TransferPacket::Ack { object_id: id, created_at: at } if id == object_id && at == created_at => Some(Ok(Ok(()))),
+        TransferPacket::Reject { object_id: id, created_at: at, reason } if id == object_id && at == created_at => Some(Ok(Err(reason))),
+        _ => None,
+    }).await
+}
+
+/// Fail with [io::ErrorKind::InvalidData] if an entry loaded from storage
+/// has no envelopes, as every entry is queued with at least one.
+fn check_envelopes(envelopes: &[SignedData]) -> io::Result<()> {
+    match envelopes.is_empty() {
+        true => Err(io::Error::new(io::ErrorKind::InvalidData, "Entry has no envelopes")),
+        false => Ok(()),
+    }
+}
+
+/// How long to wait after `attempts` failed deliveries
+fn backoff(attempts: u32) -> Duration {
+    RETRY_INITIAL.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(RETRY_MAX)
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io;
+    use uuid::Uuid;
+
+    use osp_data::{DataCapability, DataCodec, DataUsage, PeerCapabilities, RawData, SignedData};
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::outbox::{backoff, deliver, now_millis, DeliveryState, Outbox, StoredDeadLetter, StoredEntry, MAX_ATTEMPTS, RETRY_INITIAL, RETRY_MAX};
+    use crate::storage::{encode, MemoryStorage, Storage};
+    use crate::testing::{self, Connection};
+
+    fn envelope(data_type: Uuid, object_id: &str, codec: DataCodec) -> SignedData {
+        SignedData::unsigned("a.example".to_string(), object_id.to_string(), RawData::new(data_type, 1, codec, vec![1]))
+    }
+
+    fn capabilities(data_type: Uuid, codecs: Vec<DataCodec>) -> PeerCapabilities {
+        let capabilities = [DataCapability { data_type, min_version: 1, max_version: 1, usage: DataUsage::BOTH, codecs }];
+        PeerCapabilities::negotiate(&capabilities, &capabilities)
+    }
+
+    /// Acknowledge every object sent, apart from rejecting the ones called
+    /// `invalid`
+    fn follower() -> Connection {
+        testing::serve(|packet, sender| async move {
+            if let TransferPacket::Publish { envelope: SignedData { object_id, created_at, .. }, .. } = packet {
+                let reply = match object_id.as_str() {
+                    "invalid" => TransferPacket::Reject { object_id, created_at, reason: "Missing title".to_string() },
+                    _ => TransferPacket::Ack { object_id, created_at },
+                };
+                sender.send(reply).await?;
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), RETRY_INITIAL);
+        assert_eq!(backoff(3), RETRY_INITIAL * 4);
+        assert_eq!(backoff(40), RETRY_MAX);
+    }
+
+    #[test]
+    fn test_entries_survive_restart() -> io::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let data_type = Uuid::new_v4();
+        let outbox = Outbox::open(storage.clone())?;
+        outbox.push("b.example", Some("rust".to_string()), vec![envelope(data_type, "post/1", DataCodec::Bincode)])?;
+        outbox.push("b.example", None, vec![envelope(data_type, "post/2", DataCodec::Bincode)])?;
+        outbox.push("c.example", None, vec![envelope(data_type, "post/1", DataCodec::Json)])?;
+        outbox.retry_later(&outbox.pending("c.example")[0], "Connection refused".to_string())?;
+        assert!(outbox.push("c.example", None, vec![]).is_err());
+
+        let reopened = Outbox::open(storage)?;
+        assert_eq!(reopened.peers(), vec!["b.example", "c.example"]);
+        assert_eq!(reopened.pending("b.example"), outbox.pending("b.example"));
+        assert_eq!(reopened.pending("c.example")[0].attempts, 1);
+        assert_eq!(reopened.pending("c.example")[0].last_error.as_deref(), Some("Connection refused"));
+        assert_eq!(reopened.due_peers(now_millis()), vec!["b.example"]);
+        assert!(reopened.next_due().unwrap() <= now_millis());
+
+        reopened.retry_now("c.example")?;
+        assert_eq!(reopened.due_peers(now_millis()).len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_undecodable_entries_quarantined() -> io::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let outbox = Outbox::open(storage.clone())?;
+        outbox.push("b.example", None, vec![envelope(Uuid::new_v4(), "post/1", DataCodec::Bincode)])?;
+        let key = outbox.pending("b.example")[0].key.clone();
+        storage.put(&key, b"\xff")?;
+        storage.put("dead-letters/b.example/7", b"")?;
+        // Entries without envelopes have nothing to deliver
+        let empty = StoredEntry { topic: None, envelopes: vec![], attempts: 0, next_attempt: 0, last_error: None };
+        storage.put("outbox/c.example/8", &encode(empty)?)?;
+        let empty = StoredDeadLetter { topic: None, envelopes: vec![], attempts: 1, reason: "Rejected".to_string(), failed_at: 0 };
+        storage.put("dead-letters/c.example/9", &encode(empty)?)?;
+
+        let reopened = Outbox::open(storage.clone())?;
+        assert!(reopened.pending("b.example").is_empty());
+        assert!(reopened.peers().is_empty());
+        assert!(reopened.dead_letters().is_empty());
+        assert_eq!(storage.get(&format!("quarantine/{key}"))?.as_deref(), Some(&b"\xff"[..]));
+        assert_eq!(storage.keys("quarantine/")?.len(), 4);
+        assert!(storage.get(&key)?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deliver_until_acknowledged() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let outbox = Arc::new(Outbox::open(Arc::new(MemoryStorage::new()))?);
+        outbox.push("b.example", None, vec![envelope(data_type, "post/1", DataCodec::Json), envelope(data_type, "post/1", DataCodec::Bincode)])?;
+        outbox.push("b.example", None, vec![envelope(Uuid::new_v4(), "unsupported", DataCodec::Bincode)])?;
+        outbox.push("b.example", None, vec![envelope(data_type, "post/2", DataCodec::Bincode)])?;
+        outbox.push("b.example", None, vec![envelope(data_type, "invalid", DataCodec::Bincode)])?;
+
+        let claim = outbox.claim("b.example").unwrap();
+        assert!(outbox.claim("b.example").is_none());
+        assert!(outbox.due_peers(now_millis()).is_empty());
+
+        let (sender, mut receiver) = follower();
+        let capabilities = capabilities(data_type, vec![DataCodec::Bincode]);
+        deliver(&outbox, &sender, &mut receiver, &capabilities, claim.due()).await?;
+
+        assert!(outbox.pending("b.example").is_empty());
+        assert!(claim.due().is_empty());
+        drop(claim);
+        assert!(outbox.claim("b.example").is_some());
+
+        // Retrying won't make the peer take a type it can't receive
+        let dead_letters = outbox.dead_letters();
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(dead_letters[0].object_id(), "unsupported");
+        assert!(dead_letters[0].reason.contains("can't receive objects of type"));
+        assert_eq!((dead_letters[1].object_id(), dead_letters[1].reason.as_str()), ("invalid", "Missing title"));
+        assert!(matches!(
+            &outbox.object_status("post/1")?[..],
+            [status] if status.peer == "b.example" && matches!(status.state, DeliveryState::Delivered { .. })
+        ));
+        let states: Vec<_> = outbox.peer_status("b.example")?.into_iter().map(|status| (status.object_id, status.state)).collect();
+        assert_eq!(states.len(), 4);
+        assert!(matches!(&states[0], (id, DeliveryState::Failed { attempts: 1, .. }) if id == "unsupported"));
+        assert!(matches!(&states[1], (id, DeliveryState::Failed { attempts: 1, .. }) if id == "invalid"));
+        assert!(outbox.peer_status("b.exampl")?.is_empty());
+
+        outbox.requeue(&dead_letters[1])?;
+        assert_eq!(outbox.dead_letters().len(), 1);
+        assert_eq!(outbox.pending("b.example").len(), 1);
+        receiver.shutdown().await
+    }
+
+    #[test]
+    fn test_delivery_records_expire() -> io::Result<()> {
+        let outbox = Outbox::open(Arc::new(MemoryStorage::new()))?;
+        let day = 24 * 60 * 60 * 1000;
+        let now = now_millis();
+        outbox.record_delivery("b.example", "post/1", now - 40 * day)?;
+        outbox.record_delivery("c.example", "post/1", now - 40 * day)?;
+        // Delivered again since, which is what counts
+        outbox.record_delivery("c.example", "post/1", now - day)?;
+        outbox.record_delivery("b.example", "post/1/reply", now - day)?;
+
+        let data_type = Uuid::new_v4();
+        outbox.push("b.example", None, vec![envelope(data_type, "post/2", DataCodec::Bincode)])?;
+        outbox.acknowledge(&outbox.pending("b.example")[0])?;
+        let peers = |object_id| -> io::Result<Vec<String>> {
+            Ok(outbox.object_status(object_id)?.into_iter().map(|status| status.peer).collect())
+        };
+        assert_eq!(peers("post/1")?, vec!["c.example"]);
+        assert_eq!(peers("post/1/reply")?, vec!["b.example"]);
+        assert_eq!(peers("post/2")?, vec!["b.example"]);
+        assert_eq!(outbox.peer_status("b.example")?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_letter_after_max_attempts() -> io::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+        let outbox = Outbox::open(storage.clone())?;
+        outbox.push("gone.example", None, vec![envelope(Uuid::new_v4(), "post/1", DataCodec::Bincode)])?;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let pending = outbox.pending("gone.example");
+            assert_eq!(pending.len(), 1, "attempt {attempt}");
+            outbox.retry_later(&pending[0], format!("No route to host {attempt}"))?;
+        }
+        assert!(outbox.peers().is_empty());
+
+        let reopened = Outbox::open(storage)?;
+        let dead_letters = reopened.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!((dead_letters[0].attempts, dead_letters[0].reason.clone()), (MAX_ATTEMPTS, format!("No route to host {MAX_ATTEMPTS}")));
+        assert!(matches!(
+            &reopened.object_status("post/1")?[..],
+            [status] if matches!(status.state, DeliveryState::Failed { attempts: MAX_ATTEMPTS, .. })
+        ));
+        reopened.discard(&dead_letters[0])?;
+        assert!(Outbox::open(reopened.storage.clone())?.dead_letters().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_loss_retries_the_rest() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let outbox = Arc::new(Outbox::open(Arc::new(MemoryStorage::new()))?);
+        for object_id in ["post/1", "post/2"] {
+            outbox.push("b.example", None, vec![envelope(data_type, object_id, DataCodec::Bincode)])?;
+        }
+
+        // The follower goes away without acknowledging anything
+        let ((sender, mut receiver), host) = testing::pipe();
+        drop(host);
+        let capabilities = capabilities(data_type, vec![DataCodec::Bincode]);
+        let claim = outbox.claim("b.example").unwrap();
+        assert!(deliver(&outbox, &sender, &mut receiver, &capabilities, claim.due()).await.is_err());
+        assert!(outbox.pending("b.example").iter().all(|entry| entry.attempts == 1 && entry.next_attempt > now_millis()));
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+//! Asking a peer for something over a connection that may carry other
+//! traffic at the same time.
+
+use log::debug;
+
+use tokio::io;
+use tokio_stream::StreamExt;
+
+use osp_protocol::{ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::TransferPacket;
+
+/// Send `request` and wait until `reply` picks the answer out of the packets
+/// that follow. `reply` is handed every packet apart from pings,
+/// which are answered, and returns [None] until the answer is complete.
+/// `during` names the request in errors, such as "a fetch".
+pub(crate) async fn request_reply<T>(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    request: TransferPacket,
+    during: &str,
+    mut reply: impl FnMut(TransferPacket) -> Option<io::Result<T>>,
+) -> io::Result<T> {
+    sender.send(request).await?;
+
+    while let Some(packet) = receiver.next().await {
+        match packet? {
+            TransferPacket::Ping { nonce } => sender.send(TransferPacket::Pong { nonce }).await?,
+            TransferPacket::Close { err } => return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                err.unwrap_or_else(|| format!("Peer closed the connection during {during}"))
+            )),
+            packet => match reply(packet) {
+                Some(answer) => return answer,
+                None => debug!("Waiting for the answer to {during}"),
+            },
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Connection ended during {during}")))
+}
+
+/// Tell the peer we are done and wait for queued packets to be written. The
+/// connection may already be gone if a request on it failed, in which case
+/// there is nobody left to tell.
+pub(crate) async fn close_quietly(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: ProtocolReceiver<TransferPacket>,
+) -> io::Result<()> {
+    let _ = sender.send(TransferPacket::Close { err: None }).await;
+    receiver.shutdown().await
+}
@@ -0,0 +1,95 @@
+//! Asking a peer what one of its data types contains.
+
+use tokio::io;
+
+use uuid::Uuid;
+
+use osp_data::{DataRegistry, DataSchema};
+use osp_protocol::{ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::request::request_reply;
+
+/// The answer to a [TransferPacket::SchemaRequest] for `data_type`.
+pub(crate) fn schema_response(registry: &DataRegistry, data_type: Uuid) -> TransferPacket {
+    TransferPacket::Schema {
+        data_type,
+        schema: registry.schema(&data_type).cloned(),
+    }
+}
+
+/// Ask the peer for the schema of `data_type` and wait for its answer, see
+/// [request_reply].
+pub(crate) async fn fetch_schema(
+    sender: &ProtocolSender<TransferPacket>,
+    receiver: &mut ProtocolReceiver<TransferPacket>,
+    data_type: Uuid,
+) -> io::Result<DataSchema> {
+    let request = TransferPacket::SchemaRequest { data_type };
+    request_reply(sender, receiver, request, "a schema request", |packet| match packet {
+        TransferPacket::Schema { data_type: id, schema } if id == data_type => {
+            Some(schema.ok_or_else(|| io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Peer has no schema for data type {data_type}")
+            )))
+        }
+        _ => None,
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bincode::{Decode, Encode};
+    use tokio::io;
+    use uuid::{uuid, Uuid};
+
+    use osp_data::{Data, DataRegistry, DataSchema, Describe, SchemaField, SchemaType};
+    use osp_protocol::packet::transfer::TransferPacket;
+
+    use crate::schema::{fetch_schema, schema_response};
+    use crate::testing;
+
+    #[derive(Encode, Decode)]
+    struct Post {
+        title: String,
+    }
+
+    impl Data for Post {
+        fn get_id() -> Uuid {
+            uuid!("6f1c2c3e-3d0a-4c47-9a55-04a3c5f3f0d1")
+        }
+
+        fn schema() -> Option<DataSchema> {
+            Some(DataSchema {
+                id: Self::get_id(),
+                version: 1,
+                shape: SchemaType::Struct {
+                    name: "Post".to_string(),
+                    fields: vec![SchemaField { name: "title".to_string(), shape: String::describe() }],
+                },
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_schema() -> io::Result<()> {
+        let mut registry = DataRegistry::new();
+        registry.register::<Post>().unwrap();
+        let registry = Arc::new(registry);
+        let (sender, mut receiver) = testing::serve(move |packet, sender| {
+            let registry = registry.clone();
+            async move {
+                if let TransferPacket::SchemaRequest { data_type } = packet {
+                    sender.send(schema_response(&registry, data_type)).await?;
+                }
+                Ok(())
+            }
+        });
+        assert_eq!(fetch_schema(&sender, &mut receiver, Post::get_id()).await?, Post::schema().unwrap());
+        let err = fetch_schema(&sender, &mut receiver, Uuid::new_v4()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        receiver.shutdown().await
+    }
+}
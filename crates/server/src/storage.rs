@@ -0,0 +1,300 @@
+//! # Storage
+//!
+//! Where a node keeps what has to survive a restart, such as objects waiting
+//! to be delivered. A [Storage] is a flat map from string keys to bytes;
+//! callers group their entries under a prefix of their own. Set one with
+//! [OSProtocolNode::set_storage], nodes without one keep everything in memory.
+//!
+//! Entries that can't be decoded when they are loaded are moved under
+//! `quarantine/`, with the rest of their key unchanged, and left there for
+//! someone to look at rather than keeping the node from starting.
+//!
+//! Storage calls may wait on the disk, so the node makes them on the blocking
+//! thread pool and never while holding a lock that blocks the thread waiting
+//! on it.
+//!
+//! [OSProtocolNode::set_storage]: crate::OSProtocolNode::set_storage
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use bincode::{Decode, Encode};
+
+use log::warn;
+
+use tokio::io;
+
+const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// A key-value store for node state.
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Store `value` under `key`, replacing what was there. Once this returns
+    /// the value must survive a crash.
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()>;
+
+    /// Remove `key`, which doesn't have to exist.
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Every key starting with `prefix`, in order.
+    fn keys(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Up to `limit` of the keys starting with `prefix` that sort after
+    /// `after`, or from the first without it, in order. Lets callers walk a
+    /// long prefix a little at a time. Storages that can't seek should still
+    /// only return `limit` keys.
+    fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> io::Result<Vec<String>> {
+        Ok(self.keys(prefix)?.into_iter()
+            .filter(|key| after.is_none_or(|after| key.as_str() > after))
+            .take(limit)
+            .collect())
+    }
+}
+
+/// Encode a value to keep in a [Storage].
+pub(crate) fn encode<T: Encode>(value: T) -> io::Result<Vec<u8>> {
+    bincode::encode_to_vec(value, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Decode a value kept in a [Storage] by [encode].
+pub(crate) fn decode<T: Decode>(contents: &[u8]) -> io::Result<T> {
+    let (value, _) = bincode::decode_from_slice(contents, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(value)
+}
+
+/// Move the entry under `key`, which failed to decode with `e`, to
+/// `quarantine/{key}`.
+pub(crate) fn quarantine(storage: &dyn Storage, key: &str, contents: &[u8], e: &io::Error) -> io::Result<()> {
+    warn!("Moving {key} to quarantine, as it can't be decoded: {e}");
+    storage.put(&format!("{QUARANTINE_PREFIX}{key}"), contents)?;
+    storage.delete(key)
+}
+
+/// Run `f` with `owner`, whose storage I/O could block for a while, on the
+/// blocking thread pool rather than the runtime.
+pub(crate) async fn blocking<S, T, F>(owner: &Arc<S>, f: F) -> io::Result<T>
+where
+    S: Send + Sync + ?Sized + 'static,
+    T: Send + 'static,
+    F: FnOnce(&S) -> io::Result<T> + Send + 'static,
+{
+    let owner = owner.clone();
+    tokio::task::spawn_blocking(move || f(&owner)).await?
+}
+
+/// Storage that is lost when the node stops.
+#[derive(Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.entries.lock().unwrap().insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.keys_after(prefix, None, usize::MAX)
+    }
+
+    fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> io::Result<Vec<String>> {
+        Ok(self.entries.lock().unwrap().range((start_bound(prefix, after), Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Storage with one file per key in a directory. Keys are escaped into file
+/// names, so they can contain any character.
+///
+/// The keys are read once when the directory is opened and then kept in
+/// memory, so listing them never has to read the directory again. Nothing
+/// else should write to the directory while it is open.
+pub struct FileStorage {
+    root: PathBuf,
+    keys: Mutex<BTreeSet<String>>,
+}
+
+impl FileStorage {
+    /// Use the directory at `root`, creating it if needed.
+    pub fn open(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        let mut keys = BTreeSet::new();
+        for entry in fs::read_dir(&root)? {
+            let name = entry?.file_name();
+            // Anything that isn't an escaped key, such as a temporary file
+            // left by a crash, is skipped
+            if let Some(key) = name.to_str().and_then(unescape) {
+                keys.insert(key);
+            }
+        }
+        Ok(FileStorage { root, keys: Mutex::new(keys) })
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.root.join(escape(key))) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        // Written next to the file and renamed over it, so a crash never
+        // leaves it half written. Escaped keys never start with `~`.
+        let name = escape(key);
+        let temporary = self.root.join(format!("~{name}"));
+        let mut file = File::create(&temporary)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+        fs::rename(temporary, self.root.join(name))?;
+        self.keys.lock().unwrap().insert(key.to_string());
+        // The rename is only sure to survive a crash once the directory is
+        // synced too
+        File::open(&self.root)?.sync_all()
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.root.join(escape(key))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => {
+                self.keys.lock().unwrap().remove(key);
+                Ok(())
+            }
+        }
+    }
+
+    fn keys(&self, prefix: &str) -> io::Result<Vec<String>> {
+        self.keys_after(prefix, None, usize::MAX)
+    }
+
+    fn keys_after(&self, prefix: &str, after: Option<&str>, limit: usize) -> io::Result<Vec<String>> {
+        Ok(self.keys.lock().unwrap().range((start_bound(prefix, after), Bound::Unbounded))
+            .take_while(|key| key.starts_with(prefix))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+/// Where a [Storage::keys_after] walk over sorted keys starts.
+fn start_bound(prefix: &str, after: Option<&str>) -> Bound<String> {
+    match after {
+        Some(after) if after >= prefix => Bound::Excluded(after.to_string()),
+        _ => Bound::Included(prefix.to_string()),
+    }
+}
+
+/// Escape everything but ASCII letters, digits, `-`, `_` and `.` as `%XX`.
+fn escape(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                bytes.push(byte);
+                rest = tail;
+            }
+            _ => return None,
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+    use uuid::Uuid;
+
+    use crate::storage::{escape, unescape, FileStorage, MemoryStorage, Storage};
+
+    fn exercise(storage: &dyn Storage) -> io::Result<()> {
+        storage.put("outbox/a.example/2", b"two")?;
+        storage.put("outbox/a.example/1", b"one")?;
+        storage.put("outbox/b.example/1", b"other")?;
+        storage.put("inbox/post/1 ~%", b"odd")?;
+        assert_eq!(storage.get("outbox/a.example/1")?.as_deref(), Some(&b"one"[..]));
+        assert_eq!(storage.get("inbox/post/1 ~%")?.as_deref(), Some(&b"odd"[..]));
+        assert_eq!(storage.keys("outbox/a.example/")?, vec!["outbox/a.example/1", "outbox/a.example/2"]);
+
+        storage.put("outbox/a.example/1", b"replaced")?;
+        assert_eq!(storage.get("outbox/a.example/1")?.as_deref(), Some(&b"replaced"[..]));
+        storage.delete("outbox/a.example/1")?;
+        storage.delete("outbox/a.example/1")?;
+        assert_eq!(storage.get("outbox/a.example/1")?, None);
+        assert_eq!(storage.keys("outbox/")?, vec!["outbox/a.example/2", "outbox/b.example/1"]);
+
+        assert_eq!(storage.keys_after("outbox/", None, 1)?, vec!["outbox/a.example/2"]);
+        assert_eq!(storage.keys_after("outbox/", Some("outbox/a.example/2"), 5)?, vec!["outbox/b.example/1"]);
+        assert_eq!(storage.keys_after("outbox/", Some("inbox/"), 5)?.len(), 2);
+        assert!(storage.keys_after("outbox/", Some("outbox/b.example/1"), 5)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_storage() -> io::Result<()> {
+        exercise(&MemoryStorage::new())
+    }
+
+    #[test]
+    fn test_file_storage() -> io::Result<()> {
+        let root = std::env::temp_dir().join(format!("osp-storage-{}", Uuid::new_v4()));
+        exercise(&FileStorage::open(root.clone())?)?;
+        // Reopening sees the same entries
+        assert_eq!(FileStorage::open(root.clone())?.keys("outbox/")?.len(), 2);
+        std::fs::remove_dir_all(root)
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        for key in ["outbox/a.example/1", "%41", "~", "日本"] {
+            assert_eq!(unescape(&escape(key)).as_deref(), Some(key));
+        }
+        assert_eq!(unescape("~outbox"), None);
+        assert_eq!(unescape("%4"), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! # Subscriptions
+//!
+//! Peers subscribe to a data type with [TransferPacket::Subscribe], optionally
+//! narrowed down to a topic, and are sent every new object of that type the
+//! node publishes as a [TransferPacket::Publish]. [Subscriptions] keeps track
+//! of who asked for what on the publishing side, [Subscription] is the
+//! subscribing side's end of one connection.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{debug, warn};
+
+use tokio::io;
+use tokio_stream::StreamExt;
+
+use uuid::Uuid;
+
+use osp_data::{DataCodec, PeerCapabilities, SignedData};
+use osp_protocol::{ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::envelope::Verifier;
+use crate::request::close_quietly;
+
+/// Identifies a connected peer in [Subscriptions]
+pub type PeerId = u64;
+
+struct Peer {
+    sender: ProtocolSender<TransferPacket>,
+    capabilities: Arc<PeerCapabilities>,
+    /// Data types and topics, where no topic matches every object of the type
+    topics: HashSet<(Uuid, Option<String>)>,
+}
+
+impl Peer {
+    fn wants(&self, data_type: Uuid, topic: Option<&str>) -> bool {
+        self.topics.contains(&(data_type, None))
+            || topic.is_some_and(|topic| self.topics.contains(&(data_type, Some(topic.to_string()))))
+    }
+}
+
+/// The subscriptions of every connected peer. Connection handlers add their
+/// peer when the connection starts, pass it the peer's packets with
+/// [Subscriptions::handle] and remove it once the connection ends.
+#[derive(Default)]
+pub struct Subscriptions {
+    peers: Mutex<HashMap<PeerId, Peer>>,
+    next_id: AtomicU64,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking a connected peer. Objects are sent through `sender`,
+    /// encoded as `capabilities` says the peer can receive them.
+    pub fn add_peer(&self, sender: ProtocolSender<TransferPacket>, capabilities: Arc<PeerCapabilities>) -> PeerId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.peers.lock().unwrap().insert(id, Peer { sender, capabilities, topics: HashSet::new() });
+        id
+    }
+
+    /// Forget a peer and all of its subscriptions.
+    pub fn remove_peer(&self, peer: PeerId) {
+        self.peers.lock().unwrap().remove(&peer);
+    }
+
+    /// Subscribe `peer` to objects of `data_type`, or only to those published
+    /// under `topic` if one is given. Returns false if the peer isn't known.
+    pub fn subscribe(&self, peer: PeerId, data_type: Uuid, topic: Option<String>) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(peer) = peers.get_mut(&peer) else {
+            return false;
+        };
+        if !peer.capabilities.can_send(&data_type) {
+            warn!("Peer subscribed to {data_type}, which can't be sent to it");
+        }
+        peer.topics.insert((data_type, topic));
+        true
+    }
+
+    /// Undo a [Subscriptions::subscribe] with the same type and topic.
+    pub fn unsubscribe(&self, peer: PeerId, data_type: Uuid, topic: Option<String>) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&peer) {
+            peer.topics.remove(&(data_type, topic));
+        }
+    }
+
+    pub fn is_subscribed(&self, peer: PeerId, data_type: Uuid, topic: Option<&str>) -> bool {
+        self.peers.lock().unwrap().get(&peer).is_some_and(|peer| peer.wants(data_type, topic))
+    }
+
+    /// Apply `packet` if it is a [TransferPacket::Subscribe] or
+    /// [TransferPacket::Unsubscribe] from `peer`, returning whether it was.
+    pub fn handle(&self, peer: PeerId, packet: &TransferPacket) -> bool {
+        match packet {
+            TransferPacket::Subscribe { data_type, topic } => {
+                self.subscribe(peer, *data_type, topic.clone());
+                true
+            }
+            TransferPacket::Unsubscribe { data_type, topic } => {
+                self.unsubscribe(peer, *data_type, topic.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Send an object of `data_type` to every connected peer subscribed to it
+    /// under `topic`. `envelope` is called once for each codec the
+    /// subscribers need. Returns how many peers it was sent to.
+    pub(crate) async fn publish<F>(&self, data_type: Uuid, topic: Option<String>, mut envelope: F) -> io::Result<usize>
+    where
+        F: FnMut(DataCodec) -> io::Result<SignedData>,
+    {
+        let subscribers: Vec<_> = {
+            let mut peers = self.peers.lock().unwrap();
+            // Handlers that didn't remove their peer leave a closed sender behind
+            peers.retain(|_, peer| !peer.sender.is_closed());
+            peers.values()
+                .filter(|peer| peer.wants(data_type, topic.as_deref()))
+                .filter_map(|peer| match peer.capabilities.send_codec(&data_type) {
+                    Some(codec) => Some((peer.sender.clone(), codec)),
+                    None => {
+                        debug!("Skipping a subscriber that can't receive {data_type}");
+                        None
+                    }
+                })
+                .collect()
+        };
+
+        let mut envelopes = HashMap::new();
+        let mut sent = 0;
+        for (sender, codec) in subscribers {
+            let signed = match envelopes.entry(codec) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(envelope(codec)?),
+            };
+            let packet = TransferPacket::Publish { topic: topic.clone(), envelope: signed.clone() };
+            match sender.send(packet).await {
+                Ok(()) => sent += 1,
+                Err(e) => debug!("Failed to send to a subscriber: {e}"),
+            }
+        }
+        Ok(sent)
+    }
+}
+
+/// An object sent for a [Subscription]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Publication {
+    /// The topic the object was published under
+    pub topic: Option<String>,
+    pub envelope: SignedData,
+}
+
+/// A subscription to one data type and topic on a peer, over a connection of
+/// its own.
+pub struct Subscription {
+    data_type: Uuid,
+    topic: Option<String>,
+    sender: ProtocolSender<TransferPacket>,
+    receiver: ProtocolReceiver<TransferPacket>,
+    verifier: Arc<Verifier>,
+}
+
+impl Subscription {
+    /// Subscribe to `data_type` and `topic` over an established connection.
+    pub(crate) async fn open(
+        sender: ProtocolSender<TransferPacket>,
+        receiver: ProtocolReceiver<TransferPacket>,
+        data_type: Uuid,
+        topic: Option<String>,
+        verifier: Arc<Verifier>,
+    ) -> io::Result<Self> {
+        sender.send(TransferPacket::Subscribe { data_type, topic: topic.clone() }).await?;
+        Ok(Subscription { data_type, topic, sender, receiver, verifier })
+    }
+
+    pub fn data_type(&self) -> Uuid {
+        self.data_type
+    }
+
+    pub fn topic(&self) -> Option<&str> {
+        self.topic.as_deref()
+    }
+
+    /// Wait for the next object the peer publishes, answering pings in the
+    /// meantime. Returns `None` once the connection ends. Objects whose
+    /// signature doesn't match their origin's key are dropped, see
+    /// [OSProtocolNode::verify_data].
+    ///
+    /// [OSProtocolNode::verify_data]: crate::OSProtocolNode::verify_data
+    pub async fn next(&mut self) -> io::Result<Option<Publication>> {
+        while let Some(packet) = self.receiver.next().await {
+            match packet? {
+                TransferPacket::Publish { topic, envelope } => match self.verifier.verify(&envelope).await {
+                    Ok(()) => return Ok(Some(Publication { topic, envelope })),
+                    Err(e) if e.kind() == io::ErrorKind::PermissionDenied => warn!("Dropping {}: {e}", envelope.object_id),
+                    Err(e) => return Err(e),
+                },
+                TransferPacket::Ping { nonce } => self.sender.send(TransferPacket::Pong { nonce }).await?,
+                TransferPacket::Close { err: Some(err) } => return Err(io::Error::new(io::ErrorKind::ConnectionAborted, err)),
+                TransferPacket::Close { err: None } => return Ok(None),
+                _ => debug!("Skipping unrelated packet on a subscription"),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Tell the peer to stop sending objects and close the connection.
+    pub async fn unsubscribe(self) -> io::Result<()> {
+        // The connection may already be gone, in which case there is nothing
+        // left to unsubscribe from
+        let _ = self.sender.send(TransferPacket::Unsubscribe { data_type: self.data_type, topic: self.topic }).await;
+        close_quietly(&self.sender, self.receiver).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io;
+    use tokio_stream::StreamExt;
+    use uuid::Uuid;
+
+    use osp_data::{DataCapability, DataCodec, DataUsage, PeerCapabilities, RawData, SignedData};
+
+    use crate::subscription::{PeerId, Subscription, Subscriptions};
+    use crate::testing;
+
+    fn capabilities(data_type: Uuid, codecs: Vec<DataCodec>) -> Arc<PeerCapabilities> {
+        let capabilities = [DataCapability { data_type, min_version: 1, max_version: 1, usage: DataUsage::BOTH, codecs }];
+        Arc::new(PeerCapabilities::negotiate(&capabilities, &capabilities))
+    }
+
+    /// Connect a subscriber to `subscriptions`, which handles its packets on
+    /// a task of its own.
+    async fn connect(subscriptions: &Arc<Subscriptions>, capabilities: Arc<PeerCapabilities>, data_type: Uuid, topic: Option<String>) -> io::Result<(PeerId, Subscription)> {
+        let ((guest_sender, guest_receiver), (sender, mut receiver)) = testing::pipe();
+        let peer = subscriptions.add_peer(sender, capabilities);
+        let handler = subscriptions.clone();
+        tokio::spawn(async move {
+            while let Some(Ok(packet)) = receiver.next().await {
+                handler.handle(peer, &packet);
+            }
+            handler.remove_peer(peer);
+        });
+
+        let subscription = Subscription::open(guest_sender, guest_receiver, data_type, topic.clone(), testing::verifier()).await?;
+        while !subscriptions.is_subscribed(peer, data_type, topic.as_deref()) {
+            tokio::task::yield_now().await;
+        }
+        Ok((peer, subscription))
+    }
+
+    fn envelope(data_type: Uuid, codec: DataCodec) -> io::Result<SignedData> {
+        Ok(testing::signed("post/1", RawData::new(data_type, 1, codec, vec![1])))
+    }
+
+    #[tokio::test]
+    async fn test_publish_by_topic() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let subscriptions = Arc::new(Subscriptions::new());
+        let (_, mut everything) = connect(&subscriptions, capabilities(data_type, vec![DataCodec::Json]), data_type, None).await?;
+        let (peer, mut rust) = connect(&subscriptions, capabilities(data_type, vec![DataCodec::Bincode]), data_type, Some("rust".to_string())).await?;
+        assert!(!subscriptions.is_subscribed(peer, data_type, Some("go")));
+
+        assert_eq!(subscriptions.publish(data_type, Some("go".to_string()), |codec| envelope(data_type, codec)).await?, 1);
+        assert_eq!(subscriptions.publish(Uuid::new_v4(), None, |codec| envelope(data_type, codec)).await?, 0);
+        assert_eq!(subscriptions.publish(data_type, Some("rust".to_string()), |codec| envelope(data_type, codec)).await?, 2);
+
+        let first = everything.next().await?.unwrap();
+        assert_eq!((first.topic.as_deref(), first.envelope.data.codec), (Some("go"), DataCodec::Json));
+        assert_eq!(everything.next().await?.unwrap().topic.as_deref(), Some("rust"));
+        let published = rust.next().await?.unwrap();
+        assert_eq!((published.topic.as_deref(), published.envelope.data.codec), (Some("rust"), DataCodec::Bincode));
+
+        rust.unsubscribe().await?;
+        while subscriptions.is_subscribed(peer, data_type, Some("rust")) {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(subscriptions.publish(data_type, Some("rust".to_string()), |codec| envelope(data_type, codec)).await?, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skips_peers_that_cant_receive() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let subscriptions = Arc::new(Subscriptions::new());
+        let (_, _subscription) = connect(&subscriptions, Arc::new(PeerCapabilities::default()), data_type, None).await?;
+        let published = subscriptions.publish(data_type, None, |_| panic!("Nothing should be encoded")).await?;
+        assert_eq!(published, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drops_forged_objects() -> io::Result<()> {
+        let data_type = Uuid::new_v4();
+        let subscriptions = Arc::new(Subscriptions::new());
+        let (_, mut subscription) = connect(&subscriptions, capabilities(data_type, vec![DataCodec::Bincode]), data_type, None).await?;
+        let forged = |codec| {
+            let mut envelope = envelope(data_type, codec)?;
+            envelope.object_id = "post/2".to_string();
+            Ok(envelope)
+        };
+        subscriptions.publish(data_type, None, forged).await?;
+        subscriptions.publish(data_type, None, |codec| envelope(data_type, codec)).await?;
+        assert_eq!(subscription.next().await?.unwrap().envelope.object_id, "post/1");
+        Ok(())
+    }
+}
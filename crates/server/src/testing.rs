@@ -0,0 +1,63 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+
+use openssl::pkey::Private;
+use openssl::rsa::Rsa;
+
+use tokio::io;
+use tokio_stream::StreamExt;
+
+use osp_data::{RawData, SignedData};
+use osp_protocol::{Protocol, ProtocolReceiver, ProtocolSender};
+use osp_protocol::packet::transfer::TransferPacket;
+
+use crate::envelope::{self, Verifier};
+
+/// The key of `a.example`, the origin of the objects in tests. Shared, as
+/// generating one is slow.
+pub(crate) fn private_key() -> &'static Rsa<Private> {
+    static KEY: OnceLock<Rsa<Private>> = OnceLock::new();
+    KEY.get_or_init(|| Rsa::generate(2048).unwrap())
+}
+
+/// A verifier that knows the key of `a.example` without looking it up.
+pub(crate) fn verifier() -> Arc<Verifier> {
+    let verifier = Verifier::new();
+    let public_key = Rsa::public_key_from_pem(&private_key().public_key_to_pem().unwrap()).unwrap();
+    verifier.insert("a.example".to_string(), public_key);
+    Arc::new(verifier)
+}
+
+/// `data` as object `object_id`, signed by `a.example`.
+pub(crate) fn signed(object_id: &str, data: RawData) -> SignedData {
+    envelope::sign(private_key(), "a.example".to_string(), object_id.to_string(), data).unwrap()
+}
+
+/// One end of a connection, split as the node does it.
+pub(crate) type Connection = (ProtocolSender<TransferPacket>, ProtocolReceiver<TransferPacket>);
+
+/// Both ends of an in-memory connection.
+pub(crate) fn pipe() -> (Connection, Connection) {
+    let (guest, host) = io::duplex(4096);
+    (Protocol::with_io(guest).split(), Protocol::with_io(host).split())
+}
+
+/// Our end of an in-memory connection. The other end hands every packet it
+/// receives to `handle` on a task of its own, along with a sender to answer
+/// with, until the connection ends or `handle` fails.
+pub(crate) fn serve<F, Fut>(mut handle: F) -> Connection
+where
+    F: FnMut(TransferPacket, ProtocolSender<TransferPacket>) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<()>> + Send,
+{
+    let (guest, (sender, mut receiver)) = pipe();
+    tokio::spawn(async move {
+        while let Some(packet) = receiver.next().await {
+            handle(packet?, sender.clone()).await?;
+        }
+        io::Result::Ok(())
+    });
+    guest
+}
@@ -4,7 +4,6 @@ use clap::{Parser};
 use log::{info};
 use openssl::rsa::Rsa;
 use tokio::io;
-use url::Url;
 use osp_protocol::OSPUrl;
 use osp_server_sdk::connection::outbound::OutboundConnection;
 
@@ -32,11 +31,10 @@ async fn main() -> io::Result<()> {
 
     let args = Args::parse();
 
-    let key_contents = fs::read_to_string(args.private_key.clone()).expect(format!("Unable to open private key file {}", args.private_key).as_str());
+    let key_contents = fs::read_to_string(args.private_key.clone()).unwrap_or_else(|_| panic!("Unable to open private key file {}", args.private_key));
     let key = Rsa::private_key_from_pem(key_contents.as_bytes()).unwrap();
 
-    let reg_url = Url::parse(args.url.as_str()).unwrap();
-    let url = OSPUrl::from(reg_url);
+    let url: OSPUrl = args.url.parse()?;
 
     info!("Starting outbound thread");
     let mut conn = OutboundConnection::create(url, key, args.hostname).await?;
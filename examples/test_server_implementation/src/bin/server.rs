@@ -1,13 +1,16 @@
-use std::net::{SocketAddr, SocketAddrV4};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::{io};
 use clap::Parser;
-use osp_server_sdk::OSProtocolNode;
+use log::info;
+use osp_server_sdk::{handler, OSProtocolNode};
+use osp_server_sdk::storage::FileStorage;
 
 /// Test implementation of an Open Syndication Protocol server node
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
-    /// IPv4 address to bind to
+    /// IPv4 or IPv6 address to bind to, `::` listens on both
     #[arg(short, long)]
     bind: String,
 
@@ -22,6 +25,19 @@ struct Args {
     /// Used to identify myself during the handshake
     #[arg(long)]
     hostname: String,
+
+    /// Where to keep the nodes following this one
+    #[arg(long)]
+    follows_file: Option<PathBuf>,
+
+    /// Directory to keep published and undelivered objects, received object
+    /// ids and sync cursors in
+    #[arg(long)]
+    storage_dir: Option<PathBuf>,
+
+    /// Approve every follow request instead of rejecting them
+    #[arg(long)]
+    accept_follows: bool,
     //
     // /// Servers to open outbound connections to
     // #[arg(long)]
@@ -34,23 +50,35 @@ async fn main() -> io::Result<()> {
     clog.init();
 
     let args = Args::parse();
-    let addr = SocketAddrV4::new(args.bind.parse().expect("Invalid bind address"), args.port);
+    let addr = SocketAddr::new(args.bind.parse::<IpAddr>().expect("Invalid bind address"), args.port);
     let mut node = OSProtocolNode::new();
-    node.set_addr(SocketAddr::from(addr));
+    node.set_addr(addr);
     node.set_private_key_file(args.private_key);
     node.set_hostname(args.hostname);
-
-
-    let mut connection_node = node.init();
-    connection_node.listen(|connection, state| async move {
-
+    if let Some(path) = args.follows_file {
+        node.set_follows_file(path);
+    }
+    if let Some(path) = args.storage_dir {
+        node.set_storage(FileStorage::open(path)?);
+    }
+    if args.accept_follows {
+        node.set_follow_approval(|request| {
+            info!("Approving {} following {:?}", request.follower, request.data_types);
+            Ok(())
+        });
+    }
+    node.set_publish_handler(|publication| {
+        info!("Received {} from {}", publication.envelope.object_id, publication.envelope.origin);
         Ok(())
-    }).await?;
+    });
+
+    let mut connection_node = node.init()?;
+    connection_node.listen(handler::handle).await?;
 
     Ok(())
 
     // for uri in args.push_to {
-    //     let osp_url = OSPUrl::from(Url::parse(uri.as_str()).unwrap());
+    //     let osp_url: OSPUrl = uri.parse()?;
     //     info!("url: {osp_url}");
     //     let n = Arc::clone(&node);
     //     GLOBAL_THREAD_COUNT.fetch_add(1, Ordering::SeqCst);